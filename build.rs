@@ -1,5 +1,5 @@
 fn main() {
-    let shaders = ["fs.glsl", "vs.glsl", "blue.glsl", "draw.glsl"];
+    let shaders = ["fs.glsl", "vs.glsl", "blue.glsl", "draw.glsl", "bg.glsl"];
     for shader in &shaders {
         println!("cargo: rerun-if-changed=src/{}", shader);
     }
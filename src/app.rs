@@ -1,32 +1,37 @@
-use std::cell::{Cell, RefCell};
+use std::cell::RefCell;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::time::Instant;
 
-use byte_set::ByteSet;
 use once_cell::unsync::OnceCell;
 
 use native_windows_gui as nwg;
 use nwg::Event;
 
-type Canvas = crate::gl::OpenGlCanvas;
-use crate::drawing::{Command, CommandKind};
+type Canvas = crate::gl::OpenGlCanvas<Win32Surface>;
+use crate::ass;
+use crate::background;
+use crate::clipboard::{Clipboard, WindowsClipboard};
+use crate::document::{BackgroundTransform, FillRule};
+use crate::drawing::{CommandKind, NodeKind};
+use crate::editor::{Editor, InputEvent, Key, MouseButton, Request, Response};
+use crate::export::{self, ExportSettings};
+use crate::gl::win32::Win32Surface;
 use crate::nwg_util::SaneBuilder;
 use crate::point::Point;
+use crate::project::Project;
+use crate::raster::RasterMode;
+use crate::recovery::{self, Autosave};
 
-fn change_scale(mut scale: f32, factor: i32) -> f32 {
-    assert!(scale > 0.0);
-    if scale < 1.0 {
-        scale = scale.recip().round();
-        scale = -scale + 2.0;
-    }
+const GRID_SPACING: f32 = 10.0;
 
-    scale += factor as f32;
+const TITLE: &str = "nwg";
 
-    if scale < 1.0 {
-        scale = (scale - 2.0).abs();
-        scale = scale.recip();
-    }
-    scale
-}
+/// Raw event handlers need IDs of their own, above the ones nwg reserves.
+const SLIDER_HANDLER_ID: usize = 0x10000;
+
+/// How often to check whether the session needs autosaving, in milliseconds.
+const AUTOSAVE_CHECK_INTERVAL: u32 = 5000;
 
 #[derive(Default)]
 pub struct AppBuilder;
@@ -36,6 +41,7 @@ pub struct AppInner {
     pub canvas: OnceCell<Canvas>,
     canvas_handler: OnceCell<nwg::EventHandler>,
     paste_image_btn: nwg::Button,
+    open_image_btn: nwg::Button,
     clear_drawing_btn: nwg::Button,
     copy_drawing_btn: nwg::Button,
     drawing_color_btn: nwg::Button,
@@ -44,50 +50,62 @@ pub struct AppInner {
     move_mode_btn: nwg::RadioButton,
     line_mode_btn: nwg::RadioButton,
     bezier_mode_btn: nwg::RadioButton,
+    snap_check: nwg::CheckBox,
+    grid_check: nwg::CheckBox,
+    corner_node_btn: nwg::Button,
+    smooth_node_btn: nwg::Button,
+    symmetric_node_btn: nwg::Button,
+    auto_smooth_btn: nwg::Button,
+    save_project_btn: nwg::Button,
+    open_project_btn: nwg::Button,
+    export_btn: nwg::Button,
+    add_layer_btn: nwg::Button,
+    remove_layer_btn: nwg::Button,
+    layer_up_btn: nwg::Button,
+    layer_down_btn: nwg::Button,
+    layer_visible_check: nwg::CheckBox,
+    layer_locked_check: nwg::CheckBox,
+    layer_even_odd_check: nwg::CheckBox,
+    /// The active layer's effects, as override tags.
+    effects_input: nwg::TextInput,
+    effects_btn: nwg::Button,
+    png_btn: nwg::Button,
+    libass_check: nwg::CheckBox,
+    /// The document's layers, top one first.
+    layer_list: nwg::ListBox<String>,
     color_dialog: nwg::ColorDialog,
-
-    left_dragging: Cell<bool>,
-    right_dragging: Cell<bool>,
-    dragged_point: Cell<Option<usize>>,
-    pre_drag_pos: Cell<Point<f32>>,
-    drag_start_pos: Cell<Point<i32>>,
-    draw_mode: Cell<CommandKind>,
-    keys: RefCell<Keys>,
-}
-
-#[derive(Debug, Default, Copy, Clone)]
-struct Keys {
-    keys: ByteSet,
-}
-
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-enum KeyState {
-    Pressed,
-    Released,
-    Up,
-    Down,
+    save_dialog: nwg::FileDialog,
+    open_dialog: nwg::FileDialog,
+    png_dialog: nwg::FileDialog,
+    image_dialog: nwg::FileDialog,
+    /// The background's offset, scale and rotation, as "x y scale rotation".
+    bg_transform_input: nwg::TextInput,
+    bg_transform_btn: nwg::Button,
+    bg_opacity_slider: nwg::TrackBar,
+    bg_locked_check: nwg::CheckBox,
+    autosave_timer: nwg::Timer,
+
+    editor: RefCell<Editor>,
+    autosave: RefCell<Autosave>,
+    clipboard: RefCell<WindowsClipboard>,
 }
 
-impl Keys {
-    pub fn update(&mut self, ev: nwg::Event, key: u32) -> KeyState {
-        let key = key as u8;
-        let old = self.keys.contains(key);
-        match ev {
-            nwg::Event::OnKeyPress => self.keys.insert(key),
-            nwg::Event::OnKeyRelease => self.keys.remove(key),
-            _ => (),
-        }
-        match (old, self.keys.contains(key)) {
-            (false, true) => KeyState::Pressed,
-            (true, false) => KeyState::Released,
-            (false, false) => KeyState::Up,
-            (true, true) => KeyState::Down,
-        }
-    }
-
-    pub fn pressed(&self, key: u32) -> bool {
-        self.keys.contains(key as u8)
-    }
+fn translate_key(key: u32) -> Option<Key> {
+    let key = match key {
+        nwg::keys::CONTROL => Key::Control,
+        nwg::keys::SHIFT => Key::Shift,
+        nwg::keys::ALT => Key::Alt,
+        nwg::keys::_C => Key::C,
+        nwg::keys::_V => Key::V,
+        nwg::keys::_Y => Key::Y,
+        nwg::keys::_H => Key::H,
+        nwg::keys::_Z => Key::Z,
+        nwg::keys::_0 => Key::Zero,
+        nwg::keys::_1 => Key::One,
+        nwg::keys::_2 => Key::Two,
+        _ => return None,
+    };
+    Some(key)
 }
 
 impl AppInner {
@@ -96,109 +114,275 @@ impl AppInner {
     }
 
     fn cursor_pos(&self) -> Point<i32> {
-        nwg::GlobalCursor::local_position(self.get_canvas().handle(), None).into()
+        nwg::GlobalCursor::local_position(self.get_canvas().surface().handle(), None).into()
     }
 
-    fn is_dragging(&self) -> bool {
-        self.left_dragging.get() || self.right_dragging.get()
+    /// Windows reports Alt as a system key, which never reaches our key handlers, so poll it instead.
+    fn sync_alt_key(&self) {
+        use winapi::um::winuser::{GetKeyState, VK_MENU};
+        let down = unsafe { GetKeyState(VK_MENU) } < 0;
+        if down {
+            self.handle_input(InputEvent::KeyDown(Key::Alt));
+        } else {
+            self.handle_input(InputEvent::KeyUp(Key::Alt));
+        }
     }
 
-    fn get_point_at_cursor(&self) -> Point<f32> {
-        let dims = self.get_canvas().get_dimensions();
-        let cursor_pos = self.cursor_pos().cast::<f32>();
-        dims.scene_pos + (cursor_pos / dims.scale)
+    fn handle_input(&self, event: InputEvent) {
+        let response = self.editor.borrow_mut().handle(event);
+        self.apply(response);
     }
 
-    fn add_point_at_cursor(&self) {
-        let point = self.get_point_at_cursor();
+    /// Carries out whatever the editor asked for after handling some input.
+    fn apply(&self, response: Response) {
+        match response.capture {
+            Some(true) => nwg::GlobalCursor::set_capture(self.get_canvas().surface().handle()),
+            Some(false) => nwg::GlobalCursor::release(),
+            None => (),
+        }
+
+        match response.request {
+            Some(Request::Copy(text)) => {
+                self.clipboard
+                    .borrow_mut()
+                    .set_text(&text)
+                    .unwrap_or((/* ignore */));
+            }
+            Some(Request::Paste) => self.paste(),
+            None => (),
+        }
+
+        if response.drawing_changed {
+            self.sync_layers();
+        }
+
         let canvas = self.get_canvas();
-        canvas.with_drawing(|drawing| {
-            let cmd = if drawing.points().is_empty() {
-                Command::Move(point)
-            } else {
-                match self.draw_mode.get() {
-                    CommandKind::Move => Command::Move(point),
-                    CommandKind::Line => Command::Line(point),
-                    CommandKind::Bezier => {
-                        let p0 = *drawing.points().last().unwrap();
-                        let p3 = point;
-                        let p1 = p0.lerp(p3, 0.3333);
-                        let p2 = p0.lerp(p3, 0.6667);
-                        Command::Bezier(p1, p2, p3)
-                    }
-                }
-            };
-            drawing.push(cmd);
-        });
-        canvas.render();
+        let editor = self.editor.borrow();
+        if response.drawing_changed {
+            canvas.update_layers(editor.layers(), editor.active_layer(), editor.raster_mode());
+        }
+        if response.background_changed {
+            canvas.set_image(editor.background());
+        }
+        if response.redraw {
+            let transform = editor.background_transform();
+            if transform != canvas.background_transform() {
+                self.sync_background(transform);
+                canvas.set_background_transform(transform);
+            }
+            canvas.set_colors(editor.colors());
+            canvas.update_overlay(&editor.overlay());
+            canvas.render(editor.viewport());
+        }
     }
 
     fn clear_drawing(&self) {
-        let canvas = self.get_canvas();
-        canvas.clear_drawing();
-        canvas.render();
+        let response = self.editor.borrow_mut().clear();
+        self.apply(response);
     }
 
-    fn copy_drawing(&self) -> std::fmt::Result {
-        let text = self.get_canvas().with_drawing(|drawing| {
-            let mut data = Vec::new();
-            let mut last_kind = None;
-            for cmd in drawing.commands() {
-                use pretty_dtoa::{ftoa, FmtFloatConfig};
-
-                let cfg = FmtFloatConfig::default()
-                    .max_decimal_digits(2)
-                    .force_no_e_notation()
-                    .ignore_extremes(3);
+    /// Copies every layer as a `Dialogue` line.
+    fn export_layers(&self) {
+        let text = self.editor.borrow().export_text();
+        self.apply(Response {
+            request: Some(Request::Copy(text)),
+            ..Response::default()
+        });
+    }
 
-                macro_rules! f {
-                    ($fmt:literal, $($point:expr),*) => {
-                        format!($fmt, $(ftoa($point.x, cfg), ftoa($point.y, cfg)),*)
-                    }
+    /// Makes the layer list and checkboxes match the document's layers.
+    fn sync_layers(&self) {
+        let editor = self.editor.borrow();
+        let layers = editor.layers();
+        let rows: Vec<String> = layers
+            .iter()
+            .rev()
+            .map(|layer| {
+                let settings = &layer.settings;
+                let mut row = settings.name.clone();
+                if !settings.visible {
+                    row.push_str(" (hidden)");
+                }
+                if settings.locked {
+                    row.push_str(" (locked)");
                 }
+                row
+            })
+            .collect();
+        if *self.layer_list.collection() != rows {
+            self.layer_list.set_collection(rows);
+        }
+        let active = editor.active_layer();
+        self.layer_list
+            .set_selection(Some(layers.len() - 1 - active));
+
+        let settings = &layers[active].settings;
+        let state = |checked| {
+            if checked {
+                nwg::CheckBoxState::Checked
+            } else {
+                nwg::CheckBoxState::Unchecked
+            }
+        };
+        self.layer_visible_check
+            .set_check_state(state(settings.visible));
+        self.layer_locked_check
+            .set_check_state(state(settings.locked));
+        self.layer_even_odd_check
+            .set_check_state(state(settings.fill_rule == FillRule::EvenOdd));
+        let effects = ass::format_effects(&settings.effects);
+        if self.effects_input.text() != effects {
+            self.effects_input.set_text(&effects);
+        }
+    }
 
-                let element = match cmd {
-                    Command::Move(p) | Command::Line(p) if last_kind == Some(cmd.kind()) => {
-                        f!("{} {}", p)
-                    }
-                    Command::Move(p) => f!("m {} {}", p),
-                    Command::Line(p) => f!("l {} {}", p),
-                    Command::Bezier(p1, p2, p3) => {
-                        if last_kind == Some(CommandKind::Bezier) {
-                            f!("{} {} {} {} {} {}", p1, p2, p3)
-                        } else {
-                            f!("b {} {} {} {} {} {}", p1, p2, p3)
-                        }
-                    }
-                };
-                last_kind = Some(cmd.kind());
-                data.push(element);
+    /// Makes the background controls show where the background is.
+    fn sync_background(&self, transform: BackgroundTransform) {
+        let text = format!(
+            "{} {} {} {}",
+            transform.offset.x, transform.offset.y, transform.scale, transform.rotation
+        );
+        if self.bg_transform_input.text() != text {
+            self.bg_transform_input.set_text(&text);
+        }
+        self.bg_opacity_slider
+            .set_pos((transform.opacity * 100.0).round() as usize);
+        self.bg_locked_check.set_check_state(if transform.locked {
+            nwg::CheckBoxState::Checked
+        } else {
+            nwg::CheckBoxState::Unchecked
+        });
+    }
+
+    /// Places the background where the controls say, if it isn't locked.
+    fn update_background_transform(&self) {
+        let mut editor = self.editor.borrow_mut();
+        let mut transform = editor.background_transform();
+        let numbers: Result<Vec<f32>, _> = self
+            .bg_transform_input
+            .text()
+            .split_whitespace()
+            .map(str::parse)
+            .collect();
+        match numbers.as_deref() {
+            Ok(&[x, y, scale, rotation])
+                if [x, y, scale, rotation].iter().all(|v| v.is_finite()) && scale > 0.0 =>
+            {
+                transform.offset = Point::new(x, y);
+                transform.scale = scale;
+                transform.rotation = rotation;
+            }
+            _ => {
+                drop(editor);
+                nwg::error_message(
+                    "Couldn't place the background",
+                    "Give its offset, scale and rotation as four numbers, like \"0 0 1 0\".",
+                );
+                return;
             }
-            data.join(" ")
+        }
+        transform.opacity = self.bg_opacity_slider.pos() as f32 / 100.0;
+        transform.locked = self.bg_locked_check.check_state() == nwg::CheckBoxState::Checked;
+        let response = editor.set_background_transform(transform);
+        let transform = editor.background_transform();
+        drop(editor);
+        self.apply(response);
+        // Show what the background ended up as, which isn't what was asked for if it's locked.
+        self.sync_background(transform);
+    }
+
+    /// Shows the background as strongly as the slider says, leaving it where it is.
+    fn update_background_opacity(&self) {
+        let opacity = self.bg_opacity_slider.pos() as f32 / 100.0;
+        let response = self.editor.borrow_mut().slide_background_opacity(opacity);
+        self.apply(response);
+    }
+
+    fn select_layer(&self) {
+        let row = match self.layer_list.selection() {
+            Some(row) => row,
+            None => return,
+        };
+        let mut editor = self.editor.borrow_mut();
+        let index = editor.layers().len() - 1 - row;
+        let response = editor.set_active_layer(index);
+        drop(editor);
+        self.apply(response);
+    }
+
+    fn update_layer_settings(&self) {
+        let checked = |check: &nwg::CheckBox| check.check_state() == nwg::CheckBoxState::Checked;
+        let mut editor = self.editor.borrow_mut();
+        let mut settings = editor.layers()[editor.active_layer()].settings.clone();
+        settings.visible = checked(&self.layer_visible_check);
+        settings.locked = checked(&self.layer_locked_check);
+        settings.fill_rule = if checked(&self.layer_even_odd_check) {
+            FillRule::EvenOdd
+        } else {
+            FillRule::NonZero
+        };
+        let response = editor.set_layer_settings(settings);
+        drop(editor);
+        self.apply(response);
+    }
+
+    fn update_effects(&self) {
+        let mut editor = self.editor.borrow_mut();
+        let mut settings = editor.layers()[editor.active_layer()].settings.clone();
+        settings.effects = ass::parse_effects(&self.effects_input.text(), settings.effects);
+        let response = editor.set_layer_settings(settings);
+        drop(editor);
+        self.apply(response);
+        // Show what was understood, even if nothing changed.
+        self.sync_layers();
+    }
+
+    fn edit_layers(&self, f: impl FnOnce(&mut Editor) -> Response) {
+        let response = f(&mut self.editor.borrow_mut());
+        self.apply(response);
+    }
+
+    fn copy_drawing(&self) {
+        let text = self.editor.borrow().copy_text();
+        self.apply(Response {
+            request: Some(Request::Copy(text)),
+            ..Response::default()
         });
-        clipboard_win::set_clipboard_string(&text).unwrap_or((/* ignore */));
-        Ok(())
     }
 
     fn show(self: Rc<Self>) {
         self.window.set_visible(true);
         self.window.set_focus();
 
-        let canvas = Canvas::new(&self.window);
+        let canvas = Canvas::new(Win32Surface::new(&self.window));
 
         let ui = Rc::downgrade(&self);
 
         let f = move |evt, evt_data, handle| {
             let ui = ui.upgrade().unwrap();
-            if &handle != ui.canvas.get().unwrap().handle() {
+            if &handle != ui.canvas.get().unwrap().surface().handle() {
                 return;
             }
-            match evt {
-                Event::OnMouseMove => ui.mouse_move(),
-                Event::OnMouseWheel => ui.zoom(evt_data),
-                Event::OnMousePress(mouse_evt) => ui.mouse_press(mouse_evt),
-                _ => (),
-            }
+            let pos = ui.cursor_pos();
+            let input = match evt {
+                Event::OnMouseMove => InputEvent::PointerMove(pos),
+                Event::OnMouseWheel => match evt_data {
+                    nwg::EventData::OnMouseWheel(i) => InputEvent::Wheel(i / 120, pos),
+                    _ => panic!(),
+                },
+                Event::OnMousePress(mouse_evt) => {
+                    use nwg::MousePressEvent as M;
+                    match mouse_evt {
+                        M::MousePressLeftDown => InputEvent::PointerDown(MouseButton::Left, pos),
+                        M::MousePressLeftUp => InputEvent::PointerUp(MouseButton::Left, pos),
+                        M::MousePressRightDown => InputEvent::PointerDown(MouseButton::Right, pos),
+                        M::MousePressRightUp => InputEvent::PointerUp(MouseButton::Right, pos),
+                    }
+                }
+                _ => return,
+            };
+            ui.sync_alt_key();
+            ui.handle_input(input);
         };
 
         let handler = nwg::full_bind_event_handler(&self.window.handle, f);
@@ -213,179 +397,287 @@ impl AppInner {
             .expect("canvas was already initialized");
 
         self.handle_resize();
+        self.offer_recovery();
+
+        let ui = Rc::downgrade(&self);
+        let path = self.autosave.borrow().path().to_owned();
+        recovery::install_panic_hook(path, move || {
+            let ui = ui.upgrade()?;
+            let editor = ui.editor.try_borrow().ok()?;
+            Some(editor.to_project())
+        });
+        self.autosave_timer.start();
+    }
+
+    /// Offers to pick up where the last session left off, if it crashed.
+    fn offer_recovery(&self) {
+        let path = self.autosave.borrow().path().to_owned();
+        let project = match recovery::find_recovery(&path) {
+            Some(Ok(project)) => project,
+            Some(Err(e)) => {
+                nwg::error_message("Couldn't recover the last session", &e.to_string());
+                return;
+            }
+            None => return,
+        };
+        let params = nwg::MessageParams {
+            title: "Recover unsaved work?",
+            content: "The editor didn't exit normally last time. Pick up where it left off?",
+            buttons: nwg::MessageButtons::YesNo,
+            icons: nwg::MessageIcons::Question,
+        };
+        if nwg::modal_message(&self.window, &params) == nwg::MessageChoice::Yes {
+            let response = self.editor.borrow_mut().load_project(project);
+            self.sync_controls();
+            self.apply(response);
+        }
+    }
+
+    /// A failed autosave is shown in the title bar until one succeeds, rather than in a dialog every few seconds.
+    fn autosave(&self) {
+        let editor = self.editor.borrow();
+        let result = self
+            .autosave
+            .borrow_mut()
+            .tick(Instant::now(), || editor.to_project());
+        let title = match result {
+            Ok(false) => return,
+            Ok(true) => TITLE.to_owned(),
+            Err(e) => format!("{} (autosave failed: {})", TITLE, e),
+        };
+        if self.window.text() != title {
+            self.window.set_text(&title);
+        }
     }
 
     fn handle_resize(&self) {
         if let Some(canvas) = self.canvas.get() {
-            let old_dims = canvas.get_dimensions().screen_dims.cast();
             let window_dims = Point::from(self.window.size());
             let new_dims = window_dims - Point::new(101, 1);
-            canvas.nwg_canvas().set_size(new_dims.x, new_dims.y);
-            canvas.resize();
-            if new_dims.x > old_dims.x || new_dims.y > old_dims.y {
-                canvas.render()
-            }
+            canvas
+                .surface()
+                .nwg_canvas()
+                .set_size(new_dims.x, new_dims.y);
+            let screen_dims = canvas.resize();
+            let response = self.editor.borrow_mut().resize(screen_dims);
+            self.apply(response);
         }
     }
     fn exit(&self) {
+        recovery::remove_panic_snapshot();
+        self.autosave
+            .borrow_mut()
+            .discard()
+            .unwrap_or((/* ignore */));
         nwg::stop_thread_dispatch();
     }
-    fn zoom(&self, data: nwg::EventData) {
-        let factor = match data {
-            nwg::EventData::OnMouseWheel(i) => i / 120,
-            _ => panic!(),
-        };
-        let canvas = self.get_canvas();
-        canvas.update_dimensions(|dims| {
-            // this is the same code as get_point_at_cursor
-            // TODO: figure out how to avoid RefCell rules preventing the use of that function here
-            let mouse_pos = self.cursor_pos().cast::<f32>();
-            let mouse_scene_pos = dims.scene_pos + (mouse_pos / dims.scale);
-
-            let new_scale = change_scale(dims.scale, factor);
-
-            let new_scene_pos = mouse_scene_pos - (mouse_pos / new_scale);
+    /// Pastes a drawing, a clip or a background, depending on what's on the clipboard.
+    fn paste(&self) {
+        let result = self
+            .editor
+            .borrow_mut()
+            .paste(&mut *self.clipboard.borrow_mut());
+        match result {
+            Ok(response) => self.apply(response),
+            Err(e) => {
+                nwg::error_message("Couldn't paste", &e.to_string());
+            }
+        }
+    }
 
-            if self.right_dragging.get() {
-                self.pre_drag_pos.set(new_scene_pos);
-                self.drag_start_pos.set(self.cursor_pos());
+    fn paste_image(&self) {
+        let img = match self.clipboard.borrow_mut().get_image() {
+            Ok(Some(img)) => img,
+            Ok(None) => {
+                nwg::error_message(
+                    "Couldn't paste the image",
+                    "There's no image on the clipboard.",
+                );
+                return;
             }
+            Err(e) => {
+                nwg::error_message("Couldn't paste the image", &e.to_string());
+                return;
+            }
+        };
+        let response = self.editor.borrow_mut().set_background(Some(Rc::new(img)));
+        self.apply(response);
+    }
 
-            dims.scale = new_scale;
-            dims.scene_pos = new_scene_pos;
-        });
-        canvas.render();
+    /// Loads the background from a file, which is either chosen or was dropped on the window.
+    fn open_image(&self, path: Option<PathBuf>) {
+        let path = match path.or_else(|| self.choose_path(&self.image_dialog)) {
+            Some(path) => path,
+            None => return,
+        };
+        let result = self.editor.borrow_mut().open_background(&path);
+        match result {
+            Ok(response) => self.apply(response),
+            Err(e) => {
+                nwg::error_message("Couldn't load the background", &e.to_string());
+            }
+        }
     }
-    fn mouse_move(&self) {
-        if !self.is_dragging() {
+
+    fn choose_color(&self, for_drawing: bool) {
+        if !self.color_dialog.run(Some(&self.window)) {
             return;
         }
+        let rgb = self.color_dialog.color();
+        let mut editor = self.editor.borrow_mut();
+        let mut colors = editor.colors();
+        let mut response = Response::default();
+        if for_drawing {
+            colors.drawing = rgb;
+        } else {
+            // New layers get the same color.
+            colors.shape = rgb;
+            let mut settings = editor.layers()[editor.active_layer()].settings.clone();
+            settings.color = rgb;
+            response = editor.set_layer_settings(settings);
+        }
+        let response = response.merge(editor.set_colors(colors));
+        drop(editor);
+        self.apply(response);
+    }
 
-        let xy0 = self.pre_drag_pos.get();
-        let dxy0 = self.drag_start_pos.get();
-        let dxy1 = self.cursor_pos();
-        let dxy = dxy1 - dxy0;
+    fn update_shape_alpha(&self) {
+        let mut editor = self.editor.borrow_mut();
+        let mut colors = editor.colors();
+        colors.shape_alpha = self.shape_alpha_slider.pos() as u8;
+        let response = editor.set_colors(colors);
+        drop(editor);
+        self.apply(response);
+    }
 
-        let canvas = self.get_canvas();
-        let mut should_redraw = false;
-        if self.right_dragging.get() {
-            canvas.update_dimensions(|dims| {
-                dims.scene_pos = xy0 - (dxy.cast::<f32>() / dims.scale);
-            });
-            should_redraw = true;
-        }
-        if self.left_dragging.get() {
-            if let Some(i) = self.dragged_point.get() {
-                canvas.with_drawing(|drawing| drawing.points_mut()[i] = self.get_point_at_cursor());
-                should_redraw = true;
-            }
-        }
-        if should_redraw {
-            canvas.render();
+    /// Asks where to save or open a file, with the given dialog.
+    fn choose_path(&self, dialog: &nwg::FileDialog) -> Option<PathBuf> {
+        if !dialog.run(Some(&self.window)) {
+            return None;
         }
+        dialog.get_selected_item().ok().map(PathBuf::from)
     }
-    fn mouse_press(&self, event: nwg::MousePressEvent) {
-        let was_dragging = self.is_dragging();
-        match event {
-            nwg::MousePressEvent::MousePressRightDown => {
-                self.right_dragging.set(true);
-            }
-            nwg::MousePressEvent::MousePressRightUp => {
-                self.right_dragging.set(false);
-            }
-            nwg::MousePressEvent::MousePressLeftDown => {
-                let mut drag_idx = None;
-                let cursor_pos = self.get_point_at_cursor();
-                let canvas = self.get_canvas();
-                let scale = canvas.get_dimensions().scale;
-                canvas.with_drawing(|drawing| {
-                    for (i, point) in drawing.points().iter().enumerate() {
-                        let dx = cursor_pos.x - point.x;
-                        let dy = cursor_pos.y - point.y;
-                        if f32::max(dx.abs(), dy.abs()) <= 5.0 / scale {
-                            drag_idx = Some(i);
-                            break;
-                        }
-                    }
-                });
-                if drag_idx.is_none() {
-                    self.add_point_at_cursor();
-                    drag_idx = Some(canvas.with_drawing(|d| d.points().len() - 1));
-                }
-                self.dragged_point.set(drag_idx);
 
-                self.left_dragging.set(true);
-
-                canvas.render();
-            }
-            nwg::MousePressEvent::MousePressLeftUp => {
-                self.left_dragging.set(false);
-            }
+    fn save_project(&self) {
+        let path = match self.choose_path(&self.save_dialog) {
+            Some(path) => path,
+            None => return,
+        };
+        let project = self.editor.borrow().to_project();
+        if let Err(e) = project.save_to(&path) {
+            nwg::error_message("Couldn't save the project", &e.to_string());
         }
-        match (was_dragging, self.is_dragging()) {
-            (false, true) => {
-                nwg::GlobalCursor::set_capture(self.get_canvas().handle());
-                self.drag_start_pos.set(self.cursor_pos());
-                self.pre_drag_pos
-                    .set(self.get_canvas().get_dimensions().scene_pos);
-            }
-            (true, false) => {
-                nwg::GlobalCursor::release();
-                if self.dragged_point.take().is_some() {
-                    self.get_canvas().commit_drawing();
-                }
-            }
-            _ => (),
+    }
+
+    /// Saves the shapes as an image, at the current zoom level.
+    fn export_png(&self) {
+        let path = match self.choose_path(&self.png_dialog) {
+            Some(path) => path,
+            None => return,
+        };
+        let editor = self.editor.borrow();
+        let settings = ExportSettings {
+            scale: editor.viewport().scale,
+            ..ExportSettings::default()
+        };
+        if let Err(e) = export::export_png(&editor.scene(), &settings, &path) {
+            nwg::error_message("Couldn't export the image", &e.to_string());
         }
     }
-    fn paste_image(&self) {
-        let buf = match clipboard_win::get_clipboard(clipboard_win::formats::Bitmap) {
-            Ok(buf) => buf,
-            e => {
-                println!("{:?}", e);
+
+    fn open_project(&self) {
+        let path = match self.choose_path(&self.open_dialog) {
+            Some(path) => path,
+            None => return,
+        };
+        let project = match Project::load_from(&path) {
+            Ok(project) => project,
+            Err(e) => {
+                nwg::error_message("Couldn't open the project", &e.to_string());
                 return;
             }
         };
-        let cursor = std::io::Cursor::new(&buf[..]);
-        let img = image::codecs::bmp::BmpDecoder::new(cursor).unwrap();
-        let canvas = self.get_canvas();
-        canvas.set_image(img);
-        canvas.render();
+        let response = self.editor.borrow_mut().load_project(project);
+        self.sync_controls();
+        self.apply(response);
     }
 
-    fn choose_color(&self, for_drawing: bool) {
-        if !self.color_dialog.run(Some(&self.window)) {
-            return;
-        }
-        let rgb = self.color_dialog.color();
-        let canvas = self.get_canvas();
-        if for_drawing {
-            canvas.recolor_drawing(rgb);
-        } else {
-            canvas.recolor_shape(rgb);
+    /// Makes the controls show the editor's settings, after they were changed from elsewhere.
+    fn sync_controls(&self) {
+        let editor = self.editor.borrow();
+        let state = |checked| {
+            if checked {
+                nwg::CheckBoxState::Checked
+            } else {
+                nwg::CheckBoxState::Unchecked
+            }
+        };
+        let settings = editor.snap_settings();
+        self.snap_check.set_check_state(state(settings.enabled));
+        self.grid_check
+            .set_check_state(state(settings.grid.is_some()));
+        self.shape_alpha_slider
+            .set_pos(editor.colors().shape_alpha as usize);
+        let mode_btns = [
+            (CommandKind::Move, &self.move_mode_btn),
+            (CommandKind::Line, &self.line_mode_btn),
+            (CommandKind::Bezier, &self.bezier_mode_btn),
+        ];
+        for (mode, btn) in &mode_btns {
+            if *mode == editor.draw_mode() {
+                btn.set_check_state(nwg::RadioButtonState::Checked);
+            }
         }
-        canvas.render();
     }
 
-    fn update_shape_alpha(&self) {
-        let canvas = self.get_canvas();
-        canvas.set_shape_alpha(self.shape_alpha_slider.pos() as u8);
-        canvas.render();
+    fn set_draw_mode(&self, mode: CommandKind) {
+        self.editor.borrow_mut().set_draw_mode(mode);
+    }
+
+    fn set_node_kind(&self, kind: NodeKind) {
+        let response = self.editor.borrow_mut().set_node_kind(kind);
+        self.apply(response);
+    }
+
+    fn update_raster_mode(&self) {
+        let mode = match self.libass_check.check_state() {
+            nwg::CheckBoxState::Checked => RasterMode::Libass,
+            _ => RasterMode::Preview,
+        };
+        let response = self.editor.borrow_mut().set_raster_mode(mode);
+        self.apply(response);
+    }
+
+    fn update_snap_settings(&self) {
+        let checked = |check: &nwg::CheckBox| check.check_state() == nwg::CheckBoxState::Checked;
+        let mut editor = self.editor.borrow_mut();
+        let mut settings = editor.snap_settings();
+        settings.enabled = checked(&self.snap_check);
+        settings.grid = if checked(&self.grid_check) {
+            Some(GRID_SPACING)
+        } else {
+            None
+        };
+        let response = editor.set_snap_settings(settings);
+        drop(editor);
+        self.apply(response);
     }
 }
 
 pub struct App {
     inner: Rc<AppInner>,
     handler: nwg::EventHandler,
+    slider_handler: nwg::RawEventHandler,
 }
 
 impl nwg::NativeUi<App> for AppBuilder {
     fn build_ui(_data: Self) -> Result<App, nwg::NwgError> {
         let window = nwg::Window::builder()
-            .size((600, 500))
+            .size((600, 950))
             .position((300, 300))
-            .title("nwg")
+            .title(TITLE)
             .flags(nwg::WindowFlags::MAIN_WINDOW)
+            .accept_files(true)
             .construct()?;
 
         // we'll initialize this later, eh?
@@ -425,19 +717,104 @@ impl nwg::NativeUi<App> for AppBuilder {
         let line_mode_btn = make_radio_button("line", 0, 175)?;
         let bezier_mode_btn = make_radio_button("bezier", 0, 200)?;
 
+        let make_check_box = |text, x, y, checked| {
+            let state = if checked {
+                nwg::CheckBoxState::Checked
+            } else {
+                nwg::CheckBoxState::Unchecked
+            };
+            nwg::CheckBox::builder()
+                .parent(&window)
+                .text(text)
+                .position((x, y))
+                .check_state(state)
+                .construct()
+        };
+
+        let snap_check = make_check_box("snap", 0, 225, true)?;
+        let grid_check = make_check_box("grid", 0, 250, false)?;
+
+        let corner_node_btn = make_button("corner", 0, 275)?;
+        let smooth_node_btn = make_button("smooth", 0, 300)?;
+        let symmetric_node_btn = make_button("symmetric", 0, 325)?;
+        let auto_smooth_btn = make_button("auto smooth", 0, 350)?;
+        let save_project_btn = make_button("save", 0, 375)?;
+        let open_project_btn = make_button("open", 0, 400)?;
+        let export_btn = make_button("export", 0, 425)?;
+        let add_layer_btn = make_button("add layer", 0, 450)?;
+        let remove_layer_btn = make_button("remove layer", 0, 475)?;
+        let layer_up_btn = make_button("layer up", 0, 500)?;
+        let layer_down_btn = make_button("layer down", 0, 525)?;
+        let layer_visible_check = make_check_box("visible", 0, 550, true)?;
+        let layer_locked_check = make_check_box("locked", 0, 575, false)?;
+        let layer_even_odd_check = make_check_box("even-odd", 0, 600, false)?;
+        let libass_check = make_check_box("libass", 0, 725, false)?;
+        let effects_input = nwg::TextInput::builder()
+            .parent(&window)
+            .position((0, 750))
+            .size((100, 25))
+            .text(r"\bord0\shad0")
+            .construct()?;
+        let effects_btn = make_button("set effects", 0, 775)?;
+        let png_btn = make_button("save png", 0, 800)?;
+        let open_image_btn = make_button("open bg", 0, 825)?;
+        let bg_transform_input = nwg::TextInput::builder()
+            .parent(&window)
+            .position((0, 850))
+            .size((100, 25))
+            .text("0 0 1 0")
+            .construct()?;
+        let bg_transform_btn = make_button("set bg", 0, 875)?;
+        let bg_locked_check = make_check_box("lock bg", 0, 925, false)?;
+        let layer_list = nwg::ListBox::builder()
+            .parent(&window)
+            .position((0, 625))
+            .size((100, 100))
+            .collection(vec!["Layer 1".to_string()])
+            .selected_index(Some(0))
+            .construct()?;
+
         let shape_alpha_slider = nwg::TrackBar::builder()
             .parent(&window)
             .position((0, 125))
             .construct()?;
         shape_alpha_slider.set_pos(50);
+        let bg_opacity_slider = nwg::TrackBar::builder()
+            .parent(&window)
+            .position((0, 900))
+            .range(Some(0..100))
+            .pos(Some(100))
+            .construct()?;
 
         let color_dialog = nwg::ColorDialog::builder().construct()?;
+        let make_file_dialog = |action, filters| {
+            nwg::FileDialog::builder()
+                .action(action)
+                .filters(filters)
+                .construct()
+        };
+        let project_filters = "Project(*.adproj)|Any(*.*)";
+        let save_dialog = make_file_dialog(nwg::FileDialogAction::Save, project_filters)?;
+        let open_dialog = make_file_dialog(nwg::FileDialogAction::Open, project_filters)?;
+        let png_dialog = make_file_dialog(nwg::FileDialogAction::Save, "PNG(*.png)|Any(*.*)")?;
+        let patterns: Vec<_> = background::EXTENSIONS
+            .iter()
+            .map(|ext| format!("*.{}", ext))
+            .collect();
+        let image_filters = format!("Image({})|Any(*.*)", patterns.join(";"));
+        let image_dialog = make_file_dialog(nwg::FileDialogAction::Open, &image_filters)?;
+
+        let autosave_timer = nwg::Timer::builder()
+            .parent(&window)
+            .interval(AUTOSAVE_CHECK_INTERVAL)
+            .construct()?;
 
         let inner = Rc::new(AppInner {
             window,
             canvas,
             canvas_handler: OnceCell::new(),
             paste_image_btn,
+            open_image_btn,
             clear_drawing_btn,
             copy_drawing_btn,
             drawing_color_btn,
@@ -446,15 +823,41 @@ impl nwg::NativeUi<App> for AppBuilder {
             move_mode_btn,
             line_mode_btn,
             bezier_mode_btn,
+            snap_check,
+            grid_check,
+            corner_node_btn,
+            smooth_node_btn,
+            symmetric_node_btn,
+            auto_smooth_btn,
+            save_project_btn,
+            open_project_btn,
+            export_btn,
+            add_layer_btn,
+            remove_layer_btn,
+            layer_up_btn,
+            layer_down_btn,
+            layer_visible_check,
+            layer_locked_check,
+            layer_even_odd_check,
+            effects_input,
+            effects_btn,
+            png_btn,
+            libass_check,
+            layer_list,
             color_dialog,
-
-            left_dragging: Default::default(),
-            right_dragging: Default::default(),
-            dragged_point: Default::default(),
-            pre_drag_pos: Default::default(),
-            drag_start_pos: Default::default(),
-            draw_mode: Cell::new(CommandKind::Line),
-            keys: Default::default(),
+            save_dialog,
+            open_dialog,
+            png_dialog,
+            image_dialog,
+            bg_transform_input,
+            bg_transform_btn,
+            bg_opacity_slider,
+            bg_locked_check,
+            autosave_timer,
+
+            editor: RefCell::new(Editor::new()),
+            autosave: RefCell::new(Autosave::new(recovery::default_path())),
+            clipboard: RefCell::new(WindowsClipboard),
         });
 
         let ui = Rc::downgrade(&inner);
@@ -470,28 +873,18 @@ impl nwg::NativeUi<App> for AppBuilder {
                         ui.handle_resize()
                     }
                     Event::OnWindowClose => ui.exit(),
+                    Event::OnFileDrop => {
+                        let files = evt_data.on_file_drop().files();
+                        if let Some(file) = files.into_iter().next() {
+                            ui.open_image(Some(PathBuf::from(file)));
+                        }
+                    }
                     Event::OnKeyPress | Event::OnKeyRelease => {
-                        let key = evt_data.on_key();
-                        let mut keys = ui.keys.borrow_mut();
-                        let state = keys.update(evt, key);
-                        if keys.pressed(nwg::keys::CONTROL) && state == KeyState::Pressed {
-                            match key {
-                                nwg::keys::_Z => {
-                                    ui.dragged_point.take();
-                                    if keys.pressed(nwg::keys::SHIFT) {
-                                        ui.get_canvas().redo();
-                                    } else {
-                                        ui.get_canvas().undo();
-                                    }
-                                }
-                                nwg::keys::_Y => ui.get_canvas().redo(),
-                                nwg::keys::_C => ui.copy_drawing().unwrap(),
-                                nwg::keys::_V => {
-                                    // TODO: paste either a drawing or the image depending on cb
-                                    // careful: pasting the image should be a history entry
-                                    ui.paste_image();
-                                }
-                                _ => (),
+                        if let Some(key) = translate_key(evt_data.on_key()) {
+                            if evt == Event::OnKeyPress {
+                                ui.handle_input(InputEvent::KeyDown(key));
+                            } else {
+                                ui.handle_input(InputEvent::KeyUp(key));
                             }
                         }
                     }
@@ -504,36 +897,107 @@ impl nwg::NativeUi<App> for AppBuilder {
             } else if evt == Event::OnButtonClick {
                 if handle == ui.paste_image_btn {
                     ui.paste_image();
+                } else if handle == ui.open_image_btn {
+                    ui.open_image(None);
                 } else if handle == ui.clear_drawing_btn {
                     ui.clear_drawing();
                 } else if handle == ui.copy_drawing_btn {
-                    ui.copy_drawing().unwrap();
+                    ui.copy_drawing();
                 } else if handle == ui.drawing_color_btn {
                     ui.choose_color(true);
                 } else if handle == ui.shape_color_btn {
                     ui.choose_color(false);
                 } else if handle == ui.move_mode_btn {
-                    ui.draw_mode.set(CommandKind::Move);
+                    ui.set_draw_mode(CommandKind::Move);
                 } else if handle == ui.line_mode_btn {
-                    ui.draw_mode.set(CommandKind::Line);
+                    ui.set_draw_mode(CommandKind::Line);
                 } else if handle == ui.bezier_mode_btn {
-                    ui.draw_mode.set(CommandKind::Bezier);
+                    ui.set_draw_mode(CommandKind::Bezier);
+                } else if handle == ui.snap_check || handle == ui.grid_check {
+                    ui.update_snap_settings();
+                } else if handle == ui.corner_node_btn {
+                    ui.set_node_kind(NodeKind::Corner);
+                } else if handle == ui.smooth_node_btn {
+                    ui.set_node_kind(NodeKind::Smooth);
+                } else if handle == ui.symmetric_node_btn {
+                    ui.set_node_kind(NodeKind::Symmetric);
+                } else if handle == ui.auto_smooth_btn {
+                    let response = ui.editor.borrow_mut().auto_smooth_selection();
+                    ui.apply(response);
+                } else if handle == ui.save_project_btn {
+                    ui.save_project();
+                } else if handle == ui.open_project_btn {
+                    ui.open_project();
+                } else if handle == ui.export_btn {
+                    ui.export_layers();
+                } else if handle == ui.add_layer_btn {
+                    ui.edit_layers(Editor::add_layer);
+                } else if handle == ui.remove_layer_btn {
+                    ui.edit_layers(Editor::remove_layer);
+                } else if handle == ui.layer_up_btn {
+                    ui.edit_layers(|editor| editor.move_layer(1));
+                } else if handle == ui.layer_down_btn {
+                    ui.edit_layers(|editor| editor.move_layer(-1));
+                } else if handle == ui.png_btn {
+                    ui.export_png();
+                } else if handle == ui.effects_btn {
+                    ui.update_effects();
+                } else if handle == ui.bg_transform_btn || handle == ui.bg_locked_check {
+                    ui.update_background_transform();
+                } else if handle == ui.libass_check {
+                    ui.update_raster_mode();
+                } else if handle == ui.layer_visible_check
+                    || handle == ui.layer_locked_check
+                    || handle == ui.layer_even_odd_check
+                {
+                    ui.update_layer_settings();
                 }
             } else if evt == Event::OnHorizontalScroll {
                 if handle == ui.shape_alpha_slider {
                     ui.update_shape_alpha();
+                } else if handle == ui.bg_opacity_slider {
+                    ui.update_background_opacity();
                 }
+            } else if evt == Event::OnListBoxSelect && handle == ui.layer_list {
+                ui.select_layer();
+            } else if evt == Event::OnTimerTick && handle == ui.autosave_timer {
+                ui.autosave();
             }
         };
         let handler = nwg::full_bind_event_handler(&inner.window.handle, handle_fn);
 
-        Ok(App { inner, handler })
+        // nwg doesn't say when a slider is let go of, which is when a slide of the opacity ends.
+        let ui = Rc::downgrade(&inner);
+        let slider_fn = move |_hwnd, msg, w, l| {
+            use winapi::shared::minwindef::LOWORD;
+            use winapi::um::commctrl::TB_ENDTRACK;
+            use winapi::um::winuser::WM_HSCROLL;
+
+            let ui = ui.upgrade()?;
+            let slider = ui.bg_opacity_slider.handle.hwnd();
+            if msg == WM_HSCROLL
+                && LOWORD(w as u32) as usize == TB_ENDTRACK
+                && slider == Some(l as _)
+            {
+                ui.editor.borrow_mut().end_opacity_slide();
+            }
+            None
+        };
+        let slider_handler =
+            nwg::bind_raw_event_handler(&inner.window.handle, SLIDER_HANDLER_ID, slider_fn)?;
+
+        Ok(App {
+            inner,
+            handler,
+            slider_handler,
+        })
     }
 }
 
 impl std::ops::Drop for App {
     fn drop(&mut self) {
         nwg::unbind_event_handler(&self.handler);
+        nwg::unbind_raw_event_handler(&self.slider_handler).unwrap_or((/* ignore */));
         if let Some(canvas_handler) = self.inner.canvas_handler.get() {
             nwg::unbind_event_handler(canvas_handler);
         }
@@ -0,0 +1,607 @@
+use std::ops::Range;
+
+use pretty_dtoa::{ftoa, FmtFloatConfig};
+use thiserror::Error;
+
+use crate::document::{Effects, Layer, LayerSettings, MAX_BE, MAX_BLUR, MAX_BORDER, MAX_SHADOW};
+use crate::drawing::{Command, CommandKind, Drawing};
+use crate::point::Point;
+
+/// How long exported lines last. They'll need retiming anyway.
+const LINE_TIMES: &str = "0:00:00.00,0:00:05.00";
+
+/// How many decimal places coordinates are written with, unless asked otherwise.
+pub const DEFAULT_PRECISION: usize = 2;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ParseError {
+    #[error("`{0}` isn't a number")]
+    Number(String),
+    #[error("unknown drawing command `{0}`")]
+    Command(char),
+    #[error("coordinates without a drawing command to go with them")]
+    NoCommand,
+    #[error("`{0}` without a spline to continue")]
+    NoSpline(char),
+    #[error("`{0}` is missing coordinates")]
+    Incomplete(char),
+}
+
+/// Formats a coordinate rounded to `precision` decimal places.
+///
+/// pretty_dtoa can't format zero, so anything that rounds to it is written out here instead.
+fn format_number(value: f32, precision: i8, cfg: FmtFloatConfig) -> String {
+    let scale = 10f64.powi(precision.into());
+    let rounded = ((value as f64 * scale).round() / scale) as f32;
+    if rounded == 0.0 {
+        return "0.0".to_owned();
+    }
+    ftoa(rounded, cfg)
+}
+
+/// Formats a drawing as ASS drawing commands, the way it would appear after `\p1`.
+pub fn format_drawing(drawing: &Drawing<Point<f32>>) -> String {
+    format_drawing_with_precision(drawing, DEFAULT_PRECISION)
+}
+
+/// Like `format_drawing`, with coordinates rounded to `precision` decimal places.
+pub fn format_drawing_with_precision(drawing: &Drawing<Point<f32>>, precision: usize) -> String {
+    let precision = precision.min(i8::MAX as usize) as i8;
+    let cfg = FmtFloatConfig::default()
+        .max_decimal_digits(precision)
+        .force_no_e_notation()
+        .ignore_extremes(3);
+
+    macro_rules! f {
+        ($fmt:literal, $($point:expr),*) => {
+            format!(
+                $fmt,
+                $(format_number($point.x, precision, cfg), format_number($point.y, precision, cfg)),*
+            )
+        }
+    }
+
+    let mut data = Vec::new();
+    let mut last_kind = None;
+    for cmd in drawing.commands() {
+        let element = match cmd {
+            Command::Move(p) | Command::Line(p) if last_kind == Some(cmd.kind()) => {
+                f!("{} {}", p)
+            }
+            Command::Move(p) => f!("m {} {}", p),
+            Command::Line(p) => f!("l {} {}", p),
+            Command::Bezier(p1, p2, p3) => {
+                if last_kind == Some(CommandKind::Bezier) {
+                    f!("{} {} {} {} {} {}", p1, p2, p3)
+                } else {
+                    f!("b {} {} {} {} {} {}", p1, p2, p3)
+                }
+            }
+        };
+        last_kind = Some(cmd.kind());
+        data.push(element);
+    }
+    data.join(" ")
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Token {
+    Command(char),
+    Number(f32),
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c.is_ascii_alphabetic() {
+            tokens.push(Token::Command(c.to_ascii_lowercase()));
+            continue;
+        }
+        let mut end = start + c.len_utf8();
+        while let Some(&(i, c)) = chars.peek() {
+            if c.is_whitespace() || c.is_ascii_alphabetic() {
+                break;
+            }
+            end = i + c.len_utf8();
+            chars.next();
+        }
+        let number = &text[start..end];
+        // Numbers too long for an `f32` come out infinite, which nothing downstream can draw.
+        let value = number
+            .parse::<f32>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| ParseError::Number(number.to_string()))?;
+        tokens.push(Token::Number(value));
+    }
+    Ok(tokens)
+}
+
+/// The curve that a uniform cubic B-spline follows between the middle two of four control points.
+fn spline_segment([p0, p1, p2, p3]: [Point<f32>; 4]) -> [Point<f32>; 4] {
+    [
+        (p0 + p1 * 4.0 + p2) / 6.0,
+        (p1 * 2.0 + p2) / 3.0,
+        (p1 + p2 * 2.0) / 3.0,
+        (p1 + p2 * 4.0 + p3) / 6.0,
+    ]
+}
+
+/// Continues a spline with one more control point, and draws the curve that it completes, if any.
+fn extend_spline(
+    drawing: &mut Drawing<Point<f32>>,
+    pen: &mut Point<f32>,
+    spline: &mut Vec<Point<f32>>,
+    p: Point<f32>,
+) {
+    spline.push(p);
+    if let [.., p0, p1, p2, p3] = spline[..] {
+        let [start, c1, c2, end] = spline_segment([p0, p1, p2, p3]);
+        if start != *pen {
+            drawing.push(Command::Line(start));
+        }
+        drawing.push(Command::Bezier(c1, c2, end));
+        *pen = end;
+    }
+}
+
+/// Parses ASS drawing commands, the way they would appear after `\p1`.
+///
+/// Splines (`s`, `p` and `c`) are converted into Bézier curves.
+pub fn parse_drawing(text: &str) -> Result<Drawing<Point<f32>>, ParseError> {
+    let mut drawing = Drawing::new();
+    let mut pen = Point::new(0.0, 0.0);
+    let mut command = None;
+    let mut numbers = Vec::new();
+    // The control points of the spline being drawn, starting from where the pen was.
+    let mut spline = Vec::new();
+
+    for token in tokenize(text)? {
+        let value = match token {
+            Token::Number(value) => value,
+            Token::Command(c) => {
+                if let Some(command) = command.filter(|_| !numbers.is_empty()) {
+                    return Err(ParseError::Incomplete(command));
+                }
+                if command == Some('s') && spline.len() < 4 && c != 'p' {
+                    return Err(ParseError::Incomplete('s'));
+                }
+                match c {
+                    'm' | 'n' | 'l' | 'b' => spline.clear(),
+                    's' => spline = vec![pen],
+                    'p' | 'c' if spline.is_empty() => return Err(ParseError::NoSpline(c)),
+                    'p' => {}
+                    'c' => {
+                        let start = [spline[0], spline[1], spline[2]];
+                        for &p in &start {
+                            extend_spline(&mut drawing, &mut pen, &mut spline, p);
+                        }
+                        spline.clear();
+                    }
+                    _ => return Err(ParseError::Command(c)),
+                }
+                command = Some(c);
+                continue;
+            }
+        };
+        let c = command.filter(|&c| c != 'c').ok_or(ParseError::NoCommand)?;
+        numbers.push(value);
+        let needed = if c == 'b' { 6 } else { 2 };
+        if numbers.len() < needed {
+            continue;
+        }
+        let points: Vec<_> = numbers
+            .chunks(2)
+            .map(|xy| Point::new(xy[0], xy[1]))
+            .collect();
+        numbers.clear();
+        match c {
+            'm' | 'n' => drawing.push(Command::Move(points[0])),
+            'l' => drawing.push(Command::Line(points[0])),
+            'b' => drawing.push(Command::Bezier(points[0], points[1], points[2])),
+            _ => {
+                extend_spline(&mut drawing, &mut pen, &mut spline, points[0]);
+                continue;
+            }
+        };
+        pen = points[points.len() - 1];
+    }
+
+    match command {
+        Some(c) if !numbers.is_empty() => Err(ParseError::Incomplete(c)),
+        Some('s') if spline.len() < 4 => Err(ParseError::Incomplete('s')),
+        _ => Ok(drawing),
+    }
+}
+
+/// Formats a color as the value of a `\c`-style tag.
+fn format_color([r, g, b]: [u8; 3]) -> String {
+    format!("&H{:02X}{:02X}{:02X}&", b, g, r)
+}
+
+/// Formats an alpha as the value of a `\a`-style tag.
+fn format_alpha(alpha: u8) -> String {
+    format!("&H{:02X}&", alpha)
+}
+
+/// Formats effects as override tags. The border's and shadow's colors are left out if there's no border or shadow.
+pub fn format_effects(effects: &Effects) -> String {
+    let mut tags = format!(r"\bord{}\shad{}", effects.border, effects.shadow);
+    if effects.be != 0 {
+        tags += &format!(r"\be{}", effects.be);
+    }
+    if effects.blur != 0.0 {
+        tags += &format!(r"\blur{}", effects.blur);
+    }
+    if effects.border != 0.0 {
+        tags += &format!(r"\3c{}", format_color(effects.border_color));
+        tags += &format!(r"\3a{}", format_alpha(effects.border_alpha));
+    }
+    if effects.shadow != 0.0 {
+        tags += &format!(r"\4c{}", format_color(effects.shadow_color));
+        tags += &format!(r"\4a{}", format_alpha(effects.shadow_alpha));
+    }
+    tags
+}
+
+fn parse_hex(value: &str) -> Option<u32> {
+    let digits = value
+        .trim_start_matches('&')
+        .trim_start_matches(['H', 'h'])
+        .trim_end_matches('&');
+    u32::from_str_radix(digits, 16).ok()
+}
+
+fn parse_color(value: &str) -> Option<[u8; 3]> {
+    let [_, b, g, r] = parse_hex(value)?.to_be_bytes();
+    Some([r, g, b])
+}
+
+fn parse_alpha(value: &str) -> Option<u8> {
+    parse_hex(value).map(|alpha| alpha as u8)
+}
+
+/// Applies the effect tags among some override tags, like `{\bord2\blur1}`, on top of `effects`.
+///
+/// Other tags, and tags with values that don't parse, are ignored.
+/// Sizes are clamped to what libass accepts.
+pub fn parse_effects(tags: &str, mut effects: Effects) -> Effects {
+    let tags = tags.trim().trim_start_matches('{').trim_end_matches('}');
+    for tag in tags.split('\\') {
+        let tag = tag.trim();
+        let float = |name: &str| {
+            let value = tag.strip_prefix(name)?.trim().parse::<f32>().ok();
+            value.filter(|v| v.is_finite())
+        };
+        if let Some(blur) = float("blur") {
+            effects.blur = blur.clamp(0.0, MAX_BLUR);
+        } else if let Some(border) = float("bord") {
+            effects.border = border.clamp(0.0, MAX_BORDER);
+        } else if let Some(shadow) = float("shad") {
+            effects.shadow = shadow.clamp(0.0, MAX_SHADOW);
+        } else if let Some(be) = float("be") {
+            effects.be = be.clamp(0.0, MAX_BE as f32).round() as u32;
+        } else if let Some(color) = tag.strip_prefix("3c").and_then(parse_color) {
+            effects.border_color = color;
+        } else if let Some(alpha) = tag.strip_prefix("3a").and_then(parse_alpha) {
+            effects.border_alpha = alpha;
+        } else if let Some(color) = tag.strip_prefix("4c").and_then(parse_color) {
+            effects.shadow_color = color;
+        } else if let Some(alpha) = tag.strip_prefix("4a").and_then(parse_alpha) {
+            effects.shadow_alpha = alpha;
+        }
+    }
+    effects
+}
+
+/// Formats a layer as a whole `Dialogue` line, positioned so that the drawing's coordinates are screen coordinates.
+pub fn format_layer(layer: &Layer) -> String {
+    let settings = &layer.settings;
+    format!(
+        r"Dialogue: {},{},Default,,0,0,0,,{{\an7\pos(0,0){}\1c{}\1a{}\p1}}{}",
+        settings.ass_layer,
+        LINE_TIMES,
+        format_effects(&settings.effects),
+        format_color(settings.color),
+        format_alpha(settings.alpha),
+        format_drawing(&layer.drawing),
+    )
+}
+
+/// One line per visible, non-empty layer, from the bottom up.
+pub fn format_layers(layers: &[Layer]) -> String {
+    let lines: Vec<_> = layers
+        .iter()
+        .filter(|layer| layer.settings.visible && !layer.drawing.is_empty())
+        .map(format_layer)
+        .collect();
+    lines.join("\n")
+}
+
+/// Applies the fill color and alpha tags among some override tags, along with the effect tags, on top of `settings`.
+pub fn parse_settings(tags: &str, mut settings: LayerSettings) -> LayerSettings {
+    settings.effects = parse_effects(tags, settings.effects);
+    let tags = tags.trim().trim_start_matches('{').trim_end_matches('}');
+    for tag in tags.split('\\') {
+        let tag = tag.trim();
+        if let Some(color) = tag.strip_prefix("1c").and_then(parse_color) {
+            settings.color = color;
+        } else if let Some(color) = tag.strip_prefix('c').and_then(parse_color) {
+            settings.color = color;
+        } else if let Some(alpha) = tag.strip_prefix("1a").and_then(parse_alpha) {
+            settings.alpha = alpha;
+        } else if let Some(alpha) = tag.strip_prefix("alpha").and_then(parse_alpha) {
+            settings.alpha = alpha;
+        }
+    }
+    settings
+}
+
+/// How much larger coordinates are written under `\p<scale>` than they appear on screen.
+#[inline]
+pub fn scale_factor(scale: u32) -> f32 {
+    2f32.powi(scale.max(1) as i32 - 1)
+}
+
+/// Where a drawing is in a `Dialogue` line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DrawingSpan {
+    /// Where the drawing commands are.
+    pub range: Range<usize>,
+    /// Where the value of the `\p` tag that turned drawing mode on is.
+    pub scale_range: Range<usize>,
+    /// That `\p` tag's value.
+    pub scale: u32,
+    /// The override tags that come before the drawing, all together.
+    pub tags: String,
+}
+
+/// Finds the drawings in a line of a script. Lines other than `Dialogue` lines have none.
+pub fn find_drawings(line: &str) -> Vec<DrawingSpan> {
+    let mut spans = Vec::new();
+    let fields = match line.strip_prefix("Dialogue:") {
+        Some(fields) => fields,
+        None => return spans,
+    };
+    // The text is the last of ten fields, and the only one that may contain commas.
+    let text_start = match fields.match_indices(',').nth(8) {
+        Some((i, _)) => line.len() - fields.len() + i + 1,
+        None => return spans,
+    };
+
+    let mut tags = String::new();
+    let mut scale = 0;
+    let mut scale_range = 0..0;
+    let mut pos = text_start;
+    while pos < line.len() {
+        let rest = &line[pos..];
+        if rest.starts_with('{') {
+            let end = rest.find('}').map_or(line.len(), |i| pos + i + 1);
+            let block = &line[pos..end];
+            tags += block;
+            for (i, _) in block.match_indices('\\') {
+                let tag_start = pos + i + 1;
+                let value = line[tag_start..end].strip_prefix('p').unwrap_or("");
+                let digits =
+                    value.len() - value.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                let after = value[digits..].trim_start();
+                if digits > 0
+                    && (after.starts_with('\\') || after.starts_with('}') || after.is_empty())
+                {
+                    scale = value[..digits].parse().unwrap_or(0);
+                    scale_range = tag_start + 1..tag_start + 1 + digits;
+                }
+            }
+            pos = end;
+        } else {
+            let end = rest.find('{').map_or(line.len(), |i| pos + i);
+            if scale > 0 && !line[pos..end].trim().is_empty() {
+                spans.push(DrawingSpan {
+                    range: pos..end,
+                    scale_range: scale_range.clone(),
+                    scale,
+                    tags: tags.clone(),
+                });
+            }
+            pos = end;
+        }
+    }
+    spans
+}
+
+/// The clip in the first `\clip` or `\iclip` tag in some text, as a drawing in screen coordinates,
+/// or `None` if there's no such tag. Rectangular clips become rectangles.
+pub fn find_clip(text: &str) -> Option<Result<Drawing<Point<f32>>, ParseError>> {
+    let (i, tag) = text
+        .match_indices("clip(")
+        .find(|&(i, _)| text[..i].ends_with('\\') || text[..i].ends_with("\\i"))?;
+    let args = &text[i + tag.len()..];
+    let args = &args[..args.find(')').unwrap_or(args.len())];
+    Some(parse_clip(args))
+}
+
+fn parse_clip(args: &str) -> Result<Drawing<Point<f32>>, ParseError> {
+    let number = |value: &str| {
+        value
+            .parse::<f32>()
+            .ok()
+            .filter(|v| v.is_finite())
+            .ok_or_else(|| ParseError::Number(value.to_owned()))
+    };
+    let parts: Vec<&str> = args.split(',').map(str::trim).collect();
+    match parts[..] {
+        [x1, y1, x2, y2] => {
+            let (x1, y1, x2, y2) = (number(x1)?, number(y1)?, number(x2)?, number(y2)?);
+            let mut drawing = Drawing::new();
+            drawing.push(Command::Move(Point::new(x1, y1)));
+            drawing.push(Command::Line(Point::new(x2, y1)));
+            drawing.push(Command::Line(Point::new(x2, y2)));
+            drawing.push(Command::Line(Point::new(x1, y2)));
+            Ok(drawing)
+        }
+        [scale, commands] => {
+            let scale = scale
+                .parse()
+                .map_err(|_| ParseError::Number(scale.to_owned()))?;
+            let mut drawing = parse_drawing(commands)?;
+            let factor = scale_factor(scale);
+            for p in drawing.points_mut() {
+                *p /= factor;
+            }
+            Ok(drawing)
+        }
+        _ => parse_drawing(args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        find_clip, find_drawings, format_drawing, format_drawing_with_precision, format_effects,
+        format_layer, parse_drawing, parse_effects, parse_settings, ParseError,
+    };
+    use crate::document::{
+        Document, Effects, LayerSettings, MAX_BE, MAX_BLUR, MAX_BORDER, MAX_SHADOW,
+    };
+    use crate::drawing::Command::{Bezier, Line, Move};
+    use crate::drawing::{Command, Drawing};
+    use crate::point::Point;
+
+    #[test]
+    fn test_effects() {
+        let effects = Effects {
+            border: 2.5,
+            shadow: 1.0,
+            be: 2,
+            blur: 0.0,
+            border_color: [255, 128, 0],
+            border_alpha: 0x40,
+            shadow_color: [0, 0, 0],
+            shadow_alpha: 0x80,
+        };
+        let tags = format_effects(&effects);
+        assert_eq!(
+            tags,
+            r"\bord2.5\shad1\be2\3c&H0080FF&\3a&H40&\4c&H000000&\4a&H80&"
+        );
+        assert_eq!(parse_effects(&tags, Effects::default()), effects);
+
+        let parsed = parse_effects(r"{\blur1.5\fs20\bord-1\4c&H00FF0000&\be}", effects);
+        assert_eq!(parsed.blur, 1.5);
+        assert_eq!(parsed.border, 0.0);
+        assert_eq!(parsed.shadow_color, [0, 0, 255]);
+        assert_eq!(parsed.be, effects.be);
+        // Sizes are clamped, so that they can't run away with the rasterizer.
+        let huge = parse_effects(r"\bord1000\be1e9\blur1e9\shad1e30", effects);
+        assert_eq!(
+            (huge.border, huge.be, huge.blur, huge.shadow),
+            (MAX_BORDER, MAX_BE, MAX_BLUR, MAX_SHADOW)
+        );
+        assert_eq!(parse_effects(r"\shadnan", effects).shadow, effects.shadow);
+        assert_eq!(format_effects(&Effects::default()), r"\bord0\shad0");
+    }
+
+    #[test]
+    fn test_format_layer() {
+        let mut drawing = Drawing::new();
+        drawing.push(Command::Move(Point::new(1.5, 1.5)));
+        drawing.push(Command::Line(Point::new(10.5, 1.5)));
+        let settings = LayerSettings {
+            color: [1, 2, 3],
+            alpha: 0xFF,
+            ass_layer: 2,
+            ..LayerSettings::default()
+        };
+        let doc = Document::from_drawing(drawing, settings);
+        assert_eq!(
+            format_layer(&doc.layers()[0]),
+            r"Dialogue: 2,0:00:00.00,0:00:05.00,Default,,0,0,0,,{\an7\pos(0,0)\bord0\shad0\1c&H030201&\1a&HFF&\p1}m 1.5 1.5 l 10.5 1.5"
+        );
+    }
+
+    #[test]
+    fn test_parse_drawing() {
+        let text = "m 0.0 0.0 l 10.5 0.0 -3.0 4.0 b 1.0 2.0 3.0 4.0 5.0 6.0 m 7.0 8.0";
+        let drawing = parse_drawing(text).unwrap();
+        assert_eq!(drawing.len(), 5);
+        assert_eq!(format_drawing(&drawing), text);
+        assert_eq!(
+            format_drawing_with_precision(&parse_drawing("m0 -0.001l1.234 5").unwrap(), 1),
+            "m 0.0 0.0 l 1.2 5.0"
+        );
+        let rounded = parse_drawing("m 1.235 -12.999 l 0.0000001 -100000").unwrap();
+        assert_eq!(format_drawing(&rounded), "m 1.24 -13.0 l 0.0 -100000.0");
+        assert_eq!(
+            format_drawing_with_precision(&rounded, 0),
+            "m 1.0 -13.0 l 0.0 -100000.0"
+        );
+
+        let spline = parse_drawing("m 0 0 s 6 0 6 6 0 6 c").unwrap();
+        let commands: Vec<_> = spline.commands().collect();
+        assert!(matches!(commands[0], Move(p) if p == Point::new(0.0, 0.0)));
+        assert!(matches!(commands[1], Line(p) if p == Point::new(5.0, 1.0)));
+        assert!(matches!(commands[2], Bezier(..)));
+        assert_eq!(commands.len(), 6);
+
+        assert_eq!(parse_drawing("m 0 0 l 1"), Err(ParseError::Incomplete('l')));
+        assert_eq!(parse_drawing("m 0 0 x 1 1"), Err(ParseError::Command('x')));
+        assert_eq!(parse_drawing("1 1"), Err(ParseError::NoCommand));
+        assert_eq!(parse_drawing("m 0 0 p 1 1"), Err(ParseError::NoSpline('p')));
+        assert_eq!(
+            parse_drawing("m 0 0 s 1 1 2 2 l 3 3"),
+            Err(ParseError::Incomplete('s'))
+        );
+        assert_eq!(
+            parse_drawing("m 1,5 2"),
+            Err(ParseError::Number("1,5".into()))
+        );
+        let overlong = format!("1{}", "0".repeat(40));
+        assert_eq!(
+            parse_drawing(&format!("m 0 {}", overlong)),
+            Err(ParseError::Number(overlong))
+        );
+    }
+
+    #[test]
+    fn test_find_drawings() {
+        let line = r"Dialogue: 0,0:00:00.00,0:00:05.00,Default,,0,0,0,,{\pos(1,2)\c&HFF0000&\p2}m 0 0 l 4 0{\p0}text, more{\p1 }m 1 1";
+        let spans = find_drawings(line);
+        assert_eq!(spans.len(), 2);
+        assert_eq!(&line[spans[0].range.clone()], "m 0 0 l 4 0");
+        assert_eq!(&line[spans[0].scale_range.clone()], "2");
+        assert_eq!(spans[0].scale, 2);
+        assert_eq!(&line[spans[1].range.clone()], "m 1 1");
+        assert_eq!(spans[1].scale, 1);
+
+        let settings = parse_settings(&spans[0].tags, LayerSettings::default());
+        assert_eq!(settings.color, [0, 0, 255]);
+        assert!(
+            find_drawings("Comment: 0,0:00:00.00,0:00:05.00,Default,,0,0,0,,{\\p1}m 0 0")
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_find_clip() {
+        let clip = |text| find_clip(text).map(|clip| format_drawing(&clip.unwrap()));
+        assert_eq!(
+            clip(r"{\pos(0,0)\clip(m 0 0 l 10 0 10 10)}text").as_deref(),
+            Some("m 0.0 0.0 l 10.0 0.0 10.0 10.0")
+        );
+        assert_eq!(
+            clip(r"{\iclip(2,m 0 0 l 20 0 20 20)}").as_deref(),
+            Some("m 0.0 0.0 l 10.0 0.0 10.0 10.0")
+        );
+        assert_eq!(
+            clip(r"{\clip(1, 2, 3.5, 4)}").as_deref(),
+            Some("m 1.0 2.0 l 3.5 2.0 3.5 4.0 1.0 4.0")
+        );
+        assert_eq!(clip("m 0 0 l 10 0 10 10"), None);
+        assert!(find_clip(r"{\clip(1,2,x,4)}").unwrap().is_err());
+        assert!(find_clip(r"{\clip(inf,0,1e40,NaN)}").unwrap().is_err());
+    }
+}
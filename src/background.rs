@@ -0,0 +1,75 @@
+//! Loading the images that drawings are traced over.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use image::{ImageFormat, RgbaImage};
+use thiserror::Error;
+
+/// The extensions of the formats backgrounds can be loaded from, for file dialogs.
+pub const EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "webp", "bmp"];
+
+#[derive(Debug, Error)]
+pub enum BackgroundError {
+    #[error("couldn't read {}: {}", .0.display(), .1)]
+    Read(PathBuf, io::Error),
+    #[error("{} isn't a PNG, JPEG, WebP or BMP image", .0.display())]
+    Format(PathBuf),
+    #[error("couldn't decode {}: {}", .0.display(), .1)]
+    Decode(PathBuf, image::ImageError),
+}
+
+/// Loads a PNG, JPEG, WebP or BMP image, whatever its pixel format, as 8-bit RGBA.
+///
+/// The format is told by the file's contents, or by its extension if that doesn't work.
+pub fn load_image(path: &Path) -> Result<RgbaImage, BackgroundError> {
+    let data = fs::read(path).map_err(|e| BackgroundError::Read(path.to_owned(), e))?;
+    let format = image::guess_format(&data)
+        .ok()
+        .or_else(|| ImageFormat::from_path(path).ok())
+        .filter(|format| {
+            matches!(
+                format,
+                ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP | ImageFormat::Bmp
+            )
+        })
+        .ok_or_else(|| BackgroundError::Format(path.to_owned()))?;
+    let image = image::load_from_memory_with_format(&data, format)
+        .map_err(|e| BackgroundError::Decode(path.to_owned(), e))?;
+    Ok(image.into_rgba8())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use image::{DynamicImage, ImageBuffer, LumaA};
+
+    use super::{load_image, BackgroundError};
+
+    #[test]
+    fn test_load_image() {
+        let dir = env::temp_dir();
+        let path = dir.join(format!("assdraw-test-{}-background.png", process::id()));
+        // 16-bit grayscale with alpha, like some screenshot tools save.
+        let levels = [0, 0x8080, 0xffff];
+        let gray = ImageBuffer::from_fn(3, 2, |x, y| {
+            LumaA([levels[x as usize], levels[2 * y as usize]])
+        });
+        DynamicImage::ImageLumaA16(gray).save(&path).unwrap();
+        let image = load_image(&path);
+        fs::remove_file(&path).unwrap();
+        let image = image.unwrap();
+        assert_eq!(image.dimensions(), (3, 2));
+        assert_eq!(image.get_pixel(1, 1).0, [0x80, 0x80, 0x80, 0xff]);
+        assert_eq!(image.get_pixel(2, 0).0, [0xff, 0xff, 0xff, 0]);
+
+        let path = dir.join(format!("assdraw-test-{}-background.txt", process::id()));
+        fs::write(&path, "m 0 0 l 10 0").unwrap();
+        let result = load_image(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(BackgroundError::Format(_))));
+        assert!(matches!(load_image(&path), Err(BackgroundError::Read(..))));
+    }
+}
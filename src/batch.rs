@@ -0,0 +1,594 @@
+//! Running without the GUI, so that scripts can process drawings and projects in batch.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::ass::{self, DrawingSpan, ParseError, DEFAULT_PRECISION};
+use crate::document::{BackgroundTransform, Document, LayerSettings};
+use crate::drawing::Drawing;
+use crate::export::{self, ExportContent, ExportError, ExportSettings};
+use crate::ops::{self, BooleanOp};
+use crate::point::Point;
+use crate::project::{Colors, Project, ProjectError};
+use crate::raster::{RasterMode, Scene};
+
+pub const USAGE: &str = "\
+usage: assdraw-cli process [options] [input...]
+       assdraw-cli export-png <project> <output.png> [options]
+
+`process` reads drawings from each input, or from stdin if there are none or an input is `-`,
+applies the operations to every drawing in the order they're given, and writes the results out.
+Scripts (`.ass` files, or anything with `Dialogue:` lines) are written out whole, with their drawings replaced.
+Anything else is read as one drawing per line.
+
+operations:
+    --translate <x>,<y>     move by x and y
+    --scale <x>[,<y>]       scale away from the origin
+    --rotate <degrees>      rotate around the origin, counterclockwise like \\frz
+    --flatten <tolerance>   replace curves with lines that stray at most this far
+    --simplify <tolerance>  leave out points and curves that change the shape at most this much
+    --reverse               make every contour go the other way around
+    --union <file>          add the area that the drawings in another file fill
+    --intersect <file>      keep only the area that the drawings in another file also fill
+    --subtract <file>       take away the area that the drawings in another file fill
+    --xor <file>            keep the area that exactly one of them fills
+                            (the last four flatten curves)
+
+process options:
+    -o, --output <path>     write to a file instead of stdout
+    --p <n>                 write drawings for \\p<n>, rescaling them to match (default: as read)
+    --precision <n>         decimal places to round coordinates to (default 2)
+    --png <path>            also render the results to a PNG file, where they are in drawing coordinates
+    --png-scale <n>         image pixels per drawing pixel (default 1)
+
+export-png options:
+    --scale <n>             image pixels per drawing pixel (default 1)
+
+rendering options:
+    --padding <n>           empty pixels around the shapes (default 0)
+    --mask                  export a white mask instead of the shapes' colors
+    --preview               export the preview, background included
+    --libass                rasterize like libass does";
+
+#[derive(Debug, Error)]
+pub enum BatchError {
+    #[error("{0}\n\n{}", USAGE)]
+    Usage(String),
+    #[error("couldn't read {0}: {1}")]
+    Read(String, io::Error),
+    #[error("couldn't write {0}: {1}")]
+    Write(String, io::Error),
+    #[error("{name}, line {line}: {error}")]
+    Parse {
+        name: String,
+        line: usize,
+        error: ParseError,
+    },
+    #[error(transparent)]
+    Project(#[from] ProjectError),
+    #[error(transparent)]
+    Export(#[from] ExportError),
+}
+
+fn usage(message: impl Into<String>) -> BatchError {
+    BatchError::Usage(message.into())
+}
+
+/// Runs the command given on the command line, without the program's own name.
+pub fn run(args: &[String]) -> Result<(), BatchError> {
+    let mut args = Args(args.iter());
+    match args.0.next().map(String::as_str) {
+        Some("process") => process(args),
+        Some("export-png") => export_png(args),
+        Some(command) => Err(usage(format!("unknown command `{}`", command))),
+        None => Err(usage("no command given")),
+    }
+}
+
+struct Args<'a>(slice::Iter<'a, String>);
+
+impl<'a> Args<'a> {
+    fn value(&mut self, name: &str) -> Result<&'a str, BatchError> {
+        self.0
+            .next()
+            .map(String::as_str)
+            .ok_or_else(|| usage(format!("`{}` needs a value", name)))
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str, what: &str) -> Result<T, BatchError> {
+        let value = self.value(name)?;
+        value
+            .parse()
+            .map_err(|_| usage(format!("`{}` needs {}, not `{}`", name, what, value)))
+    }
+
+    fn tolerance(&mut self, name: &str) -> Result<f32, BatchError> {
+        Some(self.parse(name, "a number")?)
+            .filter(|&tolerance: &f32| tolerance > 0.0)
+            .ok_or_else(|| usage(format!("`{}` needs a positive tolerance", name)))
+    }
+
+    /// Parses `x,y`, or just `x` to mean the same for both if `single` is allowed.
+    fn point(&mut self, name: &str, single: bool) -> Result<Point<f32>, BatchError> {
+        let value = self.value(name)?;
+        let numbers: Result<Vec<f32>, _> = value.split(',').map(|v| v.trim().parse()).collect();
+        match numbers.as_deref() {
+            Ok(&[x, y]) => Ok(Point::new(x, y)),
+            Ok(&[v]) if single => Ok(Point::new(v, v)),
+            _ => Err(usage(format!(
+                "`{}` needs two numbers like `1,2`, not `{}`",
+                name, value
+            ))),
+        }
+    }
+
+    /// Handles the options for how images are rendered, and returns whether `arg` was one of them.
+    fn render_option(
+        &mut self,
+        arg: &str,
+        scale_name: &str,
+        settings: &mut ExportSettings,
+        mode: &mut RasterMode,
+    ) -> Result<bool, BatchError> {
+        match arg {
+            _ if arg == scale_name => {
+                settings.scale = Some(self.parse(arg, "a number")?)
+                    .filter(|&scale: &f32| scale > 0.0)
+                    .ok_or_else(|| usage("the scale must be a positive number"))?;
+            }
+            "--padding" => settings.padding = self.parse(arg, "a whole number of pixels")?,
+            "--mask" => settings.content = ExportContent::Mask,
+            "--preview" => settings.content = ExportContent::Preview,
+            "--libass" => *mode = RasterMode::Libass,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+fn export_png(mut args: Args<'_>) -> Result<(), BatchError> {
+    let mut paths = Vec::new();
+    let mut settings = ExportSettings::default();
+    let mut mode = RasterMode::Preview;
+    while let Some(arg) = args.0.next() {
+        if args.render_option(arg, "--scale", &mut settings, &mut mode)? {
+            continue;
+        }
+        if arg.starts_with("--") {
+            return Err(usage(format!("unknown option `{}`", arg)));
+        }
+        paths.push(PathBuf::from(arg));
+    }
+    let (input, output) = match paths.as_slice() {
+        [input, output] => (input, output),
+        _ => return Err(usage("expected a project and an output path")),
+    };
+
+    let project = Project::load_from(input)?;
+    let history = &project.history;
+    let scene = Scene {
+        background: history.background.as_deref(),
+        background_transform: history.background_transform,
+        layers: history.layers(),
+        active: None,
+        colors: project.colors,
+        overlay: None,
+        mode,
+    };
+    export::export_png(&scene, &settings, output)?;
+    Ok(())
+}
+
+/// Something to do to every drawing.
+#[derive(Debug, Clone)]
+enum Operation {
+    Translate(Point<f32>),
+    Scale(Point<f32>),
+    Rotate(f32),
+    Flatten(f32),
+    Simplify(f32),
+    Reverse,
+    Boolean(BooleanOp, Box<Drawing<Point<f32>>>),
+}
+
+impl Operation {
+    fn apply(&self, drawing: &mut Drawing<Point<f32>>) {
+        match self {
+            Self::Translate(offset) => ops::translate(drawing, *offset),
+            Self::Scale(factor) => ops::scale(drawing, *factor),
+            Self::Rotate(degrees) => ops::rotate(drawing, *degrees),
+            Self::Flatten(tolerance) => *drawing = ops::flatten(drawing, *tolerance),
+            Self::Simplify(tolerance) => *drawing = ops::simplify(drawing, *tolerance),
+            Self::Reverse => *drawing = ops::reverse(drawing),
+            Self::Boolean(op, other) => *drawing = ops::boolean(drawing, other, *op),
+        }
+    }
+}
+
+fn read_input(name: &str) -> Result<String, BatchError> {
+    let result = if name == "-" {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text).map(|_| text)
+    } else {
+        fs::read_to_string(name)
+    };
+    result.map_err(|e| BatchError::Read(name.to_string(), e))
+}
+
+fn is_script(name: &str, text: &str) -> bool {
+    name.to_ascii_lowercase().ends_with(".ass")
+        || text.lines().any(|line| line.starts_with("Dialogue:"))
+}
+
+/// A drawing found in an input.
+struct Found {
+    /// Which line it's on, counting from 0.
+    line: usize,
+    /// Where it is on that line, if the input is a script.
+    span: Option<DrawingSpan>,
+    /// The drawing, in screen pixels no matter how it was scaled with `\p`.
+    drawing: Drawing<Point<f32>>,
+}
+
+fn find_drawings(name: &str, text: &str) -> Result<Vec<Found>, BatchError> {
+    let script = is_script(name, text);
+    let mut found = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let parse_error = |error| BatchError::Parse {
+            name: name.to_string(),
+            line: i + 1,
+            error,
+        };
+        if !script {
+            if !line.trim().is_empty() {
+                let drawing = ass::parse_drawing(line).map_err(parse_error)?;
+                found.push(Found {
+                    line: i,
+                    span: None,
+                    drawing,
+                });
+            }
+            continue;
+        }
+        for span in ass::find_drawings(line) {
+            let mut drawing = ass::parse_drawing(&line[span.range.clone()]).map_err(parse_error)?;
+            let factor = ass::scale_factor(span.scale);
+            ops::scale(&mut drawing, Point::new(1.0, 1.0) / factor);
+            found.push(Found {
+                line: i,
+                span: Some(span),
+                drawing,
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// All the drawings in a file, as one.
+fn read_operand(name: &str) -> Result<Drawing<Point<f32>>, BatchError> {
+    let text = read_input(name)?;
+    let mut combined = Drawing::new();
+    for found in find_drawings(name, &text)? {
+        for cmd in found.drawing.commands() {
+            combined.push(cmd);
+        }
+    }
+    Ok(combined)
+}
+
+struct ProcessOptions {
+    operations: Vec<Operation>,
+    /// The `\p` value to write drawings for, if not the one they were read with.
+    scale: Option<u32>,
+    precision: usize,
+}
+
+/// Processes every drawing in an input, and returns what it becomes, along with the drawings for rendering.
+fn process_input(
+    name: &str,
+    text: &str,
+    options: &ProcessOptions,
+    rendered: &mut Vec<(Drawing<Point<f32>>, LayerSettings)>,
+) -> Result<String, BatchError> {
+    let mut lines: Vec<String> = text.lines().map(String::from).collect();
+    // Edits to each line, as what to replace and with what.
+    let mut edits = vec![Vec::new(); lines.len()];
+    for mut found in find_drawings(name, text)? {
+        for operation in &options.operations {
+            operation.apply(&mut found.drawing);
+        }
+        let (scale, settings) = match &found.span {
+            Some(span) => (
+                span.scale,
+                ass::parse_settings(&span.tags, LayerSettings::default()),
+            ),
+            None => (1, LayerSettings::default()),
+        };
+        let scale = options.scale.unwrap_or(scale);
+        let mut scaled = found.drawing.clone();
+        let factor = ass::scale_factor(scale);
+        ops::scale(&mut scaled, Point::new(factor, factor));
+        let formatted = ass::format_drawing_with_precision(&scaled, options.precision);
+
+        let edits = &mut edits[found.line];
+        match found.span {
+            Some(span) => {
+                if !edits.iter().any(|(range, _)| *range == span.scale_range) {
+                    edits.push((span.scale_range, scale.to_string()));
+                }
+                edits.push((span.range, formatted));
+            }
+            None => edits.push((0..lines[found.line].len(), formatted)),
+        }
+        rendered.push((found.drawing, settings));
+    }
+
+    for (line, mut edits) in lines.iter_mut().zip(edits) {
+        edits.sort_by_key(|(range, _)| range.start);
+        for (range, replacement) in edits.into_iter().rev() {
+            line.replace_range(range, &replacement);
+        }
+    }
+    let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let mut output = lines.join(newline);
+    if text.ends_with('\n') {
+        output += newline;
+    }
+    Ok(output)
+}
+
+fn render_png(
+    drawings: Vec<(Drawing<Point<f32>>, LayerSettings)>,
+    settings: &ExportSettings,
+    mode: RasterMode,
+    path: &Path,
+) -> Result<(), BatchError> {
+    let mut drawings = drawings.into_iter();
+    let mut doc = match drawings.next() {
+        Some((drawing, settings)) => Document::from_drawing(drawing, settings),
+        None => return Err(ExportError::Empty.into()),
+    };
+    for (drawing, settings) in drawings {
+        let index = doc.layers().len();
+        doc.insert_layer(index, settings);
+        doc.layer_mut(index).drawing = drawing;
+    }
+    let scene = Scene {
+        background: None,
+        background_transform: BackgroundTransform::default(),
+        layers: doc.layers(),
+        active: None,
+        colors: Colors::default(),
+        overlay: None,
+        mode,
+    };
+    export::export_png(&scene, settings, path)?;
+    Ok(())
+}
+
+fn process(mut args: Args<'_>) -> Result<(), BatchError> {
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut png = None;
+    let mut export_settings = ExportSettings::default();
+    let mut mode = RasterMode::Preview;
+    let mut options = ProcessOptions {
+        operations: Vec::new(),
+        scale: None,
+        precision: DEFAULT_PRECISION,
+    };
+    while let Some(arg) = args.0.next() {
+        if args.render_option(arg, "--png-scale", &mut export_settings, &mut mode)? {
+            continue;
+        }
+        let operation = match arg.as_str() {
+            "--translate" => Operation::Translate(args.point(arg, false)?),
+            "--scale" => Operation::Scale(args.point(arg, true)?),
+            "--rotate" => Operation::Rotate(args.parse(arg, "a number of degrees")?),
+            "--flatten" => Operation::Flatten(args.tolerance(arg)?),
+            "--simplify" => Operation::Simplify(args.tolerance(arg)?),
+            "--reverse" => Operation::Reverse,
+            "--union" => boolean(&mut args, arg, BooleanOp::Union)?,
+            "--intersect" => boolean(&mut args, arg, BooleanOp::Intersection)?,
+            "--subtract" => boolean(&mut args, arg, BooleanOp::Difference)?,
+            "--xor" => boolean(&mut args, arg, BooleanOp::Xor)?,
+            "-o" | "--output" => {
+                output = Some(args.value(arg)?);
+                continue;
+            }
+            "--p" => {
+                let scale = args.parse(arg, "a whole number")?;
+                if scale == 0 {
+                    return Err(usage("`--p` needs to be at least 1"));
+                }
+                options.scale = Some(scale);
+                continue;
+            }
+            "--precision" => {
+                options.precision = args.parse(arg, "a whole number of decimal places")?;
+                continue;
+            }
+            "--png" => {
+                png = Some(PathBuf::from(args.value(arg)?));
+                continue;
+            }
+            _ if arg.starts_with("--") => return Err(usage(format!("unknown option `{}`", arg))),
+            _ => {
+                inputs.push(arg.as_str());
+                continue;
+            }
+        };
+        options.operations.push(operation);
+    }
+    if inputs.is_empty() {
+        inputs.push("-");
+    }
+
+    let mut result = String::new();
+    let mut rendered = Vec::new();
+    for name in inputs {
+        let text = read_input(name)?;
+        result += &process_input(name, &text, &options, &mut rendered)?;
+    }
+
+    let written = match output {
+        Some(path) => fs::write(path, &result).map_err(|e| BatchError::Write(path.to_string(), e)),
+        None => io::stdout()
+            .write_all(result.as_bytes())
+            .map_err(|e| BatchError::Write("stdout".to_string(), e)),
+    };
+    written?;
+    if let Some(path) = png {
+        render_png(rendered, &export_settings, mode, &path)?;
+    }
+    Ok(())
+}
+
+fn boolean(args: &mut Args<'_>, name: &str, op: BooleanOp) -> Result<Operation, BatchError> {
+    let other = read_operand(args.value(name)?)?;
+    Ok(Operation::Boolean(op, Box::new(other)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{process_input, Operation, ProcessOptions};
+    use crate::ass::parse_drawing;
+    use crate::ops::BooleanOp;
+    use crate::point::Point;
+
+    const SCRIPT: &str = "[Events]\r\n\
+        Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\r\n\
+        Dialogue: 0,0:00:00.00,0:00:05.00,Default,,0,0,0,,{\\an7\\1c&H0000FF&\\p2}m 0 0 l 20 0 20 20{\\p0}, text\r\n\
+        Dialogue: 0,0:00:00.00,0:00:05.00,Default,,0,0,0,,no drawing here\r\n";
+
+    #[test]
+    fn test_process_script() {
+        let mut options = ProcessOptions {
+            operations: vec![Operation::Translate(Point::new(1.0, 0.5))],
+            scale: None,
+            precision: 2,
+        };
+        let mut rendered = Vec::new();
+        let output = process_input("in.ass", SCRIPT, &options, &mut rendered).unwrap();
+        assert_eq!(
+            output,
+            SCRIPT.replace("m 0 0 l 20 0 20 20", "m 2.0 1.0 l 22.0 1.0 22.0 21.0")
+        );
+        assert_eq!(rendered.len(), 1);
+        assert_eq!(rendered[0].1.color, [255, 0, 0]);
+
+        options.scale = Some(1);
+        options.precision = 1;
+        let output = process_input("in.ass", SCRIPT, &options, &mut rendered).unwrap();
+        assert!(output.contains(r"\p1}m 1.0 0.5 l 11.0 0.5 11.0 10.5{\p0}, text"));
+    }
+
+    #[test]
+    fn test_process_drawings() {
+        let options = ProcessOptions {
+            operations: vec![Operation::Reverse],
+            scale: Some(2),
+            precision: 2,
+        };
+        let mut rendered = Vec::new();
+        let output = process_input(
+            "-",
+            "m 0 0 l 1 0 0 1\n\nm 0 0 b 1 1 2 1 3 0\n",
+            &options,
+            &mut rendered,
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "m 0.0 2.0 l 2.0 0.0 0.0 0.0\n\nm 6.0 0.0 b 4.0 2.0 2.0 2.0 0.0 0.0\n"
+        );
+        assert_eq!(
+            process_input("-", "m 0 0 l 5", &options, &mut rendered)
+                .unwrap_err()
+                .to_string(),
+            "-, line 1: `l` is missing coordinates"
+        );
+    }
+
+    #[test]
+    fn test_process_operations() {
+        let process = |text: &str, operations, scale, precision| {
+            let options = ProcessOptions {
+                operations,
+                scale,
+                precision,
+            };
+            process_input("-", text, &options, &mut Vec::new()).unwrap()
+        };
+        let square = "m 0 0 l 10 0 10 10 0 10";
+        let other = parse_drawing("m 5 5 l 15 5 15 15 5 15").unwrap();
+
+        let translate = vec![Operation::Translate(Point::new(1.0, -2.0))];
+        assert_eq!(
+            process(square, translate, None, 1),
+            "m 1.0 -2.0 l 11.0 -2.0 11.0 8.0 1.0 8.0"
+        );
+        let scale = vec![Operation::Scale(Point::new(2.0, 0.5))];
+        assert_eq!(
+            process(square, scale, None, 1),
+            "m 0.0 0.0 l 20.0 0.0 20.0 5.0 0.0 5.0"
+        );
+        assert_eq!(
+            process(square, vec![Operation::Rotate(90.0)], None, 1),
+            "m 0.0 0.0 l 0.0 -10.0 10.0 -10.0 10.0 0.0"
+        );
+        assert_eq!(
+            process(
+                "m 0 0 b 0 10 10 10 10 0",
+                vec![Operation::Flatten(1.0)],
+                None,
+                1
+            ),
+            "m 0.0 0.0 l 1.6 5.6 5.0 7.5 8.4 5.6 10.0 0.0"
+        );
+        // The point barely off the bottom edge goes.
+        let simplify = vec![Operation::Simplify(0.1)];
+        assert_eq!(
+            process("m 0 0 l 5 0.01 10 0 10 10 0 10", simplify, None, 1),
+            "m 0.0 0.0 l 10.0 0.0 10.0 10.0 0.0 10.0"
+        );
+        assert_eq!(
+            process(square, vec![Operation::Reverse], None, 1),
+            "m 0.0 10.0 l 10.0 10.0 10.0 0.0 0.0 0.0"
+        );
+
+        let boolean = |op| vec![Operation::Boolean(op, Box::new(other.clone()))];
+        assert_eq!(
+            process(square, boolean(BooleanOp::Union), None, 1),
+            "m 0.0 0.0 l 10.0 0.0 10.0 5.0 15.0 5.0 15.0 15.0 5.0 15.0 5.0 10.0 0.0 10.0"
+        );
+        assert_eq!(
+            process(square, boolean(BooleanOp::Intersection), None, 1),
+            "m 10.0 5.0 l 10.0 10.0 5.0 10.0 5.0 5.0"
+        );
+        assert_eq!(
+            process(square, boolean(BooleanOp::Difference), None, 1),
+            "m 0.0 0.0 l 10.0 0.0 10.0 5.0 5.0 5.0 5.0 10.0 0.0 10.0"
+        );
+
+        // Going from `\p2` to `\p3` doubles the coordinates, and the tag changes to match.
+        assert_eq!(
+            process(SCRIPT, Vec::new(), Some(3), 1),
+            SCRIPT
+                .replace(r"\p2}", r"\p3}")
+                .replace("m 0 0 l 20 0 20 20", "m 0.0 0.0 l 40.0 0.0 40.0 40.0")
+        );
+        let line = "m 0 0 l 1.23456 -0.5";
+        assert_eq!(process(line, Vec::new(), None, 0), "m 0.0 0.0 l 1.0 -1.0");
+        assert_eq!(process(line, Vec::new(), None, 3), "m 0.0 0.0 l 1.235 -0.5");
+        assert_eq!(
+            process(line, Vec::new(), Some(2), 3),
+            "m 0.0 0.0 l 2.469 -1.0"
+        );
+    }
+}
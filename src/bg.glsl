@@ -0,0 +1,32 @@
+#version 330
+#extension GL_ARB_explicit_uniform_location : require
+
+layout (location=0) uniform vec2 screen_dims;
+layout (location=2) uniform vec2 scene_pos;
+layout (location=4) uniform float scale;
+layout (location=5) uniform vec2 drawing_pos;
+// Scales and rotates the background around its top-left corner, before it's moved to `drawing_pos`.
+layout (location=6) uniform mat2 u_Matrix;
+
+layout (location=0) in vec2 a_Position;
+
+out vec2 v_Position;
+
+void main() {
+	vec2 pos = u_Matrix * a_Position;
+
+	pos += drawing_pos;
+
+	pos -= scene_pos;
+	pos /= screen_dims;
+	pos *= scale;
+
+	// top-left origin
+	pos -= 0.5;
+	pos *= 2;
+	pos.y *= -1;
+
+	v_Position = a_Position;
+
+	gl_Position = vec4(pos, 0.0, 1.0);
+}
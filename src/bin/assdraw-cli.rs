@@ -0,0 +1,14 @@
+//! Processing drawings and projects from the command line, for use in scripts and Makefiles.
+
+use std::env;
+use std::process;
+
+use assdraw_rs::batch;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = batch::run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,130 @@
+//! Getting text and images on and off the clipboard, whichever platform's clipboard that is.
+
+use image::RgbaImage;
+use thiserror::Error;
+
+use crate::ass::ParseError;
+
+#[derive(Debug, Error)]
+pub enum ClipboardError {
+    #[error("couldn't use the clipboard: {0}")]
+    System(String),
+    #[error("couldn't read the image on the clipboard: {0}")]
+    Image(#[from] image::ImageError),
+}
+
+/// Why pasting didn't work.
+#[derive(Debug, Error)]
+pub enum PasteError {
+    #[error(transparent)]
+    Clipboard(#[from] ClipboardError),
+    #[error("the text on the clipboard isn't a drawing: {0}")]
+    Parse(#[from] ParseError),
+    #[error("there's nothing on the clipboard to paste")]
+    Empty,
+}
+
+/// A clipboard that can hold text or an image. Having nothing to give isn't an error, just `None`.
+pub trait Clipboard {
+    fn get_text(&mut self) -> Result<Option<String>, ClipboardError>;
+    fn set_text(&mut self, text: &str) -> Result<(), ClipboardError>;
+    fn get_image(&mut self) -> Result<Option<RgbaImage>, ClipboardError>;
+}
+
+/// A clipboard that's just a variable, for tests.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryClipboard {
+    pub text: Option<String>,
+    pub image: Option<RgbaImage>,
+}
+
+impl Clipboard for MemoryClipboard {
+    fn get_text(&mut self) -> Result<Option<String>, ClipboardError> {
+        Ok(self.text.clone())
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), ClipboardError> {
+        self.text = Some(text.to_owned());
+        Ok(())
+    }
+
+    fn get_image(&mut self) -> Result<Option<RgbaImage>, ClipboardError> {
+        Ok(self.image.clone())
+    }
+}
+
+/// The Windows clipboard.
+#[cfg(windows)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct WindowsClipboard;
+
+#[cfg(windows)]
+impl Clipboard for WindowsClipboard {
+    fn get_text(&mut self) -> Result<Option<String>, ClipboardError> {
+        use clipboard_win::formats::CF_UNICODETEXT;
+        if !clipboard_win::is_format_avail(CF_UNICODETEXT) {
+            return Ok(None);
+        }
+        clipboard_win::get_clipboard_string()
+            .map(Some)
+            .map_err(|e| ClipboardError::System(e.to_string()))
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), ClipboardError> {
+        clipboard_win::set_clipboard_string(text).map_err(|e| ClipboardError::System(e.to_string()))
+    }
+
+    /// Bitmaps come off the clipboard as BMP files.
+    fn get_image(&mut self) -> Result<Option<RgbaImage>, ClipboardError> {
+        use clipboard_win::formats::{Bitmap, CF_BITMAP};
+        if !clipboard_win::is_format_avail(CF_BITMAP) {
+            return Ok(None);
+        }
+        let bmp: Vec<u8> = clipboard_win::get_clipboard(Bitmap)
+            .map_err(|e| ClipboardError::System(e.to_string()))?;
+        let image = image::load_from_memory_with_format(&bmp, image::ImageFormat::Bmp)?;
+        Ok(Some(image.into_rgba8()))
+    }
+}
+
+/// The clipboard on any platform `arboard` supports, X11 and Wayland included.
+#[cfg(feature = "winit")]
+pub struct PortableClipboard(arboard::Clipboard);
+
+#[cfg(feature = "winit")]
+impl PortableClipboard {
+    pub fn new() -> Result<Self, ClipboardError> {
+        arboard::Clipboard::new()
+            .map(Self)
+            .map_err(|e| ClipboardError::System(e.to_string()))
+    }
+}
+
+#[cfg(feature = "winit")]
+impl Clipboard for PortableClipboard {
+    fn get_text(&mut self) -> Result<Option<String>, ClipboardError> {
+        match self.0.get_text() {
+            Ok(text) => Ok(Some(text)),
+            Err(arboard::Error::ContentNotAvailable) => Ok(None),
+            Err(e) => Err(ClipboardError::System(e.to_string())),
+        }
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), ClipboardError> {
+        self.0
+            .set_text(text)
+            .map_err(|e| ClipboardError::System(e.to_string()))
+    }
+
+    fn get_image(&mut self) -> Result<Option<RgbaImage>, ClipboardError> {
+        let image = match self.0.get_image() {
+            Ok(image) => image,
+            Err(arboard::Error::ContentNotAvailable) => return Ok(None),
+            Err(e) => return Err(ClipboardError::System(e.to_string())),
+        };
+        let (width, height) = (image.width as u32, image.height as u32);
+        RgbaImage::from_raw(width, height, image.bytes.into_owned())
+            .map(Some)
+            .ok_or_else(|| ClipboardError::System("the image on the clipboard is truncated".into()))
+    }
+}
@@ -0,0 +1,435 @@
+//! A small binary format for saving editor state.
+//!
+//! Everything is little-endian, lengths are `u64`s, and floats are stored bit-for-bit,
+//! so that decoding gives back exactly what was encoded.
+
+use std::convert::TryInto;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use image::RgbaImage;
+use thiserror::Error;
+
+use crate::point::Point;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DecodeError {
+    #[error("the data ended unexpectedly")]
+    UnexpectedEof,
+    #[error("the data isn't in the expected format")]
+    BadMagic,
+    #[error("format version {0} is newer than this version of the program supports")]
+    UnsupportedVersion(u32),
+    #[error("invalid {0}")]
+    Invalid(&'static str),
+    #[error("{0} bytes of unexpected data at the end")]
+    TrailingData(usize),
+}
+
+pub type Result<T> = std::result::Result<T, DecodeError>;
+
+/// Accumulates encoded data.
+#[derive(Debug, Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+    /// Images that have already been written, so that shared images are only written once.
+    images: Vec<Rc<RgbaImage>>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a file with a four-byte tag identifying what it contains, and the version of its format.
+    pub fn with_header(magic: &[u8; 4], version: u32) -> Self {
+        let mut w = Self::new();
+        w.write_bytes(magic);
+        version.encode(&mut w);
+        w
+    }
+
+    #[inline]
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    #[inline]
+    pub fn write<T: Encode + ?Sized>(&mut self, value: &T) {
+        value.encode(self);
+    }
+
+    #[inline]
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads back what a `Writer` wrote.
+#[derive(Debug)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+    images: Vec<Rc<RgbaImage>>,
+    /// The format version from the header, if there was one.
+    version: Option<u32>,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            images: Vec::new(),
+            version: None,
+        }
+    }
+
+    /// Checks the header written by `Writer::with_header`, returning the format version.
+    pub fn with_header(data: &'a [u8], magic: &[u8; 4], version: u32) -> Result<(Self, u32)> {
+        let mut r = Self::new(data);
+        if r.read_bytes(4)? != magic {
+            return Err(DecodeError::BadMagic);
+        }
+        let file_version = u32::decode(&mut r)?;
+        if file_version > version {
+            return Err(DecodeError::UnsupportedVersion(file_version));
+        }
+        r.version = Some(file_version);
+        Ok((r, file_version))
+    }
+
+    /// The version read by `with_header`, so that types whose encoding changed can still read older files.
+    #[inline]
+    pub fn version(&self) -> Option<u32> {
+        self.version
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    #[inline]
+    pub fn read<T: Decode>(&mut self) -> Result<T> {
+        T::decode(self)
+    }
+
+    /// Reads a length, checking that there are at least that many bytes left,
+    /// so that corrupt data can't cause a huge allocation.
+    fn read_len(&mut self) -> Result<usize> {
+        let len = usize::decode(self)?;
+        if len > self.data.len() {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        Ok(len)
+    }
+
+    /// Fails if there's anything left over.
+    pub fn finish(self) -> Result<()> {
+        match self.data.len() {
+            0 => Ok(()),
+            n => Err(DecodeError::TrailingData(n)),
+        }
+    }
+}
+
+pub trait Encode {
+    fn encode(&self, w: &mut Writer);
+}
+
+pub trait Decode: Sized {
+    fn decode(r: &mut Reader<'_>) -> Result<Self>;
+}
+
+macro_rules! impl_le_bytes {
+    ($($ty:ty),*) => {$(
+        impl Encode for $ty {
+            #[inline]
+            fn encode(&self, w: &mut Writer) {
+                w.write_bytes(&self.to_le_bytes());
+            }
+        }
+
+        impl Decode for $ty {
+            #[inline]
+            fn decode(r: &mut Reader<'_>) -> Result<Self> {
+                let bytes = r.read_bytes(std::mem::size_of::<$ty>())?;
+                Ok(Self::from_le_bytes(bytes.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+
+impl_le_bytes!(u8, u16, u32, u64, i32, f32);
+
+impl<T: Encode + ?Sized> Encode for &T {
+    #[inline]
+    fn encode(&self, w: &mut Writer) {
+        (**self).encode(w);
+    }
+}
+
+impl Encode for usize {
+    #[inline]
+    fn encode(&self, w: &mut Writer) {
+        (*self as u64).encode(w);
+    }
+}
+
+impl Decode for usize {
+    #[inline]
+    fn decode(r: &mut Reader<'_>) -> Result<Self> {
+        u64::decode(r)?
+            .try_into()
+            .map_err(|_| DecodeError::Invalid("length"))
+    }
+}
+
+impl Encode for bool {
+    #[inline]
+    fn encode(&self, w: &mut Writer) {
+        (*self as u8).encode(w);
+    }
+}
+
+impl Decode for bool {
+    fn decode(r: &mut Reader<'_>) -> Result<Self> {
+        match u8::decode(r)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::Invalid("bool")),
+        }
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, w: &mut Writer) {
+        self.len().encode(w);
+        w.write_bytes(self.as_bytes());
+    }
+}
+
+impl Encode for String {
+    #[inline]
+    fn encode(&self, w: &mut Writer) {
+        self.as_str().encode(w);
+    }
+}
+
+impl Decode for String {
+    fn decode(r: &mut Reader<'_>) -> Result<Self> {
+        let len = r.read_len()?;
+        let bytes = r.read_bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::Invalid("string"))
+    }
+}
+
+impl<T: Encode> Encode for [T] {
+    fn encode(&self, w: &mut Writer) {
+        self.len().encode(w);
+        for item in self {
+            item.encode(w);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    #[inline]
+    fn encode(&self, w: &mut Writer) {
+        self[..].encode(w);
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(r: &mut Reader<'_>) -> Result<Self> {
+        let len = r.read_len()?;
+        (0..len).map(|_| T::decode(r)).collect()
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Some(value) => {
+                true.encode(w);
+                value.encode(w);
+            }
+            None => false.encode(w),
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(r: &mut Reader<'_>) -> Result<Self> {
+        match bool::decode(r)? {
+            true => T::decode(r).map(Some),
+            false => Ok(None),
+        }
+    }
+}
+
+impl<T: Encode> Encode for [T; 2] {
+    fn encode(&self, w: &mut Writer) {
+        self[0].encode(w);
+        self[1].encode(w);
+    }
+}
+
+impl<T: Decode> Decode for [T; 2] {
+    fn decode(r: &mut Reader<'_>) -> Result<Self> {
+        Ok([T::decode(r)?, T::decode(r)?])
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident),*) => {
+        impl<$($name: Encode),*> Encode for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn encode(&self, w: &mut Writer) {
+                let ($($name,)*) = self;
+                $($name.encode(w);)*
+            }
+        }
+
+        impl<$($name: Decode),*> Decode for ($($name,)*) {
+            fn decode(r: &mut Reader<'_>) -> Result<Self> {
+                Ok(($($name::decode(r)?,)*))
+            }
+        }
+    };
+}
+
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+
+impl<T: Encode> Encode for Point<T> {
+    fn encode(&self, w: &mut Writer) {
+        self.x.encode(w);
+        self.y.encode(w);
+    }
+}
+
+impl<T: Decode> Decode for Point<T> {
+    fn decode(r: &mut Reader<'_>) -> Result<Self> {
+        Ok(Point::new(T::decode(r)?, T::decode(r)?))
+    }
+}
+
+/// Stored as the time since the Unix epoch. Earlier times are clamped to the epoch.
+impl Encode for SystemTime {
+    fn encode(&self, w: &mut Writer) {
+        let since_epoch = self.duration_since(UNIX_EPOCH).unwrap_or_default();
+        since_epoch.as_secs().encode(w);
+        since_epoch.subsec_nanos().encode(w);
+    }
+}
+
+impl Decode for SystemTime {
+    fn decode(r: &mut Reader<'_>) -> Result<Self> {
+        let (secs, nanos) = <(u64, u32)>::decode(r)?;
+        if nanos >= 1_000_000_000 {
+            return Err(DecodeError::Invalid("timestamp"));
+        }
+        UNIX_EPOCH
+            .checked_add(Duration::new(secs, nanos))
+            .ok_or(DecodeError::Invalid("timestamp"))
+    }
+}
+
+/// Each image is written out in full the first time, and referred to by index after that.
+/// Decoding gives back images that are shared the same way.
+impl Encode for Rc<RgbaImage> {
+    fn encode(&self, w: &mut Writer) {
+        match w.images.iter().position(|img| Rc::ptr_eq(img, self)) {
+            Some(i) => i.encode(w),
+            None => {
+                w.images.len().encode(w);
+                w.images.push(self.clone());
+                (self.width(), self.height()).encode(w);
+                w.write_bytes(self.as_raw());
+            }
+        }
+    }
+}
+
+impl Decode for Rc<RgbaImage> {
+    fn decode(r: &mut Reader<'_>) -> Result<Self> {
+        let i = usize::decode(r)?;
+        if let Some(img) = r.images.get(i) {
+            return Ok(img.clone());
+        } else if i != r.images.len() {
+            return Err(DecodeError::Invalid("image reference"));
+        }
+
+        let (width, height) = <(u32, u32)>::decode(r)?;
+        let len = (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|n| n.checked_mul(4))
+            .ok_or(DecodeError::Invalid("image size"))?;
+        let pixels = r.read_bytes(len)?.to_vec();
+        let img = Rc::new(RgbaImage::from_raw(width, height, pixels).unwrap());
+        r.images.push(img.clone());
+        Ok(img)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::time::SystemTime;
+
+    use image::RgbaImage;
+
+    use super::{DecodeError, Reader, Writer};
+    use crate::point::Point;
+
+    type Value = (
+        Vec<Option<Point<f32>>>,
+        (String, SystemTime),
+        [Rc<RgbaImage>; 2],
+    );
+
+    #[test]
+    fn test_round_trip() {
+        let img = Rc::new(RgbaImage::from_pixel(3, 2, image::Rgba([1, 2, 3, 4])));
+        let time = SystemTime::now();
+        let value: Value = (
+            vec![Some(Point::new(0.1f32, -f32::MAX)), None],
+            ("hello".to_string(), time),
+            [img.clone(), img],
+        );
+
+        let mut w = Writer::with_header(b"TEST", 2);
+        w.write(&value);
+        let data = w.finish();
+        let (mut r, version) = Reader::with_header(&data, b"TEST", 3).unwrap();
+        assert_eq!(version, 2);
+        let decoded: Value = r.read().unwrap();
+        r.finish().unwrap();
+
+        assert_eq!(decoded.0, value.0);
+        assert_eq!(decoded.1, value.1);
+        assert_eq!(decoded.2[0], value.2[0]);
+        assert!(Rc::ptr_eq(&decoded.2[0], &decoded.2[1]));
+    }
+
+    #[test]
+    fn test_bad_data() {
+        let data = Writer::with_header(b"TEST", 5).finish();
+        let err = Reader::with_header(&data, b"TEST", 4).unwrap_err();
+        assert_eq!(err, DecodeError::UnsupportedVersion(5));
+        let err = Reader::with_header(&data, b"NOPE", 5).unwrap_err();
+        assert_eq!(err, DecodeError::BadMagic);
+
+        let mut w = Writer::new();
+        w.write(&u64::MAX);
+        let data = w.finish();
+        let err = Reader::new(&data).read::<Vec<u8>>().unwrap_err();
+        assert_eq!(err, DecodeError::UnexpectedEof);
+        let err = Reader::new(&data[..7]).read::<u64>().unwrap_err();
+        assert_eq!(err, DecodeError::UnexpectedEof);
+    }
+}
@@ -0,0 +1,1154 @@
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+use std::rc::Rc;
+
+use image::RgbaImage;
+
+use crate::codec::{self, Decode, DecodeError, Encode, Reader, Writer};
+use crate::drawing::{Drawing, DrawingDelta};
+use crate::point::{Point, Rect};
+use crate::undo::{Delta, UndoStack};
+
+/// Identifies a saved history.
+const HISTORY_MAGIC: &[u8; 4] = b"ADUH";
+/// Bumped whenever the saved history format changes. Older versions are still loaded.
+const HISTORY_VERSION: u32 = 5;
+/// The first version of both the history and the project formats in which documents have layers.
+/// Before that, a document was a single drawing.
+pub const LAYERS_VERSION: u32 = 2;
+/// The first version of both formats in which layers have a fill rule.
+pub const FILL_RULE_VERSION: u32 = 3;
+/// The first version of both formats in which layers have a border, shadow and blur.
+pub const EFFECTS_VERSION: u32 = 4;
+/// The first version of both formats in which the background has a transform in the document.
+pub const BACKGROUND_VERSION: u32 = 5;
+
+pub fn older_than(r: &Reader<'_>, version: u32) -> bool {
+    r.version().is_some_and(|v| v < version)
+}
+
+fn before_layers(r: &Reader<'_>) -> bool {
+    older_than(r, LAYERS_VERSION)
+}
+
+/// Identifies a layer, even as layers are added, removed and reordered.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LayerId(u32);
+
+/// Which parts of a drawing with overlapping or nested contours are filled.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum FillRule {
+    /// Filled wherever the contours wind around a point at all, like libass does.
+    #[default]
+    NonZero,
+    /// Filled wherever a point is inside an odd number of contours.
+    EvenOdd,
+}
+
+/// The most `\be` passes libass does; it clamps anything above this.
+pub const MAX_BE: u32 = 127;
+/// The strongest `\blur` libass accepts.
+pub const MAX_BLUR: f32 = 100.0;
+/// The widest `\bord` kept. libass has no limit, but nothing drawn by hand needs more,
+/// and the border is rasterized one pixel at a time.
+pub const MAX_BORDER: f32 = 100.0;
+/// The furthest `\shad` kept. libass has no limit either, but the shadow's offset grows the exported image.
+pub const MAX_SHADOW: f32 = 100.0;
+
+/// How a layer's border, shadow and blur look, exported as the override tags of the same names.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Effects {
+    /// `\bord`: how far the border reaches outside the shape, in pixels.
+    pub border: f32,
+    /// `\shad`: how far down and to the right the shadow is.
+    pub shadow: f32,
+    /// `\be`: how many times the edges are softened.
+    pub be: u32,
+    /// `\blur`: how strongly the edges are blurred.
+    pub blur: f32,
+    /// `\3c` and `\3a`.
+    pub border_color: [u8; 3],
+    pub border_alpha: u8,
+    /// `\4c` and `\4a`.
+    pub shadow_color: [u8; 3],
+    pub shadow_alpha: u8,
+}
+
+/// Everything about a layer other than its drawing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerSettings {
+    pub name: String,
+    pub visible: bool,
+    /// Locked layers can't be edited.
+    pub locked: bool,
+    /// The fill color, exported as `\1c`.
+    pub color: [u8; 3],
+    /// The fill's transparency, exported as `\1a`: 0 is opaque and 255 is invisible.
+    pub alpha: u8,
+    /// The layer number of the exported `Dialogue` line.
+    pub ass_layer: i32,
+    pub fill_rule: FillRule,
+    pub effects: Effects,
+}
+
+impl Default for LayerSettings {
+    fn default() -> Self {
+        Self {
+            name: "Layer 1".to_string(),
+            visible: true,
+            locked: false,
+            color: [127, 127, 127],
+            alpha: 0,
+            ass_layer: 0,
+            fill_rule: FillRule::default(),
+            effects: Effects::default(),
+        }
+    }
+}
+
+/// One drawing, along with how it's shown and exported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    id: LayerId,
+    pub settings: LayerSettings,
+    pub drawing: Drawing<Point<f32>>,
+}
+
+impl Layer {
+    #[inline]
+    pub fn id(&self) -> LayerId {
+        self.id
+    }
+}
+
+/// How the background is placed in the scene, and how strongly it shows.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BackgroundTransform {
+    /// Where the image's top-left corner is in the scene.
+    pub offset: Point<f32>,
+    /// How many scene pixels each image pixel covers.
+    pub scale: f32,
+    /// How far the image is turned around its top-left corner, in degrees, counterclockwise like `\frz`.
+    pub rotation: f32,
+    /// From 0 for invisible to 1 for fully opaque.
+    pub opacity: f32,
+    /// A locked background can't be moved, scaled or turned.
+    pub locked: bool,
+}
+
+impl Default for BackgroundTransform {
+    fn default() -> Self {
+        Self {
+            offset: Point::new(0.0, 0.0),
+            scale: 1.0,
+            rotation: 0.0,
+            opacity: 1.0,
+            locked: false,
+        }
+    }
+}
+
+impl BackgroundTransform {
+    /// The columns of the matrix that scales and turns image coordinates, before they're offset.
+    pub fn matrix(&self) -> [Point<f32>; 2] {
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        [
+            Point::new(cos, -sin) * self.scale,
+            Point::new(sin, cos) * self.scale,
+        ]
+    }
+
+    /// Where a point in the image ends up in the scene.
+    pub fn to_scene(&self, p: Point<f32>) -> Point<f32> {
+        let [x, y] = self.matrix();
+        self.offset + x * p.x + y * p.y
+    }
+
+    /// Which point in the image ends up at a point in the scene.
+    pub fn to_image(&self, p: Point<f32>) -> Point<f32> {
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        let d = (p - self.offset) / self.scale;
+        Point::new(d.x * cos - d.y * sin, d.x * sin + d.y * cos)
+    }
+
+    /// Whether every number is finite, the scale is positive and the opacity is between 0 and 1.
+    pub fn is_valid(&self) -> bool {
+        self.offset.x.is_finite()
+            && self.offset.y.is_finite()
+            && self.scale.is_finite()
+            && self.scale > 0.0
+            && self.rotation.is_finite()
+            && (0.0..=1.0).contains(&self.opacity)
+    }
+
+    /// Whether the transforms place the background differently, regardless of how it shows.
+    pub fn moves(&self, other: &Self) -> bool {
+        self.offset != other.offset || self.scale != other.scale || self.rotation != other.rotation
+    }
+}
+
+/// Everything that undo and redo apply to.
+#[derive(Debug, Clone)]
+pub struct Document {
+    /// From bottom to top. There's always at least one.
+    layers: Vec<Layer>,
+    /// The image being traced over. It's shared rather than copied between history entries.
+    pub background: Option<Rc<RgbaImage>>,
+    pub background_transform: BackgroundTransform,
+    next_layer_id: u32,
+}
+
+impl Default for Document {
+    fn default() -> Self {
+        Self::from_drawing(Drawing::new(), LayerSettings::default())
+    }
+}
+
+impl Document {
+    /// A document with a single layer.
+    pub fn from_drawing(drawing: Drawing<Point<f32>>, settings: LayerSettings) -> Self {
+        Self {
+            layers: vec![Layer {
+                id: LayerId(0),
+                settings,
+                drawing,
+            }],
+            background: None,
+            background_transform: BackgroundTransform::default(),
+            next_layer_id: 1,
+        }
+    }
+
+    pub fn background_size(&self) -> Option<Point<f32>> {
+        let img = self.background.as_ref()?;
+        Some(Point::new(img.width() as f32, img.height() as f32))
+    }
+
+    /// The part of the scene the background covers, once it's transformed.
+    pub fn background_bounds(&self) -> Option<Rect> {
+        let size = self.background_size()?;
+        let corners = [(0.0, 0.0), (size.x, 0.0), (0.0, size.y), (size.x, size.y)];
+        let t = &self.background_transform;
+        Rect::from_points(corners.iter().map(|&c| t.to_scene(Point::from(c))))
+    }
+
+    #[inline]
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    #[inline]
+    pub fn layer_mut(&mut self, index: usize) -> &mut Layer {
+        &mut self.layers[index]
+    }
+
+    pub fn layer_index(&self, id: LayerId) -> Option<usize> {
+        self.layers.iter().position(|layer| layer.id == id)
+    }
+
+    /// Adds an empty layer, so that it ends up at `index`.
+    pub fn insert_layer(&mut self, index: usize, settings: LayerSettings) -> LayerId {
+        let id = LayerId(self.next_layer_id);
+        self.next_layer_id += 1;
+        let layer = Layer {
+            id,
+            settings,
+            drawing: Drawing::new(),
+        };
+        self.layers.insert(index, layer);
+        id
+    }
+
+    /// Removes a layer, unless it's the only one.
+    pub fn remove_layer(&mut self, index: usize) -> Option<Layer> {
+        if self.layers.len() == 1 {
+            return None;
+        }
+        Some(self.layers.remove(index))
+    }
+
+    /// Moves the layer at `from` so that it ends up at `to`, shifting the ones in between.
+    pub fn move_layer(&mut self, from: usize, to: usize) {
+        let layer = self.layers.remove(from);
+        self.layers.insert(to, layer);
+    }
+}
+
+/// Whether two backgrounds are the same image, as opposed to merely looking the same.
+pub fn same_background(a: &Option<Rc<RgbaImage>>, b: &Option<Rc<RgbaImage>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+/// The difference between two versions of one layer.
+#[derive(Debug, Clone)]
+struct LayerDelta {
+    index: usize,
+    /// The old and new settings, if they changed.
+    settings: Option<[LayerSettings; 2]>,
+    drawing: DrawingDelta<Point<f32>>,
+}
+
+/// A layer being added, removed or moved, which shifts the ones after it.
+#[derive(Debug, Clone)]
+enum LayerChange {
+    /// The layer was added at the index.
+    Insert(usize, Layer),
+    /// The layer at the index was removed.
+    Remove(usize, Layer),
+    /// The layer at `from` was moved to `to`.
+    Move { from: usize, to: usize },
+}
+
+impl LayerChange {
+    /// Makes the change to a list of layers, or undoes it going `backward`,
+    /// unless it doesn't fit them, in which case it returns false and leaves them alone.
+    fn change<T>(
+        &self,
+        layers: &mut Vec<T>,
+        backward: bool,
+        id: impl Fn(&T) -> LayerId,
+        layer: impl Fn(&Layer) -> T,
+    ) -> bool {
+        match (self, backward) {
+            (Self::Insert(index, added), false) | (Self::Remove(index, added), true) => {
+                if *index > layers.len() || layers.iter().any(|other| id(other) == added.id) {
+                    return false;
+                }
+                layers.insert(*index, layer(added));
+            }
+            (Self::Remove(index, removed), false) | (Self::Insert(index, removed), true) => {
+                if layers.get(*index).map(&id) != Some(removed.id) {
+                    return false;
+                }
+                layers.remove(*index);
+            }
+            (&Self::Move { from, to }, backward) => {
+                let (from, to) = if backward { (to, from) } else { (from, to) };
+                if from >= layers.len() || to >= layers.len() {
+                    return false;
+                }
+                let moved = layers.remove(from);
+                layers.insert(to, moved);
+            }
+        }
+        true
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct LayersDelta {
+    /// The layers that were removed, moved and added, in the order that happened.
+    changes: Vec<LayerChange>,
+    /// How the layers that were there both before and after changed, by where they are after.
+    edits: Vec<LayerDelta>,
+}
+
+impl LayersDelta {
+    fn diff(old: Vec<Layer>, new: &[Layer]) -> Self {
+        let new_ids: HashSet<_> = new.iter().map(|layer| layer.id).collect();
+        let mut changes = Vec::new();
+        // Going from the top, so that the layers below keep their indexes.
+        let mut kept = Vec::new();
+        for (index, layer) in old.into_iter().enumerate().rev() {
+            if new_ids.contains(&layer.id) {
+                kept.push(layer);
+            } else {
+                changes.push(LayerChange::Remove(index, layer));
+            }
+        }
+        kept.reverse();
+
+        let mut order: Vec<_> = kept.iter().map(|layer| layer.id).collect();
+        let mut kept: HashMap<_, _> = kept.into_iter().map(|layer| (layer.id, layer)).collect();
+        let target: Vec<_> = new
+            .iter()
+            .map(|layer| layer.id)
+            .filter(|id| kept.contains_key(id))
+            .collect();
+        for (index, &id) in target.iter().enumerate() {
+            if order[index] == id {
+                continue;
+            }
+            // Either the layer that's here was moved up past the next ones,
+            // or the one that belongs here was moved down to it.
+            let (from, to) = if order[index + 1] == id {
+                (
+                    index,
+                    target.iter().position(|&id| id == order[index]).unwrap(),
+                )
+            } else {
+                (order.iter().position(|&other| other == id).unwrap(), index)
+            };
+            let moved = order.remove(from);
+            order.insert(to, moved);
+            changes.push(LayerChange::Move { from, to });
+        }
+
+        let mut edits = Vec::new();
+        for (index, layer) in new.iter().enumerate() {
+            match kept.remove(&layer.id) {
+                Some(old) if old != *layer => edits.push(LayerDelta {
+                    index,
+                    settings: Some([old.settings, layer.settings.clone()]).filter(|[a, b]| a != b),
+                    drawing: DrawingDelta::diff(old.drawing, &layer.drawing),
+                }),
+                Some(_) => {}
+                None => changes.push(LayerChange::Insert(index, layer.clone())),
+            }
+        }
+        Self { changes, edits }
+    }
+}
+
+/// The difference between two versions of a document.
+#[derive(Debug, Clone)]
+pub struct DocumentDelta {
+    layers: LayersDelta,
+    /// The old and new backgrounds, if the background changed.
+    background: Option<[Option<Rc<RgbaImage>>; 2]>,
+    /// The old and new background transforms, if that changed.
+    background_transform: Option<[BackgroundTransform; 2]>,
+    next_layer_ids: [u32; 2],
+}
+
+impl DocumentDelta {
+    /// Whether the layers that were added, removed and moved are where they'd have to be in `doc`,
+    /// and each layer delta is for a different layer that's in `doc`, and `fits` its drawing.
+    fn layers_fit(
+        &self,
+        doc: &Document,
+        backward: bool,
+        fits: impl Fn(&DrawingDelta<Point<f32>>, &Drawing<Point<f32>>) -> bool,
+    ) -> bool {
+        let ids: Vec<_> = doc.layers.iter().map(|layer| layer.id).collect();
+        let mut changed = ids.clone();
+        let changes_fit = if backward {
+            let mut changes = self.layers.changes.iter().rev();
+            changes.all(|change| change.change(&mut changed, true, |&id| id, Layer::id))
+        } else {
+            let mut changes = self.layers.changes.iter();
+            changes.all(|change| change.change(&mut changed, false, |&id| id, Layer::id))
+        };
+        // The layer deltas are for the layers as they are after the changes.
+        let after = if backward { &ids } else { &changed };
+        let deltas = &self.layers.edits;
+        changes_fit
+            && !changed.is_empty()
+            && deltas.windows(2).all(|pair| pair[0].index < pair[1].index)
+            && deltas.iter().all(|delta| {
+                let layer = after.get(delta.index).and_then(|&id| doc.layer_index(id));
+                layer.is_some_and(|index| fits(&delta.drawing, &doc.layers[index].drawing))
+            })
+    }
+}
+
+impl Delta<Document> for DocumentDelta {
+    fn diff(old: Document, new: &Document) -> Self {
+        let background = if same_background(&old.background, &new.background) {
+            None
+        } else {
+            Some([old.background, new.background.clone()])
+        };
+        let background_transform =
+            Some([old.background_transform, new.background_transform]).filter(|[a, b]| a != b);
+        Self {
+            layers: LayersDelta::diff(old.layers, &new.layers),
+            background,
+            background_transform,
+            next_layer_ids: [old.next_layer_id, new.next_layer_id],
+        }
+    }
+
+    fn apply(&mut self, doc: &mut Document) {
+        for change in &self.layers.changes {
+            change.change(&mut doc.layers, false, Layer::id, Layer::clone);
+        }
+        for delta in &mut self.layers.edits {
+            let layer = &mut doc.layers[delta.index];
+            if let Some([_, new]) = &delta.settings {
+                layer.settings = new.clone();
+            }
+            delta.drawing.apply(&mut layer.drawing);
+        }
+        if let Some([_, new]) = &self.background {
+            doc.background = new.clone();
+        }
+        if let Some([_, new]) = self.background_transform {
+            doc.background_transform = new;
+        }
+        doc.next_layer_id = self.next_layer_ids[1];
+    }
+
+    fn revert(&mut self, doc: &mut Document) {
+        for delta in &mut self.layers.edits {
+            let layer = &mut doc.layers[delta.index];
+            if let Some([old, _]) = &delta.settings {
+                layer.settings = old.clone();
+            }
+            delta.drawing.revert(&mut layer.drawing);
+        }
+        for change in self.layers.changes.iter().rev() {
+            change.change(&mut doc.layers, true, Layer::id, Layer::clone);
+        }
+        if let Some([old, _]) = &self.background {
+            doc.background = old.clone();
+        }
+        if let Some([old, _]) = self.background_transform {
+            doc.background_transform = old;
+        }
+        doc.next_layer_id = self.next_layer_ids[0];
+    }
+
+    fn can_apply(&self, doc: &Document) -> bool {
+        self.layers_fit(doc, false, |delta, drawing| delta.can_apply(drawing))
+    }
+
+    fn can_revert(&self, doc: &Document) -> bool {
+        self.layers_fit(doc, true, |delta, drawing| delta.can_revert(drawing))
+    }
+
+    fn size(&self) -> usize {
+        let changes = self.layers.changes.iter().map(|change| match change {
+            LayerChange::Insert(_, layer) | LayerChange::Remove(_, layer) => {
+                size_of::<LayerChange>() + layer.drawing.memory_usage()
+            }
+            LayerChange::Move { .. } => size_of::<LayerChange>(),
+        });
+        let edits = self.layers.edits.iter();
+        let edits = edits.map(|delta| size_of::<LayerDelta>() + delta.drawing.size());
+        let layers: usize = changes.chain(edits).sum();
+        let images = self.background.iter().flatten().flatten();
+        size_of::<Self>() + layers + images.map(|img| img.len()).sum::<usize>()
+    }
+}
+
+impl Encode for LayerId {
+    fn encode(&self, w: &mut Writer) {
+        self.0.encode(w);
+    }
+}
+
+impl Decode for LayerId {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        r.read().map(Self)
+    }
+}
+
+impl Encode for LayerSettings {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.name);
+        w.write(&self.visible);
+        w.write(&self.locked);
+        w.write_bytes(&self.color);
+        w.write(&self.alpha);
+        w.write(&self.ass_layer);
+        w.write(&self.fill_rule);
+        w.write(&self.effects);
+    }
+}
+
+impl Decode for LayerSettings {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        let name = r.read()?;
+        let visible = r.read()?;
+        let locked = r.read()?;
+        let mut color = [0; 3];
+        color.copy_from_slice(r.read_bytes(3)?);
+        Ok(Self {
+            name,
+            visible,
+            locked,
+            color,
+            alpha: r.read()?,
+            ass_layer: r.read()?,
+            fill_rule: if older_than(r, FILL_RULE_VERSION) {
+                FillRule::default()
+            } else {
+                r.read()?
+            },
+            effects: if older_than(r, EFFECTS_VERSION) {
+                Effects::default()
+            } else {
+                r.read()?
+            },
+        })
+    }
+}
+
+impl Encode for Effects {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&(self.border, self.shadow));
+        w.write(&(self.be, self.blur));
+        w.write_bytes(&self.border_color);
+        w.write(&self.border_alpha);
+        w.write_bytes(&self.shadow_color);
+        w.write(&self.shadow_alpha);
+    }
+}
+
+impl Decode for Effects {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        fn rgb(r: &mut Reader<'_>) -> codec::Result<[u8; 3]> {
+            let mut rgb = [0; 3];
+            rgb.copy_from_slice(r.read_bytes(3)?);
+            Ok(rgb)
+        }
+
+        let (border, shadow): (f32, f32) = r.read()?;
+        let (be, blur): (u32, f32) = r.read()?;
+        let in_range = |v: f32, max| (0.0..=max).contains(&v);
+        if !in_range(border, MAX_BORDER)
+            || !in_range(shadow, MAX_SHADOW)
+            || !in_range(blur, MAX_BLUR)
+            || be > MAX_BE
+        {
+            return Err(DecodeError::Invalid("effects"));
+        }
+        Ok(Self {
+            border,
+            shadow,
+            be,
+            blur,
+            border_color: rgb(r)?,
+            border_alpha: r.read()?,
+            shadow_color: rgb(r)?,
+            shadow_alpha: r.read()?,
+        })
+    }
+}
+
+impl Encode for FillRule {
+    fn encode(&self, w: &mut Writer) {
+        let tag: u8 = match self {
+            Self::NonZero => 0,
+            Self::EvenOdd => 1,
+        };
+        w.write(&tag);
+    }
+}
+
+impl Decode for FillRule {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        match r.read::<u8>()? {
+            0 => Ok(Self::NonZero),
+            1 => Ok(Self::EvenOdd),
+            _ => Err(DecodeError::Invalid("fill rule")),
+        }
+    }
+}
+
+impl Encode for BackgroundTransform {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.offset);
+        w.write(&(self.scale, self.rotation, self.opacity));
+        w.write(&self.locked);
+    }
+}
+
+impl Decode for BackgroundTransform {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        let offset = r.read()?;
+        let (scale, rotation, opacity) = r.read()?;
+        let transform = Self {
+            offset,
+            scale,
+            rotation,
+            opacity,
+            locked: r.read()?,
+        };
+        if !transform.is_valid() {
+            return Err(DecodeError::Invalid("background transform"));
+        }
+        Ok(transform)
+    }
+}
+
+impl Encode for Layer {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.id);
+        w.write(&self.settings);
+        w.write(&self.drawing);
+    }
+}
+
+impl Decode for Layer {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        Ok(Self {
+            id: r.read()?,
+            settings: r.read()?,
+            drawing: r.read()?,
+        })
+    }
+}
+
+/// Checks that a list of layers could have come from a document whose next layer ID is `next_id`.
+fn check_layers(layers: &[Layer], next_id: u32) -> codec::Result<()> {
+    let mut ids: Vec<_> = layers.iter().map(|layer| layer.id).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    let ids_ok = ids.len() == layers.len() && ids.iter().all(|id| id.0 < next_id);
+    if layers.is_empty() || !ids_ok {
+        return Err(DecodeError::Invalid("layers"));
+    }
+    Ok(())
+}
+
+impl Encode for Document {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.layers);
+        w.write(&self.background);
+        w.write(&self.background_transform);
+        w.write(&self.next_layer_id);
+    }
+}
+
+impl Decode for Document {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        if before_layers(r) {
+            let mut doc = Self::from_drawing(r.read()?, LayerSettings::default());
+            doc.background = r.read()?;
+            return Ok(doc);
+        }
+        let doc = Self {
+            layers: r.read()?,
+            background: r.read()?,
+            background_transform: if older_than(r, BACKGROUND_VERSION) {
+                BackgroundTransform::default()
+            } else {
+                r.read()?
+            },
+            next_layer_id: r.read()?,
+        };
+        check_layers(&doc.layers, doc.next_layer_id)?;
+        Ok(doc)
+    }
+}
+
+impl Encode for LayerDelta {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.index);
+        w.write(&self.settings);
+        w.write(&self.drawing);
+    }
+}
+
+impl Decode for LayerDelta {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        Ok(Self {
+            index: r.read()?,
+            settings: r.read()?,
+            drawing: r.read()?,
+        })
+    }
+}
+
+impl Encode for LayerChange {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Self::Insert(index, layer) => w.write(&(0u8, index, layer)),
+            Self::Remove(index, layer) => w.write(&(1u8, index, layer)),
+            Self::Move { from, to } => w.write(&(2u8, from, to)),
+        }
+    }
+}
+
+impl Decode for LayerChange {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        match r.read::<u8>()? {
+            0 => Ok(Self::Insert(r.read()?, r.read()?)),
+            1 => Ok(Self::Remove(r.read()?, r.read()?)),
+            2 => Ok(Self::Move {
+                from: r.read()?,
+                to: r.read()?,
+            }),
+            _ => Err(DecodeError::Invalid("layer change")),
+        }
+    }
+}
+
+impl Encode for DocumentDelta {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.layers.changes);
+        w.write(&self.layers.edits);
+        w.write(&self.background);
+        w.write(&self.background_transform);
+        w.write(&self.next_layer_ids);
+    }
+}
+
+impl Decode for DocumentDelta {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        if before_layers(r) {
+            let drawing = r.read()?;
+            return Ok(Self {
+                layers: LayersDelta {
+                    changes: Vec::new(),
+                    edits: vec![LayerDelta {
+                        index: 0,
+                        settings: None,
+                        drawing,
+                    }],
+                },
+                background: r.read()?,
+                background_transform: None,
+                next_layer_ids: [1, 1],
+            });
+        }
+        let delta = Self {
+            layers: LayersDelta {
+                changes: r.read()?,
+                edits: r.read()?,
+            },
+            background: r.read()?,
+            background_transform: if older_than(r, BACKGROUND_VERSION) {
+                None
+            } else {
+                r.read()?
+            },
+            next_layer_ids: r.read()?,
+        };
+        let [old_next_id, new_next_id] = delta.next_layer_ids;
+        let ids_ok = delta.layers.changes.iter().all(|change| match change {
+            LayerChange::Insert(_, layer) => layer.id.0 < new_next_id,
+            LayerChange::Remove(_, layer) => layer.id.0 < old_next_id,
+            LayerChange::Move { .. } => true,
+        });
+        if !ids_ok {
+            return Err(DecodeError::Invalid("layers"));
+        }
+        Ok(delta)
+    }
+}
+
+/// Saves a document along with its whole undo history.
+pub fn save_history(history: &UndoStack<Document, DocumentDelta>) -> Vec<u8> {
+    let mut w = Writer::with_header(HISTORY_MAGIC, HISTORY_VERSION);
+    w.write(history);
+    w.finish()
+}
+
+pub fn load_history(data: &[u8]) -> codec::Result<UndoStack<Document, DocumentDelta>> {
+    let (mut r, _version) = Reader::with_header(data, HISTORY_MAGIC, HISTORY_VERSION)?;
+    let history = r.read()?;
+    r.finish()?;
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use std::time::SystemTime;
+
+    use image::RgbaImage;
+
+    use super::{
+        load_history, same_background, save_history, BackgroundTransform, Document, DocumentDelta,
+        Effects, FillRule, LayerChange, LayerSettings, HISTORY_MAGIC, HISTORY_VERSION,
+    };
+    use crate::codec::{DecodeError, Reader, Writer};
+    use crate::drawing::{Command, Drawing, DrawingDelta, NodeKind};
+    use crate::point::Point;
+    use crate::undo::{Delta, HistoryMode, UndoStack};
+
+    #[test]
+    fn test_history_round_trip() {
+        let mut history: UndoStack<Document, DocumentDelta> = UndoStack::default();
+        history.set_mode(HistoryMode::Tree);
+        let drawing = &mut history.layer_mut(0).drawing;
+        drawing.push(Command::Move(Point::new(0.5, 0.25)));
+        drawing.push(Command::Bezier(
+            Point::new(1.0, 2.0),
+            Point::new(3.0, 4.0),
+            Point::new(5.0, 6.0),
+        ));
+        history.commit_as("Add bezier");
+        history.background = Some(Rc::new(RgbaImage::new(2, 2)));
+        history.commit_as("Paste background");
+        let drawing = &mut history.layer_mut(0).drawing;
+        let id = drawing.point_id(3).unwrap();
+        drawing.set_node_kind(id, NodeKind::Smooth);
+        drawing[3].x = 0.1;
+        history.commit_as("Move point 3");
+        history.undo();
+        history.layer_mut(0).drawing.remove(0);
+        history.commit_as("Remove");
+        history.layer_mut(0).drawing[0].y = -7.0; // Left uncommitted
+
+        let data = save_history(&history);
+        let mut loaded = load_history(&data).unwrap();
+        assert_eq!(save_history(&loaded), data);
+        assert_eq!(loaded.layers(), history.layers());
+        assert_eq!(loaded.mode(), HistoryMode::Tree);
+        assert_eq!(loaded.memory_usage(), history.memory_usage());
+
+        assert!(loaded.go_to_sibling(-1));
+        let drawing = &loaded.layers()[0].drawing;
+        assert_eq!(drawing[3].x, 0.1);
+        assert_eq!(drawing.node_kind(id), NodeKind::Smooth);
+        loaded.undo();
+        assert_eq!(loaded.layers()[0].drawing.len(), 2);
+        let background = loaded.background.clone();
+        loaded.jump_to(1);
+        assert!(loaded.background.is_none());
+        loaded.redo();
+        assert!(same_background(&loaded.background, &background));
+
+        assert!(load_history(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_background_transform() {
+        let transform = BackgroundTransform {
+            offset: Point::new(10.0, 20.0),
+            scale: 2.0,
+            rotation: 90.0,
+            ..BackgroundTransform::default()
+        };
+        // Turning counterclockwise on screen, where y points down, takes the x axis up.
+        let p = transform.to_scene(Point::new(3.0, 0.0));
+        assert!((p - Point::new(10.0, 14.0)).length() < 1e-4);
+        let back = transform.to_image(p);
+        assert!((back - Point::new(3.0, 0.0)).length() < 1e-4);
+
+        let mut history: UndoStack<Document, DocumentDelta> = UndoStack::default();
+        history.background = Some(Rc::new(RgbaImage::new(4, 2)));
+        history.commit_as("Paste background");
+        history.background_transform = transform;
+        history.commit_as("Move background");
+        let bounds = history.background_bounds().unwrap();
+        assert!((bounds.min - Point::new(10.0, 12.0)).length() < 1e-4);
+        assert!((bounds.max - Point::new(14.0, 20.0)).length() < 1e-4);
+
+        let data = save_history(&history);
+        let mut loaded = load_history(&data).unwrap();
+        assert_eq!(loaded.background_transform, transform);
+        loaded.undo();
+        assert_eq!(loaded.background_transform, BackgroundTransform::default());
+        assert!(loaded.background.is_some());
+
+        for transform in [
+            BackgroundTransform {
+                scale: 0.0,
+                ..transform
+            },
+            BackgroundTransform {
+                rotation: f32::INFINITY,
+                ..transform
+            },
+            BackgroundTransform {
+                opacity: 1.5,
+                ..transform
+            },
+            BackgroundTransform {
+                opacity: f32::NAN,
+                ..transform
+            },
+        ] {
+            assert!(!transform.is_valid());
+            let mut w = Writer::new();
+            w.write(&transform);
+            let data = w.finish();
+            assert_eq!(
+                Reader::new(&data).read::<BackgroundTransform>(),
+                Err(DecodeError::Invalid("background transform"))
+            );
+        }
+    }
+
+    #[test]
+    fn test_layers() {
+        let mut history: UndoStack<Document, DocumentDelta> = UndoStack::default();
+        let bottom = history.layers()[0].id();
+        let top = history.insert_layer(1, LayerSettings::default());
+        history
+            .layer_mut(1)
+            .drawing
+            .push(Command::Move(Point::new(1.0, 1.0)));
+        history.commit_as("Add layer");
+
+        history.layer_mut(0).settings.visible = false;
+        history.layer_mut(1).drawing[0].x = 2.0;
+        history.commit_as("Edit both");
+        history.move_layer(1, 0);
+        history.commit_as("Move layer down");
+        assert!(history.remove_layer(0).is_some());
+        assert!(history.remove_layer(0).is_none());
+        history.commit_as("Remove layer");
+        assert_eq!(history.layers()[0].id(), bottom);
+
+        history.undo();
+        assert_eq!(history.layer_index(top), Some(0));
+        history.undo();
+        assert_eq!(history.layer_index(top), Some(1));
+        history.undo();
+        assert!(history.layers()[0].settings.visible);
+        assert_eq!(history.layers()[1].drawing[0].x, 1.0);
+        history.undo();
+        assert_eq!(history.layers().len(), 1);
+        // IDs aren't reused, even for layers that were undone.
+        history.redo();
+        assert_eq!(history.insert_layer(0, LayerSettings::default()).0, 2);
+
+        let data = save_history(&history);
+        let loaded = load_history(&data).unwrap();
+        assert_eq!(loaded.layers(), history.layers());
+
+        // Deltas that don't fit the document, as a corrupt file could have, are caught before they're used.
+        let one_layer = Document::default();
+        let mut before = one_layer.clone();
+        before.insert_layer(1, LayerSettings::default());
+        before
+            .layer_mut(1)
+            .drawing
+            .push(Command::Move(Point::new(1.0, 1.0)));
+        let mut after = before.clone();
+        after.layer_mut(1).drawing.remove(0);
+        let delta = DocumentDelta::diff(before.clone(), &after);
+        assert!(delta.can_apply(&before) && delta.can_revert(&after));
+        assert!(!delta.can_apply(&one_layer));
+        assert!(!delta.can_apply(&after));
+        assert!(!delta.can_revert(&before));
+    }
+
+    #[test]
+    fn test_layer_changes() {
+        let mut before = Document::default();
+        for index in 1..4 {
+            before.insert_layer(index, LayerSettings::default());
+            before
+                .layer_mut(index)
+                .drawing
+                .push(Command::Move(Point::new(index as f32, 0.0)));
+        }
+        let mut after = before.clone();
+        after.remove_layer(1);
+        after.move_layer(2, 0);
+        after.insert_layer(3, LayerSettings::default());
+        after.layer_mut(2).drawing[0].y = 5.0;
+
+        // Only the layer that's gone and the one that's new are kept, along with the edit to the one between.
+        let mut delta = DocumentDelta::diff(before.clone(), &after);
+        assert!(matches!(
+            delta.layers.changes[..],
+            [
+                LayerChange::Remove(1, _),
+                LayerChange::Move { from: 2, to: 0 },
+                LayerChange::Insert(3, _),
+            ]
+        ));
+        assert_eq!(delta.layers.edits.len(), 1);
+        assert_eq!(delta.layers.edits[0].index, 2);
+        assert!(delta.can_apply(&before) && delta.can_revert(&after));
+        assert!(!delta.can_apply(&after) && !delta.can_revert(&before));
+
+        let mut doc = before.clone();
+        delta.apply(&mut doc);
+        assert_eq!(doc.layers(), after.layers());
+        delta.revert(&mut doc);
+        assert_eq!(doc.layers(), before.layers());
+
+        // A layer moved up past others is a single move too.
+        let mut moved = before.clone();
+        moved.move_layer(0, 3);
+        let delta = DocumentDelta::diff(before.clone(), &moved);
+        assert!(matches!(
+            delta.layers.changes[..],
+            [LayerChange::Move { from: 0, to: 3 }]
+        ));
+    }
+
+    #[test]
+    fn test_single_drawing_migration() {
+        // Before layers, a history file held a single drawing, laid out like this.
+        let mut drawing = Drawing::new();
+        drawing.push(Command::Move(Point::new(3.0, 4.0)));
+        let delta = DrawingDelta::diff(Drawing::new(), &drawing);
+        let no_background: Option<Rc<RgbaImage>> = None;
+        let mut w = Writer::with_header(HISTORY_MAGIC, 1);
+        w.write(&(&drawing, &no_background)); // The current state
+        w.write(&None::<u8>); // No uncommitted edit
+        w.write(&1usize);
+        w.write(&0u64); // The entry's ID
+        w.write(&(delta, None::<[Option<Rc<RgbaImage>>; 2]>));
+        w.write(&("Add move", SystemTime::now()));
+        w.write(&(None::<u64>, Vec::<u64>::new(), None::<u64>));
+        w.write(&Some(0u64)); // The head
+        w.write(&(vec![0u64], Some(0u64), 1u64));
+        w.write(&0u8); // Linear history
+        let data = w.finish();
+
+        let mut history = load_history(&data).unwrap();
+        assert_eq!(history.layers().len(), 1);
+        assert_eq!(history.layers()[0].drawing, drawing);
+        assert_eq!(history.undo_label(), Some("Add move"));
+        history.undo();
+        assert!(history.layers()[0].drawing.is_empty());
+        history.redo();
+        let id = history.insert_layer(1, LayerSettings::default());
+        assert_eq!(history.layer_index(id), Some(1));
+    }
+
+    #[test]
+    fn test_layer_settings_migration() {
+        // Layers had no fill rule before version 3, and no effects before version 4.
+        let mut w = Writer::with_header(HISTORY_MAGIC, 2);
+        w.write(&("Outline", true, false));
+        w.write_bytes(&[1, 2, 3]);
+        w.write(&(0u8, 4i32));
+        let data = w.finish();
+        let (mut r, _) = Reader::with_header(&data, HISTORY_MAGIC, HISTORY_VERSION).unwrap();
+        let settings: LayerSettings = r.read().unwrap();
+        r.finish().unwrap();
+        assert_eq!(settings.fill_rule, FillRule::NonZero);
+        assert_eq!(settings.effects, Effects::default());
+        assert_eq!(settings.ass_layer, 4);
+
+        let settings = LayerSettings {
+            fill_rule: FillRule::EvenOdd,
+            effects: Effects {
+                border: 2.0,
+                be: 1,
+                shadow_color: [4, 5, 6],
+                ..Effects::default()
+            },
+            ..settings
+        };
+        let mut w = Writer::with_header(HISTORY_MAGIC, HISTORY_VERSION);
+        w.write(&settings);
+        let data = w.finish();
+        let (mut r, _) = Reader::with_header(&data, HISTORY_MAGIC, HISTORY_VERSION).unwrap();
+        assert_eq!(r.read::<LayerSettings>().unwrap(), settings);
+
+        // Effects too big to rasterize, or that aren't numbers, don't load.
+        for effects in [
+            Effects {
+                border: 1000.0,
+                ..Effects::default()
+            },
+            Effects {
+                be: u32::MAX,
+                ..Effects::default()
+            },
+            Effects {
+                blur: f32::NAN,
+                ..Effects::default()
+            },
+            Effects {
+                shadow: 1e30,
+                ..Effects::default()
+            },
+        ] {
+            let mut w = Writer::new();
+            w.write(&effects);
+            let data = w.finish();
+            assert_eq!(
+                Reader::new(&data).read::<Effects>(),
+                Err(DecodeError::Invalid("effects"))
+            );
+        }
+    }
+}
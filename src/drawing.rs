@@ -1,7 +1,13 @@
 use either::Either;
 use itertools::Itertools;
 
-use std::ops::{Index, IndexMut};
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+use std::ops::{Index, IndexMut, Range};
+
+use crate::codec::{self, Decode, DecodeError, Encode, Reader, Writer};
+use crate::point::{Point, Rect};
+use crate::undo::Delta;
 
 #[derive(Debug, Copy, Clone)]
 pub enum Command<P> {
@@ -37,6 +43,51 @@ pub enum CommandKind {
     Bezier,
 }
 
+impl CommandKind {
+    /// A lowercase name for use in the UI.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Move => "move",
+            Self::Line => "line",
+            Self::Bezier => "bezier",
+        }
+    }
+
+    #[inline]
+    pub const fn num_points(self) -> usize {
+        match self {
+            Self::Move | Self::Line => 1,
+            Self::Bezier => 3,
+        }
+    }
+}
+
+/// How a node constrains the bezier control points on either side of it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NodeKind {
+    /// The control points move independently.
+    Corner,
+    /// The control points stay collinear with the node, so the curve has no kink.
+    Smooth,
+    /// Like `Smooth`, but the control points are also the same distance from the node.
+    Symmetric,
+}
+
+impl Default for NodeKind {
+    #[inline]
+    fn default() -> Self {
+        Self::Corner
+    }
+}
+
+/// A handle to a point that stays valid when other points are inserted or removed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PointId(u32);
+
+/// A handle to a command that stays valid when other commands are inserted or removed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CommandId(u32);
+
 #[derive(Debug, Copy, Clone)]
 pub enum Segment<P> {
     Line(P, P),
@@ -56,29 +107,185 @@ impl<P> Segment<P> {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Drawing<P> {
     segments: Vec<CommandKind>,
     points: Vec<P>,
+
+    // IDs are kept parallel to the flat arrays above, and are never reused within a drawing.
+    command_ids: Vec<CommandId>,
+    point_ids: Vec<PointId>,
+    command_indices: HashMap<CommandId, usize>,
+    point_indices: HashMap<PointId, usize>,
+    next_id: u32,
+
+    /// Nodes that aren't corners.
+    node_kinds: HashMap<PointId, NodeKind>,
 }
 
 impl<P> Drawing<P> {
     #[inline]
-    pub const fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             segments: Vec::new(),
             points: Vec::new(),
+            command_ids: Vec::new(),
+            point_ids: Vec::new(),
+            command_indices: HashMap::new(),
+            point_indices: HashMap::new(),
+            next_id: 0,
+            node_kinds: HashMap::new(),
         }
     }
 
-    pub fn push(&mut self, command: Command<P>) {
-        self.segments.push(command.kind());
-        self.points.extend(command.points());
+    fn next_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn push(&mut self, command: Command<P>) -> CommandId {
+        self.insert(self.segments.len(), command)
+    }
+
+    /// Inserts a command so that it ends up at position `index` among the commands.
+    ///
+    /// Panics if `index > self.len()`.
+    pub fn insert(&mut self, index: usize, command: Command<P>) -> CommandId {
+        assert!(index <= self.segments.len(), "command index out of bounds");
+        let point_index = self.first_point_of(index);
+
+        let kind = command.kind();
+        let command_id = CommandId(self.next_id());
+        self.segments.insert(index, kind);
+        self.command_ids.insert(index, command_id);
+
+        for (i, point) in command.points().enumerate() {
+            let point_id = PointId(self.next_id());
+            self.points.insert(point_index + i, point);
+            self.point_ids.insert(point_index + i, point_id);
+        }
+
+        self.reindex(index, point_index);
+        command_id
+    }
+
+    /// Removes the command at position `index`, along with its points.
+    ///
+    /// Panics if `index >= self.len()`.
+    pub fn remove(&mut self, index: usize) -> Command<P> {
+        assert!(index < self.segments.len(), "command index out of bounds");
+        let point_index = self.first_point_of(index);
+
+        let kind = self.segments.remove(index);
+        let command_id = self.command_ids.remove(index);
+        self.command_indices.remove(&command_id);
+
+        let range = point_index..point_index + kind.num_points();
+        for point_id in self.point_ids.drain(range.clone()) {
+            self.point_indices.remove(&point_id);
+            self.node_kinds.remove(&point_id);
+        }
+        let mut points = self.points.drain(range);
+        let command = match kind {
+            CommandKind::Move => Command::Move(points.next().unwrap()),
+            CommandKind::Line => Command::Line(points.next().unwrap()),
+            CommandKind::Bezier => {
+                let (p1, p2, p3) = points.next_tuple().unwrap();
+                Command::Bezier(p1, p2, p3)
+            }
+        };
+        drop(points);
+
+        self.reindex(index, point_index);
+        command
     }
 
     pub fn clear(&mut self) {
         self.segments.clear();
         self.points.clear();
+        self.command_ids.clear();
+        self.point_ids.clear();
+        self.command_indices.clear();
+        self.point_indices.clear();
+        self.node_kinds.clear();
+    }
+
+    /// Brings the ID lookup tables up to date for every command and point from the given positions onward.
+    fn reindex(&mut self, first_command: usize, first_point: usize) {
+        for (i, &id) in self.command_ids.iter().enumerate().skip(first_command) {
+            self.command_indices.insert(id, i);
+        }
+        for (i, &id) in self.point_ids.iter().enumerate().skip(first_point) {
+            self.point_indices.insert(id, i);
+        }
+    }
+
+    /// Copies out a run of commands, IDs and all.
+    fn extract(&self, commands: Range<usize>) -> Splice<P>
+    where
+        P: Clone,
+    {
+        let points = self.first_point_of(commands.start)..self.first_point_of(commands.end);
+        Splice {
+            segments: self.segments[commands.clone()].to_vec(),
+            command_ids: self.command_ids[commands].to_vec(),
+            points: self.points[points.clone()].to_vec(),
+            point_ids: self.point_ids[points].to_vec(),
+        }
+    }
+
+    /// Replaces `count` commands starting at position `index` with the contents of `splice`.
+    ///
+    /// Node kinds are left alone, so the caller has to take care of them.
+    fn splice(&mut self, index: usize, count: usize, splice: &Splice<P>)
+    where
+        P: Clone,
+    {
+        let commands = index..index + count;
+        let points = self.first_point_of(commands.start)..self.first_point_of(commands.end);
+
+        self.segments
+            .splice(commands.clone(), splice.segments.iter().copied());
+        for id in self
+            .command_ids
+            .splice(commands, splice.command_ids.iter().copied())
+        {
+            self.command_indices.remove(&id);
+        }
+        self.points
+            .splice(points.clone(), splice.points.iter().cloned());
+        for id in self
+            .point_ids
+            .splice(points.clone(), splice.point_ids.iter().copied())
+        {
+            self.point_indices.remove(&id);
+        }
+
+        self.reindex(index, points.start);
+    }
+
+    /// Returns the position in `points()` of the first point belonging to the command at `index`.
+    fn first_point_of(&self, index: usize) -> usize {
+        self.segments[..index].iter().map(|k| k.num_points()).sum()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Roughly how much memory the drawing's contents take up, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        let id_entry = size_of::<(u32, usize)>();
+        self.segments.len() * (size_of::<CommandKind>() + size_of::<CommandId>() + id_entry)
+            + self.points.len() * (size_of::<P>() + size_of::<PointId>() + id_entry)
+            + self.node_kinds.len() * size_of::<(PointId, NodeKind)>()
     }
 
     pub fn segments<'a>(&'a self) -> impl Iterator<Item = Segment<P>> + 'a
@@ -93,6 +300,85 @@ impl<P> Drawing<P> {
         }
     }
 
+    /// Like `segments`, but yielding indices into `points()` rather than the points themselves.
+    pub fn segment_indices(&self) -> impl Iterator<Item = Segment<usize>> + '_ {
+        SegmentsIter {
+            segments: self.segments.iter().cloned(),
+            points: 0..self.points.len(),
+            pen: 0,
+            shape_start: None,
+        }
+    }
+
+    /// Finds the command that the point at `index` belongs to, and its position within that command.
+    pub fn locate_point(&self, index: usize) -> Option<(usize, usize)> {
+        let mut start = 0;
+        for (i, kind) in self.segments.iter().enumerate() {
+            let end = start + kind.num_points();
+            if index < end {
+                return Some((i, index - start));
+            }
+            start = end;
+        }
+        None
+    }
+
+    /// Whether the point at `index` is on-curve, as opposed to being a bezier control point.
+    pub fn is_node(&self, index: usize) -> bool {
+        match self.locate_point(index) {
+            Some((i, pos)) => pos + 1 == self.segments[i].num_points(),
+            None => false,
+        }
+    }
+
+    /// The control points attached to the node at `index`: the one leading into it, and the one leading out of it.
+    pub fn node_handles(&self, index: usize) -> [Option<usize>; 2] {
+        let cmd = match self.locate_point(index) {
+            Some((i, pos)) if pos + 1 == self.segments[i].num_points() => i,
+            _ => return [None, None],
+        };
+        let is_bezier = |i| self.segments.get(i) == Some(&CommandKind::Bezier);
+        let incoming = if is_bezier(cmd) {
+            Some(index - 1)
+        } else {
+            None
+        };
+        let outgoing = if is_bezier(cmd + 1) {
+            Some(index + 1)
+        } else {
+            None
+        };
+        [incoming, outgoing]
+    }
+
+    /// For the bezier control point at `index`, the node it's attached to,
+    /// and the control point on the other side of that node, if there is one.
+    pub fn handle_partner(&self, index: usize) -> Option<(usize, Option<usize>)> {
+        let (cmd, pos) = self.locate_point(index)?;
+        if self.segments[cmd] != CommandKind::Bezier {
+            return None;
+        }
+        match pos {
+            0 => {
+                let node = index.checked_sub(1)?;
+                Some((node, self.node_handles(node)[0]))
+            }
+            1 => {
+                let node = index + 1;
+                Some((node, self.node_handles(node)[1]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Indices into `points()` of the on-curve points, i.e. the ones that aren't bezier control points.
+    pub fn node_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.segments.iter().scan(0, |end, kind| {
+            *end += kind.num_points();
+            Some(*end - 1)
+        })
+    }
+
     pub fn commands<'a>(&'a self) -> impl Iterator<Item = Command<P>> + 'a
     where
         P: Clone,
@@ -112,6 +398,154 @@ impl<P> Drawing<P> {
     pub fn points_mut(&mut self) -> &mut [P] {
         &mut self.points[..]
     }
+
+    #[inline]
+    pub fn command_ids(&self) -> &[CommandId] {
+        &self.command_ids[..]
+    }
+
+    #[inline]
+    pub fn point_ids(&self) -> &[PointId] {
+        &self.point_ids[..]
+    }
+
+    #[inline]
+    pub fn command_id(&self, index: usize) -> Option<CommandId> {
+        self.command_ids.get(index).copied()
+    }
+
+    #[inline]
+    pub fn command_index(&self, id: CommandId) -> Option<usize> {
+        self.command_indices.get(&id).copied()
+    }
+
+    #[inline]
+    pub fn point_id(&self, index: usize) -> Option<PointId> {
+        self.point_ids.get(index).copied()
+    }
+
+    #[inline]
+    pub fn point_index(&self, id: PointId) -> Option<usize> {
+        self.point_indices.get(&id).copied()
+    }
+
+    #[inline]
+    pub fn node_kind(&self, id: PointId) -> NodeKind {
+        self.node_kinds.get(&id).copied().unwrap_or_default()
+    }
+
+    pub fn set_node_kind(&mut self, id: PointId, kind: NodeKind) {
+        if kind == NodeKind::Corner {
+            self.node_kinds.remove(&id);
+        } else {
+            self.node_kinds.insert(id, kind);
+        }
+    }
+
+    /// The kind of the node at `index`. Control points are always corners.
+    #[inline]
+    pub fn node_kind_at(&self, index: usize) -> NodeKind {
+        match self.point_ids.get(index) {
+            Some(&id) => self.node_kind(id),
+            None => NodeKind::Corner,
+        }
+    }
+
+    #[inline]
+    pub fn point(&self, id: PointId) -> Option<&P> {
+        self.point_index(id).map(|i| &self.points[i])
+    }
+
+    #[inline]
+    pub fn point_mut(&mut self, id: PointId) -> Option<&mut P> {
+        let i = self.point_index(id)?;
+        Some(&mut self.points[i])
+    }
+}
+
+impl Drawing<Point<f32>> {
+    /// The bounding box of every point, control points included.
+    pub fn bounds(&self) -> Option<Rect> {
+        Rect::from_points(self.points.iter().copied())
+    }
+
+    /// After the control point at `index` has moved, moves the control point
+    /// on the other side of its node to keep that node smooth or symmetric.
+    pub fn enforce_continuity(&mut self, index: usize) {
+        let (node, opposite) = match self.handle_partner(index) {
+            Some((node, Some(opposite))) => (node, opposite),
+            _ => return,
+        };
+        let center = self.points[node];
+        match self.node_kind_at(node) {
+            NodeKind::Corner => {}
+            NodeKind::Smooth => {
+                if let Some(dir) = (center - self.points[index]).normalize() {
+                    let len = (self.points[opposite] - center).length();
+                    self.points[opposite] = center + dir * len;
+                }
+            }
+            NodeKind::Symmetric => self.points[opposite] = center * 2.0 - self.points[index],
+        }
+    }
+
+    /// Changes the kind of the node at `index`, straightening its control points to match.
+    ///
+    /// Both control points keep their distance from the node unless it becomes symmetric,
+    /// in which case they meet in the middle.
+    pub fn change_node_kind(&mut self, index: usize, kind: NodeKind) {
+        let id = match self.point_ids.get(index) {
+            Some(&id) if self.is_node(index) => id,
+            _ => return,
+        };
+        self.set_node_kind(id, kind);
+
+        let (incoming, outgoing) = match self.node_handles(index) {
+            [Some(incoming), Some(outgoing)] => (incoming, outgoing),
+            _ => return,
+        };
+        let center = self.points[index];
+        let dir = match (self.points[outgoing] - self.points[incoming]).normalize() {
+            Some(dir) => dir,
+            None => return,
+        };
+        let len_in = (self.points[incoming] - center).length();
+        let len_out = (self.points[outgoing] - center).length();
+        let (len_in, len_out) = match kind {
+            NodeKind::Corner => return,
+            NodeKind::Smooth => (len_in, len_out),
+            NodeKind::Symmetric => {
+                let len = (len_in + len_out) / 2.0;
+                (len, len)
+            }
+        };
+        self.points[incoming] = center - dir * len_in;
+        self.points[outgoing] = center + dir * len_out;
+    }
+
+    /// Makes the node at `index` smooth, pointing its control points along the line between
+    /// the neighboring nodes, each a third of the way to the node on its side.
+    pub fn auto_smooth(&mut self, index: usize) {
+        let (incoming, outgoing) = match self.node_handles(index) {
+            [Some(incoming), Some(outgoing)] => (incoming, outgoing),
+            _ => return,
+        };
+        // Both neighbors are on the far ends of the beziers the control points belong to.
+        // A bezier at the very start has no point before it, so there's nothing to line up with.
+        let prev = match incoming.checked_sub(2) {
+            Some(prev) => self.points[prev],
+            None => return,
+        };
+        let next = self.points[outgoing + 2];
+        let center = self.points[index];
+        let dir = match (next - prev).normalize() {
+            Some(dir) => dir,
+            None => return,
+        };
+        self.points[incoming] = center - dir * ((center - prev).length() / 3.0);
+        self.points[outgoing] = center + dir * ((next - center).length() / 3.0);
+        self.set_node_kind(self.point_ids[index], NodeKind::Smooth);
+    }
 }
 
 impl<P> Default for Drawing<P> {
@@ -140,6 +574,320 @@ where
     }
 }
 
+/// A run of commands cut out of a drawing, with everything needed to put them back.
+#[derive(Debug, Clone)]
+struct Splice<P> {
+    segments: Vec<CommandKind>,
+    command_ids: Vec<CommandId>,
+    points: Vec<P>,
+    point_ids: Vec<PointId>,
+}
+
+impl<P> Splice<P> {
+    fn size(&self) -> usize {
+        self.segments.len() * size_of::<CommandKind>()
+            + self.command_ids.len() * size_of::<CommandId>()
+            + self.points.len() * size_of::<P>()
+            + self.point_ids.len() * size_of::<PointId>()
+    }
+}
+
+/// The difference between two versions of a drawing, for keeping history without storing whole copies.
+///
+/// Commands that were added or removed are stored as a single splice spanning all of them.
+/// Everything outside of that is only recorded if it changed.
+#[derive(Debug, Clone)]
+pub struct DrawingDelta<P> {
+    /// The position of the first command in the splice.
+    start: usize,
+    removed: Splice<P>,
+    inserted: Splice<P>,
+    /// Points outside of the splice that were moved, with their old and new positions.
+    moved: Vec<(PointId, P, P)>,
+    /// Nodes whose kind changed, with their old and new kinds.
+    node_kinds: Vec<(PointId, NodeKind, NodeKind)>,
+    next_ids: [u32; 2],
+}
+
+impl<P> DrawingDelta<P> {
+    /// Whether `drawing` has the commands of `out` at `self.start`, none of the IDs in `into` besides those,
+    /// and every point in `moved` outside of them.
+    fn fits(&self, drawing: &Drawing<P>, out: &Splice<P>, into: &Splice<P>) -> bool {
+        let commands = self.start..self.start + out.segments.len();
+        if commands.end > drawing.len()
+            || drawing.segments[commands.clone()] != out.segments[..]
+            || drawing.command_ids[commands.clone()] != out.command_ids[..]
+        {
+            return false;
+        }
+        let first_point = drawing.first_point_of(commands.start);
+        let points = first_point..first_point + out.point_ids.len();
+        if drawing.point_ids[points.clone()] != out.point_ids[..] {
+            return false;
+        }
+
+        let mut command_ids = HashSet::new();
+        let new_commands_ok = into.command_ids.iter().all(|&id| {
+            command_ids.insert(id)
+                && drawing
+                    .command_index(id)
+                    .is_none_or(|i| commands.contains(&i))
+        });
+        let mut point_ids = HashSet::new();
+        let new_points_ok = into.point_ids.iter().all(|&id| {
+            point_ids.insert(id) && drawing.point_index(id).is_none_or(|i| points.contains(&i))
+        });
+        let moved_ok = self.moved.iter().all(|(id, _, _)| {
+            drawing
+                .point_index(*id)
+                .is_some_and(|i| !points.contains(&i))
+        });
+        new_commands_ok && new_points_ok && moved_ok
+    }
+}
+
+impl<P: Clone + PartialEq> Delta<Drawing<P>> for DrawingDelta<P> {
+    fn diff(old: Drawing<P>, new: &Drawing<P>) -> Self {
+        let same_ids = |(a, b): &(&CommandId, &CommandId)| a == b;
+        let prefix = Iterator::zip(old.command_ids.iter(), &new.command_ids)
+            .take_while(same_ids)
+            .count();
+        let suffix = Iterator::zip(old.command_ids.iter().rev(), new.command_ids.iter().rev())
+            .take(old.len().min(new.len()) - prefix)
+            .take_while(same_ids)
+            .count();
+
+        let removed = old.extract(prefix..old.len() - suffix);
+        let inserted = new.extract(prefix..new.len() - suffix);
+
+        // Commands outside of the splice are the same in both, so their points line up from either end.
+        let head = old.first_point_of(prefix);
+        let tail = old.points.len() - head - removed.points.len();
+        fn outside<P>(
+            d: &Drawing<P>,
+            head: usize,
+            tail: usize,
+        ) -> impl Iterator<Item = (&PointId, &P)> {
+            let n = d.points.len();
+            Iterator::zip(d.point_ids.iter(), &d.points)
+                .take(head)
+                .chain(d.point_ids[n - tail..].iter().zip(&d.points[n - tail..]))
+        }
+        let moved = Iterator::zip(outside(&old, head, tail), outside(new, head, tail))
+            .filter(|((_, a), (_, b))| a != b)
+            .map(|((&id, a), (_, b))| (id, a.clone(), b.clone()))
+            .collect();
+
+        let mut node_kinds: Vec<_> = old
+            .node_kinds
+            .iter()
+            .map(|(&id, &kind)| (id, kind, new.node_kind(id)))
+            .filter(|&(_, a, b)| a != b)
+            .collect();
+        node_kinds.extend(
+            new.node_kinds
+                .iter()
+                .filter(|(id, _)| !old.node_kinds.contains_key(id))
+                .map(|(&id, &kind)| (id, NodeKind::Corner, kind)),
+        );
+
+        Self {
+            start: prefix,
+            removed,
+            inserted,
+            moved,
+            node_kinds,
+            next_ids: [old.next_id, new.next_id],
+        }
+    }
+
+    fn apply(&mut self, drawing: &mut Drawing<P>) {
+        drawing.splice(self.start, self.removed.segments.len(), &self.inserted);
+        for (id, _, new) in &self.moved {
+            *drawing.point_mut(*id).unwrap() = new.clone();
+        }
+        for &(id, _, new) in &self.node_kinds {
+            drawing.set_node_kind(id, new);
+        }
+        drawing.next_id = self.next_ids[1];
+    }
+
+    fn revert(&mut self, drawing: &mut Drawing<P>) {
+        drawing.splice(self.start, self.inserted.segments.len(), &self.removed);
+        for (id, old, _) in &self.moved {
+            *drawing.point_mut(*id).unwrap() = old.clone();
+        }
+        for &(id, old, _) in &self.node_kinds {
+            drawing.set_node_kind(id, old);
+        }
+        drawing.next_id = self.next_ids[0];
+    }
+
+    fn can_apply(&self, drawing: &Drawing<P>) -> bool {
+        self.fits(drawing, &self.removed, &self.inserted)
+    }
+
+    fn can_revert(&self, drawing: &Drawing<P>) -> bool {
+        self.fits(drawing, &self.inserted, &self.removed)
+    }
+
+    fn size(&self) -> usize {
+        size_of::<Self>()
+            + self.removed.size()
+            + self.inserted.size()
+            + self.moved.len() * size_of::<(PointId, P, P)>()
+            + self.node_kinds.len() * size_of::<(PointId, NodeKind, NodeKind)>()
+    }
+}
+
+impl Encode for CommandKind {
+    fn encode(&self, w: &mut Writer) {
+        (*self as u8).encode(w);
+    }
+}
+
+impl Decode for CommandKind {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        match u8::decode(r)? {
+            0 => Ok(Self::Move),
+            1 => Ok(Self::Line),
+            2 => Ok(Self::Bezier),
+            _ => Err(DecodeError::Invalid("command kind")),
+        }
+    }
+}
+
+impl Encode for NodeKind {
+    fn encode(&self, w: &mut Writer) {
+        (*self as u8).encode(w);
+    }
+}
+
+impl Decode for NodeKind {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        match u8::decode(r)? {
+            0 => Ok(Self::Corner),
+            1 => Ok(Self::Smooth),
+            2 => Ok(Self::Symmetric),
+            _ => Err(DecodeError::Invalid("node kind")),
+        }
+    }
+}
+
+impl Encode for PointId {
+    fn encode(&self, w: &mut Writer) {
+        self.0.encode(w);
+    }
+}
+
+impl Decode for PointId {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        u32::decode(r).map(Self)
+    }
+}
+
+impl Encode for CommandId {
+    fn encode(&self, w: &mut Writer) {
+        self.0.encode(w);
+    }
+}
+
+impl Decode for CommandId {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        u32::decode(r).map(Self)
+    }
+}
+
+impl<P: Encode> Encode for Splice<P> {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.segments);
+        w.write(&self.command_ids);
+        w.write(&self.points);
+        w.write(&self.point_ids);
+    }
+}
+
+impl<P: Decode> Decode for Splice<P> {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        let splice = Self {
+            segments: r.read()?,
+            command_ids: r.read()?,
+            points: r.read()?,
+            point_ids: r.read()?,
+        };
+        let num_points: usize = splice.segments.iter().map(|k| k.num_points()).sum();
+        if splice.command_ids.len() != splice.segments.len()
+            || splice.points.len() != num_points
+            || splice.point_ids.len() != num_points
+        {
+            return Err(DecodeError::Invalid("drawing"));
+        }
+        Ok(splice)
+    }
+}
+
+/// Stored as one splice holding every command, followed by the node kinds and the next ID.
+impl<P: Encode + Clone> Encode for Drawing<P> {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.extract(0..self.len()));
+        let mut node_kinds: Vec<_> = self.node_kinds.iter().map(|(&id, &k)| (id, k)).collect();
+        node_kinds.sort_unstable_by_key(|&(id, _)| id);
+        w.write(&node_kinds);
+        w.write(&self.next_id);
+    }
+}
+
+impl<P: Decode + Clone> Decode for Drawing<P> {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        let all: Splice<P> = r.read()?;
+        let node_kinds: Vec<(PointId, NodeKind)> = r.read()?;
+        let next_id: u32 = r.read()?;
+
+        let mut drawing = Drawing::new();
+        drawing.splice(0, 0, &all);
+        drawing.next_id = next_id;
+        let ids = Iterator::chain(
+            drawing.command_ids.iter().map(|id| id.0),
+            drawing.point_ids.iter().map(|id| id.0),
+        );
+        let unique_ids = drawing.command_indices.len() + drawing.point_indices.len();
+        if ids.clone().any(|id| id >= next_id) || unique_ids != ids.count() {
+            return Err(DecodeError::Invalid("drawing"));
+        }
+        for (id, kind) in node_kinds {
+            if drawing.point_index(id).is_none() {
+                return Err(DecodeError::Invalid("drawing"));
+            }
+            drawing.set_node_kind(id, kind);
+        }
+        Ok(drawing)
+    }
+}
+
+impl<P: Encode> Encode for DrawingDelta<P> {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.start);
+        w.write(&self.removed);
+        w.write(&self.inserted);
+        w.write(&self.moved);
+        w.write(&self.node_kinds);
+        w.write(&self.next_ids);
+    }
+}
+
+impl<P: Decode> Decode for DrawingDelta<P> {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        Ok(Self {
+            start: r.read()?,
+            removed: r.read()?,
+            inserted: r.read()?,
+            moved: r.read()?,
+            node_kinds: r.read()?,
+            next_ids: r.read()?,
+        })
+    }
+}
+
 pub struct CommandsIter<Si, Pi> {
     segments: Si,
     points: Pi,
@@ -231,3 +979,168 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Drawing, DrawingDelta, NodeKind};
+    use crate::point::Point;
+    use crate::undo::Delta;
+
+    #[test]
+    fn test_stable_ids() {
+        let mut d = Drawing::new();
+        d.push(Command::Move(0));
+        let line = d.push(Command::Line(1));
+        d.push(Command::Line(2));
+
+        let p1 = d.point_id(1).unwrap();
+        let p2 = d.point_id(2).unwrap();
+
+        d.insert(1, Command::Bezier(10, 11, 12));
+        assert_eq!(d.points(), &[0, 10, 11, 12, 1, 2]);
+        assert_eq!(d.point_index(p1), Some(4));
+        assert_eq!(d.point_index(p2), Some(5));
+        assert_eq!(d.command_index(line), Some(2));
+        assert_eq!(d.point(p1), Some(&1));
+
+        let bezier = d.command_id(1).unwrap();
+        let ctrl = d.point_id(2).unwrap();
+        assert!(matches!(d.remove(2), Command::Line(1)));
+        assert_eq!(d.point_index(p1), None);
+        assert_eq!(d.command_index(line), None);
+        assert_eq!(d.point_index(p2), Some(4));
+
+        d.remove(0);
+        assert_eq!(d.points(), &[10, 11, 12, 2]);
+        assert_eq!(d.command_index(bezier), Some(0));
+        assert_eq!(d.point_index(ctrl), Some(1));
+        *d.point_mut(p2).unwrap() = 20;
+        assert_eq!(d[3], 20);
+
+        // IDs are not handed out again after a removal.
+        let new = d.push(Command::Line(3));
+        assert_ne!(new, line);
+        assert_ne!(d.point_id(4), Some(p1));
+    }
+
+    #[test]
+    fn test_handles() {
+        let mut d = Drawing::new();
+        d.push(Command::Move(0));
+        d.push(Command::Bezier(1, 2, 3));
+        d.push(Command::Bezier(4, 5, 6));
+        d.push(Command::Line(7));
+
+        assert_eq!(d.locate_point(5), Some((2, 1)));
+        assert_eq!(d.locate_point(8), None);
+        assert!(d.is_node(3));
+        assert!(!d.is_node(4));
+
+        assert_eq!(d.node_handles(0), [None, Some(1)]);
+        assert_eq!(d.node_handles(3), [Some(2), Some(4)]);
+        assert_eq!(d.node_handles(6), [Some(5), None]);
+        assert_eq!(d.node_handles(2), [None, None]);
+
+        assert_eq!(d.handle_partner(2), Some((3, Some(4))));
+        assert_eq!(d.handle_partner(4), Some((3, Some(2))));
+        assert_eq!(d.handle_partner(1), Some((0, None)));
+        assert_eq!(d.handle_partner(3), None);
+        assert_eq!(d.handle_partner(7), None);
+    }
+
+    #[test]
+    fn test_node_kinds() {
+        let mut d = Drawing::new();
+        d.push(Command::Move(Point::new(0.0, 0.0)));
+        d.push(Command::Bezier(
+            Point::new(0.0, 30.0),
+            Point::new(60.0, 30.0),
+            Point::new(90.0, 0.0),
+        ));
+        d.push(Command::Bezier(
+            Point::new(120.0, 0.0),
+            Point::new(180.0, 30.0),
+            Point::new(180.0, 0.0),
+        ));
+        let node = d.point_id(3).unwrap();
+        assert_eq!(d.node_kind(node), NodeKind::Corner);
+
+        d.change_node_kind(3, NodeKind::Symmetric);
+        assert_eq!(d.node_kind(node), NodeKind::Symmetric);
+        let mirrored = d[3] * 2.0 - d[2] - d[4];
+        assert!(mirrored.length() < 1e-4);
+
+        d[4] = Point::new(100.0, 10.0);
+        d.enforce_continuity(4);
+        assert_eq!(d[2], Point::new(80.0, -10.0));
+
+        d.change_node_kind(3, NodeKind::Smooth);
+        d[2] = Point::new(90.0, 20.0);
+        d.enforce_continuity(2);
+        assert_eq!(d[4].x, 90.0);
+        assert!((d[4].y + 10f32.hypot(10.0)).abs() < 1e-4);
+
+        d.auto_smooth(3);
+        assert_eq!(d[2], Point::new(60.0, 0.0));
+        assert_eq!(d[4], Point::new(120.0, 0.0));
+
+        d.remove(1);
+        assert_eq!(d.node_kind(node), NodeKind::Corner);
+    }
+
+    #[test]
+    fn test_auto_smooth_first_bezier() {
+        // There's no point before a bezier that the drawing starts with, to line the handles up with.
+        let mut d = Drawing::new();
+        d.push(Command::Bezier(
+            Point::new(0.0, 30.0),
+            Point::new(60.0, 30.0),
+            Point::new(90.0, 0.0),
+        ));
+        d.push(Command::Bezier(
+            Point::new(120.0, 0.0),
+            Point::new(180.0, 30.0),
+            Point::new(180.0, 0.0),
+        ));
+        d.auto_smooth(2);
+        assert_eq!(d[1], Point::new(60.0, 30.0));
+        assert_eq!(d.node_kind(d.point_id(2).unwrap()), NodeKind::Corner);
+    }
+
+    #[test]
+    fn test_delta() {
+        let mut old = Drawing::new();
+        old.push(Command::Move(0));
+        old.push(Command::Line(1));
+        old.push(Command::Bezier(2, 3, 4));
+        old.push(Command::Line(5));
+        old.push(Command::Line(6));
+        old.set_node_kind(old.point_id(4).unwrap(), NodeKind::Smooth);
+
+        let mut new = old.clone();
+        new[0] = 10;
+        new[6] = 16;
+        new.remove(1);
+        new.insert(2, Command::Line(7));
+        new.insert(2, Command::Bezier(8, 9, 10));
+        new.set_node_kind(new.point_id(3).unwrap(), NodeKind::Symmetric);
+        new.set_node_kind(new.point_id(6).unwrap(), NodeKind::Smooth);
+
+        let mut drawing = old.clone();
+        let mut delta = DrawingDelta::diff(old.clone(), &new);
+        assert_eq!(delta.start, 1);
+        assert_eq!(delta.removed.points, [1, 2, 3, 4]);
+        assert_eq!(delta.inserted.points, [2, 3, 4, 8, 9, 10, 7]);
+        assert_eq!(delta.moved.len(), 2);
+
+        delta.apply(&mut drawing);
+        assert_eq!(drawing, new);
+        delta.revert(&mut drawing);
+        assert_eq!(drawing, old);
+
+        // IDs handed out after the change must not collide with ones from before it was undone.
+        delta.apply(&mut drawing);
+        let id = drawing.push(Command::Line(11));
+        assert_eq!(new.clone().push(Command::Line(11)), id);
+    }
+}
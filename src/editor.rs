@@ -0,0 +1,1672 @@
+use std::collections::BTreeSet;
+use std::mem;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::SystemTime;
+
+use byte_set::ByteSet;
+use image::RgbaImage;
+
+use crate::ass::{self, ParseError};
+use crate::background::{self, BackgroundError};
+use crate::clipboard::{Clipboard, PasteError};
+use crate::codec::DecodeError;
+use crate::document::{
+    self, BackgroundTransform, Document, DocumentDelta, Layer, LayerId, LayerSettings,
+};
+use crate::drawing::{Command, CommandKind, Drawing, NodeKind, PointId};
+use crate::point::Point;
+use crate::project::{BackgroundSettings, Colors, DrawingInfo, Project};
+use crate::raster::{self, RasterMode, Scene};
+use crate::snap::{self, Guide, Overlay, Snap, SnapSettings};
+use crate::undo::{EntryId, HistoryMode, Limits, UndoStack};
+use crate::viewport::Viewport;
+
+/// How much undo history the editor keeps by default.
+const HISTORY_LIMITS: Limits = Limits {
+    max_entries: None,
+    max_bytes: Some(64 << 20),
+};
+
+/// With Shift held, dragged points move along multiples of this angle, in degrees.
+const ANGLE_STEP: f32 = 15.0;
+
+/// Moves `target` onto the nearest ray from `origin` whose angle is a multiple of `step` degrees.
+fn constrain_angle(origin: Point<f32>, target: Point<f32>, step: f32) -> Point<f32> {
+    let v = target - origin;
+    let step = step.to_radians();
+    let angle = (v.y.atan2(v.x) / step).round() * step;
+    let dir = Point::new(angle.cos(), angle.sin());
+    origin + dir * (v.x * dir.x + v.y * dir.y)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+}
+
+/// The keys the editor cares about. Frontends translate their own key codes into these.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Key {
+    Control,
+    Shift,
+    Alt,
+    C,
+    V,
+    Y,
+    H,
+    Z,
+    Zero,
+    One,
+    Two,
+}
+
+/// Platform-neutral input. Positions are in screen pixels, relative to the canvas' top-left corner.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum InputEvent {
+    PointerDown(MouseButton, Point<i32>),
+    PointerMove(Point<i32>),
+    PointerUp(MouseButton, Point<i32>),
+    /// Scrolling by some number of notches; positive is away from the user.
+    Wheel(i32, Point<i32>),
+    KeyDown(Key),
+    KeyUp(Key),
+}
+
+/// Something the editor can't do on its own and needs the frontend to take care of.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Copy(String),
+    Paste,
+}
+
+/// What the frontend should do after the editor has handled some input.
+#[must_use]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Response {
+    /// The view needs to be repainted.
+    pub redraw: bool,
+    /// The drawing changed, so anything derived from it is stale.
+    pub drawing_changed: bool,
+    /// The background image was replaced or removed.
+    pub background_changed: bool,
+    /// The pointer should be captured (`Some(true)`) or released (`Some(false)`).
+    pub capture: Option<bool>,
+    pub request: Option<Request>,
+}
+
+impl Response {
+    const REDRAW: Self = Self {
+        redraw: true,
+        drawing_changed: false,
+        background_changed: false,
+        capture: None,
+        request: None,
+    };
+
+    const DRAWING_CHANGED: Self = Self {
+        redraw: true,
+        drawing_changed: true,
+        background_changed: false,
+        capture: None,
+        request: None,
+    };
+
+    fn request(request: Request) -> Self {
+        Self {
+            request: Some(request),
+            ..Self::default()
+        }
+    }
+
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            redraw: self.redraw || other.redraw,
+            drawing_changed: self.drawing_changed || other.drawing_changed,
+            background_changed: self.background_changed || other.background_changed,
+            capture: other.capture.or(self.capture),
+            request: other.request.or(self.request),
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct Keys {
+    keys: ByteSet,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum KeyState {
+    Pressed,
+    Released,
+    Up,
+    Down,
+}
+
+impl Keys {
+    fn update(&mut self, key: Key, down: bool) -> KeyState {
+        let key = key as u8;
+        let old = self.keys.contains(key);
+        if down {
+            self.keys.insert(key);
+        } else {
+            self.keys.remove(key);
+        }
+        match (old, self.keys.contains(key)) {
+            (false, true) => KeyState::Pressed,
+            (true, false) => KeyState::Released,
+            (false, false) => KeyState::Up,
+            (true, true) => KeyState::Down,
+        }
+    }
+
+    fn pressed(&self, key: Key) -> bool {
+        self.keys.contains(key as u8)
+    }
+}
+
+/// The document being edited, along with its undo history.
+pub type History = UndoStack<Document, DocumentDelta>;
+
+/// The editing state machine behind the GUI: everything that happens between input and rendering.
+pub struct Editor {
+    doc: History,
+    /// The layer being edited.
+    active: LayerId,
+    viewport: Viewport,
+    draw_mode: CommandKind,
+    selection: BTreeSet<PointId>,
+    keys: Keys,
+    snap_settings: SnapSettings,
+    guides: Vec<Guide>,
+    colors: Colors,
+    raster_mode: RasterMode,
+    info: DrawingInfo,
+    background_settings: BackgroundSettings,
+    /// What the point being placed or dragged last snapped to, if anything.
+    snap: Option<Snap>,
+    /// The history entry that the opacity slider being dragged adds to.
+    opacity_slide: Option<EntryId>,
+
+    cursor_pos: Point<i32>,
+    left_dragging: bool,
+    right_dragging: bool,
+    /// Whether the right drag moves the background rather than the view.
+    dragging_background: bool,
+    dragged_point: Option<PointId>,
+    /// Whether the dragged point was added by the drag, so that it's committed even if it doesn't move.
+    drag_added_point: bool,
+    /// What to call the history entry for the current drag.
+    drag_label: String,
+    /// Where Shift-constrained dragging measures angles from.
+    drag_origin: Point<f32>,
+    pre_drag_pos: Point<f32>,
+    drag_start_pos: Point<i32>,
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        let mut doc = History::with_limits(Document::default(), HISTORY_LIMITS);
+        doc.set_mode(HistoryMode::Tree);
+        Self {
+            active: doc.layers()[0].id(),
+            doc,
+            viewport: Viewport::default(),
+            draw_mode: CommandKind::Line,
+            selection: BTreeSet::new(),
+            keys: Keys::default(),
+            snap_settings: SnapSettings::default(),
+            guides: Vec::new(),
+            colors: Colors::default(),
+            raster_mode: RasterMode::default(),
+            info: DrawingInfo::default(),
+            background_settings: BackgroundSettings::default(),
+            snap: None,
+            opacity_slide: None,
+
+            cursor_pos: Point::default(),
+            left_dragging: false,
+            right_dragging: false,
+            dragging_background: false,
+            dragged_point: None,
+            drag_added_point: false,
+            drag_label: String::new(),
+            drag_origin: Point::default(),
+            pre_drag_pos: Point::default(),
+            drag_start_pos: Point::default(),
+        }
+    }
+
+    /// The active layer's drawing.
+    #[inline]
+    pub fn drawing(&self) -> &Drawing<Point<f32>> {
+        &self.doc.layers()[self.active_layer()].drawing
+    }
+
+    fn drawing_mut(&mut self) -> &mut Drawing<Point<f32>> {
+        let index = self.active_layer();
+        &mut self.doc.layer_mut(index).drawing
+    }
+
+    #[inline]
+    pub fn layers(&self) -> &[Layer] {
+        self.doc.layers()
+    }
+
+    /// The index of the layer being edited.
+    pub fn active_layer(&self) -> usize {
+        let top = self.doc.layers().len() - 1;
+        self.doc.layer_index(self.active).unwrap_or(top)
+    }
+
+    pub fn set_active_layer(&mut self, index: usize) -> Response {
+        if self.is_dragging() || index == self.active_layer() {
+            return Response::default();
+        }
+        match self.doc.layers().get(index) {
+            Some(layer) => self.active = layer.id(),
+            None => return Response::default(),
+        }
+        self.selection.clear();
+        Response::DRAWING_CHANGED
+    }
+
+    /// Whether the active layer can be drawn on.
+    fn can_edit(&self) -> bool {
+        let settings = &self.doc.layers()[self.active_layer()].settings;
+        settings.visible && !settings.locked
+    }
+
+    /// Adds an empty layer above the active one, and makes it the active one.
+    pub fn add_layer(&mut self) -> Response {
+        if self.is_dragging() {
+            return Response::default();
+        }
+        let settings = LayerSettings {
+            name: format!("Layer {}", self.doc.layers().len() + 1),
+            color: self.colors.shape,
+            ..LayerSettings::default()
+        };
+        let index = self.active_layer() + 1;
+        self.active = self.doc.insert_layer(index, settings);
+        self.doc.commit_as("Add layer");
+        self.selection.clear();
+        Response::DRAWING_CHANGED
+    }
+
+    /// Removes the active layer, unless it's the only one. The layer below it becomes the active one.
+    pub fn remove_layer(&mut self) -> Response {
+        let index = self.active_layer();
+        if self.is_dragging() || self.doc.remove_layer(index).is_none() {
+            return Response::default();
+        }
+        self.active = self.doc.layers()[index.saturating_sub(1)].id();
+        self.doc.commit_as("Remove layer");
+        self.selection.clear();
+        Response::DRAWING_CHANGED
+    }
+
+    /// Moves the active layer `offset` places up (or down, if negative) the stack.
+    pub fn move_layer(&mut self, offset: isize) -> Response {
+        let from = self.active_layer();
+        let top = self.doc.layers().len() as isize - 1;
+        let to = (from as isize + offset).clamp(0, top) as usize;
+        if self.is_dragging() || to == from {
+            return Response::default();
+        }
+        self.doc.move_layer(from, to);
+        let label = if to > from {
+            "Move layer up"
+        } else {
+            "Move layer down"
+        };
+        self.doc.commit_as(label);
+        Response::DRAWING_CHANGED
+    }
+
+    /// Changes the active layer's name, visibility, color and so on, as an undoable edit.
+    pub fn set_layer_settings(&mut self, settings: LayerSettings) -> Response {
+        let index = self.active_layer();
+        let old = &self.doc.layers()[index].settings;
+        let label = if self.is_dragging() || settings == *old {
+            return Response::default();
+        } else if settings.visible != old.visible {
+            if settings.visible {
+                "Show layer"
+            } else {
+                "Hide layer"
+            }
+        } else if settings.locked != old.locked {
+            if settings.locked {
+                "Lock layer"
+            } else {
+                "Unlock layer"
+            }
+        } else if settings.name != old.name {
+            "Rename layer"
+        } else if settings.fill_rule != old.fill_rule {
+            "Change fill rule"
+        } else if settings.effects != old.effects {
+            "Change effects"
+        } else {
+            "Change layer"
+        };
+        self.doc.layer_mut(index).settings = settings;
+        self.doc.commit_as(label);
+        Response::DRAWING_CHANGED
+    }
+
+    #[inline]
+    pub fn background(&self) -> Option<&RgbaImage> {
+        self.doc.background.as_deref()
+    }
+
+    #[inline]
+    pub fn background_transform(&self) -> BackgroundTransform {
+        self.doc.background_transform
+    }
+
+    /// Places the background differently, or changes how strongly it shows, as an undoable edit.
+    /// A locked background keeps its place until it's unlocked, even if it's unlocked by this same change.
+    /// Transforms that aren't `BackgroundTransform::is_valid` are ignored, and so is anything in the middle of a drag.
+    pub fn set_background_transform(&mut self, transform: BackgroundTransform) -> Response {
+        self.change_background_transform(transform, false)
+    }
+
+    /// Changes how strongly the background shows, as one step of dragging a slider.
+    /// Every step until `end_opacity_slide` adds to the same undoable edit.
+    pub fn slide_background_opacity(&mut self, opacity: f32) -> Response {
+        let transform = BackgroundTransform {
+            opacity,
+            ..self.doc.background_transform
+        };
+        let amend = self.opacity_slide.is_some() && self.opacity_slide == self.doc.head();
+        let response = self.change_background_transform(transform, amend);
+        if response.redraw {
+            self.opacity_slide = self.doc.head();
+        }
+        response
+    }
+
+    /// Lets go of the slider, so that the next change to the opacity is an edit of its own.
+    pub fn end_opacity_slide(&mut self) {
+        self.opacity_slide = None;
+    }
+
+    fn change_background_transform(
+        &mut self,
+        transform: BackgroundTransform,
+        amend: bool,
+    ) -> Response {
+        let old = self.doc.background_transform;
+        if self.is_dragging()
+            || transform == old
+            || !transform.is_valid()
+            || (old.locked && transform.moves(&old))
+        {
+            return Response::default();
+        }
+        let label = if transform.locked != old.locked {
+            if transform.locked {
+                "Lock background"
+            } else {
+                "Unlock background"
+            }
+        } else if transform.offset != old.offset {
+            "Move background"
+        } else if transform.scale != old.scale {
+            "Scale background"
+        } else if transform.rotation != old.rotation {
+            "Rotate background"
+        } else {
+            "Change background opacity"
+        };
+        self.doc.background_transform = transform;
+        if amend {
+            self.doc.amend_as(label);
+        } else {
+            self.doc.commit_as(label);
+        }
+        Response::REDRAW
+    }
+
+    #[inline]
+    pub fn history(&self) -> &History {
+        &self.doc
+    }
+
+    pub fn set_history_limits(&mut self, limits: Limits) {
+        self.doc.set_limits(limits);
+    }
+
+    /// Saves the document along with its undo history.
+    pub fn save_history(&self) -> Vec<u8> {
+        document::save_history(&self.doc)
+    }
+
+    /// Replaces the document and its undo history with ones saved by `save_history`.
+    pub fn load_history(&mut self, data: &[u8]) -> Result<Response, DecodeError> {
+        let mut doc = document::load_history(data)?;
+        doc.set_limits(self.doc.limits());
+        self.doc = doc;
+        self.dragged_point = None;
+        self.opacity_slide = None;
+        self.prune_selection();
+        Ok(Response {
+            background_changed: true,
+            ..Response::DRAWING_CHANGED
+        })
+    }
+
+    pub fn set_history_mode(&mut self, mode: HistoryMode) {
+        self.doc.set_mode(mode);
+    }
+
+    /// Everything needed to save the editing session.
+    pub fn to_project(&self) -> Project {
+        Project {
+            history: self.doc.clone(),
+            info: self.info.clone(),
+            viewport: self.viewport,
+            background: self.background_settings.clone(),
+            colors: self.colors,
+            snap: self.snap_settings,
+            guides: self.guides.clone(),
+        }
+    }
+
+    /// Picks up a saved editing session where it was left off.
+    pub fn load_project(&mut self, project: Project) -> Response {
+        let mut doc = project.history;
+        doc.set_limits(self.doc.limits());
+        self.doc = doc;
+        self.opacity_slide = None;
+        self.info = project.info;
+        self.viewport = Viewport {
+            screen_dims: self.viewport.screen_dims,
+            ..project.viewport
+        };
+        self.background_settings = project.background;
+        self.colors = project.colors;
+        self.snap_settings = project.snap;
+        self.guides = project.guides;
+        self.snap = None;
+        self.dragged_point = None;
+        self.prune_selection();
+        Response {
+            background_changed: true,
+            ..Response::DRAWING_CHANGED
+        }
+    }
+
+    #[inline]
+    pub fn colors(&self) -> Colors {
+        self.colors
+    }
+
+    pub fn set_colors(&mut self, colors: Colors) -> Response {
+        self.colors = colors;
+        Response::REDRAW
+    }
+
+    #[inline]
+    pub fn raster_mode(&self) -> RasterMode {
+        self.raster_mode
+    }
+
+    /// Switches between the quick preview and libass-accurate shapes.
+    pub fn set_raster_mode(&mut self, mode: RasterMode) -> Response {
+        self.raster_mode = mode;
+        Response::DRAWING_CHANGED
+    }
+
+    #[inline]
+    pub fn drawing_info(&self) -> &DrawingInfo {
+        &self.info
+    }
+
+    pub fn set_drawing_info(&mut self, info: DrawingInfo) {
+        self.info = info;
+    }
+
+    #[inline]
+    pub fn background_settings(&self) -> &BackgroundSettings {
+        &self.background_settings
+    }
+
+    pub fn set_background_settings(&mut self, settings: BackgroundSettings) -> Response {
+        self.background_settings = settings;
+        Response::REDRAW
+    }
+
+    #[inline]
+    pub fn viewport(&self) -> Viewport {
+        self.viewport
+    }
+
+    #[inline]
+    pub fn selection(&self) -> &BTreeSet<PointId> {
+        &self.selection
+    }
+
+    #[inline]
+    pub fn draw_mode(&self) -> CommandKind {
+        self.draw_mode
+    }
+
+    #[inline]
+    pub fn set_draw_mode(&mut self, mode: CommandKind) {
+        self.draw_mode = mode;
+    }
+
+    #[inline]
+    pub fn snap_settings(&self) -> SnapSettings {
+        self.snap_settings
+    }
+
+    #[inline]
+    pub fn set_snap_settings(&mut self, settings: SnapSettings) -> Response {
+        self.snap_settings = settings;
+        Response::REDRAW
+    }
+
+    #[inline]
+    pub fn guides(&self) -> &[Guide] {
+        &self.guides[..]
+    }
+
+    pub fn add_guide(&mut self, guide: Guide) -> Response {
+        self.guides.push(guide);
+        Response::REDRAW
+    }
+
+    pub fn clear_guides(&mut self) -> Response {
+        self.guides.clear();
+        Response::REDRAW
+    }
+
+    /// The grid, guides and snapping hints, as they should appear in the current viewport.
+    pub fn overlay(&self) -> Overlay {
+        let rect = self.viewport.visible_rect();
+        let grid = match self.snap_settings.grid {
+            Some(spacing) => snap::grid_lines(&self.viewport, spacing),
+            None => Vec::new(),
+        };
+        let guides = self.guides.iter().map(|g| g.line(rect)).collect();
+        let hints = match &self.snap {
+            Some(snap) => snap.hint_lines(self.drawing(), &self.viewport),
+            None => Vec::new(),
+        };
+        Overlay {
+            grid,
+            guides,
+            hints,
+        }
+    }
+
+    #[inline]
+    pub fn is_dragging(&self) -> bool {
+        self.left_dragging || self.right_dragging
+    }
+
+    pub fn resize(&mut self, screen_dims: Point<f32>) -> Response {
+        let old_dims = self.viewport.screen_dims;
+        self.viewport.screen_dims = screen_dims;
+        Response {
+            redraw: screen_dims.x > old_dims.x || screen_dims.y > old_dims.y,
+            ..Response::default()
+        }
+    }
+
+    /// Replaces the image being traced over, as an undoable edit. Nothing is replaced in the middle of a drag.
+    pub fn set_background(&mut self, image: Option<Rc<RgbaImage>>) -> Response {
+        let label = if image.is_some() {
+            "Paste background"
+        } else {
+            "Remove background"
+        };
+        self.replace_background(image, None, label)
+    }
+
+    /// Loads the image to trace over from a file, as an undoable edit, unless in the middle of a drag.
+    pub fn open_background(&mut self, path: &Path) -> Result<Response, BackgroundError> {
+        let image = background::load_image(path)?;
+        Ok(self.replace_background(Some(Rc::new(image)), Some(path), "Load background"))
+    }
+
+    fn replace_background(
+        &mut self,
+        image: Option<Rc<RgbaImage>>,
+        path: Option<&Path>,
+        label: &str,
+    ) -> Response {
+        if self.is_dragging() || document::same_background(&self.doc.background, &image) {
+            return Response::default();
+        }
+        self.doc.background = image;
+        self.doc.commit_as(label);
+        self.background_settings.path = path.map(Path::to_owned);
+        Response {
+            background_changed: true,
+            ..Response::REDRAW
+        }
+    }
+
+    /// Pastes whatever is on the clipboard: text goes through `paste_text`, and an image becomes the background.
+    /// Text that isn't a drawing is only an error if there's no image to fall back on.
+    /// Nothing is pasted in the middle of a drag.
+    pub fn paste(&mut self, clipboard: &mut dyn Clipboard) -> Result<Response, PasteError> {
+        if self.is_dragging() {
+            return Ok(Response::default());
+        }
+        let error = match clipboard.get_text()? {
+            Some(text) if !text.trim().is_empty() => match self.paste_text(&text) {
+                Ok(response) => return Ok(response),
+                Err(e) => Some(e),
+            },
+            _ => None,
+        };
+        match clipboard.get_image()? {
+            Some(image) => Ok(self.set_background(Some(Rc::new(image)))),
+            None => Err(error.map_or(PasteError::Empty, PasteError::Parse)),
+        }
+    }
+
+    /// Adds the drawings in some text to the active layer, as an undoable edit. The text can be a drawing,
+    /// or `Dialogue` lines with drawings in them. If there's a `\clip` tag in it, the clip is imported instead.
+    pub fn paste_text(&mut self, text: &str) -> Result<Response, ParseError> {
+        if let Some(clip) = ass::find_clip(text) {
+            return Ok(self.import_clip(clip?));
+        }
+        let mut drawings = Vec::new();
+        for line in text.lines() {
+            for span in ass::find_drawings(line) {
+                let mut drawing = ass::parse_drawing(&line[span.range])?;
+                let factor = ass::scale_factor(span.scale);
+                for p in drawing.points_mut() {
+                    *p /= factor;
+                }
+                drawings.push(drawing);
+            }
+        }
+        if drawings.is_empty() {
+            drawings.push(ass::parse_drawing(text)?);
+        }
+
+        if self.is_dragging() || !self.can_edit() || drawings.iter().all(Drawing::is_empty) {
+            return Ok(Response::default());
+        }
+        let target = self.drawing_mut();
+        for drawing in drawings {
+            // Each pasted drawing starts a contour of its own, like it would on its own line.
+            let mut commands = drawing.commands().peekable();
+            if !matches!(commands.peek(), Some(Command::Move(_)) | None) {
+                target.push(Command::Move(Point::new(0.0, 0.0)));
+            }
+            for cmd in commands {
+                target.push(cmd);
+            }
+        }
+        self.doc.commit_as("Paste drawing");
+        Ok(Response::DRAWING_CHANGED)
+    }
+
+    /// Adds a clip as a layer of its own above the active one, so that it can be edited and copied back.
+    /// Like pasting a drawing, this isn't done in the middle of a drag, but the active layer may be locked or hidden,
+    /// since it's left as it is.
+    pub fn import_clip(&mut self, clip: Drawing<Point<f32>>) -> Response {
+        if self.is_dragging() {
+            return Response::default();
+        }
+        let settings = LayerSettings {
+            name: "Clip".to_owned(),
+            color: self.colors.shape,
+            ..LayerSettings::default()
+        };
+        let index = self.active_layer() + 1;
+        self.active = self.doc.insert_layer(index, settings);
+        self.doc.layer_mut(index).drawing = clip;
+        self.doc.commit_as("Import clip");
+        self.selection.clear();
+        Response::DRAWING_CHANGED
+    }
+
+    pub fn scene_pos_at(&self, screen_pos: Point<i32>) -> Point<f32> {
+        self.viewport.screen_to_scene(screen_pos.cast())
+    }
+
+    pub fn handle(&mut self, event: InputEvent) -> Response {
+        match event {
+            InputEvent::PointerDown(button, pos) => {
+                self.cursor_pos = pos;
+                self.pointer_down(button)
+            }
+            InputEvent::PointerMove(pos) => {
+                self.cursor_pos = pos;
+                self.pointer_move()
+            }
+            InputEvent::PointerUp(button, pos) => {
+                self.cursor_pos = pos;
+                self.pointer_up(button)
+            }
+            InputEvent::Wheel(notches, pos) => {
+                self.cursor_pos = pos;
+                self.zoom(notches)
+            }
+            InputEvent::KeyDown(key) => self.key(key, true),
+            InputEvent::KeyUp(key) => self.key(key, false),
+        }
+    }
+
+    pub fn undo(&mut self) -> Response {
+        self.travel(History::undo)
+    }
+
+    pub fn redo(&mut self) -> Response {
+        self.travel(History::redo)
+    }
+
+    /// Undoes or redoes until `position` history entries are applied.
+    pub fn jump_to(&mut self, position: usize) -> Response {
+        self.travel(|doc| doc.jump_to(position))
+    }
+
+    /// Switches to another branch of the undo tree, if there is one `offset` places away.
+    pub fn go_to_sibling(&mut self, offset: isize) -> Response {
+        self.travel(|doc| {
+            doc.go_to_sibling(offset);
+        })
+    }
+
+    pub fn go_to_entry(&mut self, id: Option<EntryId>) -> Response {
+        self.travel(|doc| doc.go_to(id))
+    }
+
+    /// Goes back (or forward) to how the document looked at some point in time.
+    pub fn jump_to_time(&mut self, time: SystemTime) -> Response {
+        self.travel(|doc| doc.jump_to_time(time))
+    }
+
+    /// Moves through the history somehow.
+    fn travel(&mut self, f: impl FnOnce(&mut History)) -> Response {
+        if self.is_dragging() {
+            return Response::default();
+        }
+        self.opacity_slide = None;
+        let background = self.doc.background.clone();
+        f(&mut self.doc);
+        // If the active layer is gone, stick with whichever one took its place.
+        self.active = self.doc.layers()[self.active_layer()].id();
+        self.prune_selection();
+        Response {
+            background_changed: !document::same_background(&background, &self.doc.background),
+            ..Response::DRAWING_CHANGED
+        }
+    }
+
+    pub fn clear(&mut self) -> Response {
+        if self.is_dragging() {
+            return Response::default();
+        }
+        self.dragged_point = None;
+        self.selection.clear();
+        if !self.can_edit() || self.drawing().is_empty() {
+            return Response::default();
+        }
+        self.drawing_mut().clear();
+        self.doc.commit_as("Clear");
+        Response::DRAWING_CHANGED
+    }
+
+    /// Changes the kind of every selected node, or of the nodes that selected control points belong to.
+    pub fn set_node_kind(&mut self, kind: NodeKind) -> Response {
+        let label = match kind {
+            NodeKind::Corner => "Make corner",
+            NodeKind::Smooth => "Make smooth",
+            NodeKind::Symmetric => "Make symmetric",
+        };
+        self.edit_selected_nodes(label, |drawing, node| drawing.change_node_kind(node, kind))
+    }
+
+    pub fn auto_smooth_selection(&mut self) -> Response {
+        self.edit_selected_nodes("Auto-smooth", Drawing::auto_smooth)
+    }
+
+    fn edit_selected_nodes(
+        &mut self,
+        label: &str,
+        f: impl Fn(&mut Drawing<Point<f32>>, usize),
+    ) -> Response {
+        if self.is_dragging() || !self.can_edit() {
+            return Response::default();
+        }
+        let index = self.active_layer();
+        let drawing = &self.doc.layers()[index].drawing;
+        let nodes: BTreeSet<usize> = self
+            .selection
+            .iter()
+            .filter_map(|&id| drawing.point_index(id))
+            .filter_map(|i| match drawing.handle_partner(i) {
+                Some((node, _)) => Some(node),
+                None => Some(i).filter(|&i| drawing.is_node(i)),
+            })
+            .collect();
+        if nodes.is_empty() {
+            return Response::default();
+        }
+        let mut tx = self.doc.transaction();
+        for node in nodes {
+            f(&mut tx.layer_mut(index).drawing, node);
+        }
+        tx.commit(label);
+        Response::DRAWING_CHANGED
+    }
+
+    pub fn reset_zoom(&mut self) -> Response {
+        self.viewport.reset_zoom();
+        Response::REDRAW
+    }
+
+    pub fn zoom_to_drawing(&mut self) -> Response {
+        match self.drawing().bounds() {
+            Some(bounds) => {
+                self.viewport.fit(bounds, 20.0);
+                Response::REDRAW
+            }
+            None => Response::default(),
+        }
+    }
+
+    pub fn zoom_to_background(&mut self) -> Response {
+        match self.doc.background_bounds() {
+            Some(bounds) => {
+                self.viewport.fit(bounds, 0.0);
+                Response::REDRAW
+            }
+            None => Response::default(),
+        }
+    }
+
+    pub fn copy_text(&self) -> String {
+        crate::ass::format_drawing(self.drawing())
+    }
+
+    /// A `Dialogue` line for every visible layer.
+    pub fn export_text(&self) -> String {
+        crate::ass::format_layers(self.doc.layers())
+    }
+
+    /// The document as the renderers see it, without nodes or overlays.
+    pub fn scene(&self) -> Scene<'_> {
+        Scene {
+            background: self.background(),
+            background_transform: self.doc.background_transform,
+            layers: self.layers(),
+            active: None,
+            colors: self.colors,
+            overlay: None,
+            mode: self.raster_mode,
+        }
+    }
+
+    /// Renders what the canvas shows, in software.
+    pub fn render(&self) -> RgbaImage {
+        let overlay = self.overlay();
+        let scene = Scene {
+            active: Some(self.active_layer()),
+            overlay: Some(&overlay),
+            ..self.scene()
+        };
+        raster::render(&scene, &self.viewport)
+    }
+
+    fn prune_selection(&mut self) {
+        let drawing = &self.doc.layers()[self.active_layer()].drawing;
+        self.selection
+            .retain(|&id| drawing.point_index(id).is_some());
+    }
+
+    /// Where the point `moving` (or a new point) should go if the user is trying to put it under the cursor.
+    fn snap_cursor(&mut self, moving: Option<PointId>) -> Point<f32> {
+        let candidate = self.scene_pos_at(self.cursor_pos);
+        let snap = self.snap_settings.snap(
+            candidate,
+            self.drawing(),
+            &self.guides,
+            moving,
+            self.viewport.scale,
+        );
+        self.snap = Some(snap).filter(Snap::is_snapped);
+        snap.point
+    }
+
+    fn add_point_at_cursor(&mut self) -> Option<PointId> {
+        let point = self.snap_cursor(None);
+        let draw_mode = self.draw_mode;
+        let drawing = self.drawing_mut();
+        let cmd = if drawing.points().is_empty() {
+            Command::Move(point)
+        } else {
+            match draw_mode {
+                CommandKind::Move => Command::Move(point),
+                CommandKind::Line => Command::Line(point),
+                CommandKind::Bezier => {
+                    let p0 = *drawing.points().last().unwrap();
+                    let p3 = point;
+                    let p1 = p0.lerp(p3, 0.3333);
+                    let p2 = p0.lerp(p3, 0.6667);
+                    Command::Bezier(p1, p2, p3)
+                }
+            }
+        };
+        let label = format!("Add {}", cmd.kind().name());
+        drawing.push(cmd);
+        let id = drawing.point_ids().last().copied();
+        self.drag_label = label;
+        id
+    }
+
+    fn point_near_cursor(&self) -> Option<PointId> {
+        let cursor_pos = self.scene_pos_at(self.cursor_pos);
+        let scale = self.viewport.scale;
+        let drawing = self.drawing();
+        let i = drawing.points().iter().position(|point| {
+            let dx = cursor_pos.x - point.x;
+            let dy = cursor_pos.y - point.y;
+            f32::max(dx.abs(), dy.abs()) <= 5.0 / scale
+        })?;
+        drawing.point_id(i)
+    }
+
+    fn pointer_down(&mut self, button: MouseButton) -> Response {
+        let was_dragging = self.is_dragging();
+        let mut response = Response::default();
+        match button {
+            MouseButton::Right => {
+                // Shift+right-drag moves the background, unless it's locked.
+                self.dragging_background = !was_dragging
+                    && self.keys.pressed(Key::Shift)
+                    && self.doc.background.is_some()
+                    && !self.doc.background_transform.locked;
+                if self.dragging_background {
+                    self.drag_origin = self.doc.background_transform.offset;
+                }
+                self.right_dragging = true;
+            }
+            MouseButton::Left if self.dragging_background => (),
+            MouseButton::Left if !self.can_edit() => (),
+            MouseButton::Left => {
+                let mut drag_id = self.point_near_cursor();
+                if let Some(id) = drag_id {
+                    self.drag_origin = *self.drawing().point(id).unwrap();
+                    let index = self.drawing().point_index(id).unwrap();
+                    self.drag_label = format!("Move point {}", index);
+                    self.drag_added_point = false;
+                } else {
+                    drag_id = self.add_point_at_cursor();
+                    // A new point is constrained relative to the node it continues from,
+                    // which comes before the new command's points.
+                    let drawing = self.drawing();
+                    let new_points = drawing
+                        .commands()
+                        .last()
+                        .map_or(0, |cmd| cmd.points().count());
+                    let points = drawing.points();
+                    self.drag_origin = points[points.len().saturating_sub(new_points + 1)];
+                    self.drag_added_point = true;
+                    response = Response::DRAWING_CHANGED;
+                }
+                self.dragged_point = drag_id;
+                if !self.keys.pressed(Key::Shift) {
+                    self.selection.clear();
+                }
+                self.selection.extend(drag_id);
+                self.left_dragging = true;
+                response.redraw = true;
+            }
+        }
+        response.merge(self.drag_transition(was_dragging))
+    }
+
+    fn pointer_up(&mut self, button: MouseButton) -> Response {
+        let was_dragging = self.is_dragging();
+        match button {
+            MouseButton::Right => self.right_dragging = false,
+            MouseButton::Left => self.left_dragging = false,
+        }
+        self.drag_transition(was_dragging)
+    }
+
+    fn drag_transition(&mut self, was_dragging: bool) -> Response {
+        match (was_dragging, self.is_dragging()) {
+            (false, true) => {
+                self.drag_start_pos = self.cursor_pos;
+                self.pre_drag_pos = self.viewport.scene_pos;
+                Response {
+                    capture: Some(true),
+                    ..Response::default()
+                }
+            }
+            (true, false) => {
+                // A drag that ends where it started isn't worth an entry of its own.
+                if let Some(id) = self.dragged_point.take() {
+                    let label = mem::take(&mut self.drag_label);
+                    if self.drag_added_point || self.drawing().point(id) != Some(&self.drag_origin)
+                    {
+                        self.doc.commit_as(label);
+                    }
+                }
+                if mem::take(&mut self.dragging_background)
+                    && self.doc.background_transform.offset != self.drag_origin
+                {
+                    self.doc.commit_as("Move background");
+                }
+                self.snap = None;
+                Response {
+                    redraw: true,
+                    capture: Some(false),
+                    ..Response::default()
+                }
+            }
+            _ => Response::default(),
+        }
+    }
+
+    fn pointer_move(&mut self) -> Response {
+        if !self.is_dragging() {
+            return Response::default();
+        }
+
+        let xy0 = self.pre_drag_pos;
+        let dxy = self.cursor_pos - self.drag_start_pos;
+
+        let mut response = Response::default();
+        if self.dragging_background {
+            let offset = self.drag_origin + dxy.cast::<f32>() / self.viewport.scale;
+            self.doc.background_transform.offset = offset;
+            response = response.merge(Response::REDRAW);
+        } else if self.right_dragging {
+            self.viewport.scene_pos = xy0 - (dxy.cast::<f32>() / self.viewport.scale);
+            response = response.merge(Response::REDRAW);
+        }
+        if self.left_dragging {
+            if let Some(id) = self.dragged_point {
+                let new_pos = if self.keys.pressed(Key::Shift) {
+                    self.snap = None;
+                    let cursor = self.scene_pos_at(self.cursor_pos);
+                    constrain_angle(self.drag_origin, cursor, ANGLE_STEP)
+                } else {
+                    self.snap_cursor(Some(id))
+                };
+                self.move_point(id, new_pos);
+                response = response.merge(Response::DRAWING_CHANGED);
+            }
+        }
+        response
+    }
+
+    /// Moves a point, dragging along whatever the held modifiers or the node kinds say should move with it.
+    ///
+    /// - Ctrl: a node's control points move along with it. Smooth and symmetric nodes always do this.
+    /// - Alt: moving a control point mirrors the one on the other side of its node.
+    ///   Otherwise, the other control point only moves if the node is smooth or symmetric.
+    fn move_point(&mut self, id: PointId, new_pos: Point<f32>) {
+        let carry_handles = self.keys.pressed(Key::Control);
+        let mirror = self.keys.pressed(Key::Alt);
+        let drawing = self.drawing_mut();
+        let i = match drawing.point_index(id) {
+            Some(i) => i,
+            None => return,
+        };
+        let delta = new_pos - drawing[i];
+        drawing[i] = new_pos;
+
+        if carry_handles || drawing.node_kind_at(i) != NodeKind::Corner {
+            for &handle in drawing.node_handles(i).iter().flatten() {
+                drawing[handle] += delta;
+            }
+        }
+        if mirror {
+            if let Some((node, Some(opposite))) = drawing.handle_partner(i) {
+                drawing[opposite] = drawing[node] * 2.0 - new_pos;
+            }
+        } else {
+            drawing.enforce_continuity(i);
+        }
+    }
+
+    fn zoom(&mut self, factor: i32) -> Response {
+        self.viewport.zoom_steps(self.cursor_pos.cast(), factor);
+
+        if self.right_dragging {
+            self.pre_drag_pos = self.viewport.scene_pos;
+            self.drag_start_pos = self.cursor_pos;
+            if self.dragging_background {
+                self.drag_origin = self.doc.background_transform.offset;
+            }
+        }
+
+        Response::REDRAW
+    }
+
+    fn key(&mut self, key: Key, down: bool) -> Response {
+        let state = self.keys.update(key, down);
+        if !self.keys.pressed(Key::Control) || state != KeyState::Pressed {
+            return Response::default();
+        }
+        match key {
+            Key::Z if self.keys.pressed(Key::Shift) => self.redo(),
+            Key::Z => self.undo(),
+            Key::Y => self.redo(),
+            Key::C => Response::request(Request::Copy(self.copy_text())),
+            Key::V => Response::request(Request::Paste),
+            Key::H => {
+                let cursor = self.scene_pos_at(self.cursor_pos);
+                if self.keys.pressed(Key::Shift) {
+                    self.add_guide(Guide::Vertical(cursor.x))
+                } else {
+                    self.add_guide(Guide::Horizontal(cursor.y))
+                }
+            }
+            Key::Zero => self.reset_zoom(),
+            Key::One => self.zoom_to_drawing(),
+            Key::Two => self.zoom_to_background(),
+            _ => Response::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use image::RgbaImage;
+
+    use super::{Editor, InputEvent, Key, MouseButton, Request};
+    use crate::clipboard::{MemoryClipboard, PasteError};
+    use crate::drawing::{CommandKind, NodeKind};
+    use crate::point::Point;
+    use crate::project::{Colors, Project};
+    use crate::snap::{Guide, SnapSettings};
+
+    fn click(editor: &mut Editor, x: i32, y: i32) {
+        let pos = Point::new(x, y);
+        let _ = editor.handle(InputEvent::PointerDown(MouseButton::Left, pos));
+        let _ = editor.handle(InputEvent::PointerUp(MouseButton::Left, pos));
+    }
+
+    fn drag(editor: &mut Editor, from: (i32, i32), to: (i32, i32), modifier: Option<Key>) {
+        if let Some(key) = modifier {
+            let _ = editor.handle(InputEvent::KeyDown(key));
+        }
+        let (from, to) = (Point::from(from), Point::from(to));
+        let _ = editor.handle(InputEvent::PointerDown(MouseButton::Left, from));
+        let _ = editor.handle(InputEvent::PointerMove(to));
+        let _ = editor.handle(InputEvent::PointerUp(MouseButton::Left, to));
+        if let Some(key) = modifier {
+            let _ = editor.handle(InputEvent::KeyUp(key));
+        }
+    }
+
+    /// `m 0 0 b 30 0 60 0 90 0 b 120 0 150 0 180 0`, give or take some rounding in the control points.
+    fn two_beziers() -> Editor {
+        let mut editor = Editor::new();
+        let _ = editor.set_snap_settings(SnapSettings {
+            enabled: false,
+            ..SnapSettings::default()
+        });
+        editor.set_draw_mode(CommandKind::Bezier);
+        click(&mut editor, 0, 0);
+        click(&mut editor, 90, 0);
+        click(&mut editor, 180, 0);
+        editor
+    }
+
+    fn assert_near(actual: Point<f32>, expected: (f32, f32)) {
+        let d = actual - Point::from(expected);
+        assert!(d.x.abs() < 0.01 && d.y.abs() < 0.01, "{:?}", actual);
+    }
+
+    #[test]
+    fn test_draw_and_drag() {
+        let mut editor = Editor::new();
+        click(&mut editor, 10, 10);
+        click(&mut editor, 50, 10);
+        assert_eq!(editor.drawing().len(), 2);
+        assert_eq!(editor.copy_text(), "m 10.0 10.0 l 50.0 10.0");
+
+        let r = editor.handle(InputEvent::PointerDown(
+            MouseButton::Left,
+            Point::new(52, 12),
+        ));
+        assert_eq!(r.capture, Some(true));
+        assert_eq!(editor.drawing().len(), 2);
+        let _ = editor.handle(InputEvent::PointerMove(Point::new(60, 30)));
+        let r = editor.handle(InputEvent::PointerUp(MouseButton::Left, Point::new(60, 30)));
+        assert_eq!(r.capture, Some(false));
+        assert_eq!(editor.copy_text(), "m 10.0 10.0 l 60.0 30.0");
+
+        // Pressing on a point and letting go without moving it isn't an edit.
+        drag(&mut editor, (60, 30), (60, 30), None);
+        drag(&mut editor, (10, 10), (12, 12), None);
+        drag(&mut editor, (12, 12), (10, 10), None);
+        let labels: Vec<_> = editor.history().entries().map(|e| e.label).collect();
+        assert_eq!(
+            labels,
+            [
+                "Add move",
+                "Add line",
+                "Move point 1",
+                "Move point 0",
+                "Move point 0"
+            ]
+        );
+        let _ = editor.jump_to(3);
+
+        let _ = editor.handle(InputEvent::KeyDown(Key::Control));
+        let _ = editor.handle(InputEvent::KeyDown(Key::Z));
+        assert_eq!(editor.copy_text(), "m 10.0 10.0 l 50.0 10.0");
+        let _ = editor.handle(InputEvent::KeyUp(Key::Z));
+        let _ = editor.handle(InputEvent::KeyDown(Key::Y));
+        assert_eq!(editor.copy_text(), "m 10.0 10.0 l 60.0 30.0");
+
+        let r = editor.handle(InputEvent::KeyDown(Key::C));
+        assert_eq!(
+            r.request,
+            Some(Request::Copy("m 10.0 10.0 l 60.0 30.0".into()))
+        );
+    }
+
+    #[test]
+    fn test_undo_waits_for_drag() {
+        let mut editor = Editor::new();
+        click(&mut editor, 10, 10);
+        click(&mut editor, 50, 10);
+
+        // Undoing mid-drag would throw the drag away along with the entry before it.
+        let _ = editor.handle(InputEvent::PointerDown(
+            MouseButton::Left,
+            Point::new(50, 10),
+        ));
+        let _ = editor.handle(InputEvent::PointerMove(Point::new(60, 30)));
+        let _ = editor.handle(InputEvent::KeyDown(Key::Control));
+        let r = editor.handle(InputEvent::KeyDown(Key::Z));
+        assert!(!r.drawing_changed);
+        let _ = editor.handle(InputEvent::KeyUp(Key::Z));
+        let _ = editor.handle(InputEvent::KeyUp(Key::Control));
+        assert!(!editor.undo().drawing_changed);
+        assert!(!editor.jump_to(0).drawing_changed);
+        let _ = editor.handle(InputEvent::PointerUp(MouseButton::Left, Point::new(60, 30)));
+        assert_eq!(editor.copy_text(), "m 10.0 10.0 l 60.0 30.0");
+
+        let labels: Vec<_> = editor.history().entries().map(|e| e.label).collect();
+        assert_eq!(labels, ["Add move", "Add line", "Move point 1"]);
+        let _ = editor.undo();
+        assert_eq!(editor.copy_text(), "m 10.0 10.0 l 50.0 10.0");
+    }
+
+    #[test]
+    fn test_zoom_keeps_cursor_anchored() {
+        let mut editor = Editor::new();
+        let cursor = Point::new(40, 25);
+        let before = editor.scene_pos_at(cursor);
+        let _ = editor.handle(InputEvent::Wheel(3, cursor));
+        assert_eq!(editor.viewport().scale, 4.0);
+        assert_eq!(editor.scene_pos_at(cursor), before);
+
+        let _ = editor.handle(InputEvent::Wheel(-4, cursor));
+        assert_eq!(editor.viewport().scale, 0.5);
+        assert_eq!(editor.scene_pos_at(cursor), before);
+    }
+
+    #[test]
+    fn test_constrained_drag() {
+        let mut editor = two_beziers();
+        drag(&mut editor, (180, 0), (200, 2), Some(Key::Shift));
+        assert_near(editor.drawing()[6], (200.0, 0.0));
+
+        // A new curve's end is constrained around the node it starts from, not its control points.
+        drag(&mut editor, (260, 3), (300, 4), Some(Key::Shift));
+        assert_near(editor.drawing()[9], (300.0, 0.0));
+        let _ = editor.undo();
+
+        drag(&mut editor, (90, 0), (90, 10), Some(Key::Control));
+        assert_near(editor.drawing()[2], (60.0, 10.0));
+        assert_near(editor.drawing()[3], (90.0, 10.0));
+        assert_near(editor.drawing()[4], (120.0, 10.0));
+
+        drag(&mut editor, (60, 10), (60, 40), Some(Key::Alt));
+        assert_near(editor.drawing()[2], (60.0, 40.0));
+        assert_near(editor.drawing()[4], (120.0, -20.0));
+
+        // Without modifiers, nothing else moves.
+        drag(&mut editor, (120, -20), (130, -20), None);
+        assert_near(editor.drawing()[2], (60.0, 40.0));
+        assert_near(editor.drawing()[4], (130.0, -20.0));
+    }
+
+    #[test]
+    fn test_node_kinds() {
+        let mut editor = two_beziers();
+        drag(&mut editor, (60, 0), (60, 30), None);
+        drag(&mut editor, (120, 0), (120, -30), None);
+        let _ = editor.set_node_kind(NodeKind::Symmetric);
+        assert_near(editor.drawing()[2], (60.0, 30.0));
+        assert_near(editor.drawing()[4], (120.0, -30.0));
+
+        drag(&mut editor, (120, -30), (130, -10), None);
+        assert_near(editor.drawing()[2], (50.0, 10.0));
+
+        // Smooth and symmetric nodes carry their control points with them.
+        drag(&mut editor, (90, 0), (90, 10), None);
+        assert_near(editor.drawing()[2], (50.0, 20.0));
+        assert_near(editor.drawing()[4], (130.0, 0.0));
+
+        let _ = editor.auto_smooth_selection();
+        let reach = 90f32.hypot(10.0) / 3.0;
+        assert_near(editor.drawing()[2], (90.0 - reach, 10.0));
+        assert_near(editor.drawing()[4], (90.0 + reach, 10.0));
+
+        let _ = editor.handle(InputEvent::KeyDown(Key::Control));
+        let _ = editor.handle(InputEvent::KeyDown(Key::Z));
+        assert_near(editor.drawing()[2], (50.0, 20.0));
+
+        let labels: Vec<_> = editor.history().entries().map(|e| e.label).collect();
+        assert_eq!(
+            labels,
+            [
+                "Add move",
+                "Add bezier",
+                "Add bezier",
+                "Move point 2",
+                "Move point 4",
+                "Make symmetric",
+                "Move point 4",
+                "Move point 3",
+                "Auto-smooth",
+            ]
+        );
+        assert_eq!(editor.history().redo_label(), Some("Auto-smooth"));
+        let _ = editor.jump_to(3);
+        assert_eq!(editor.drawing().len(), 3);
+        assert_near(editor.drawing()[2], (60.0, 0.0));
+    }
+
+    #[test]
+    fn test_background_transform() {
+        let mut editor = Editor::new();
+        let _ = editor.set_background(Some(Rc::new(RgbaImage::new(40, 30))));
+
+        // Shift+right-drag moves the background instead of the view.
+        let _ = editor.handle(InputEvent::KeyDown(Key::Shift));
+        let _ = editor.handle(InputEvent::PointerDown(
+            MouseButton::Right,
+            Point::new(10, 10),
+        ));
+        let _ = editor.handle(InputEvent::PointerMove(Point::new(15, 12)));
+        // Replacing the background mid-drag would commit half of the move along with it.
+        let r = editor.set_background(Some(Rc::new(RgbaImage::new(4, 4))));
+        assert!(!r.background_changed);
+        let mut transform = editor.background_transform();
+        transform.opacity = 0.5;
+        assert!(!editor.set_background_transform(transform).redraw);
+        // Undoing the paste would leave the drag moving a background that isn't there.
+        let _ = editor.handle(InputEvent::KeyUp(Key::Shift));
+        let _ = editor.handle(InputEvent::KeyDown(Key::Control));
+        let _ = editor.handle(InputEvent::KeyDown(Key::Z));
+        let _ = editor.handle(InputEvent::KeyUp(Key::Z));
+        let _ = editor.handle(InputEvent::KeyUp(Key::Control));
+        let _ = editor.handle(InputEvent::KeyDown(Key::Shift));
+        assert!(editor.background().is_some());
+        let _ = editor.handle(InputEvent::PointerUp(
+            MouseButton::Right,
+            Point::new(15, 12),
+        ));
+        assert_eq!(editor.background_transform().offset, Point::new(5.0, 2.0));
+        assert_eq!(editor.viewport().scene_pos, Point::new(0.0, 0.0));
+        assert_eq!(editor.history().undo_label(), Some("Move background"));
+        assert_eq!(editor.background().map(|image| image.width()), Some(40));
+        let labels: Vec<_> = editor.history().entries().map(|e| e.label).collect();
+        assert_eq!(labels, ["Paste background", "Move background"]);
+        // A drag that leaves it where it was isn't an edit either.
+        let _ = editor.handle(InputEvent::PointerDown(
+            MouseButton::Right,
+            Point::new(15, 12),
+        ));
+        let _ = editor.handle(InputEvent::PointerMove(Point::new(15, 12)));
+        let _ = editor.handle(InputEvent::PointerUp(
+            MouseButton::Right,
+            Point::new(15, 12),
+        ));
+        assert_eq!(editor.history().len(), 2);
+
+        let mut transform = editor.background_transform();
+        transform.locked = true;
+        let _ = editor.set_background_transform(transform);
+        let _ = editor.handle(InputEvent::PointerDown(
+            MouseButton::Right,
+            Point::new(10, 10),
+        ));
+        let _ = editor.handle(InputEvent::PointerMove(Point::new(20, 20)));
+        let _ = editor.handle(InputEvent::PointerUp(
+            MouseButton::Right,
+            Point::new(20, 20),
+        ));
+        let _ = editor.handle(InputEvent::KeyUp(Key::Shift));
+        assert_eq!(editor.background_transform().offset, Point::new(5.0, 2.0));
+        transform.scale = 2.0;
+        assert!(!editor.set_background_transform(transform).redraw);
+        transform.locked = false;
+        transform.scale = f32::NAN;
+        assert!(!editor.set_background_transform(transform).redraw);
+        transform.locked = true;
+        transform.scale = 1.0;
+        transform.opacity = 0.5;
+        assert!(editor.set_background_transform(transform).redraw);
+        assert_eq!(
+            editor.history().undo_label(),
+            Some("Change background opacity")
+        );
+        // Every step of a slide makes up one edit, and the next slide is another.
+        let len = editor.history().len();
+        let _ = editor.slide_background_opacity(0.3);
+        let _ = editor.slide_background_opacity(0.2);
+        assert_eq!(editor.history().len(), len + 1);
+        editor.end_opacity_slide();
+        let _ = editor.slide_background_opacity(0.1);
+        assert_eq!(editor.history().len(), len + 2);
+        // Going back through the history ends the slide too.
+        let _ = editor.undo();
+        let _ = editor.slide_background_opacity(0.4);
+        editor.end_opacity_slide();
+        assert_eq!(editor.history().len(), len + 3);
+
+        let _ = editor.undo();
+        assert_eq!(editor.background_transform().opacity, 0.2);
+        let _ = editor.undo();
+        assert_eq!(editor.background_transform().opacity, 0.5);
+        let _ = editor.undo();
+        assert_eq!(editor.background_transform().opacity, 1.0);
+        let _ = editor.undo();
+        assert!(!editor.background_transform().locked);
+        let _ = editor.undo();
+        assert_eq!(editor.background_transform().offset, Point::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn test_clear_and_background_are_undoable() {
+        let mut editor = Editor::new();
+        click(&mut editor, 10, 10);
+        let r = editor.set_background(Some(Rc::new(RgbaImage::new(40, 30))));
+        assert!(r.background_changed);
+        let r = editor.clear();
+        assert!(r.drawing_changed);
+        assert_eq!(editor.history().undo_label(), Some("Clear"));
+
+        let r = editor.undo();
+        assert!(!r.background_changed);
+        assert_eq!(editor.drawing().len(), 1);
+        let r = editor.undo();
+        assert!(r.background_changed);
+        assert!(editor.background().is_none());
+        let r = editor.redo();
+        assert!(r.background_changed);
+        assert_eq!(editor.background().map(|img| img.width()), Some(40));
+    }
+
+    #[test]
+    fn test_project_round_trip() {
+        let mut editor = Editor::new();
+        click(&mut editor, 10, 10);
+        click(&mut editor, 20, 10);
+        let _ = editor.add_guide(Guide::Vertical(20.0));
+        let colors = Colors {
+            shape_alpha: 0,
+            ..Colors::default()
+        };
+        let _ = editor.set_colors(colors);
+        let data = editor.to_project().save();
+
+        let mut other = Editor::new();
+        let _ = other.resize(Point::new(300.0, 200.0));
+        let r = other.load_project(Project::load(&data).unwrap());
+        assert!(r.drawing_changed && r.background_changed);
+        assert_eq!(other.drawing(), editor.drawing());
+        assert_eq!(other.guides(), editor.guides());
+        assert_eq!(other.colors(), colors);
+        assert_eq!(other.viewport().screen_dims, Point::new(300.0, 200.0));
+        assert_eq!(other.history().undo_label(), Some("Add line"));
+    }
+
+    #[test]
+    fn test_layers() {
+        let mut editor = Editor::new();
+        click(&mut editor, 10, 10);
+        let r = editor.add_layer();
+        assert!(r.drawing_changed);
+        assert_eq!(editor.active_layer(), 1);
+        assert!(editor.drawing().is_empty());
+        click(&mut editor, 20, 20);
+        click(&mut editor, 30, 20);
+
+        let mut settings = editor.layers()[1].settings.clone();
+        settings.color = [255, 0, 16];
+        settings.alpha = 0x80;
+        settings.ass_layer = 2;
+        let _ = editor.set_layer_settings(settings.clone());
+        assert_eq!(editor.history().undo_label(), Some("Change layer"));
+        settings.locked = true;
+        let _ = editor.set_layer_settings(settings);
+        assert_eq!(editor.history().undo_label(), Some("Lock layer"));
+        click(&mut editor, 40, 40);
+        assert_eq!(editor.drawing().len(), 2);
+
+        let export = editor.export_text();
+        let lines: Vec<_> = export.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r"Dialogue: 0,0:00:00.00,0:00:05.00,Default,,0,0,0,,{\an7\pos(0,0)\bord0\shad0\1c&H7F7F7F&\1a&H00&\p1}m 10.0 10.0",
+                r"Dialogue: 2,0:00:00.00,0:00:05.00,Default,,0,0,0,,{\an7\pos(0,0)\bord0\shad0\1c&H1000FF&\1a&H80&\p1}m 20.0 20.0 l 30.0 20.0",
+            ]
+        );
+
+        let _ = editor.move_layer(-1);
+        assert_eq!(editor.active_layer(), 0);
+        assert_eq!(editor.copy_text(), "m 20.0 20.0 l 30.0 20.0");
+        let _ = editor.remove_layer();
+        assert_eq!(editor.layers().len(), 1);
+        assert_eq!(editor.copy_text(), "m 10.0 10.0");
+        let _ = editor.remove_layer();
+        assert_eq!(editor.layers().len(), 1);
+
+        // Undoing brings the layer back, but the one that was being edited stays active.
+        let _ = editor.undo();
+        assert_eq!(editor.layers().len(), 2);
+        assert_eq!(editor.copy_text(), "m 10.0 10.0");
+        let _ = editor.jump_to(1);
+        assert_eq!(editor.layers().len(), 1);
+        assert_eq!(editor.copy_text(), "m 10.0 10.0");
+
+        // Nothing about the layers changes halfway through dragging a point.
+        let len = editor.history().len();
+        let _ = editor.handle(InputEvent::PointerDown(
+            MouseButton::Left,
+            Point::new(10, 10),
+        ));
+        let _ = editor.handle(InputEvent::PointerMove(Point::new(15, 10)));
+        assert!(!editor.add_layer().drawing_changed);
+        assert!(!editor.clear().drawing_changed);
+        let mut settings = editor.layers()[0].settings.clone();
+        settings.visible = false;
+        assert!(!editor.set_layer_settings(settings).drawing_changed);
+        assert_eq!(editor.history().len(), len);
+        let _ = editor.handle(InputEvent::PointerUp(MouseButton::Left, Point::new(15, 10)));
+        assert_eq!(editor.history().len(), len + 1);
+        assert_eq!(editor.copy_text(), "m 15.0 10.0");
+    }
+
+    #[test]
+    fn test_paste() {
+        let mut editor = Editor::new();
+        let mut clipboard = MemoryClipboard::default();
+        assert!(matches!(
+            editor.paste(&mut clipboard),
+            Err(PasteError::Empty)
+        ));
+
+        clipboard.text = Some("m 0 0 l 10 0".into());
+        assert!(editor.paste(&mut clipboard).unwrap().drawing_changed);
+        clipboard.text = Some(
+            "Dialogue: 0,0:00:00.00,0:00:05.00,Default,,0,0,0,,{\\p2}l 20 20\n\
+             Dialogue: 0,0:00:00.00,0:00:05.00,Default,,0,0,0,,{\\p1}m 5 5"
+                .into(),
+        );
+        let _ = editor.paste(&mut clipboard).unwrap();
+        assert_eq!(
+            editor.copy_text(),
+            "m 0.0 0.0 l 10.0 0.0 m 0.0 0.0 l 10.0 10.0 m 5.0 5.0"
+        );
+        assert_eq!(editor.history().undo_label(), Some("Paste drawing"));
+        let _ = editor.undo();
+        assert_eq!(editor.copy_text(), "m 0.0 0.0 l 10.0 0.0");
+
+        clipboard.text = Some(r"{\clip(0,0,4,4)}".into());
+        let _ = editor.paste(&mut clipboard).unwrap();
+        assert_eq!(editor.layers().len(), 2);
+        assert_eq!(editor.active_layer(), 1);
+        assert_eq!(editor.copy_text(), "m 0.0 0.0 l 4.0 0.0 4.0 4.0 0.0 4.0");
+
+        // Pasting in the middle of a drag would commit the drag as part of the paste.
+        let _ = editor.handle(InputEvent::PointerDown(MouseButton::Left, Point::new(4, 4)));
+        let response = editor.paste(&mut clipboard).unwrap();
+        assert!(!response.drawing_changed);
+        let _ = editor.handle(InputEvent::PointerUp(MouseButton::Left, Point::new(4, 4)));
+        assert_eq!(editor.layers().len(), 2);
+
+        // A clip goes on a layer of its own, so the active one being locked doesn't matter.
+        let mut settings = editor.layers()[1].settings.clone();
+        settings.locked = true;
+        let _ = editor.set_layer_settings(settings);
+        assert!(editor.paste(&mut clipboard).unwrap().drawing_changed);
+        assert_eq!(editor.layers().len(), 3);
+
+        // Text that isn't a drawing only matters if there's no image.
+        clipboard.text = Some("https://example.com/reference.png".into());
+        assert!(matches!(
+            editor.paste(&mut clipboard),
+            Err(PasteError::Parse(_))
+        ));
+        clipboard.image = Some(RgbaImage::new(2, 2));
+        assert!(editor.paste(&mut clipboard).unwrap().background_changed);
+        assert_eq!(editor.background().map(|image| image.width()), Some(2));
+    }
+}
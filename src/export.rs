@@ -0,0 +1,295 @@
+//! Saving what the drawing looks like as an image, for thumbnails, reviews, or masks for other tools.
+
+use std::path::Path;
+
+use image::{ImageFormat, Rgba, RgbaImage};
+use thiserror::Error;
+
+use crate::document::Layer;
+use crate::point::{Point, Rect};
+use crate::raster::{self, part_colors, Coverage, Scene, BACKGROUND_COLOR};
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("there's nothing to export")]
+    Empty,
+    #[error("the image would be too large to export")]
+    TooLarge,
+    #[error("couldn't write the image: {0}")]
+    Image(#[from] image::ImageError),
+}
+
+/// What ends up in an exported image.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ExportContent {
+    /// The layers' shapes in their own colors and alphas, on a transparent background.
+    #[default]
+    Shapes,
+    /// White wherever there's a shape, with how much it covers as the alpha.
+    Mask,
+    /// What the canvas shows, background and all, but without nodes or overlays.
+    Preview,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ExportSettings {
+    pub content: ExportContent,
+    /// Image pixels per scene pixel.
+    pub scale: f32,
+    /// Empty space around the shapes, in image pixels.
+    pub padding: u32,
+}
+
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            content: ExportContent::default(),
+            scale: 1.0,
+            padding: 0,
+        }
+    }
+}
+
+/// A copy of the layer that's `scale` times as large, effects included.
+fn scale_layer(layer: &Layer, scale: f32) -> Layer {
+    let mut layer = layer.clone();
+    for p in layer.drawing.points_mut() {
+        *p *= scale;
+    }
+    let effects = &mut layer.settings.effects;
+    effects.border *= scale;
+    effects.shadow *= scale;
+    effects.blur *= scale;
+    layer
+}
+
+/// Blends a color over a pixel that may itself be transparent.
+fn blend_over(px: &mut Rgba<u8>, color: [u8; 3], alpha: f32) {
+    let dst_alpha = px[3] as f32 / 255.0;
+    let out_alpha = alpha + dst_alpha * (1.0 - alpha);
+    if out_alpha <= 0.0 {
+        return;
+    }
+    for (dst, &src) in px.0.iter_mut().zip(&color) {
+        let value = (src as f32 * alpha + *dst as f32 * dst_alpha * (1.0 - alpha)) / out_alpha;
+        *dst = value.round() as u8;
+    }
+    px[3] = (out_alpha * 255.0).round() as u8;
+}
+
+/// Draws a coverage buffer into the image, whose top-left corner is at `origin` in scaled scene coordinates.
+fn draw_coverage(
+    img: &mut RgbaImage,
+    origin: Point<f32>,
+    coverage: &Coverage,
+    color: [u8; 3],
+    opacity: f32,
+) {
+    let offset = (coverage.pos - origin).map(|v| v.round() as i64);
+    for (i, &value) in coverage.pixels.iter().enumerate() {
+        if value == 0 {
+            continue;
+        }
+        let x = offset.x + (i % coverage.width) as i64;
+        let y = offset.y + (i / coverage.width) as i64;
+        if x < 0 || y < 0 || x >= img.width() as i64 || y >= img.height() as i64 {
+            continue;
+        }
+        let alpha = value as f32 / 255.0 * opacity;
+        blend_over(img.get_pixel_mut(x as u32, y as u32), color, alpha);
+    }
+}
+
+/// Renders the scene's visible layers into an image just large enough to hold them, plus padding.
+///
+/// When exporting the preview, the background is included in the image as well.
+pub fn export_image(
+    scene: &Scene<'_>,
+    settings: &ExportSettings,
+) -> Result<RgbaImage, ExportError> {
+    let scale = settings.scale;
+    let padding = settings.padding as f32;
+    let layers: Vec<_> = scene
+        .layers
+        .iter()
+        .filter(|layer| layer.settings.visible)
+        .map(|layer| scale_layer(layer, scale))
+        .collect();
+
+    let background = match settings.content {
+        ExportContent::Preview => scene.background,
+        _ => None,
+    };
+    let mut background_corners = Vec::new();
+    if let Some(background) = background {
+        let (width, height) = background.dimensions();
+        let transform = &scene.background_transform;
+        for &corner in &[(0, 0), (width, 0), (0, height), (width, height)] {
+            background_corners.push(transform.to_scene(Point::from(corner).cast()) * scale);
+        }
+    }
+
+    // Rasterizing a shape that's too large would run out of memory before the image could be checked.
+    let extents = layers.iter().filter_map(raster::layer_extent);
+    let reach = extents.flat_map(|rect| [rect.min, rect.max]);
+    if let Some(reach) = Rect::from_points(reach.chain(background_corners.iter().copied())) {
+        let size = reach.size() + padding * 2.0;
+        if !raster::fits(size.x, size.y) {
+            return Err(ExportError::TooLarge);
+        }
+    }
+
+    let shapes: Vec<_> = layers
+        .iter()
+        .filter_map(|layer| {
+            let shape = raster::rasterize_layer(layer, scene.mode)?;
+            Some((shape, &layer.settings))
+        })
+        .collect();
+
+    let mut corners = background_corners;
+    for (shape, _) in &shapes {
+        for coverage in shape.parts().iter().flatten() {
+            corners.push(coverage.pos);
+            corners.push(coverage.pos + Point::new(coverage.width, coverage.height).cast());
+        }
+    }
+    let bounds = Rect::from_points(corners).ok_or(ExportError::Empty)?;
+
+    let origin = bounds.min.map(f32::floor) - padding;
+    let size = bounds.max.map(f32::ceil) + padding - origin;
+    if !raster::fits(size.x, size.y) {
+        return Err(ExportError::TooLarge);
+    }
+    let size = size.map(|v| v as u32);
+    if size.x == 0 || size.y == 0 {
+        return Err(ExportError::Empty);
+    }
+    let mut img = RgbaImage::new(size.x, size.y);
+
+    if settings.content == ExportContent::Preview {
+        let [r, g, b] = BACKGROUND_COLOR;
+        for px in img.pixels_mut() {
+            *px = Rgba([r, g, b, 255]);
+        }
+    }
+    if let Some(background) = background {
+        for (x, y, px) in img.enumerate_pixels_mut() {
+            let p = (origin + Point::new(x as f32 + 0.5, y as f32 + 0.5)) / scale;
+            raster::blend_background(px, background, &scene.background_transform, p);
+        }
+    }
+
+    for (shape, layer_settings) in &shapes {
+        for (part, &(color, alpha)) in shape.parts().iter().zip(&part_colors(layer_settings)) {
+            let coverage = match part {
+                Some(coverage) => coverage,
+                None => continue,
+            };
+            let (color, opacity) = match settings.content {
+                ExportContent::Shapes => (color, (255 - alpha) as f32 / 255.0),
+                ExportContent::Mask => ([255; 3], 1.0),
+                ExportContent::Preview => {
+                    let opacity = raster::shape_opacity(&scene.colors, alpha);
+                    (color, opacity as f32 / 100.0)
+                }
+            };
+            draw_coverage(&mut img, origin, coverage, color, opacity);
+        }
+    }
+
+    Ok(img)
+}
+
+/// Renders the scene like `export_image`, and saves it as a PNG file.
+pub fn export_png(
+    scene: &Scene<'_>,
+    settings: &ExportSettings,
+    path: &Path,
+) -> Result<(), ExportError> {
+    let img = export_image(scene, settings)?;
+    img.save_with_format(path, ImageFormat::Png)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use image::{Rgba, RgbaImage};
+
+    use super::{export_image, export_png, ExportContent, ExportError, ExportSettings};
+    use crate::document::{Document, LayerSettings};
+    use crate::raster::tests::{scene, square};
+
+    fn sample_document() -> Document {
+        let settings = LayerSettings {
+            color: [255, 0, 0],
+            alpha: 0x80,
+            ..LayerSettings::default()
+        };
+        Document::from_drawing(square(1.0, 1.0, 2.0), settings)
+    }
+
+    #[test]
+    fn test_export_shapes() {
+        let doc = sample_document();
+        let settings = ExportSettings {
+            scale: 2.0,
+            padding: 1,
+            ..ExportSettings::default()
+        };
+        let img = export_image(&scene(doc.layers(), None), &settings).unwrap();
+        assert_eq!(img.dimensions(), (6, 6));
+        assert_eq!(*img.get_pixel(0, 0), Rgba([0, 0, 0, 0]));
+        assert_eq!(*img.get_pixel(1, 1), Rgba([255, 0, 0, 127]));
+        assert_eq!(*img.get_pixel(4, 4), Rgba([255, 0, 0, 127]));
+        assert_eq!(*img.get_pixel(5, 4), Rgba([0, 0, 0, 0]));
+
+        let mask = ExportSettings {
+            content: ExportContent::Mask,
+            ..settings
+        };
+        let img = export_image(&scene(doc.layers(), None), &mask).unwrap();
+        assert_eq!(*img.get_pixel(2, 3), Rgba([255, 255, 255, 255]));
+        assert_eq!(*img.get_pixel(0, 3), Rgba([0, 0, 0, 0]));
+
+        let empty = Document::default();
+        let err = export_image(&scene(empty.layers(), None), &settings).unwrap_err();
+        assert!(matches!(err, ExportError::Empty));
+
+        let huge = Document::from_drawing(square(0.0, 0.0, 100000.0), LayerSettings::default());
+        let err = export_image(&scene(huge.layers(), None), &settings).unwrap_err();
+        assert!(matches!(err, ExportError::TooLarge));
+        let far = ExportSettings {
+            scale: 1e6,
+            ..settings
+        };
+        let err = export_image(&scene(doc.layers(), None), &far).unwrap_err();
+        assert!(matches!(err, ExportError::TooLarge));
+    }
+
+    #[test]
+    fn test_export_preview() {
+        let doc = sample_document();
+        let background = RgbaImage::from_pixel(8, 4, Rgba([0, 0, 200, 255]));
+        let settings = ExportSettings {
+            content: ExportContent::Preview,
+            ..ExportSettings::default()
+        };
+        let img = export_image(&scene(doc.layers(), Some(&background)), &settings).unwrap();
+        assert_eq!(img.dimensions(), (8, 4));
+        assert_eq!(*img.get_pixel(0, 0), Rgba([0, 0, 200, 255]));
+        // Half the preview opacity, with the layer's own alpha on top.
+        let px = img.get_pixel(1, 1);
+        assert_eq!(px[3], 255);
+        assert!(px[0] > 0 && px[0] < 255);
+
+        let path = env::temp_dir().join(format!("assdraw-test-{}-export.png", process::id()));
+        export_png(&scene(doc.layers(), Some(&background)), &settings, &path).unwrap();
+        let loaded = image::open(&path).unwrap().to_rgba8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, img);
+    }
+}
@@ -3,11 +3,12 @@
 precision mediump float;
 
 uniform sampler2DRect u_Texture;
+uniform float u_Opacity;
 
 in vec2 v_Position;
 out vec4 outColor;
  
 void main() {
 	vec4 c = texture(u_Texture, v_Position);
-	outColor = c;
+	outColor = vec4(c.rgb, c.a * u_Opacity);
 }
\ No newline at end of file
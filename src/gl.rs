@@ -1,21 +1,22 @@
-use native_windows_gui as nwg;
+use std::ffi::c_void;
 
 #[rustfmt::skip]
 use glutin::{
-    ContextBuilder, GlRequest, GlProfile, PossiblyCurrent, RawContext, Api,
-    dpi::PhysicalSize,
-    platform::windows::RawContextExt,
+    ContextBuilder, GlRequest, GlProfile, NotCurrent, Api,
 };
-use ab_glyph_rasterizer::Rasterizer;
 use cstr::cstr;
-use image::ImageDecoder;
+use image::RgbaImage;
 
-use crate::nwg_util::SaneBuilder;
+use crate::document::{BackgroundTransform, Layer, LayerSettings};
 use crate::point::Point;
-use crate::undo::UndoStack;
+use crate::project::Colors;
+use crate::raster::{
+    self, part_colors, Coverage, RasterMode, GRID_COLOR, GUIDE_COLOR, HINT_COLOR, POINT_SIZE,
+};
+use crate::snap::Overlay;
+use crate::viewport::Viewport;
 
-use std::cell::{Cell, RefCell, RefMut};
-use std::convert::TryInto;
+use std::cell::{Cell, RefCell};
 
 pub mod abstraction;
 use abstraction::{
@@ -28,23 +29,34 @@ use abstraction::{
 };
 
 mod get;
-
-type Ctx = RawContext<PossiblyCurrent>;
-
-use gl::types::{GLfloat, GLint};
-
-#[derive(Default, Copy, Clone, Debug)]
-pub struct Dimensions {
-    pub screen_dims: Point<f32>,
-    pub scene_pos: Point<f32>,
-    pub scale: GLfloat,
+#[cfg(all(windows, not(feature = "winit")))]
+pub mod win32;
+#[cfg(feature = "winit")]
+mod windowed;
+
+use gl::types::GLint;
+
+use crate::drawing::Drawing;
+
+/// What the canvas draws on, with a GL context that's current on this thread.
+pub trait Surface {
+    fn get_proc_address(&self, name: &str) -> *const c_void;
+    /// How large it is, in physical pixels.
+    fn size(&self) -> (u32, u32);
+    /// Makes the context's buffers match a new size.
+    fn resize(&self, width: u32, height: u32);
+    fn swap_buffers(&self);
 }
 
-use crate::drawing::{Drawing, Segment};
+/// A context builder for what the canvas needs: core OpenGL 3.3.
+pub fn context_builder<'a>() -> ContextBuilder<'a, NotCurrent> {
+    ContextBuilder::new()
+        .with_gl(GlRequest::Specific(Api::OpenGl, (3, 3)))
+        .with_gl_profile(GlProfile::Core)
+}
 
-pub struct OpenGlCanvas {
-    ctx: Ctx,
-    canvas: nwg::ExternCanvas,
+pub struct OpenGlCanvas<S: Surface> {
+    surface: S,
 
     img_prgm: Program,
     draw_prgm: Program,
@@ -53,89 +65,181 @@ pub struct OpenGlCanvas {
     img_vb: Buffer,
     points_vb: Buffer,
     lines_vb: Buffer,
-    shape_vb: Buffer,
+    overlay_vb: Buffer,
 
     img_vao: VertexArray,
     points_vao: VertexArray,
     lines_vao: VertexArray,
-    shape_vao: VertexArray,
+    overlay_vao: VertexArray,
 
     img_tex: Texture,
-    shape_tex: Texture,
+    background_transform: Cell<BackgroundTransform>,
 
     drawing: RefCell<DrawingData>,
+    /// One for each layer, from bottom to top.
+    shapes: RefCell<Vec<Shape>>,
+    /// How many grid, guide, and hint lines are in `overlay_vb`, in that order.
+    overlay_lines: Cell<[usize; 3]>,
+
+    colors: Cell<Colors>,
+}
 
-    dimensions: Cell<Dimensions>,
-    drawing_pos: Cell<Point<f32>>,
+/// A layer's shadow, border and fill, each rasterized into a texture.
+struct Shape {
+    /// From the bottom up.
+    parts: [Part; 3],
+    /// What the textures were rasterized from, so that layers that didn't change aren't rasterized again.
+    drawing: Drawing<Point<f32>>,
+    mode: RasterMode,
+    settings: LayerSettings,
+}
 
-    drawing_color: Cell<[u8; 3]>,
-    shape_color: Cell<[u8; 3]>,
-    shape_alpha: Cell<u8>,
+/// One coverage buffer, uploaded as a texture.
+struct Part {
+    vb: Buffer,
+    vao: VertexArray,
+    tex: Texture,
+    /// Where the texture's top-left corner is in the scene.
+    pos: Point<f32>,
 }
 
+#[derive(Default)]
 struct DrawingData {
-    pixels: Vec<u8>,
-    drawing: UndoStack<Drawing<Point<f32>>>,
+    n_points: usize,
     n_lines: usize,
-    rasterizer: Rasterizer,
 }
 
-impl Default for DrawingData {
-    fn default() -> Self {
+impl Shape {
+    fn new() -> Self {
         Self {
-            pixels: Vec::new(),
-            drawing: UndoStack::new(Drawing::new()),
-            rasterizer: Rasterizer::new(0, 0),
-            n_lines: 0,
+            parts: [Part::new(), Part::new(), Part::new()],
+            drawing: Drawing::new(),
+            mode: RasterMode::default(),
+            settings: LayerSettings::default(),
         }
     }
-}
 
-fn make_extern_canvas<W: Into<nwg::ControlHandle>>(parent: W) -> nwg::ExternCanvas {
-    nwg::ExternCanvas::builder()
-        .parent(Some(parent.into()))
-        .position((100, 0))
-        .construct()
-        .expect("Failed to build nwg::ExternCanvas")
+    /// Whether the textures need rasterizing again to show `layer`.
+    fn is_stale(&self, layer: &Layer, mode: RasterMode) -> bool {
+        let settings = &layer.settings;
+        self.drawing != layer.drawing
+            || self.mode != mode
+            || self.settings.fill_rule != settings.fill_rule
+            || self.settings.effects != settings.effects
+    }
+
+    fn rasterize(&mut self, layer: &Layer, mode: RasterMode) {
+        self.drawing = layer.drawing.clone();
+        self.mode = mode;
+
+        let shape = raster::rasterize_layer(layer, mode);
+        let coverages = match &shape {
+            Some(shape) => shape.parts(),
+            None => [None; 3],
+        };
+        for (part, coverage) in self.parts.iter_mut().zip(&coverages) {
+            match coverage {
+                Some(coverage) => part.upload(coverage),
+                None => part.clear(),
+            }
+        }
+    }
 }
 
-#[allow(dead_code)]
-impl OpenGlCanvas {
-    pub fn handle(&self) -> &nwg::ControlHandle {
-        &self.canvas.handle
+impl Part {
+    fn new() -> Self {
+        const VEC2_STRIDE: i32 = (std::mem::size_of::<f32>() * 2) as i32;
+
+        unsafe {
+            let vb = Buffer::new();
+            vb.bind(BufferTarget::Array);
+            Buffer::buffer_data(BufferTarget::Array, &[0_f32; 8], Usage::StaticDraw).unwrap();
+
+            let vao = VertexArray::new();
+            vao.bind();
+            gl::EnableVertexAttribArray(0);
+            gl::VertexAttribPointer(0, 2, gl::FLOAT, 0, VEC2_STRIDE, std::ptr::null());
+
+            let tex = Texture::new();
+            tex.bind(TextureTarget::Rectangle);
+
+            Self {
+                vb,
+                vao,
+                tex,
+                pos: Point::default(),
+            }
+        }
     }
-    pub fn nwg_canvas(&self) -> &nwg::ExternCanvas {
-        &self.canvas
+
+    fn upload(&mut self, coverage: &Coverage) {
+        self.pos = coverage.pos;
+
+        unsafe {
+            let (width, height) = (coverage.width as f32, coverage.height as f32);
+            #[rustfmt::skip]
+            let vertex_data = &[
+                0.0, 0.0,
+                width, 0.0,
+                0.0, height,
+                width, height,
+            ];
+
+            self.tex.bind(TextureTarget::Rectangle);
+            gl::TexImage2D(
+                gl::TEXTURE_RECTANGLE,
+                0,
+                gl::R8 as _,
+                coverage.width as _,
+                coverage.height as _,
+                0,
+                gl::RED,
+                gl::UNSIGNED_BYTE,
+                coverage.pixels.as_ptr().cast(),
+            );
+
+            self.vb.bind(BufferTarget::Array);
+            Buffer::buffer_data(BufferTarget::Array, vertex_data, Usage::StaticDraw).unwrap();
+        }
     }
 
-    pub fn new<W: Into<nwg::ControlHandle>>(parent: W) -> Self {
-        use std::ffi::c_void;
-        const NULL: *const c_void = std::ptr::null();
+    fn clear(&self) {
+        unsafe {
+            let vertex_data = [0.0; 8];
+            self.vb.bind(BufferTarget::Array);
+            Buffer::buffer_data(BufferTarget::Array, &vertex_data, Usage::StaticDraw).unwrap();
+        }
+    }
+}
 
-        let canvas = make_extern_canvas(parent);
+#[allow(dead_code)]
+impl<S: Surface> OpenGlCanvas<S> {
+    #[inline]
+    pub fn surface(&self) -> &S {
+        &self.surface
+    }
 
-        let ctx = unsafe {
-            let ctx = ContextBuilder::new()
-                .with_gl(GlRequest::Specific(Api::OpenGl, (3, 3)))
-                .with_gl_profile(GlProfile::Core)
-                .build_raw_context(canvas.handle.hwnd().unwrap() as *mut c_void)
-                .expect("Failed to build opengl context")
-                .make_current()
-                .expect("Failed to set opengl context as current");
+    pub fn new(surface: S) -> Self {
+        const NULL: *const c_void = std::ptr::null();
 
-            gl::load_with(|s| ctx.get_proc_address(s) as *const c_void);
+        unsafe {
+            gl::load_with(|s| surface.get_proc_address(s));
             gl::ClearColor(0.0, 0.0, 0.0, 1.0);
-            ctx
-        };
+        }
 
         let (img_prgm, draw_prgm, shape_prgm) = {
             let vs = Shader::build(ShaderType::Vertex, include_str!("vs.glsl"));
+            let img_vs = Shader::build(ShaderType::Vertex, include_str!("bg.glsl"));
             let img_fs = Shader::build(ShaderType::Fragment, include_str!("fs.glsl"));
             let draw_fs = Shader::build(ShaderType::Fragment, include_str!("blue.glsl"));
             let shape_fs = Shader::build(ShaderType::Fragment, include_str!("draw.glsl"));
 
             let build = |fs| Program::build(&vs, fs);
-            (build(&img_fs), build(&draw_fs), build(&shape_fs))
+            (
+                Program::build(&img_vs, &img_fs),
+                build(&draw_fs),
+                build(&shape_fs),
+            )
         };
 
         let drawing = RefCell::new(DrawingData::default());
@@ -166,23 +270,19 @@ impl OpenGlCanvas {
             (vb, vao)
         };
 
-        let (img_vb, img_vao, img_tex) = unsafe {
+        let (overlay_vb, overlay_vao) = unsafe {
             let vb = Buffer::new();
             vb.bind(BufferTarget::Array);
-            Buffer::buffer_data(BufferTarget::Array, &[0_f32; 8], Usage::StaticDraw).unwrap();
 
             let vao = VertexArray::new();
             vao.bind();
             gl::EnableVertexAttribArray(0);
             gl::VertexAttribPointer(0, 2, gl::FLOAT, 0, VEC2_STRIDE, NULL);
 
-            let tex = Texture::new();
-            tex.bind(TextureTarget::Rectangle);
-
-            (vb, vao, tex)
+            (vb, vao)
         };
 
-        let (shape_vb, shape_vao, shape_tex) = unsafe {
+        let (img_vb, img_vao, img_tex) = unsafe {
             let vb = Buffer::new();
             vb.bind(BufferTarget::Array);
             Buffer::buffer_data(BufferTarget::Array, &[0_f32; 8], Usage::StaticDraw).unwrap();
@@ -195,23 +295,20 @@ impl OpenGlCanvas {
             let tex = Texture::new();
             tex.bind(TextureTarget::Rectangle);
 
-            gl::PointSize(5.0);
-            gl::Enable(gl::BLEND);
-            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
-            check_errors().unwrap();
-
             (vb, vao, tex)
         };
 
-        let dimensions = Dimensions {
-            screen_dims: [100.0, 100.0].into(),
-            scene_pos: [0.0, 0.0].into(),
-            scale: 1.0,
-        };
+        unsafe {
+            gl::PointSize(POINT_SIZE);
+            // Coverage rows are tightly packed, rather than padded to four bytes.
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            gl::Enable(gl::BLEND);
+            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+            check_errors().unwrap();
+        }
 
         Self {
-            ctx,
-            canvas,
+            surface,
 
             img_prgm,
             draw_prgm,
@@ -220,28 +317,25 @@ impl OpenGlCanvas {
             img_vb,
             points_vb,
             lines_vb,
-            shape_vb,
+            overlay_vb,
 
             img_vao,
             points_vao,
             lines_vao,
-            shape_vao,
+            overlay_vao,
 
             img_tex,
-            shape_tex,
+            background_transform: Cell::new(BackgroundTransform::default()),
 
             drawing,
+            shapes: RefCell::new(Vec::new()),
+            overlay_lines: Cell::new([0; 3]),
 
-            dimensions: Cell::new(dimensions),
-            drawing_pos: Cell::new(Point::default()),
-
-            drawing_color: Cell::new([0, 0, 255]),
-            shape_color: Cell::new([127, 127, 127]),
-            shape_alpha: Cell::new(50),
+            colors: Cell::new(Colors::default()),
         }
     }
 
-    pub fn render(&self) {
+    pub fn render(&self, viewport: Viewport) {
         unsafe {
             gl::Clear(gl::COLOR_BUFFER_BIT);
 
@@ -249,46 +343,62 @@ impl OpenGlCanvas {
 
             self.img_vao.bind();
             gl::UseProgram(*self.img_prgm);
-            self.update_dimension_uniforms(&self.img_prgm);
+            self.update_viewport_uniforms(&self.img_prgm, viewport);
+            let transform = self.background_transform.get();
+            let [x, y] = transform.matrix();
+            let offset_loc = uniform(&self.img_prgm, cstr!("drawing_pos"));
+            let matrix_loc = uniform(&self.img_prgm, cstr!("u_Matrix"));
+            let opacity_loc = uniform(&self.img_prgm, cstr!("u_Opacity"));
+            gl::Uniform2f(*offset_loc, transform.offset.x, transform.offset.y);
+            gl::UniformMatrix2fv(*matrix_loc, 1, gl::FALSE, [x.x, x.y, y.x, y.y].as_ptr());
+            gl::Uniform1f(*opacity_loc, transform.opacity.clamp(0.0, 1.0));
             self.img_tex.bind(TextureTarget::Rectangle);
             gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
 
-            self.shape_vao.bind();
             gl::UseProgram(*self.shape_prgm);
-
-            self.update_dimension_uniforms(&self.img_prgm);
+            self.update_viewport_uniforms(&self.shape_prgm, viewport);
 
             let pos_loc = uniform(&self.shape_prgm, cstr!("drawing_pos"));
-            let pos = self.drawing_pos.get();
-            gl::Uniform2f(*pos_loc, pos.x, pos.y);
-
-            {
-                let color_loc = uniform(&self.shape_prgm, cstr!("u_Color"));
-                let [r, g, b] = self.shape_color.get();
-                gl::Uniform3ui(*color_loc, r as _, g as _, b as _);
-            }
-
-            {
-                let alpha_loc = uniform(&self.shape_prgm, cstr!("u_Alpha"));
-                gl::Uniform1ui(*alpha_loc, self.shape_alpha.get() as _);
+            let color_loc = uniform(&self.shape_prgm, cstr!("u_Color"));
+            let alpha_loc = uniform(&self.shape_prgm, cstr!("u_Alpha"));
+            let colors = self.colors.get();
+            for shape in self.shapes.borrow().iter() {
+                let settings = &shape.settings;
+                if !settings.visible {
+                    continue;
+                }
+                for (part, &(color, alpha)) in shape.parts.iter().zip(&part_colors(settings)) {
+                    gl::Uniform2f(*pos_loc, part.pos.x, part.pos.y);
+                    let [r, g, b] = color;
+                    gl::Uniform3ui(*color_loc, r as _, g as _, b as _);
+                    gl::Uniform1ui(*alpha_loc, raster::shape_opacity(&colors, alpha));
+
+                    part.vao.bind();
+                    part.tex.bind(TextureTarget::Rectangle);
+                    gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+                }
             }
-
-            self.shape_tex.bind(TextureTarget::Rectangle);
-            gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
             gl::Uniform2f(*pos_loc, 0.0, 0.0);
 
-            self.points_vao.bind();
             gl::UseProgram(*self.draw_prgm);
-
-            self.update_dimension_uniforms(&self.draw_prgm);
-
-            {
-                let color_loc = uniform(&self.draw_prgm, cstr!("u_Color"));
-                let [r, g, b] = self.drawing_color.get();
-                gl::Uniform3ui(*color_loc, r as _, g as _, b as _);
+            self.update_viewport_uniforms(&self.draw_prgm, viewport);
+
+            let color_loc = uniform(&self.draw_prgm, cstr!("u_Color"));
+            let set_color = |[r, g, b]: [u8; 3]| gl::Uniform3ui(*color_loc, r as _, g as _, b as _);
+
+            self.overlay_vao.bind();
+            let mut first = 0;
+            let colors = [GRID_COLOR, GUIDE_COLOR, HINT_COLOR];
+            for (&n_lines, &color) in self.overlay_lines.get().iter().zip(&colors) {
+                set_color(color);
+                gl::DrawArrays(gl::LINES, first * 2, n_lines as i32 * 2);
+                first += n_lines as i32;
             }
 
-            let n_points = self.drawing.borrow().drawing.points().len() as i32;
+            self.points_vao.bind();
+            set_color(self.colors.get().drawing);
+
+            let n_points = self.drawing.borrow().n_points as i32;
             gl::DrawArrays(gl::POINTS, 0, n_points);
 
             self.lines_vao.bind();
@@ -297,68 +407,41 @@ impl OpenGlCanvas {
 
             check_errors().unwrap();
 
-            self.ctx.swap_buffers().unwrap();
+            self.surface.swap_buffers();
         }
     }
 
-    pub fn resize(&self) {
-        let (w, h) = self.canvas.physical_size();
-        self.update_dimensions(|dims| dims.screen_dims = [w as f32, h as f32].into());
+    /// Resizes the GL surface to match what it's drawn on, returning the new size in pixels.
+    pub fn resize(&self) -> Point<f32> {
+        let (w, h) = self.surface.size();
         unsafe {
             gl::Viewport(0, 0, w as _, h as _);
         }
-        self.ctx.resize(PhysicalSize::new(w, h));
-    }
-
-    pub fn get_dimensions(&self) -> Dimensions {
-        self.dimensions.get()
-    }
-
-    pub fn update_dimensions<F: FnOnce(&mut Dimensions)>(&self, f: F) {
-        let mut dims = self.dimensions.get();
-        f(&mut dims);
-        self.set_dimensions(dims);
+        self.surface.resize(w, h);
+        Point::new(w as f32, h as f32)
     }
 
-    pub fn set_dimensions(&self, dims: Dimensions) {
-        self.dimensions.set(dims);
-    }
-
-    fn update_dimension_uniforms(&self, prog: &Program) {
-        let dims = self.get_dimensions();
-
+    fn update_viewport_uniforms(&self, prog: &Program, viewport: Viewport) {
         let uniform = |name| prog.get_uniform_location(name).unwrap().unwrap();
         let screen_dims_loc = uniform(cstr!("screen_dims"));
         let scene_pos_loc = uniform(cstr!("scene_pos"));
         let scale_loc = uniform(cstr!("scale"));
 
         unsafe {
-            gl::Uniform2f(*screen_dims_loc, dims.screen_dims.x, dims.screen_dims.y);
-            gl::Uniform2f(*scene_pos_loc, dims.scene_pos.x, dims.scene_pos.y);
-            gl::Uniform1f(*scale_loc, dims.scale);
+            let Viewport {
+                screen_dims,
+                scene_pos,
+                scale,
+            } = viewport;
+            gl::Uniform2f(*screen_dims_loc, screen_dims.x, screen_dims.y);
+            gl::Uniform2f(*scene_pos_loc, scene_pos.x, scene_pos.y);
+            gl::Uniform1f(*scale_loc, scale);
         }
     }
 
-    pub fn set_image<'a>(&self, img: impl ImageDecoder<'a>) {
-        let (width, height) = img.dimensions();
-
-        if img.color_type() != image::ColorType::Rgb8 {
-            println!("unexpected color format: {:?}", img.color_type());
-            return;
-        }
-
-        let buf_len: usize = img.total_bytes().try_into().expect("image too large");
-        let mut buf = vec![0; buf_len];
-        img.read_image(&mut buf[..]).unwrap();
-
-        let buf2 = buf
-            .chunks_exact(3)
-            .map(|rgb| {
-                let (r, g, b) = (rgb[0], rgb[1], rgb[2]);
-                vec![127, r, g, b]
-            })
-            .flatten()
-            .collect::<Vec<u8>>();
+    /// Uploads a new background image, keeping its alpha, or hides the background if there's none.
+    pub fn set_image(&self, img: Option<&RgbaImage>) {
+        let (width, height) = img.map_or((0, 0), |img| img.dimensions());
 
         #[rustfmt::skip]
         let vertex_data = &[
@@ -369,206 +452,83 @@ impl OpenGlCanvas {
         ];
 
         unsafe {
-            self.img_tex.bind(TextureTarget::Rectangle);
-            gl::TexImage2D(
-                gl::TEXTURE_RECTANGLE,
-                0,
-                gl::RGB8 as _,
-                width as GLint,
-                height as GLint,
-                0,
-                gl::BGRA,
-                gl::UNSIGNED_INT_8_8_8_8,
-                buf2.as_ptr().cast(),
-            );
+            if let Some(img) = img {
+                self.img_tex.bind(TextureTarget::Rectangle);
+                gl::TexImage2D(
+                    gl::TEXTURE_RECTANGLE,
+                    0,
+                    gl::RGBA8 as _,
+                    width as GLint,
+                    height as GLint,
+                    0,
+                    gl::RGBA,
+                    gl::UNSIGNED_BYTE,
+                    img.as_ptr().cast(),
+                );
+            }
 
             self.img_vb.bind(BufferTarget::Array);
             Buffer::buffer_data(BufferTarget::Array, vertex_data, Usage::StaticDraw).unwrap();
         }
     }
 
-    pub fn with_drawing<F, T>(&self, f: F) -> T
-    where
-        F: FnOnce(&mut UndoStack<Drawing<Point<f32>>>) -> T,
-    {
-        let mut drawing_data = self.drawing.borrow_mut();
-        let ret = f(&mut drawing_data.drawing);
-        drop(drawing_data);
-        self.update_drawing();
-        ret
-    }
-
-    pub fn commit_drawing(&self) {
-        self.drawing.borrow_mut().drawing.commit();
-    }
-
-    pub fn undo(&self) {
-        self.with_drawing(UndoStack::undo);
+    /// Where the background goes and how much it shows, taking effect on the next render.
+    pub fn set_background_transform(&self, transform: BackgroundTransform) {
+        self.background_transform.set(transform);
     }
 
-    pub fn redo(&self) {
-        self.with_drawing(UndoStack::redo);
+    pub fn background_transform(&self) -> BackgroundTransform {
+        self.background_transform.get()
     }
 
-    pub fn clear_drawing(&self) {
-        let mut drawing = self.drawing.borrow_mut();
-        drawing.drawing.clear();
+    pub fn update_overlay(&self, overlay: &Overlay) {
+        let Overlay {
+            grid,
+            guides,
+            hints,
+        } = overlay;
+        let line_data: Vec<_> = grid.iter().chain(guides).chain(hints).copied().collect();
         unsafe {
-            self.points_vb.bind(BufferTarget::Array);
-            let points = drawing.drawing.points();
-            Buffer::buffer_data(BufferTarget::Array, points, Usage::StaticDraw).unwrap();
-
-            drawing.n_lines = 0;
-
-            let vertex_data = [0.0; 8];
-            self.shape_vb.bind(BufferTarget::Array);
-            Buffer::buffer_data(BufferTarget::Array, &vertex_data, Usage::StaticDraw).unwrap();
-
-            self.shape_tex.bind(TextureTarget::Rectangle);
-            gl::TexImage2D(
-                gl::TEXTURE_RECTANGLE,
-                0,
-                gl::RGBA8 as _,
-                0,
-                0,
-                0,
-                gl::BGRA,
-                gl::UNSIGNED_INT_8_8_8_8,
-                drawing.pixels.as_ptr().cast(),
-            );
+            self.overlay_vb.bind(BufferTarget::Array);
+            Buffer::buffer_data(BufferTarget::Array, &line_data, Usage::StaticDraw).unwrap();
         }
+        self.overlay_lines
+            .set([grid.len(), guides.len(), hints.len()]);
     }
 
-    pub fn update_drawing(&self) {
-        let mut data = self.drawing.borrow_mut();
-
-        unsafe {
-            self.points_vb.bind(BufferTarget::Array);
-            Buffer::buffer_data(
-                BufferTarget::Array,
-                data.drawing.points(),
-                Usage::StaticDraw,
-            )
-            .unwrap();
+    /// Rasterizes every layer that changed, and shows the points and control lines of the active one.
+    pub fn update_layers(&self, layers: &[Layer], active: usize, mode: RasterMode) {
+        let mut shapes = self.shapes.borrow_mut();
+        shapes.truncate(layers.len());
+        while shapes.len() < layers.len() {
+            shapes.push(Shape::new());
         }
 
-        let (mut x_min, mut y_min, mut x_max, mut y_max) = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
-        let mut segments = vec![];
-        let mut line_data = vec![];
-        for seg in data.drawing.segments() {
-            for pt in seg.points() {
-                x_min = x_min.min(pt.x);
-                y_min = y_min.min(pt.y);
-                x_max = x_max.max(pt.x);
-                y_max = y_max.max(pt.y);
-            }
-            segments.push(seg);
-
-            match seg {
-                Segment::Line(p0, p1) => {
-                    line_data.push((p0, p1));
-                }
-                // Don't draw a line for a shape's closing line.
-                Segment::ClosingLine(..) => (),
-                Segment::Bezier(p0, p1, p2, p3) => {
-                    line_data.push((p0, p1));
-                    line_data.push((p2, p3));
-                }
+        let mut data = self.drawing.borrow_mut();
+        for (shape, layer) in shapes.iter_mut().zip(layers) {
+            let stale = shape.is_stale(layer, mode);
+            shape.settings = layer.settings.clone();
+            if stale {
+                shape.rasterize(layer, mode);
             }
         }
 
-        if segments.is_empty() {
-            return;
+        let drawing = &layers[active].drawing;
+        data.n_points = drawing.points().len();
+        unsafe {
+            self.points_vb.bind(BufferTarget::Array);
+            Buffer::buffer_data(BufferTarget::Array, drawing.points(), Usage::StaticDraw).unwrap();
         }
 
+        let line_data = raster::handle_lines(drawing);
         data.n_lines = line_data.len();
         unsafe {
             self.lines_vb.bind(BufferTarget::Array);
             Buffer::buffer_data(BufferTarget::Array, &line_data, Usage::StaticDraw).unwrap();
         }
-
-        assert_ne!(x_min, f32::MAX);
-        assert_ne!(y_min, f32::MAX);
-        let (width, height) = (x_max - x_min, y_max - y_min);
-        if width <= 0.0 || height <= 0.0 {
-            return;
-        }
-        let top_left = Point::new(x_min, y_min);
-
-        // If I don't do this, then using GL_R8/GL_RED seems to break in a weird way.
-        // I wish I knew why. Something about stride/alignment, maybe?
-        let width = {
-            if width % 4.0 == 0.0 {
-                width
-            } else {
-                4.0 + (width - (width % 4.0))
-            }
-        };
-
-        let (mut rasterizer, mut img_buf) =
-            RefMut::map_split(data, |r| (&mut r.rasterizer, &mut r.pixels));
-        rasterizer.reset(width as usize, height as usize);
-
-        let cnv = |p| ab_glyph_rasterizer::Point::from(p - top_left);
-        for segment in segments {
-            match segment {
-                Segment::Line(p0, p1) | Segment::ClosingLine(p0, p1) => {
-                    rasterizer.draw_line(cnv(p0), cnv(p1));
-                }
-                Segment::Bezier(p0, p1, p2, p3) => {
-                    rasterizer.draw_cubic(cnv(p0), cnv(p1), cnv(p2), cnv(p3))
-                }
-            }
-        }
-
-        img_buf.clear();
-        let buf_size = width as usize * height as usize;
-        img_buf.reserve(buf_size);
-        rasterizer.for_each_pixel(|i, v| {
-            debug_assert_eq!(i, img_buf.len());
-            let px = (v * 256.0) as u8;
-            img_buf.push(px);
-        });
-        assert_eq!(img_buf.len(), buf_size);
-
-        self.drawing_pos.set(Point::new(x_min, y_min));
-
-        unsafe {
-            #[rustfmt::skip]
-            let vertex_data = &[
-                0.0, 0.0,
-                width, 0.0,
-                0.0, height,
-                width, height,
-            ];
-
-            self.shape_tex.bind(TextureTarget::Rectangle);
-            gl::TexImage2D(
-                gl::TEXTURE_RECTANGLE,
-                0,
-                gl::R8 as _,
-                width as _,
-                height as _,
-                0,
-                gl::RED,
-                gl::UNSIGNED_BYTE,
-                img_buf.as_ptr().cast(),
-            );
-
-            self.shape_vb.bind(BufferTarget::Array);
-            Buffer::buffer_data(BufferTarget::Array, vertex_data, Usage::StaticDraw).unwrap();
-        }
-    }
-
-    pub fn recolor_drawing(&self, rgb: [u8; 3]) {
-        self.drawing_color.set(rgb);
-    }
-
-    pub fn recolor_shape(&self, rgb: [u8; 3]) {
-        self.shape_color.set(rgb);
     }
 
-    pub fn set_shape_alpha(&self, alpha: u8) {
-        self.shape_alpha.set(alpha);
+    pub fn set_colors(&self, colors: Colors) {
+        self.colors.set(colors);
     }
 }
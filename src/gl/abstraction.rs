@@ -1,3 +1,6 @@
+// The wrappers' constructors aren't `Default`, since they need a current GL context.
+#![allow(clippy::new_without_default)]
+
 pub mod error;
 pub use error::Result;
 
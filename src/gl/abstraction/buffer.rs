@@ -92,17 +92,23 @@ impl Buffer {
         }
     }
 
+    /// # Safety
+    ///
+    /// A GL context has to be current on this thread.
     pub unsafe fn bind(&self, target: BufferTarget) {
         gl::BindBuffer(target as GLenum, self.0);
         check_errors().unwrap();
     }
 
+    /// # Safety
+    ///
+    /// A GL context has to be current on this thread, with a buffer bound to `target`.
     pub unsafe fn buffer_data<T: Sized, U: Into<Usage>>(
         target: BufferTarget,
         data: &[T],
         usage: U,
     ) -> Result<()> {
-        let size = std::mem::size_of::<T>() * data.len();
+        let size = std::mem::size_of_val(data);
         gl::BufferData(
             target as GLenum,
             size as _,
@@ -38,6 +38,9 @@ impl Texture {
         buf.into_iter().map(Self).collect()
     }
 
+    /// # Safety
+    ///
+    /// A GL context has to be current on this thread.
     pub unsafe fn bind(&self, target: TextureTarget) {
         gl::BindTexture(target as GLenum, self.0);
         check_errors().unwrap();
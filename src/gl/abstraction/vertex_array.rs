@@ -23,6 +23,9 @@ impl VertexArray {
         buf.into_iter().map(Self).collect()
     }
 
+    /// # Safety
+    ///
+    /// A GL context has to be current on this thread.
     pub unsafe fn bind(&self) {
         gl::BindVertexArray(self.0);
         check_errors().unwrap();
@@ -3,6 +3,10 @@ use gl::types::{GLboolean, GLdouble, GLenum, GLfloat, GLint, GLint64};
 
 type Getter<T> = unsafe fn(pname: GLenum, params: *mut T);
 
+/// # Safety
+///
+/// `F` has to write values of type `T`, and `Self` has to be made of nothing but `T`s,
+/// as many as `F` writes for the names it's used with.
 pub unsafe trait GlGet<T: Pod = Self>: Pod {
     const F: Getter<T>;
 }
@@ -35,15 +39,12 @@ where
 }
 
 pub fn get_errors() -> Result<(), &'static str> {
-    loop {
-        let msg = match unsafe { gl::GetError() } {
-            gl::INVALID_ENUM => "invalid enum",
-            gl::INVALID_VALUE => "invalid value",
-            gl::INVALID_OPERATION => "invalid operation",
-            gl::NO_ERROR => return Ok(()),
-            e => panic!("Unfamiliar error: {}", e),
-        };
-        return Err(msg);
+    match unsafe { gl::GetError() } {
+        gl::NO_ERROR => Ok(()),
+        gl::INVALID_ENUM => Err("invalid enum"),
+        gl::INVALID_VALUE => Err("invalid value"),
+        gl::INVALID_OPERATION => Err("invalid operation"),
+        e => panic!("Unfamiliar error: {}", e),
     }
 }
 
@@ -0,0 +1,61 @@
+//! Drawing into an nwg `ExternCanvas`, through a raw WGL context.
+
+use std::ffi::c_void;
+
+use glutin::{dpi::PhysicalSize, platform::windows::RawContextExt, PossiblyCurrent, RawContext};
+use native_windows_gui as nwg;
+
+use super::{context_builder, Surface};
+use crate::nwg_util::SaneBuilder;
+
+pub struct Win32Surface {
+    ctx: RawContext<PossiblyCurrent>,
+    canvas: nwg::ExternCanvas,
+}
+
+#[allow(dead_code)]
+impl Win32Surface {
+    pub fn new<W: Into<nwg::ControlHandle>>(parent: W) -> Self {
+        let canvas = nwg::ExternCanvas::builder()
+            .parent(Some(parent.into()))
+            .position((100, 0))
+            .construct()
+            .expect("Failed to build nwg::ExternCanvas");
+
+        let ctx = unsafe {
+            context_builder()
+                .build_raw_context(canvas.handle.hwnd().unwrap() as *mut c_void)
+                .expect("Failed to build opengl context")
+                .make_current()
+                .expect("Failed to set opengl context as current")
+        };
+
+        Self { ctx, canvas }
+    }
+
+    pub fn handle(&self) -> &nwg::ControlHandle {
+        &self.canvas.handle
+    }
+
+    pub fn nwg_canvas(&self) -> &nwg::ExternCanvas {
+        &self.canvas
+    }
+}
+
+impl Surface for Win32Surface {
+    fn get_proc_address(&self, name: &str) -> *const c_void {
+        self.ctx.get_proc_address(name)
+    }
+
+    fn size(&self) -> (u32, u32) {
+        self.canvas.physical_size()
+    }
+
+    fn resize(&self, width: u32, height: u32) {
+        self.ctx.resize(PhysicalSize::new(width, height));
+    }
+
+    fn swap_buffers(&self) {
+        self.ctx.swap_buffers().unwrap();
+    }
+}
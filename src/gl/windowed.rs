@@ -0,0 +1,26 @@
+//! Drawing into a whole winit window.
+
+use std::ffi::c_void;
+
+use glutin::{dpi::PhysicalSize, PossiblyCurrent, WindowedContext};
+
+use super::Surface;
+
+impl Surface for WindowedContext<PossiblyCurrent> {
+    fn get_proc_address(&self, name: &str) -> *const c_void {
+        // Not `Surface::get_proc_address`, which would call itself.
+        glutin::ContextWrapper::get_proc_address(self, name)
+    }
+
+    fn size(&self) -> (u32, u32) {
+        self.window().inner_size().into()
+    }
+
+    fn resize(&self, width: u32, height: u32) {
+        glutin::ContextWrapper::resize(self, PhysicalSize::new(width, height));
+    }
+
+    fn swap_buffers(&self) {
+        glutin::ContextWrapper::swap_buffers(self).unwrap();
+    }
+}
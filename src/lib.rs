@@ -0,0 +1,20 @@
+//! The parts of the editor that don't depend on the platform: drawings and documents, undo,
+//! reading and writing ASS, projects, and rendering in software.
+
+pub mod ass;
+pub mod background;
+pub mod batch;
+pub mod clipboard;
+pub mod codec;
+pub mod document;
+pub mod drawing;
+pub mod editor;
+pub mod export;
+pub mod ops;
+pub mod point;
+pub mod project;
+pub mod raster;
+pub mod recovery;
+pub mod snap;
+pub mod undo;
+pub mod viewport;
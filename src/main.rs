@@ -1,21 +1,48 @@
+#[cfg(all(windows, not(feature = "winit")))]
 use native_windows_gui as nwg;
 
+#[cfg(all(windows, not(feature = "winit")))]
 use nwg::NativeUi;
 
+#[cfg(all(windows, not(feature = "winit")))]
+use assdraw_rs::{ass, background, export};
+#[cfg(any(windows, feature = "winit"))]
+use assdraw_rs::{
+    clipboard, document, drawing, editor, point, project, raster, recovery, snap, viewport,
+};
+
+#[cfg(all(windows, not(feature = "winit")))]
 mod app;
 //mod ass_outline;
 //mod canvas;
-mod drawing;
+#[cfg(any(windows, feature = "winit"))]
 mod gl;
-mod point;
-mod undo;
 //mod vk;
+#[cfg(all(windows, not(feature = "winit")))]
 mod nwg_util;
+#[cfg(feature = "winit")]
+mod winit_app;
 
+#[cfg(any(windows, feature = "winit"))]
 pub use crate::gl::abstraction;
 
+#[cfg(all(windows, not(feature = "winit")))]
 fn main() {
     nwg::init().unwrap();
     let _app = app::AppBuilder::build_ui(Default::default()).unwrap();
     nwg::dispatch_thread_events();
 }
+
+#[cfg(feature = "winit")]
+fn main() {
+    winit_app::run();
+}
+
+#[cfg(not(any(windows, feature = "winit")))]
+fn main() {
+    eprintln!(
+        "The editor only runs on Windows unless it's built with `--features winit`. \
+         To process drawings, use assdraw-cli."
+    );
+    std::process::exit(1);
+}
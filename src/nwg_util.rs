@@ -24,7 +24,12 @@ macro_rules! sane_builder {
 sane_builder!(nwg::ButtonBuilder<'_>, nwg::Button);
 sane_builder!(nwg::WindowBuilder<'_>, nwg::Window);
 sane_builder!(nwg::RadioButtonBuilder<'_>, nwg::RadioButton);
+sane_builder!(nwg::CheckBoxBuilder<'_>, nwg::CheckBox);
 sane_builder!(nwg::ExternCanvasBuilder<'_>, nwg::ExternCanvas);
 sane_builder!(nwg::FontBuilder<'_>, nwg::Font);
 sane_builder!(nwg::TrackBarBuilder, nwg::TrackBar);
 sane_builder!(nwg::ColorDialogBuilder, nwg::ColorDialog);
+sane_builder!(nwg::FileDialogBuilder, nwg::FileDialog);
+sane_builder!(nwg::TimerBuilder, nwg::Timer);
+sane_builder!(nwg::TextInputBuilder<'_>, nwg::TextInput);
+sane_builder!(nwg::ListBoxBuilder<'_, String>, nwg::ListBox<String>);
@@ -0,0 +1,243 @@
+//! Whole-drawing operations, for processing drawings in bulk rather than by hand.
+//!
+//! Contours in ASS drawings are closed implicitly, so a contour here is a move followed by everything
+//! that's drawn before the next one.
+
+use crate::drawing::{Command, Drawing};
+use crate::point::Point;
+use crate::raster;
+
+mod boolean;
+
+pub use boolean::{boolean, BooleanOp};
+
+/// Moves every point by `offset`.
+pub fn translate(drawing: &mut Drawing<Point<f32>>, offset: Point<f32>) {
+    for p in drawing.points_mut() {
+        *p += offset;
+    }
+}
+
+/// Scales the drawing away from the origin, separately along each axis.
+pub fn scale(drawing: &mut Drawing<Point<f32>>, factor: Point<f32>) {
+    for p in drawing.points_mut() {
+        *p *= factor;
+    }
+}
+
+/// Rotates the drawing around the origin, counterclockwise on screen like `\frz`.
+pub fn rotate(drawing: &mut Drawing<Point<f32>>, degrees: f32) {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    for p in drawing.points_mut() {
+        *p = Point::new(p.x * cos + p.y * sin, p.y * cos - p.x * sin);
+    }
+}
+
+/// Where a contour starts, and what's drawn from there.
+type Contour = (Point<f32>, Vec<Command<Point<f32>>>);
+
+/// Splits the drawing into its contours.
+///
+/// Anything drawn before the first move starts at the origin, like it would in libass.
+fn contours(drawing: &Drawing<Point<f32>>) -> Vec<Contour> {
+    let mut contours = Vec::new();
+    for cmd in drawing.commands() {
+        match cmd {
+            Command::Move(p) => contours.push((p, Vec::new())),
+            _ => {
+                if contours.is_empty() {
+                    contours.push((Point::new(0.0, 0.0), Vec::new()));
+                }
+                contours.last_mut().unwrap().1.push(cmd);
+            }
+        }
+    }
+    contours
+}
+
+/// The point a command ends at.
+fn end_point(cmd: Command<Point<f32>>) -> Point<f32> {
+    match cmd {
+        Command::Move(p) | Command::Line(p) | Command::Bezier(_, _, p) => p,
+    }
+}
+
+/// Replaces every curve with lines that stray no further than `tolerance` from it.
+pub fn flatten(drawing: &Drawing<Point<f32>>, tolerance: f32) -> Drawing<Point<f32>> {
+    let mut flat = Drawing::new();
+    let mut pen = Point::new(0.0, 0.0);
+    let mut points = Vec::new();
+    for cmd in drawing.commands() {
+        if let Command::Bezier(p1, p2, p3) = cmd {
+            points.clear();
+            raster::flatten_within([pen, p1, p2, p3], tolerance, &mut points);
+            for &p in &points {
+                flat.push(Command::Line(p));
+            }
+        } else {
+            flat.push(cmd);
+        }
+        pen = end_point(cmd);
+    }
+    flat
+}
+
+/// Makes every contour go the other way around.
+///
+/// This matters for the holes in shapes filled with the nonzero rule.
+pub fn reverse(drawing: &Drawing<Point<f32>>) -> Drawing<Point<f32>> {
+    let mut reversed = Drawing::new();
+    for (start, cmds) in contours(drawing) {
+        let mut from = start;
+        let mut starts = Vec::with_capacity(cmds.len());
+        for &cmd in &cmds {
+            starts.push(from);
+            from = end_point(cmd);
+        }
+        reversed.push(Command::Move(from));
+        for (cmd, to) in cmds.into_iter().zip(starts).rev() {
+            reversed.push(match cmd {
+                Command::Bezier(p1, p2, _) => Command::Bezier(p2, p1, to),
+                _ => Command::Line(to),
+            });
+        }
+    }
+    reversed
+}
+
+/// How far `p` is from the segment between `a` and `b`.
+fn distance_to_segment(p: Point<f32>, a: Point<f32>, b: Point<f32>) -> f32 {
+    let ab = b - a;
+    let len_sq = ab.x * ab.x + ab.y * ab.y;
+    let t = if len_sq == 0.0 {
+        0.0
+    } else {
+        (((p.x - a.x) * ab.x + (p.y - a.y) * ab.y) / len_sq).clamp(0.0, 1.0)
+    };
+    (p - a.lerp(b, t)).length()
+}
+
+/// Picks out the points of a polyline that keep it within `tolerance` of the original (Ramer–Douglas–Peucker).
+/// The first and last points are always kept.
+fn simplify_polyline(points: &[Point<f32>], tolerance: f32, keep: &mut Vec<Point<f32>>) {
+    let (first, last) = (points[0], points[points.len() - 1]);
+    let farthest = points[1..points.len() - 1]
+        .iter()
+        .enumerate()
+        .map(|(i, &p)| (i + 1, distance_to_segment(p, first, last)))
+        .fold((0, 0.0), |a, b| if b.1 > a.1 { b } else { a });
+    if farthest.1 > tolerance {
+        simplify_polyline(&points[..=farthest.0], tolerance, keep);
+        simplify_polyline(&points[farthest.0..], tolerance, keep);
+    } else {
+        keep.push(last);
+    }
+}
+
+/// Removes what doesn't change the shape by more than `tolerance`: points along nearly straight runs of lines,
+/// curves that are nearly straight, lines that lead back to where the contour started, and empty contours.
+pub fn simplify(drawing: &Drawing<Point<f32>>, tolerance: f32) -> Drawing<Point<f32>> {
+    let mut simple = Drawing::new();
+    for (start, cmds) in contours(drawing) {
+        let mut run = vec![start];
+        let mut out = Vec::new();
+        let flush = |run: &mut Vec<Point<f32>>, out: &mut Vec<_>| {
+            if run.len() > 1 {
+                let mut keep = Vec::new();
+                simplify_polyline(run, tolerance, &mut keep);
+                out.extend(keep.into_iter().map(Command::Line));
+            }
+            let last = run[run.len() - 1];
+            run.clear();
+            run.push(last);
+        };
+        for cmd in cmds {
+            let from = run[run.len() - 1];
+            match cmd {
+                Command::Line(p) if p == from => {}
+                Command::Line(p) => run.push(p),
+                Command::Bezier(p1, p2, p3)
+                    if distance_to_segment(p1, from, p3) <= tolerance
+                        && distance_to_segment(p2, from, p3) <= tolerance =>
+                {
+                    if p3 != from {
+                        run.push(p3);
+                    }
+                }
+                _ => {
+                    flush(&mut run, &mut out);
+                    out.push(cmd);
+                    run[0] = end_point(cmd);
+                }
+            }
+        }
+        while run.len() > 1 && run[run.len() - 1] == start {
+            run.pop();
+        }
+        flush(&mut run, &mut out);
+        if out.is_empty() {
+            continue;
+        }
+        simple.push(Command::Move(start));
+        for cmd in out {
+            simple.push(cmd);
+        }
+    }
+    simple
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{flatten, reverse, rotate, simplify};
+    use crate::ass::{format_drawing, parse_drawing};
+    use crate::drawing::CommandKind;
+
+    #[test]
+    fn test_reverse() {
+        let drawing = parse_drawing("m 0 0 l 10 0 b 10 5 5 10 0 10 m 20 20 l 30 20").unwrap();
+        assert_eq!(
+            format_drawing(&reverse(&drawing)),
+            "m 0.0 10.0 b 5.0 10.0 10.0 5.0 10.0 0.0 l 0.0 0.0 m 30.0 20.0 l 20.0 20.0"
+        );
+        assert_eq!(
+            format_drawing(&reverse(&reverse(&drawing))),
+            format_drawing(&drawing)
+        );
+    }
+
+    #[test]
+    fn test_simplify() {
+        let drawing =
+            parse_drawing("m 0 0 l 5 0.01 10 0 10 10 10 10 0 10 0 0 m 50 50 m 0 0 b 1 1 2 2 3 3")
+                .unwrap();
+        assert_eq!(
+            format_drawing(&simplify(&drawing, 0.1)),
+            "m 0.0 0.0 l 10.0 0.0 10.0 10.0 0.0 10.0 m 0.0 0.0 l 3.0 3.0"
+        );
+        assert_eq!(
+            format_drawing(&simplify(&drawing, 0.001)),
+            "m 0.0 0.0 l 5.0 0.01 10.0 0.0 10.0 10.0 0.0 10.0 m 0.0 0.0 l 3.0 3.0"
+        );
+    }
+
+    #[test]
+    fn test_flatten_and_rotate() {
+        let mut drawing = parse_drawing("m 0 0 b 0 10 10 10 10 0").unwrap();
+        let flat = flatten(&drawing, 0.5);
+        assert!(flat
+            .commands()
+            .skip(1)
+            .all(|cmd| cmd.kind() == CommandKind::Line));
+        assert!(flat.len() > 2);
+        assert_eq!(
+            format_drawing(&flatten(&drawing, 100.0)),
+            "m 0.0 0.0 l 10.0 0.0"
+        );
+
+        rotate(&mut drawing, 90.0);
+        assert_eq!(
+            format_drawing(&drawing),
+            "m 0.0 0.0 b 10.0 0.0 10.0 -10.0 0.0 -10.0"
+        );
+    }
+}
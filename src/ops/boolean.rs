@@ -0,0 +1,414 @@
+//! Union, intersection and difference of the areas that drawings fill.
+//!
+//! Both drawings are flattened into polygons, and every edge is split wherever another edge crosses or touches it.
+//! The pieces with the result's inside on one side and its outside on the other are the result's outline,
+//! so they're kept, and chained back together into contours.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::drawing::{Command, Drawing};
+use crate::point::Point;
+
+use super::flatten;
+
+/// How far curves may stray from the real ones once flattened, in pixels.
+const FLATNESS: f32 = 0.05;
+/// How far to either side of an edge the inside is looked for.
+const SIDE_OFFSET: f64 = 1e-4;
+/// How close to the end of an edge something has to be to count as touching the end.
+const EPSILON: f64 = 1e-9;
+
+type Vector = Point<f64>;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BooleanOp {
+    /// Everything inside either drawing.
+    Union,
+    /// Everything inside both drawings.
+    Intersection,
+    /// Everything inside the first drawing, but not the second.
+    Difference,
+    /// Everything inside exactly one of the drawings.
+    Xor,
+}
+
+impl BooleanOp {
+    fn contains(self, a: bool, b: bool) -> bool {
+        match self {
+            Self::Union => a || b,
+            Self::Intersection => a && b,
+            Self::Difference => a && !b,
+            Self::Xor => a != b,
+        }
+    }
+}
+
+#[inline]
+fn cross(a: Vector, b: Vector) -> f64 {
+    a.x * b.y - a.y * b.x
+}
+
+#[inline]
+fn dot(a: Vector, b: Vector) -> f64 {
+    a.x * b.x + a.y * b.y
+}
+
+/// The drawing as closed polygons, one per contour.
+fn polygons(drawing: &Drawing<Point<f32>>) -> Vec<Vec<Vector>> {
+    let mut polygons: Vec<Vec<Vector>> = Vec::new();
+    for cmd in flatten(drawing, FLATNESS).commands() {
+        match cmd {
+            Command::Move(p) => polygons.push(vec![p.cast()]),
+            Command::Line(p) => match polygons.last_mut() {
+                Some(polygon) => polygon.push(p.cast()),
+                None => polygons.push(vec![Point::new(0.0, 0.0), p.cast()]),
+            },
+            Command::Bezier(..) => unreachable!(),
+        }
+    }
+    polygons
+}
+
+/// The nonzero winding number of the polygons around `p`.
+fn winding(polygons: &[Vec<Vector>], p: Vector) -> i32 {
+    let mut winding = 0;
+    for polygon in polygons {
+        for (i, &a) in polygon.iter().enumerate() {
+            let b = polygon[(i + 1) % polygon.len()];
+            if a.y <= p.y && b.y > p.y && cross(b - a, p - a) > 0.0 {
+                winding += 1;
+            } else if b.y <= p.y && a.y > p.y && cross(b - a, p - a) < 0.0 {
+                winding -= 1;
+            }
+        }
+    }
+    winding
+}
+
+struct Edge {
+    a: Vector,
+    b: Vector,
+    /// Where the edge gets split, as how far along it, and the exact point, which other edges share.
+    splits: Vec<(f64, Vector)>,
+}
+
+impl Edge {
+    fn new(a: Vector, b: Vector) -> Self {
+        Self {
+            a,
+            b,
+            splits: Vec::new(),
+        }
+    }
+
+    /// How far along the edge `p`'s projection onto it is.
+    fn param(&self, p: Vector) -> f64 {
+        let d = self.b - self.a;
+        dot(p - self.a, d) / dot(d, d)
+    }
+
+    /// The corners of the box around the edge, grown by as much as `intersect` lets it miss by.
+    fn bounds(&self) -> (Vector, Vector) {
+        let d = self.b - self.a;
+        let slack = EPSILON * (1.0 + dot(d, d).sqrt());
+        let slack = Point::new(slack, slack);
+        let min = Point::new(self.a.x.min(self.b.x), self.a.y.min(self.b.y));
+        let max = Point::new(self.a.x.max(self.b.x), self.a.y.max(self.b.y));
+        (min - slack, max + slack)
+    }
+
+    fn split(&mut self, t: f64, p: Vector) {
+        if t > EPSILON && t < 1.0 - EPSILON {
+            self.splits.push((t, p));
+        }
+    }
+}
+
+/// Two different elements of a slice, mutably.
+fn pair_mut<T>(slice: &mut [T], i: usize, j: usize) -> (&mut T, &mut T) {
+    if i < j {
+        let (before, after) = slice.split_at_mut(j);
+        (&mut before[i], &mut after[0])
+    } else {
+        let (before, after) = slice.split_at_mut(i);
+        (&mut after[0], &mut before[j])
+    }
+}
+
+fn edges(polygons: &[Vec<Vector>]) -> impl Iterator<Item = Edge> + '_ {
+    polygons.iter().flat_map(|polygon| {
+        (0..polygon.len())
+            .map(move |i| Edge::new(polygon[i], polygon[(i + 1) % polygon.len()]))
+            .filter(|edge| edge.a != edge.b)
+    })
+}
+
+/// Splits both edges where they cross, or where either one's end touches the other.
+fn intersect(e1: &mut Edge, e2: &mut Edge) {
+    let r = e1.b - e1.a;
+    let s = e2.b - e2.a;
+    let denom = cross(r, s);
+    let offset = e2.a - e1.a;
+    let scale = dot(r, r).sqrt() * dot(s, s).sqrt();
+
+    if denom.abs() <= EPSILON * scale {
+        // Parallel, so they only meet if they overlap along the same line.
+        if cross(offset, r).abs() > EPSILON * dot(r, r) {
+            return;
+        }
+        for p in [e2.a, e2.b] {
+            let t = e1.param(p);
+            e1.split(t, p);
+        }
+        for p in [e1.a, e1.b] {
+            let u = e2.param(p);
+            e2.split(u, p);
+        }
+        return;
+    }
+
+    let t = cross(offset, s) / denom;
+    let u = cross(offset, r) / denom;
+    if !(-EPSILON..=1.0 + EPSILON).contains(&t) || !(-EPSILON..=1.0 + EPSILON).contains(&u) {
+        return;
+    }
+    // Where an end is involved, that end is the exact point, so that the pieces join up exactly.
+    let p = if t <= EPSILON {
+        e1.a
+    } else if t >= 1.0 - EPSILON {
+        e1.b
+    } else if u <= EPSILON {
+        e2.a
+    } else if u >= 1.0 - EPSILON {
+        e2.b
+    } else {
+        e1.a + r * t
+    };
+    e1.split(t, p);
+    e2.split(u, p);
+}
+
+fn key(p: Vector) -> [u64; 2] {
+    [p.x.to_bits(), p.y.to_bits()]
+}
+
+/// Combines the areas that two drawings fill with the nonzero rule. Curves come out flattened.
+pub fn boolean(
+    a: &Drawing<Point<f32>>,
+    b: &Drawing<Point<f32>>,
+    op: BooleanOp,
+) -> Drawing<Point<f32>> {
+    let (a, b) = (polygons(a), polygons(b));
+    let mut edges: Vec<Edge> = edges(&a).chain(edges(&b)).collect();
+    // Sweeping from left to right, only edges whose bounds overlap can meet.
+    let bounds: Vec<_> = edges.iter().map(Edge::bounds).collect();
+    let mut order: Vec<usize> = (0..edges.len()).collect();
+    order.sort_by(|&i, &j| bounds[i].0.x.total_cmp(&bounds[j].0.x));
+    for (n, &i) in order.iter().enumerate() {
+        let (min, max) = bounds[i];
+        for &j in &order[n + 1..] {
+            let (other_min, other_max) = bounds[j];
+            if other_min.x > max.x {
+                break;
+            }
+            if other_min.y <= max.y && other_max.y >= min.y {
+                let (e1, e2) = pair_mut(&mut edges, i, j);
+                intersect(e1, e2);
+            }
+        }
+    }
+
+    // The outline's pieces, each going such that the inside is on its left.
+    let mut pieces = Vec::new();
+    let mut seen = HashSet::new();
+    for edge in &mut edges {
+        edge.splits.sort_by(|x, y| x.0.total_cmp(&y.0));
+        let mut points = vec![edge.a];
+        points.extend(edge.splits.iter().map(|&(_, p)| p));
+        points.push(edge.b);
+        points.dedup();
+
+        for piece in points.windows(2) {
+            let (p, q) = (piece[0], piece[1]);
+            let mid = (p + q) / 2.0;
+            let d = q - p;
+            let normal = Point::new(-d.y, d.x) / dot(d, d).sqrt() * SIDE_OFFSET;
+            let inside = |p| op.contains(winding(&a, p) != 0, winding(&b, p) != 0);
+            let (left, right) = (inside(mid + normal), inside(mid - normal));
+            let piece = match (left, right) {
+                (true, false) => (p, q),
+                (false, true) => (q, p),
+                _ => continue,
+            };
+            // Edges that both drawings share would otherwise be in the outline twice.
+            if seen.insert([key(piece.0), key(piece.1)]) {
+                pieces.push(piece);
+            }
+        }
+    }
+
+    let mut starting_at: HashMap<[u64; 2], Vec<usize>> = HashMap::new();
+    for (i, &(p, _)) in pieces.iter().enumerate() {
+        starting_at.entry(key(p)).or_default().push(i);
+    }
+    let mut used = vec![false; pieces.len()];
+    let mut result = Drawing::new();
+    for first in 0..pieces.len() {
+        if used[first] {
+            continue;
+        }
+        used[first] = true;
+        let (start, mut end) = pieces[first];
+        let mut contour = vec![start];
+        while end != start {
+            contour.push(end);
+            let next = starting_at
+                .get(&key(end))
+                .and_then(|next| next.iter().copied().find(|&i| !used[i]));
+            match next {
+                Some(next) => {
+                    used[next] = true;
+                    end = pieces[next].1;
+                }
+                None => break,
+            }
+        }
+        push_contour(&mut result, &contour);
+    }
+    result
+}
+
+/// Adds a contour to the drawing, leaving out points that are on a straight line between their neighbors,
+/// which splitting edges leaves behind.
+fn push_contour(drawing: &mut Drawing<Point<f32>>, contour: &[Vector]) {
+    let n = contour.len();
+    let corners = (0..n).filter(|&i| {
+        let (prev, p, next) = (contour[(i + n - 1) % n], contour[i], contour[(i + 1) % n]);
+        let (d1, d2) = (p - prev, next - p);
+        cross(d1, d2).abs() > EPSILON * dot(d1, d1).sqrt() * dot(d2, d2).sqrt() || dot(d1, d2) < 0.0
+    });
+    for (i, corner) in corners.enumerate() {
+        let p = contour[corner].cast();
+        drawing.push(if i == 0 {
+            Command::Move(p)
+        } else {
+            Command::Line(p)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{boolean, BooleanOp};
+    use crate::ass::parse_drawing;
+    use crate::drawing::Drawing;
+    use crate::point::Point;
+
+    /// The area a drawing made of lines fills, assuming its contours don't overlap.
+    fn area(drawing: &Drawing<Point<f32>>) -> f32 {
+        let polygons = super::polygons(drawing);
+        let mut area = 0.0;
+        for polygon in polygons {
+            for (i, &a) in polygon.iter().enumerate() {
+                let b = polygon[(i + 1) % polygon.len()];
+                area += super::cross(a, b) / 2.0;
+            }
+        }
+        area.abs() as f32
+    }
+
+    /// The drawing's points, rounded and sorted, since which one a contour starts at doesn't matter.
+    fn corners(drawing: &Drawing<Point<f32>>) -> Vec<(i32, i32)> {
+        let mut corners: Vec<_> = drawing
+            .points()
+            .iter()
+            .map(|p| (p.x.round() as i32, p.y.round() as i32))
+            .collect();
+        corners.sort();
+        corners
+    }
+
+    #[test]
+    fn test_boolean() {
+        let a = parse_drawing("m 0 0 l 10 0 10 10 0 10").unwrap();
+        let b = parse_drawing("m 5 5 l 15 5 15 15 5 15").unwrap();
+
+        let union = boolean(&a, &b, BooleanOp::Union);
+        assert_eq!(area(&union), 175.0);
+        assert_eq!(union.commands().count(), 8);
+        let intersection = boolean(&a, &b, BooleanOp::Intersection);
+        assert_eq!(area(&intersection), 25.0);
+        assert_eq!(intersection.commands().count(), 4);
+        assert_eq!(area(&boolean(&a, &b, BooleanOp::Difference)), 75.0);
+        assert_eq!(area(&boolean(&a, &b, BooleanOp::Xor)), 150.0);
+
+        // Squares side by side merge into one rectangle, without the edge they share.
+        let c = parse_drawing("m 10 0 l 20 0 20 10 10 10").unwrap();
+        let merged = boolean(&a, &c, BooleanOp::Union);
+        assert_eq!(area(&merged), 200.0);
+        assert_eq!(merged.commands().count(), 4);
+        assert!(boolean(&a, &c, BooleanOp::Intersection).is_empty());
+
+        // Points that aren't numbers don't make sense, but they mustn't bring everything down either.
+        let mut nan = a.clone();
+        nan.points_mut()[2] = Point::new(f32::NAN, 5.0);
+        let _ = boolean(&nan, &b, BooleanOp::Union);
+    }
+
+    #[test]
+    fn test_boolean_results() {
+        let a = parse_drawing("m 0 0 l 10 0 10 10 0 10").unwrap();
+        let b = parse_drawing("m 5 5 l 15 5 15 15 5 15").unwrap();
+
+        // What's left of the first square is an L, with the corner the second one took out.
+        let difference = boolean(&a, &b, BooleanOp::Difference);
+        assert_eq!(difference.commands().count(), 6);
+        assert_eq!(
+            corners(&difference),
+            [(0, 0), (0, 10), (5, 5), (5, 10), (10, 0), (10, 5)]
+        );
+        let intersection = boolean(&a, &b, BooleanOp::Intersection);
+        assert_eq!(corners(&intersection), [(5, 5), (5, 10), (10, 5), (10, 10)]);
+        // The other way around takes the other corner out.
+        let difference = boolean(&b, &a, BooleanOp::Difference);
+        assert_eq!(
+            corners(&difference),
+            [(5, 10), (5, 15), (10, 5), (10, 10), (15, 5), (15, 15)]
+        );
+    }
+
+    #[test]
+    fn test_boolean_touching() {
+        let a = parse_drawing("m 0 0 l 10 0 10 10 0 10").unwrap();
+        let b = parse_drawing("m 10 10 l 20 10 20 20 10 20").unwrap();
+
+        // Squares that only share a corner stay apart.
+        let union = boolean(&a, &b, BooleanOp::Union);
+        assert_eq!(area(&union), 200.0);
+        assert_eq!(union.commands().count(), 8);
+        assert!(boolean(&a, &b, BooleanOp::Intersection).is_empty());
+        let difference = boolean(&a, &b, BooleanOp::Difference);
+        assert_eq!(corners(&difference), corners(&a));
+    }
+
+    #[test]
+    fn test_boolean_nested() {
+        let outer = parse_drawing("m 0 0 l 10 0 10 10 0 10").unwrap();
+        let inner = parse_drawing("m 3 3 l 7 3 7 7 3 7").unwrap();
+
+        assert_eq!(
+            corners(&boolean(&outer, &inner, BooleanOp::Union)),
+            corners(&outer)
+        );
+        assert_eq!(
+            corners(&boolean(&outer, &inner, BooleanOp::Intersection)),
+            corners(&inner)
+        );
+        // Taking out the inner square leaves a hole, going the other way around.
+        let difference = boolean(&outer, &inner, BooleanOp::Difference);
+        assert_eq!(difference.commands().count(), 8);
+        assert_eq!(area(&difference), 84.0);
+        assert_eq!(area(&boolean(&outer, &inner, BooleanOp::Xor)), 84.0);
+        assert!(boolean(&inner, &outer, BooleanOp::Difference).is_empty());
+    }
+}
@@ -107,6 +107,25 @@ impl<T> Point<T> {
     }
 }
 
+impl Point<f32> {
+    /// Distance from the origin.
+    #[inline]
+    pub fn length(self) -> f32 {
+        self.x.hypot(self.y)
+    }
+
+    /// A unit vector pointing the same way, or `None` for the zero vector.
+    #[inline]
+    pub fn normalize(self) -> Option<Self> {
+        let len = self.length();
+        if len > 0.0 {
+            Some(self / len)
+        } else {
+            None
+        }
+    }
+}
+
 impl<T> From<(T, T)> for Point<T> {
     #[inline]
     fn from((x, y): (T, T)) -> Self {
@@ -135,26 +154,6 @@ impl<T> From<Point<T>> for [T; 2] {
     }
 }
 
-impl From<ab_glyph_rasterizer::Point> for Point<f32> {
-    #[inline]
-    fn from(point: ab_glyph_rasterizer::Point) -> Self {
-        Self {
-            x: point.x,
-            y: point.y,
-        }
-    }
-}
-
-impl From<Point<f32>> for ab_glyph_rasterizer::Point {
-    #[inline]
-    fn from(point: Point<f32>) -> Self {
-        Self {
-            x: point.x,
-            y: point.y,
-        }
-    }
-}
-
 macro_rules! binary_op {
     ($trait:ident, $func:ident, $op:tt) => {
         impl<T: $trait> $trait for Point<T> {
@@ -225,3 +224,41 @@ impl<T: Neg> Neg for Point<T> {
         }
     }
 }
+
+/// An axis-aligned rectangle, spanning from `min` to `max` inclusive.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Rect {
+    pub min: Point<f32>,
+    pub max: Point<f32>,
+}
+
+impl Rect {
+    #[inline]
+    pub const fn new(min: Point<f32>, max: Point<f32>) -> Self {
+        Self { min, max }
+    }
+
+    /// The smallest rectangle containing all of the given points, if there are any.
+    pub fn from_points<I: IntoIterator<Item = Point<f32>>>(points: I) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        let mut rect = Self::new(first, first);
+        for p in points {
+            rect.min.x = rect.min.x.min(p.x);
+            rect.min.y = rect.min.y.min(p.y);
+            rect.max.x = rect.max.x.max(p.x);
+            rect.max.y = rect.max.y.max(p.y);
+        }
+        Some(rect)
+    }
+
+    #[inline]
+    pub fn size(&self) -> Point<f32> {
+        self.max - self.min
+    }
+
+    #[inline]
+    pub fn center(&self) -> Point<f32> {
+        self.min.lerp(self.max, 0.5)
+    }
+}
@@ -0,0 +1,355 @@
+//! Saving and loading whole editing sessions, so that work can be picked up later or handed to someone else.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::codec::{self, Decode, DecodeError, Encode, Reader, Writer};
+use crate::document;
+use crate::editor::History;
+use crate::point::Point;
+use crate::snap::{Guide, SnapSettings};
+use crate::undo::HistoryMode;
+use crate::viewport::Viewport;
+
+/// Identifies a project file.
+const PROJECT_MAGIC: &[u8; 4] = b"ADRP";
+/// Bumped whenever the project format changes. Older versions are still loaded.
+///
+/// - 2: documents have layers (see `document::LAYERS_VERSION`).
+/// - 3: layers have a fill rule (see `document::FILL_RULE_VERSION`).
+/// - 4: layers have effects (see `document::EFFECTS_VERSION`).
+/// - 5: the background's placement is part of the document (see `document::BACKGROUND_VERSION`).
+const PROJECT_VERSION: u32 = 5;
+
+#[derive(Debug, Error)]
+pub enum ProjectError {
+    #[error("couldn't access the project file: {0}")]
+    Io(#[from] io::Error),
+    #[error("couldn't read the project: {0}")]
+    Decode(#[from] DecodeError),
+}
+
+/// How the drawing and the shape it fills are displayed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Colors {
+    pub drawing: [u8; 3],
+    /// The fill color new layers start out with.
+    pub shape: [u8; 3],
+    /// How opaque filled shapes are shown, in percent, so that the background shows through.
+    pub shape_alpha: u8,
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Self {
+            drawing: [0, 0, 255],
+            shape: [127, 127, 127],
+            shape_alpha: 50,
+        }
+    }
+}
+
+/// Where the background came from. How it's placed in the scene is part of the document, so that it can be undone.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BackgroundSettings {
+    /// The file the background was loaded from, if any.
+    /// The image itself is always saved with the project, so this is only a reminder.
+    pub path: Option<PathBuf>,
+}
+
+/// Information about a drawing that doesn't affect how it looks.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DrawingInfo {
+    pub name: String,
+    pub notes: String,
+}
+
+/// Everything needed to pick up editing where it was left off.
+#[derive(Debug, Clone)]
+pub struct Project {
+    /// The document, along with its undo history.
+    pub history: History,
+    pub info: DrawingInfo,
+    /// Only the scene position and scale matter; the screen size is whatever the window is.
+    pub viewport: Viewport,
+    pub background: BackgroundSettings,
+    pub colors: Colors,
+    pub snap: SnapSettings,
+    pub guides: Vec<Guide>,
+}
+
+impl Default for Project {
+    fn default() -> Self {
+        let mut history = History::default();
+        history.set_mode(HistoryMode::Tree);
+        Self::from_history(history)
+    }
+}
+
+impl Project {
+    /// A project with default settings around an existing history.
+    pub fn from_history(history: History) -> Self {
+        Self {
+            history,
+            info: DrawingInfo::default(),
+            viewport: Viewport::default(),
+            background: BackgroundSettings::default(),
+            colors: Colors::default(),
+            snap: SnapSettings::default(),
+            guides: Vec::new(),
+        }
+    }
+
+    pub fn save(&self) -> Vec<u8> {
+        let mut w = Writer::with_header(PROJECT_MAGIC, PROJECT_VERSION);
+        w.write(self);
+        w.finish()
+    }
+
+    /// Loads a project saved by `save`.
+    ///
+    /// Histories saved on their own by `document::save_history` are loaded as projects with default settings.
+    pub fn load(data: &[u8]) -> codec::Result<Self> {
+        let (mut r, _version) = match Reader::with_header(data, PROJECT_MAGIC, PROJECT_VERSION) {
+            Err(DecodeError::BadMagic) => return Self::migrate_history(data),
+            result => result?,
+        };
+        let project = r.read()?;
+        r.finish()?;
+        Ok(project)
+    }
+
+    fn migrate_history(data: &[u8]) -> codec::Result<Self> {
+        document::load_history(data).map(Self::from_history)
+    }
+
+    pub fn save_to(&self, path: &Path) -> Result<(), ProjectError> {
+        fs::write(path, self.save())?;
+        Ok(())
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self, ProjectError> {
+        let data = fs::read(path)?;
+        Ok(Self::load(&data)?)
+    }
+}
+
+impl Encode for Colors {
+    fn encode(&self, w: &mut Writer) {
+        w.write_bytes(&self.drawing);
+        w.write_bytes(&self.shape);
+        w.write(&self.shape_alpha);
+    }
+}
+
+impl Decode for Colors {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        fn rgb(r: &mut Reader<'_>) -> codec::Result<[u8; 3]> {
+            let mut rgb = [0; 3];
+            rgb.copy_from_slice(r.read_bytes(3)?);
+            Ok(rgb)
+        }
+
+        Ok(Self {
+            drawing: rgb(r)?,
+            shape: rgb(r)?,
+            shape_alpha: r.read()?,
+        })
+    }
+}
+
+impl Encode for BackgroundSettings {
+    fn encode(&self, w: &mut Writer) {
+        let path = self.path.as_ref().map(|p| p.to_string_lossy());
+        w.write(&path.as_deref());
+    }
+}
+
+impl Decode for BackgroundSettings {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        let path: Option<String> = r.read()?;
+        if document::older_than(r, document::BACKGROUND_VERSION) {
+            // An offset and scale that were never shown, so they're dropped.
+            let _: (Point<f32>, f32) = r.read()?;
+        }
+        Ok(Self {
+            path: path.map(PathBuf::from),
+        })
+    }
+}
+
+impl Encode for DrawingInfo {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.name);
+        w.write(&self.notes);
+    }
+}
+
+impl Decode for DrawingInfo {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        Ok(Self {
+            name: r.read()?,
+            notes: r.read()?,
+        })
+    }
+}
+
+impl Encode for Project {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.history);
+        w.write(&self.info);
+        w.write(&self.viewport);
+        w.write(&self.background);
+        w.write(&self.colors);
+        w.write(&self.snap);
+        w.write(&self.guides);
+    }
+}
+
+impl Decode for Project {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        Ok(Self {
+            history: r.read()?,
+            info: r.read()?,
+            viewport: r.read()?,
+            background: r.read()?,
+            colors: r.read()?,
+            snap: r.read()?,
+            guides: r.read()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::rc::Rc;
+
+    use image::RgbaImage;
+
+    use super::{Colors, DrawingInfo, Project, PROJECT_MAGIC};
+    use crate::codec::{DecodeError, Writer};
+    use crate::document::{self, Document, LayerSettings};
+    use crate::drawing::{Command, Drawing};
+    use crate::point::Point;
+    use crate::snap::Guide;
+    use crate::undo::HistoryMode;
+
+    fn sample_project() -> Project {
+        let mut project = Project::default();
+        let history = &mut project.history;
+        let drawing = &mut history.layer_mut(0).drawing;
+        drawing.push(Command::Move(Point::new(1.0, 2.0)));
+        drawing.push(Command::Line(Point::new(3.0, 4.0)));
+        history.commit_as("Add line");
+        history.insert_layer(1, LayerSettings::default());
+        history.commit_as("Add layer");
+        history.background = Some(Rc::new(RgbaImage::new(4, 3)));
+        history.commit_as("Paste background");
+        history.background_transform.offset = Point::new(8.0, -2.0);
+        history.background_transform.opacity = 0.5;
+        history.commit_as("Move background");
+
+        project.info = DrawingInfo {
+            name: "Sign".to_string(),
+            notes: "Traced from frame 1234".to_string(),
+        };
+        project.viewport.scene_pos = Point::new(-10.0, 5.5);
+        project.viewport.scale = 3.0;
+        project.background.path = Some(PathBuf::from("frames/1234.png"));
+        project.colors = Colors {
+            drawing: [255, 0, 0],
+            shape: [1, 2, 3],
+            shape_alpha: 200,
+        };
+        project.snap.grid = Some(16.0);
+        project.guides = vec![Guide::Horizontal(10.0), Guide::Vertical(-3.0)];
+        project
+    }
+
+    #[test]
+    fn test_project_round_trip() {
+        let project = sample_project();
+        let data = project.save();
+        let mut loaded = Project::load(&data).unwrap();
+        assert_eq!(loaded.save(), data);
+
+        assert_eq!(loaded.history.layers(), project.history.layers());
+        assert_eq!(loaded.info, project.info);
+        assert_eq!(loaded.viewport, project.viewport);
+        assert_eq!(loaded.background, project.background);
+        assert_eq!(loaded.colors, project.colors);
+        assert_eq!(loaded.snap, project.snap);
+        assert_eq!(loaded.guides, project.guides);
+
+        assert_eq!(loaded.history.background_size(), Some(Point::new(4.0, 3.0)));
+        assert_eq!(
+            loaded.history.background_transform,
+            project.history.background_transform
+        );
+        loaded.history.undo();
+        assert_eq!(loaded.history.background_transform, Default::default());
+        assert!(loaded.history.background.is_some());
+        loaded.history.undo();
+        assert!(loaded.history.background.is_none());
+
+        assert!(Project::load(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_project_migration() {
+        // Before there were project files, only the history could be saved.
+        let project = sample_project();
+        let data = document::save_history(&project.history);
+        let loaded = Project::load(&data).unwrap();
+        assert_eq!(loaded.history.layers(), project.history.layers());
+        assert_eq!(loaded.history.len(), project.history.len());
+        assert_eq!(loaded.colors, Colors::default());
+        assert_eq!(loaded.info, DrawingInfo::default());
+        assert!(loaded.guides.is_empty());
+
+        let future = Writer::with_header(PROJECT_MAGIC, 6).finish();
+        let err = Project::load(&future).unwrap_err();
+        assert_eq!(err, DecodeError::UnsupportedVersion(6));
+        let err = Project::load(b"GIF89a").unwrap_err();
+        assert_eq!(err, DecodeError::BadMagic);
+    }
+
+    #[test]
+    fn test_background_migration() {
+        // Before version 5, the background's offset and scale were project settings, and never shown.
+        let mut drawing = Drawing::new();
+        drawing.push(Command::Move(Point::new(1.0, 2.0)));
+        let doc = Document::from_drawing(drawing, LayerSettings::default());
+        let background = Some(Rc::new(RgbaImage::new(4, 3)));
+        let project = sample_project();
+        let mut w = Writer::with_header(PROJECT_MAGIC, 4);
+        w.write(&(doc.layers(), &background, 1u32)); // The current state
+        w.write(&None::<u8>); // No uncommitted edit
+        w.write(&0usize); // No entries
+        w.write(&None::<u64>); // The head
+        w.write(&(Vec::<u64>::new(), None::<u64>, 0u64));
+        w.write(&HistoryMode::Tree);
+        w.write(&project.info);
+        w.write(&project.viewport);
+        w.write(&Some("frames/1234.png"));
+        w.write(&(Point::new(8.0f32, -2.0), 2.0f32)); // The offset and scale
+        w.write(&project.colors);
+        w.write(&project.snap);
+        w.write(&project.guides);
+        let data = w.finish();
+
+        let loaded = Project::load(&data).unwrap();
+        assert_eq!(loaded.history.layers(), doc.layers());
+        assert_eq!(loaded.history.background_size(), Some(Point::new(4.0, 3.0)));
+        assert_eq!(loaded.history.background_transform, Default::default());
+        assert_eq!(loaded.background, project.background);
+        assert_eq!(loaded.colors, project.colors);
+        assert_eq!(loaded.snap, project.snap);
+        assert_eq!(loaded.guides, project.guides);
+        assert_eq!(Project::load(&loaded.save()).unwrap().save(), loaded.save());
+    }
+}
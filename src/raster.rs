@@ -0,0 +1,510 @@
+//! Rendering the scene in software, without touching the GPU.
+//!
+//! This produces the same picture as the OpenGL canvas, so it's useful for testing the rendering path
+//! headlessly, and as a fallback where GL isn't available.
+
+use image::{Rgba, RgbaImage};
+
+use crate::document::{BackgroundTransform, FillRule, Layer};
+use crate::drawing::{Drawing, Segment};
+use crate::point::{Point, Rect};
+use crate::project::Colors;
+use crate::snap::{Line, Overlay};
+use crate::viewport::Viewport;
+
+mod effects;
+mod libass;
+mod scanline;
+
+pub use effects::{part_colors, EffectCoverage};
+pub use scanline::flatten_within;
+
+pub const BACKGROUND_COLOR: [u8; 3] = [0, 0, 0];
+pub const GRID_COLOR: [u8; 3] = [64, 64, 64];
+pub const GUIDE_COLOR: [u8; 3] = [0, 192, 192];
+pub const HINT_COLOR: [u8; 3] = [255, 0, 255];
+/// How large nodes are drawn, in screen pixels.
+pub const POINT_SIZE: f32 = 5.0;
+/// The most pixels rasterized at once, for a shape or an exported image.
+/// Anything larger would take gigabytes, so it's refused instead.
+pub const MAX_PIXELS: u64 = 1 << 26;
+
+/// Whether a buffer of `width` by `height` pixels stays within `MAX_PIXELS`.
+pub fn fits(width: f32, height: f32) -> bool {
+    width.is_finite() && height.is_finite() && width as f64 * height as f64 <= MAX_PIXELS as f64
+}
+
+/// How drawings are turned into coverage.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum RasterMode {
+    #[default]
+    /// Quick, in floating point, with pixels lined up with the drawing's bounding box.
+    Preview,
+    /// Reproduces libass's outline handling, so that shapes look the way they will in players.
+    Libass,
+}
+
+/// How much of each pixel a drawing's filled shape covers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coverage {
+    /// Where the top-left pixel is in the scene.
+    pub pos: Point<f32>,
+    pub width: usize,
+    pub height: usize,
+    /// One byte per pixel, row by row, where 255 means fully covered.
+    pub pixels: Vec<u8>,
+}
+
+impl Coverage {
+    /// Rasterizes the shape a drawing fills, or returns `None` if it doesn't fill anything
+    /// or would take more than `MAX_PIXELS`.
+    ///
+    /// libass has only the one fill rule, so `rule` only matters for the preview.
+    pub fn rasterize(
+        drawing: &Drawing<Point<f32>>,
+        mode: RasterMode,
+        rule: FillRule,
+    ) -> Option<Self> {
+        match mode {
+            RasterMode::Preview => Self::rasterize_preview(drawing, rule),
+            RasterMode::Libass => libass::rasterize(drawing),
+        }
+    }
+
+    fn rasterize_preview(drawing: &Drawing<Point<f32>>, rule: FillRule) -> Option<Self> {
+        let mut segments = Vec::new();
+        let mut min = Point::new(f32::MAX, f32::MAX);
+        let mut max = Point::new(f32::MIN, f32::MIN);
+        for seg in drawing.segments() {
+            for pt in seg.points() {
+                min.x = min.x.min(pt.x);
+                min.y = min.y.min(pt.y);
+                max.x = max.x.max(pt.x);
+                max.y = max.y.max(pt.y);
+            }
+            segments.push(seg);
+        }
+
+        let size = max - min;
+        if segments.is_empty()
+            || size.x <= 0.0
+            || size.y <= 0.0
+            || !fits(size.x.ceil(), size.y.ceil())
+        {
+            return None;
+        }
+        let (width, height) = (size.x.ceil() as usize, size.y.ceil() as usize);
+
+        let mut edges = Vec::new();
+        for segment in segments {
+            match segment {
+                Segment::Line(p0, p1) | Segment::ClosingLine(p0, p1) => {
+                    edges.push((p0 - min, p1 - min));
+                }
+                Segment::Bezier(p0, p1, p2, p3) => {
+                    let mut points = vec![p0 - min];
+                    scanline::flatten([p0 - min, p1 - min, p2 - min, p3 - min], &mut points);
+                    edges.extend(points.windows(2).map(|w| (w[0], w[1])));
+                }
+            }
+        }
+
+        Some(Self {
+            pos: min,
+            width,
+            height,
+            pixels: scanline::sample(&edges, width, height, rule),
+        })
+    }
+
+    /// The coverage of the pixel containing a scene point, which is zero outside the shape's bounds.
+    pub fn at(&self, scene_pos: Point<f32>) -> u8 {
+        let p = scene_pos - self.pos;
+        if p.x < 0.0 || p.y < 0.0 {
+            return 0;
+        }
+        let (x, y) = (p.x as usize, p.y as usize);
+        if x >= self.width || y >= self.height {
+            return 0;
+        }
+        self.pixels[y * self.width + x]
+    }
+}
+
+/// How opaque part of a layer's shape is drawn, in percent.
+///
+/// The preview opacity applies on top of the part's own alpha, e.g. `\1a`.
+pub fn shape_opacity(colors: &Colors, alpha: u8) -> u32 {
+    colors.shape_alpha as u32 * (255 - alpha as u32) / 255
+}
+
+/// Rasterizes a layer's shape along with its border and shadow, or returns `None` if it doesn't fill anything.
+pub fn rasterize_layer(layer: &Layer, mode: RasterMode) -> Option<EffectCoverage> {
+    let settings = &layer.settings;
+    let coverage = Coverage::rasterize(&layer.drawing, mode, settings.fill_rule)?;
+    let margin = effects::margin(&settings.effects) as f32 * 2.0;
+    if !fits(
+        coverage.width as f32 + margin,
+        coverage.height as f32 + margin,
+    ) {
+        return None;
+    }
+    Some(effects::apply(&coverage, &settings.effects))
+}
+
+/// Everything the layer's shape can cover, effects included, or `None` if the drawing is empty.
+/// This is known without rasterizing, so that a shape too large for it can be caught first.
+pub fn layer_extent(layer: &Layer) -> Option<Rect> {
+    let bounds = layer.drawing.bounds()?;
+    let effects = &layer.settings.effects;
+    let margin = effects::margin(effects) as f32;
+    let shadow = effects.shadow.max(0.0);
+    Some(Rect::new(bounds.min - margin, bounds.max + margin + shadow))
+}
+
+/// The control lines shown for a drawing: its lines, and the handles of its curves.
+pub fn handle_lines(drawing: &Drawing<Point<f32>>) -> Vec<Line> {
+    let mut lines = Vec::new();
+    for seg in drawing.segments() {
+        match seg {
+            Segment::Line(p0, p1) => lines.push((p0, p1)),
+            // Don't draw a line for a shape's closing line.
+            Segment::ClosingLine(..) => (),
+            Segment::Bezier(p0, p1, p2, p3) => {
+                lines.push((p0, p1));
+                lines.push((p2, p3));
+            }
+        }
+    }
+    lines
+}
+
+/// Everything that makes up a picture of the document.
+#[derive(Debug, Clone, Copy)]
+pub struct Scene<'a> {
+    /// Drawn under everything else, like the canvas does.
+    pub background: Option<&'a RgbaImage>,
+    pub background_transform: BackgroundTransform,
+    /// From bottom to top.
+    pub layers: &'a [Layer],
+    /// The layer whose nodes and control lines are shown, if any.
+    pub active: Option<usize>,
+    pub colors: Colors,
+    pub overlay: Option<&'a Overlay>,
+    pub mode: RasterMode,
+}
+
+/// Renders the scene as it appears in the viewport.
+pub fn render(scene: &Scene<'_>, viewport: &Viewport) -> RgbaImage {
+    let dims = viewport.screen_dims.map(|d| d.max(0.0).round() as u32);
+    let [r, g, b] = BACKGROUND_COLOR;
+    let mut img = RgbaImage::from_pixel(dims.x, dims.y, Rgba([r, g, b, 255]));
+
+    if let Some(background) = scene.background {
+        for_each_pixel(&mut img, viewport, |px, p| {
+            blend_background(px, background, &scene.background_transform, p);
+        });
+    }
+
+    for layer in scene.layers {
+        let settings = &layer.settings;
+        if !settings.visible {
+            continue;
+        }
+        let shape = match rasterize_layer(layer, scene.mode) {
+            Some(shape) => shape,
+            None => continue,
+        };
+        for (part, (color, alpha)) in shape.parts().iter().zip(&part_colors(settings)) {
+            if let Some(coverage) = part {
+                let opacity = shape_opacity(&scene.colors, *alpha) as f32 / 100.0;
+                fill(&mut img, coverage, *color, opacity, viewport);
+            }
+        }
+    }
+
+    if let Some(overlay) = scene.overlay {
+        let groups = [
+            (&overlay.grid, GRID_COLOR),
+            (&overlay.guides, GUIDE_COLOR),
+            (&overlay.hints, HINT_COLOR),
+        ];
+        for (lines, color) in groups.iter() {
+            for &(a, b) in lines.iter() {
+                draw_line(&mut img, viewport, a, b, *color);
+            }
+        }
+    }
+
+    if let Some(layer) = scene.active.and_then(|i| scene.layers.get(i)) {
+        let color = scene.colors.drawing;
+        for &pt in layer.drawing.points() {
+            draw_point(&mut img, viewport, pt, color);
+        }
+        for (a, b) in handle_lines(&layer.drawing) {
+            draw_line(&mut img, viewport, a, b, color);
+        }
+    }
+
+    img
+}
+
+/// Calls `f` with each pixel of `img` and the scene point at its center.
+fn for_each_pixel(
+    img: &mut RgbaImage,
+    viewport: &Viewport,
+    mut f: impl FnMut(&mut Rgba<u8>, Point<f32>),
+) {
+    for (x, y, px) in img.enumerate_pixels_mut() {
+        let screen_pos = Point::new(x as f32 + 0.5, y as f32 + 0.5);
+        f(px, viewport.screen_to_scene(screen_pos));
+    }
+}
+
+/// Blends the background pixel at the scene point `p` over `px`, by the pixel's own alpha times
+/// the background's opacity, the same way the canvas blends it.
+pub fn blend_background(
+    px: &mut Rgba<u8>,
+    background: &RgbaImage,
+    transform: &BackgroundTransform,
+    p: Point<f32>,
+) {
+    let (width, height) = background.dimensions();
+    let p = transform.to_image(p);
+    if p.x < 0.0 || p.y < 0.0 || p.x >= width as f32 || p.y >= height as f32 {
+        return;
+    }
+    let src = background.get_pixel(p.x as u32, p.y as u32);
+    let alpha = src[3] as f32 / 255.0 * transform.opacity.clamp(0.0, 1.0);
+    for i in 0..3 {
+        let blended = px[i] as f32 * (1.0 - alpha) + src[i] as f32 * alpha;
+        px[i] = blended.round() as u8;
+    }
+}
+
+fn fill(
+    img: &mut RgbaImage,
+    coverage: &Coverage,
+    color: [u8; 3],
+    opacity: f32,
+    viewport: &Viewport,
+) {
+    for_each_pixel(img, viewport, |px, p| {
+        let a = coverage.at(p) as f32 / 255.0 * opacity;
+        if a > 0.0 {
+            blend(px, color, a);
+        }
+    });
+}
+
+/// Blends `color` over the pixel with the given opacity, from 0 to 1.
+fn blend(px: &mut Rgba<u8>, color: [u8; 3], alpha: f32) {
+    for (dst, &src) in px.0.iter_mut().zip(&color) {
+        *dst = (*dst as f32 * (1.0 - alpha) + src as f32 * alpha).round() as u8;
+    }
+}
+
+fn put(img: &mut RgbaImage, x: i64, y: i64, [r, g, b]: [u8; 3]) {
+    if x >= 0 && y >= 0 && x < img.width() as i64 && y < img.height() as i64 {
+        img.put_pixel(x as u32, y as u32, Rgba([r, g, b, 255]));
+    }
+}
+
+/// Draws a square node centered on a scene point.
+fn draw_point(img: &mut RgbaImage, viewport: &Viewport, pt: Point<f32>, color: [u8; 3]) {
+    let corner = viewport.scene_to_screen(pt) - POINT_SIZE / 2.0;
+    let (x0, y0) = (corner.x.round() as i64, corner.y.round() as i64);
+    let size = POINT_SIZE as i64;
+    for y in y0..y0 + size {
+        for x in x0..x0 + size {
+            put(img, x, y, color);
+        }
+    }
+}
+
+/// Draws a one pixel wide line between two scene points.
+fn draw_line(
+    img: &mut RgbaImage,
+    viewport: &Viewport,
+    a: Point<f32>,
+    b: Point<f32>,
+    color: [u8; 3],
+) {
+    let (a, b) = (viewport.scene_to_screen(a), viewport.scene_to_screen(b));
+    let delta = b - a;
+    // Lines can reach far off screen, e.g. guides, so only step through the visible part.
+    let limit = (img.width() + img.height()) as f32 * 4.0;
+    let steps = delta.x.abs().max(delta.y.abs()).ceil().min(limit) as usize;
+    for i in 0..=steps {
+        let t = if steps == 0 {
+            0.0
+        } else {
+            i as f32 / steps as f32
+        };
+        let p = a.lerp(b, t);
+        put(img, p.x.floor() as i64, p.y.floor() as i64, color);
+    }
+}
+
+/// Also used by the tests of modules that render scenes.
+#[cfg(test)]
+pub mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::{render, Coverage, RasterMode, Scene, BACKGROUND_COLOR};
+    use crate::document::{BackgroundTransform, Document, Effects, FillRule, Layer, LayerSettings};
+    use crate::drawing::{Command, Drawing};
+    use crate::point::Point;
+    use crate::project::Colors;
+    use crate::viewport::Viewport;
+
+    pub fn square(x: f32, y: f32, size: f32) -> Drawing<Point<f32>> {
+        let mut drawing = Drawing::new();
+        drawing.push(Command::Move(Point::new(x, y)));
+        drawing.push(Command::Line(Point::new(x + size, y)));
+        drawing.push(Command::Line(Point::new(x + size, y + size)));
+        drawing.push(Command::Line(Point::new(x, y + size)));
+        drawing
+    }
+
+    /// The layers over the background, previewed with the default colors.
+    pub fn scene<'a>(layers: &'a [Layer], background: Option<&'a RgbaImage>) -> Scene<'a> {
+        Scene {
+            background,
+            background_transform: BackgroundTransform::default(),
+            layers,
+            active: None,
+            colors: Colors::default(),
+            overlay: None,
+            mode: RasterMode::Preview,
+        }
+    }
+
+    fn viewport(width: f32, height: f32, scale: f32) -> Viewport {
+        Viewport {
+            screen_dims: Point::new(width, height),
+            scene_pos: Point::new(0.0, 0.0),
+            scale,
+        }
+    }
+
+    #[test]
+    fn test_coverage() {
+        let coverage = Coverage::rasterize(
+            &square(2.0, 3.0, 4.0),
+            RasterMode::Preview,
+            FillRule::NonZero,
+        )
+        .unwrap();
+        assert_eq!(coverage.pos, Point::new(2.0, 3.0));
+        assert_eq!((coverage.width, coverage.height), (4, 4));
+        assert!(coverage.pixels.iter().all(|&v| v == 255));
+        assert_eq!(coverage.at(Point::new(5.5, 6.5)), 255);
+        assert_eq!(coverage.at(Point::new(6.5, 6.5)), 0);
+        assert_eq!(coverage.at(Point::new(1.5, 3.5)), 0);
+
+        // Half of each pixel in the middle column is covered.
+        let mut drawing = square(0.0, 0.0, 2.0);
+        drawing.points_mut()[1].x = 1.5;
+        drawing.points_mut()[2].x = 1.5;
+        let coverage =
+            Coverage::rasterize(&drawing, RasterMode::Preview, FillRule::NonZero).unwrap();
+        assert_eq!(coverage.pixels, vec![255, 128, 255, 128]);
+
+        assert!(
+            Coverage::rasterize(&Drawing::new(), RasterMode::Preview, FillRule::NonZero).is_none()
+        );
+        // Too large to allocate, in either mode.
+        for mode in [RasterMode::Preview, RasterMode::Libass] {
+            for size in [100000.0, 1e8] {
+                let huge = square(0.0, 0.0, size);
+                assert!(Coverage::rasterize(&huge, mode, FillRule::NonZero).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let mut background = RgbaImage::from_pixel(8, 8, Rgba([200, 100, 0, 255]));
+        background.put_pixel(1, 1, Rgba([0, 255, 0, 255]));
+        background.put_pixel(2, 0, Rgba([200, 100, 0, 128]));
+
+        let blue = LayerSettings {
+            color: [0, 0, 255],
+            ..LayerSettings::default()
+        };
+        let mut doc = Document::from_drawing(square(4.0, 4.0, 2.0), blue);
+        let hidden = LayerSettings {
+            visible: false,
+            ..LayerSettings::default()
+        };
+        doc.insert_layer(1, hidden);
+        doc.layer_mut(1).drawing = square(0.0, 0.0, 8.0);
+        let layers: Vec<Layer> = doc.layers().to_vec();
+
+        let colors = Colors {
+            drawing: [255, 0, 0],
+            shape: [0; 3],
+            shape_alpha: 100,
+        };
+        let mut scene = Scene {
+            colors,
+            ..scene(&layers, Some(&background))
+        };
+
+        // Zoomed in 2x, so each scene pixel is 2x2 on screen.
+        let vp = viewport(20.0, 20.0, 2.0);
+        let img = render(&scene, &vp);
+        assert_eq!(img.dimensions(), (20, 20));
+        assert_eq!(*img.get_pixel(0, 0), Rgba([200, 100, 0, 255]));
+        assert_eq!(*img.get_pixel(3, 2), Rgba([0, 255, 0, 255]));
+        assert_eq!(*img.get_pixel(9, 9), Rgba([0, 0, 255, 255]));
+        let [r, g, b] = BACKGROUND_COLOR;
+        assert_eq!(*img.get_pixel(17, 17), Rgba([r, g, b, 255]));
+        // A half transparent background pixel lets half of the backdrop through.
+        assert_eq!(*img.get_pixel(4, 0), Rgba([100, 50, 0, 255]));
+
+        // The shape lets half of the background through.
+        scene.colors.shape_alpha = 50;
+        let img = render(&scene, &vp);
+        assert_eq!(*img.get_pixel(9, 9), Rgba([100, 50, 128, 255]));
+
+        // Nodes are drawn on top of everything.
+        scene.active = Some(0);
+        let img = render(&scene, &vp);
+        assert_eq!(*img.get_pixel(8, 8), Rgba([255, 0, 0, 255]));
+        assert_eq!(*img.get_pixel(11, 11), Rgba([255, 0, 0, 255]));
+        assert_eq!(*img.get_pixel(10, 8), Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn test_render_effects() {
+        let settings = LayerSettings {
+            color: [0, 0, 255],
+            effects: Effects {
+                border: 1.0,
+                border_color: [255, 255, 255],
+                shadow: 3.0,
+                shadow_color: [0, 255, 0],
+                ..Effects::default()
+            },
+            ..LayerSettings::default()
+        };
+        let doc = Document::from_drawing(square(4.0, 4.0, 2.0), settings);
+        let scene = Scene {
+            colors: Colors {
+                shape_alpha: 100,
+                ..Colors::default()
+            },
+            ..scene(doc.layers(), None)
+        };
+
+        // The fill is on top of the border, which is on top of the shadow.
+        let img = render(&scene, &viewport(12.0, 12.0, 1.0));
+        assert_eq!(*img.get_pixel(4, 4), Rgba([0, 0, 255, 255]));
+        assert_eq!(*img.get_pixel(3, 5), Rgba([255, 255, 255, 255]));
+        assert_eq!(*img.get_pixel(6, 5), Rgba([255, 255, 255, 255]));
+        assert_eq!(*img.get_pixel(8, 8), Rgba([0, 255, 0, 255]));
+        assert_eq!(*img.get_pixel(1, 1), Rgba([0, 0, 0, 255]));
+    }
+}
@@ -0,0 +1,405 @@
+//! The border, shadow and blur that libass adds to a shape after filling it.
+//!
+//! Each stage works on coverage buffers. Like libass, the border is grown from the fill, the blur
+//! softens the border if there is one and the fill otherwise, and the shadow is a copy of whatever
+//! was blurred, offset down and to the right.
+
+use super::Coverage;
+use crate::document::{Effects, LayerSettings};
+use crate::point::Point;
+
+/// How many standard deviations out the gaussian kernel reaches before it's cut off.
+const GAUSSIAN_EXTENT: f32 = 3.0;
+
+/// A shape's coverage, along with its border and shadow, if it has them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectCoverage {
+    pub shadow: Option<Coverage>,
+    pub border: Option<Coverage>,
+    pub fill: Coverage,
+}
+
+impl EffectCoverage {
+    /// The shadow, border and fill, from the bottom up.
+    pub fn parts(&self) -> [Option<&Coverage>; 3] {
+        [self.shadow.as_ref(), self.border.as_ref(), Some(&self.fill)]
+    }
+}
+
+/// The colors and alphas of a layer's shadow, border and fill, from the bottom up.
+pub fn part_colors(settings: &LayerSettings) -> [([u8; 3], u8); 3] {
+    let effects = &settings.effects;
+    [
+        (effects.shadow_color, effects.shadow_alpha),
+        (effects.border_color, effects.border_alpha),
+        (settings.color, settings.alpha),
+    ]
+}
+
+/// The standard deviation of the gaussian libass uses for a given `\blur`.
+pub fn blur_sigma(blur: f32) -> f32 {
+    2.0 * blur / 256f32.ln().sqrt()
+}
+
+/// Adds `margin` empty pixels around every side.
+pub fn pad(coverage: &Coverage, margin: usize) -> Coverage {
+    let width = coverage.width + margin * 2;
+    let height = coverage.height + margin * 2;
+    let mut pixels = vec![0; width * height];
+    for (y, row) in coverage.pixels.chunks(coverage.width.max(1)).enumerate() {
+        let start = (y + margin) * width + margin;
+        pixels[start..start + row.len()].copy_from_slice(row);
+    }
+    Coverage {
+        pos: coverage.pos - margin as f32,
+        width,
+        height,
+        pixels,
+    }
+}
+
+/// The squared distance from each position on a line to the nearest source, and which source that is,
+/// given how far each position is from a source along the other axis, squared (or infinite if none is).
+///
+/// This is the lower envelope of parabolas from Felzenszwalb and Huttenlocher's distance transform.
+fn nearest_sources(f: &[f32], out: &mut [(f32, usize)]) {
+    // The parabolas that make up the envelope, and where each one starts.
+    let mut parabolas: Vec<usize> = Vec::new();
+    let mut starts: Vec<f32> = Vec::new();
+    for (q, &fq) in f.iter().enumerate() {
+        if !fq.is_finite() {
+            continue;
+        }
+        while let Some(&p) = parabolas.last() {
+            let (pf, qf) = (p as f32, q as f32);
+            let start = ((fq + qf * qf) - (f[p] + pf * pf)) / (2.0 * (qf - pf));
+            if start > *starts.last().unwrap() {
+                parabolas.push(q);
+                starts.push(start);
+                break;
+            }
+            parabolas.pop();
+            starts.pop();
+        }
+        if parabolas.is_empty() {
+            parabolas.push(q);
+            starts.push(f32::NEG_INFINITY);
+        }
+    }
+
+    let mut k = 0;
+    for (q, out) in out.iter_mut().enumerate() {
+        if parabolas.is_empty() {
+            *out = (f32::INFINITY, 0);
+            continue;
+        }
+        while k + 1 < parabolas.len() && starts[k + 1] < q as f32 {
+            k += 1;
+        }
+        let p = parabolas[k];
+        let d = q as f32 - p as f32;
+        *out = (d * d + f[p], p);
+    }
+}
+
+/// Grows the shape by `radius` pixels in every direction, with rounded corners.
+///
+/// Each pixel is covered by how far it is from the nearest covered pixel, less how much of that pixel
+/// is uncovered, so the edges stay antialiased. Finding the nearest pixels with a distance transform
+/// takes the same time whatever the radius.
+///
+/// Pixels near the edge of the buffer are grown outwards only as far as the buffer reaches,
+/// so it should be padded by at least `radius` first.
+pub fn outline(coverage: &Coverage, radius: f32) -> Coverage {
+    let (width, height) = (coverage.width, coverage.height);
+    let mut pixels = vec![0; coverage.pixels.len()];
+
+    // Down each column first: how far each pixel is from a covered one in the same column, and which row that's in.
+    let mut columns = vec![(f32::INFINITY, 0); pixels.len()];
+    let mut f = vec![0.0; height];
+    let mut nearest = vec![(0.0, 0); height];
+    for x in 0..width {
+        for (y, f) in f.iter_mut().enumerate() {
+            *f = if coverage.pixels[y * width + x] > 0 {
+                0.0
+            } else {
+                f32::INFINITY
+            };
+        }
+        nearest_sources(&f, &mut nearest);
+        for (y, &n) in nearest.iter().enumerate() {
+            columns[y * width + x] = n;
+        }
+    }
+
+    // Then along each row, which finds the nearest covered pixel overall.
+    let mut f = vec![0.0; width];
+    let mut nearest = vec![(0.0, 0); width];
+    for y in 0..height {
+        let row = &columns[y * width..(y + 1) * width];
+        for (f, &(distance, _)) in f.iter_mut().zip(row) {
+            *f = distance;
+        }
+        nearest_sources(&f, &mut nearest);
+        for (x, &(distance, sx)) in nearest.iter().enumerate() {
+            if !distance.is_finite() {
+                continue;
+            }
+            let sy = row[sx].1;
+            let uncovered = 1.0 - coverage.pixels[sy * width + sx] as f32 / 255.0;
+            let value = (radius + 1.0 - distance.sqrt() - uncovered).clamp(0.0, 1.0);
+            pixels[y * width + x] = (value * 255.0).round() as u8;
+        }
+    }
+    Coverage {
+        pixels,
+        ..coverage.clone()
+    }
+}
+
+/// Runs a separable filter over the rows and then the columns.
+fn convolve(coverage: &mut Coverage, mut filter: impl FnMut(&mut [u8])) {
+    let (width, height) = (coverage.width, coverage.height);
+    if width == 0 || height == 0 {
+        return;
+    }
+    for row in coverage.pixels.chunks_mut(width) {
+        filter(row);
+    }
+    let mut column = vec![0; height];
+    for x in 0..width {
+        for (y, v) in column.iter_mut().enumerate() {
+            *v = coverage.pixels[y * width + x];
+        }
+        filter(&mut column);
+        for (y, &v) in column.iter().enumerate() {
+            coverage.pixels[y * width + x] = v;
+        }
+    }
+}
+
+/// `\be`: softens the edges with `passes` rounds of a 3x3 `[1 2 1]` blur.
+pub fn box_blur(coverage: &mut Coverage, passes: u32) {
+    for _ in 0..passes {
+        convolve(coverage, |line| {
+            let mut prev = 0;
+            for i in 0..line.len() {
+                let next = line.get(i + 1).copied().unwrap_or(0) as u16;
+                let cur = line[i] as u16;
+                line[i] = ((prev + cur * 2 + next + 2) / 4) as u8;
+                prev = cur;
+            }
+        });
+    }
+}
+
+/// `\blur`: a gaussian blur with the strength libass gives it.
+pub fn gaussian_blur(coverage: &mut Coverage, blur: f32) {
+    let sigma = blur_sigma(blur);
+    if sigma <= 0.0 {
+        return;
+    }
+    let reach = (sigma * GAUSSIAN_EXTENT).ceil() as isize;
+    let mut kernel: Vec<f32> = (-reach..=reach)
+        .map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp())
+        .collect();
+    let total: f32 = kernel.iter().sum();
+    kernel.iter_mut().for_each(|k| *k /= total);
+
+    let mut src = Vec::new();
+    convolve(coverage, |line| {
+        src.clear();
+        src.extend_from_slice(line);
+        for (i, out) in line.iter_mut().enumerate() {
+            let mut value = 0.0;
+            for (j, &k) in kernel.iter().enumerate() {
+                let s = i as isize + j as isize - reach;
+                if s >= 0 && (s as usize) < src.len() {
+                    value += src[s as usize] as f32 * k;
+                }
+            }
+            *out = value.round().min(255.0) as u8;
+        }
+    });
+}
+
+/// How far the effects can reach outside the shape, in whole pixels.
+pub fn margin(effects: &Effects) -> usize {
+    let border = effects.border.max(0.0).ceil() as usize;
+    let blur = (blur_sigma(effects.blur.max(0.0)) * GAUSSIAN_EXTENT).ceil() as usize;
+    border
+        .saturating_add(effects.be as usize)
+        .saturating_add(blur)
+}
+
+/// Adds a layer's border, shadow and blur to its fill.
+pub fn apply(fill: &Coverage, effects: &Effects) -> EffectCoverage {
+    let margin = margin(effects);
+    let mut fill = pad(fill, margin);
+    let mut border = if effects.border > 0.0 {
+        Some(outline(&fill, effects.border))
+    } else {
+        None
+    };
+
+    let blurred = border.as_mut().unwrap_or(&mut fill);
+    box_blur(blurred, effects.be);
+    gaussian_blur(blurred, effects.blur);
+
+    let shadow = if effects.shadow > 0.0 {
+        let mut shadow = blurred.clone();
+        shadow.pos += Point::new(effects.shadow, effects.shadow);
+        Some(shadow)
+    } else {
+        None
+    };
+
+    EffectCoverage {
+        shadow,
+        border,
+        fill,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, box_blur, gaussian_blur, outline, pad};
+    use crate::document::Effects;
+    use crate::point::Point;
+    use crate::raster::Coverage;
+
+    fn coverage(width: usize, pixels: Vec<u8>) -> Coverage {
+        Coverage {
+            pos: Point::new(10.0, 20.0),
+            width,
+            height: pixels.len() / width,
+            pixels,
+        }
+    }
+
+    fn dot(size: usize) -> Coverage {
+        let mut pixels = vec![0; size * size];
+        pixels[size * size / 2] = 255;
+        coverage(size, pixels)
+    }
+
+    #[test]
+    fn test_pad() {
+        let padded = pad(&coverage(2, vec![1, 2, 3, 4]), 1);
+        assert_eq!(padded.pos, Point::new(9.0, 19.0));
+        assert_eq!((padded.width, padded.height), (4, 4));
+        #[rustfmt::skip]
+        assert_eq!(padded.pixels, vec![
+            0, 0, 0, 0,
+            0, 1, 2, 0,
+            0, 3, 4, 0,
+            0, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn test_outline() {
+        let border = outline(&dot(5), 1.0);
+        #[rustfmt::skip]
+        assert_eq!(border.pixels, vec![
+            0, 0, 0, 0, 0,
+            0, 149, 255, 149, 0,
+            0, 255, 255, 255, 0,
+            0, 149, 255, 149, 0,
+            0, 0, 0, 0, 0,
+        ]);
+
+        // Partial coverage stays partial.
+        let mut half = dot(5);
+        half.pixels[12] = 128;
+        assert_eq!(outline(&half, 1.0).pixels[7], 128);
+
+        // The distance transform finds pixels as near as looking at every one of them would.
+        let mut seed = 1_u32;
+        let pixels = (0..12 * 9)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                [0, 0, 0, 0, 0, 0, 0, 255][(seed >> 16) as usize % 8]
+            })
+            .collect();
+        let scattered = coverage(12, pixels);
+        let border = outline(&scattered, 2.5);
+        for (i, &value) in border.pixels.iter().enumerate() {
+            let (x, y) = ((i % 12) as f32, (i / 12) as f32);
+            let expected = scattered
+                .pixels
+                .iter()
+                .enumerate()
+                .filter(|&(_, &c)| c > 0)
+                .map(|(j, _)| {
+                    let (dx, dy) = ((j % 12) as f32 - x, (j / 12) as f32 - y);
+                    (3.5 - (dx * dx + dy * dy).sqrt()).clamp(0.0, 1.0)
+                })
+                .fold(0.0_f32, f32::max);
+            assert_eq!(value, (expected * 255.0).round() as u8, "{}", i);
+        }
+    }
+
+    #[test]
+    fn test_box_blur() {
+        let mut blurred = dot(5);
+        box_blur(&mut blurred, 1);
+        #[rustfmt::skip]
+        assert_eq!(blurred.pixels, vec![
+            0, 0, 0, 0, 0,
+            0, 16, 32, 16, 0,
+            0, 32, 64, 32, 0,
+            0, 16, 32, 16, 0,
+            0, 0, 0, 0, 0,
+        ]);
+
+        // Each pass spreads it one pixel further.
+        box_blur(&mut blurred, 1);
+        assert_ne!(blurred.pixels[0], 0);
+    }
+
+    #[test]
+    fn test_gaussian_blur() {
+        let mut blurred = dot(9);
+        gaussian_blur(&mut blurred, 1.0);
+        let center = blurred.pixels[40];
+        assert!(center < 255 && center > 0);
+        // The blur is symmetric, and falls off away from the center.
+        assert_eq!(blurred.pixels[39], blurred.pixels[41]);
+        assert_eq!(blurred.pixels[31], blurred.pixels[49]);
+        assert!(blurred.pixels[39] < center && blurred.pixels[38] < blurred.pixels[39]);
+        let total: u32 = blurred.pixels.iter().map(|&v| v as u32).sum();
+        assert!((240..=270).contains(&total), "{}", total);
+
+        let mut unchanged = dot(3);
+        gaussian_blur(&mut unchanged, 0.0);
+        assert_eq!(unchanged, dot(3));
+    }
+
+    #[test]
+    fn test_apply() {
+        let fill = coverage(1, vec![255]);
+        let plain = apply(&fill, &Effects::default());
+        assert_eq!(plain.fill, fill);
+        assert!(plain.border.is_none() && plain.shadow.is_none());
+
+        let effects = Effects {
+            border: 1.0,
+            shadow: 2.0,
+            be: 1,
+            ..Effects::default()
+        };
+        let result = apply(&fill, &effects);
+        // Room for the border and one pass of `\be`.
+        assert_eq!(result.fill.width, 5);
+        assert_eq!(result.fill.pos, Point::new(8.0, 18.0));
+        // With a border, the border is blurred and the fill isn't.
+        assert_eq!(result.fill.pixels.iter().filter(|&&v| v != 0).count(), 1);
+        let border = result.border.unwrap();
+        assert!(border.pixels[0] > 0);
+        // The shadow is the blurred border, moved.
+        let shadow = result.shadow.unwrap();
+        assert_eq!(shadow.pixels, border.pixels);
+        assert_eq!(shadow.pos, border.pos + 2.0);
+    }
+}
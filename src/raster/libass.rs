@@ -0,0 +1,315 @@
+//! Rasterizing drawings the way libass does, so that the preview matches what players show.
+//!
+//! libass differs from the preview rasterizer in a few ways that are visible on screen:
+//! coordinates are truncated to 26.6 fixed point, curves are flattened into lines with a fixed tolerance,
+//! and pixels are aligned to the scene origin rather than to the drawing's bounding box.
+//! Coverage is accumulated with nonzero winding and clamped, with full coverage being 256 before clamping to 255.
+
+use super::Coverage;
+use crate::drawing::{Drawing, Segment};
+use crate::point::Point;
+
+/// One pixel in 26.6 fixed point.
+const ONE: i32 = 64;
+/// How far flattened curves may stray from the real ones, in 1/64 pixels.
+const OUTLINE_ERROR: i64 = 16;
+/// How many times a curve is split at most, which bounds the number of lines it turns into.
+const MAX_SPLITS: u32 = 16;
+/// How far from the origin libass lets outline points be, in 26.6 fixed point.
+/// Drawings that go any further aren't drawn at all.
+const OUTLINE_MAX: i32 = (1 << 28) - 1;
+
+/// Converts a drawing coordinate to 26.6 fixed point, the same way libass's drawing parser does.
+#[inline]
+pub fn double_to_d6(val: f32) -> i32 {
+    (val as f64 * 64.0) as i32
+}
+
+type Vector = Point<i32>;
+
+/// Whether a curve is close enough to the line between its ends to be drawn as one.
+fn is_flat([p0, p1, p2, p3]: [Vector; 4]) -> bool {
+    let chord = (p3 - p0).cast::<i64>();
+    let len_sq = chord.x * chord.x + chord.y * chord.y;
+    let deviates = |p: Vector| {
+        let d = (p - p0).cast::<i64>();
+        if len_sq == 0 {
+            d.x * d.x + d.y * d.y > OUTLINE_ERROR * OUTLINE_ERROR
+        } else {
+            let cross = chord.x * d.y - chord.y * d.x;
+            (cross * cross) as f64 > (OUTLINE_ERROR * OUTLINE_ERROR) as f64 * len_sq as f64
+        }
+    };
+    !deviates(p1) && !deviates(p2)
+}
+
+/// Splits a curve in half until each piece is flat, and adds the end of every piece to `out`.
+fn flatten_cubic(p: [Vector; 4], splits: u32, out: &mut Vec<Vector>) {
+    if splits == 0 || is_flat(p) {
+        out.push(p[3]);
+        return;
+    }
+    let mid = |a: Vector, b: Vector| (a + b) >> 1;
+    let p01 = mid(p[0], p[1]);
+    let p12 = mid(p[1], p[2]);
+    let p23 = mid(p[2], p[3]);
+    let p012 = mid(p01, p12);
+    let p123 = mid(p12, p23);
+    let center = mid(p012, p123);
+    flatten_cubic([p[0], p01, p012, center], splits - 1, out);
+    flatten_cubic([center, p123, p23, p[3]], splits - 1, out);
+}
+
+/// The outline of a drawing as lines in 26.6 fixed point,
+/// or `None` if it has points that are too far out for libass.
+fn outline(drawing: &Drawing<Point<f32>>) -> Option<Vec<(Vector, Vector)>> {
+    let d6 = |p: Point<f32>| {
+        let p = Point::new(double_to_d6(p.x), double_to_d6(p.y));
+        // Keeping well inside `i32` also means the arithmetic on points below can't overflow.
+        Some(p).filter(|p| p.x.abs() <= OUTLINE_MAX && p.y.abs() <= OUTLINE_MAX)
+    };
+    let mut lines = Vec::new();
+    for seg in drawing.segments() {
+        match seg {
+            Segment::Line(p0, p1) | Segment::ClosingLine(p0, p1) => lines.push((d6(p0)?, d6(p1)?)),
+            Segment::Bezier(p0, p1, p2, p3) => {
+                let p0 = d6(p0)?;
+                let mut points = vec![p0];
+                flatten_cubic([p0, d6(p1)?, d6(p2)?, d6(p3)?], MAX_SPLITS, &mut points);
+                lines.extend(points.windows(2).map(|w| (w[0], w[1])));
+            }
+        }
+    }
+    Some(lines)
+}
+
+/// Signed area accumulated per pixel, summed across each row to get coverage.
+struct Accumulator {
+    width: usize,
+    height: usize,
+    /// A couple of extra cells, since lines along the right edge spill over.
+    cells: Vec<f64>,
+}
+
+impl Accumulator {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![0.0; width * height + 2],
+        }
+    }
+
+    /// Adds a line, in pixels relative to the top-left corner.
+    fn line(&mut self, p0: Point<f64>, p1: Point<f64>) {
+        if p0.y == p1.y {
+            return;
+        }
+        let (dir, p0, p1) = if p0.y < p1.y {
+            (1.0, p0, p1)
+        } else {
+            (-1.0, p1, p0)
+        };
+        let dxdy = (p1.x - p0.x) / (p1.y - p0.y);
+        let mut x = p0.x;
+        let y_end = (p1.y.ceil() as usize).min(self.height);
+        for y in p0.y.max(0.0) as usize..y_end {
+            let row = y * self.width;
+            let dy = p1.y.min(y as f64 + 1.0) - p0.y.max(y as f64);
+            let x_next = x + dxdy * dy;
+            let d = dy * dir;
+            let (x0, x1) = if x < x_next { (x, x_next) } else { (x_next, x) };
+            let x0_floor = x0.floor();
+            let x0i = x0_floor as usize;
+            let x1i = x1.ceil() as usize;
+            if x1i <= x0i + 1 {
+                let xmf = 0.5 * (x + x_next) - x0_floor;
+                self.cells[row + x0i] += d - d * xmf;
+                self.cells[row + x0i + 1] += d * xmf;
+            } else {
+                let s = (x1 - x0).recip();
+                let x0f = x0 - x0_floor;
+                let a0 = 0.5 * s * (1.0 - x0f) * (1.0 - x0f);
+                let x1f = x1 - x1.ceil() + 1.0;
+                let am = 0.5 * s * x1f * x1f;
+                self.cells[row + x0i] += d * a0;
+                if x1i == x0i + 2 {
+                    self.cells[row + x0i + 1] += d * (1.0 - a0 - am);
+                } else {
+                    let a1 = s * (1.5 - x0f);
+                    self.cells[row + x0i + 1] += d * (a1 - a0);
+                    for xi in x0i + 2..x1i - 1 {
+                        self.cells[row + xi] += d * s;
+                    }
+                    let a2 = a1 + (x1i - x0i - 3) as f64 * s;
+                    self.cells[row + x1i - 1] += d * (1.0 - a2 - am);
+                }
+                self.cells[row + x1i] += d * am;
+            }
+            x = x_next;
+        }
+    }
+
+    /// Coverage from 0 to 255, where 256ths of a pixel are clamped like libass does.
+    fn finish(self) -> Vec<u8> {
+        let mut acc = 0.0;
+        let n = self.width * self.height;
+        self.cells[..n]
+            .iter()
+            .map(|cell| {
+                acc += cell;
+                ((acc.abs() * 256.0).round() as u32).min(255) as u8
+            })
+            .collect()
+    }
+}
+
+/// Rasterizes the shape a drawing fills, or returns `None` if it doesn't cover any pixels,
+/// or libass wouldn't draw it.
+pub fn rasterize(drawing: &Drawing<Point<f32>>) -> Option<Coverage> {
+    let lines = outline(drawing)?;
+    let mut points = lines.iter().flat_map(|&(a, b)| vec![a, b]);
+    let first = points.next()?;
+    let (mut min, mut max) = (first, first);
+    for p in points {
+        min = Point::new(min.x.min(p.x), min.y.min(p.y));
+        max = Point::new(max.x.max(p.x), max.y.max(p.y));
+    }
+
+    // Whole pixels, so that the pixel grid lines up with the scene origin.
+    let min = min.map(|v| v.div_euclid(ONE));
+    let max = max.map(|v| (v + ONE - 1).div_euclid(ONE));
+    let size = max - min;
+    if size.x <= 0 || size.y <= 0 || !super::fits(size.x as f32, size.y as f32) {
+        return None;
+    }
+    let (width, height) = (size.x as usize, size.y as usize);
+
+    let mut acc = Accumulator::new(width, height);
+    let origin = min * ONE;
+    let to_pixels = |p: Vector| (p - origin).map(|v| v as f64 / ONE as f64);
+    for (a, b) in lines {
+        acc.line(to_pixels(a), to_pixels(b));
+    }
+
+    Some(Coverage {
+        pos: min.cast(),
+        width,
+        height,
+        pixels: acc.finish(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{double_to_d6, rasterize, OUTLINE_ERROR};
+    use crate::drawing::{Command, Drawing};
+    use crate::point::Point;
+
+    fn polygon(points: &[(f32, f32)]) -> Drawing<Point<f32>> {
+        let mut drawing = Drawing::new();
+        let mut points = points.iter().map(|&p| Point::from(p));
+        drawing.push(Command::Move(points.next().unwrap()));
+        for p in points {
+            drawing.push(Command::Line(p));
+        }
+        drawing
+    }
+
+    #[test]
+    fn test_d6() {
+        assert_eq!(double_to_d6(1.0), 64);
+        assert_eq!(double_to_d6(1.999), 127);
+        assert_eq!(double_to_d6(-0.5), -32);
+        assert_eq!(double_to_d6(-0.001), 0);
+    }
+
+    #[test]
+    fn test_reference_bitmaps() {
+        // Pixels line up with the scene origin, so a square between pixels comes out blurry.
+        let square = polygon(&[(0.5, 0.5), (2.5, 0.5), (2.5, 2.5), (0.5, 2.5)]);
+        let coverage = rasterize(&square).unwrap();
+        assert_eq!(coverage.pos, Point::new(0.0, 0.0));
+        assert_eq!((coverage.width, coverage.height), (3, 3));
+        #[rustfmt::skip]
+        assert_eq!(coverage.pixels, vec![
+            64, 128, 64,
+            128, 255, 128,
+            64, 128, 64,
+        ]);
+
+        // 1.999 is truncated to 127/64, so the right column is 63/64 covered.
+        let narrow = polygon(&[(0.0, 0.0), (1.999, 0.0), (1.999, 1.0), (0.0, 1.0)]);
+        let coverage = rasterize(&narrow).unwrap();
+        assert_eq!(coverage.pixels, vec![255, 252]);
+
+        let triangle = polygon(&[(0.0, 0.0), (2.0, 0.0), (0.0, 2.0)]);
+        let coverage = rasterize(&triangle).unwrap();
+        assert_eq!(coverage.pixels, vec![255, 128, 128, 0]);
+
+        assert!(rasterize(&polygon(&[(0.0, 0.0), (4.0, 0.0)])).is_none());
+
+        // libass gives up on points more than 2^22 pixels out, however small the shape.
+        let far = polygon(&[(4e7, 0.0), (4e7 + 1.0, 0.0), (4e7 + 1.0, 1.0), (4e7, 1.0)]);
+        assert!(rasterize(&far).is_none());
+        let thin = polygon(&[(0.0, 0.0), (4e7, 0.0), (4e7, 1.0), (0.0, 1.0)]);
+        assert!(rasterize(&thin).is_none());
+        let edge = 4_194_303.0;
+        let near = polygon(&[
+            (edge - 1.0, 0.0),
+            (edge, 0.0),
+            (edge, 1.0),
+            (edge - 1.0, 1.0),
+        ]);
+        assert_eq!(rasterize(&near).unwrap().pixels, vec![255]);
+    }
+
+    #[test]
+    fn test_winding() {
+        let mut drawing = polygon(&[(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0)]);
+        let mut overlapping = drawing.clone();
+
+        // A square wound the other way cuts a hole.
+        drawing.push(Command::Move(Point::new(1.0, 1.0)));
+        for &(x, y) in &[(1.0, 3.0), (3.0, 3.0), (3.0, 1.0)] {
+            drawing.push(Command::Line(Point::new(x, y)));
+        }
+        let coverage = rasterize(&drawing).unwrap();
+        assert_eq!(coverage.pixels[5], 0);
+        assert_eq!(coverage.pixels[0], 255);
+
+        // One wound the same way doesn't.
+        overlapping.push(Command::Move(Point::new(1.0, 1.0)));
+        for &(x, y) in &[(3.0, 1.0), (3.0, 3.0), (1.0, 3.0)] {
+            overlapping.push(Command::Line(Point::new(x, y)));
+        }
+        let coverage = rasterize(&overlapping).unwrap();
+        assert!(coverage.pixels.iter().all(|&v| v == 255));
+    }
+
+    #[test]
+    fn test_curves() {
+        // A circle of radius 10 around (12, 12), made of four curves.
+        const K: f32 = 10.0 * 0.552_284_8;
+        let mut drawing = Drawing::new();
+        drawing.push(Command::Move(Point::new(22.0, 12.0)));
+        let quadrants = [
+            [(22.0, 12.0 + K), (12.0 + K, 22.0), (12.0, 22.0)],
+            [(12.0 - K, 22.0), (2.0, 12.0 + K), (2.0, 12.0)],
+            [(2.0, 12.0 - K), (12.0 - K, 2.0), (12.0, 2.0)],
+            [(12.0 + K, 2.0), (22.0, 12.0 - K), (22.0, 12.0)],
+        ];
+        for &[p1, p2, p3] in &quadrants {
+            drawing.push(Command::Bezier(p1.into(), p2.into(), p3.into()));
+        }
+
+        let coverage = rasterize(&drawing).unwrap();
+        assert_eq!(coverage.pos, Point::new(2.0, 2.0));
+        assert_eq!((coverage.width, coverage.height), (20, 20));
+        let area: f32 = coverage.pixels.iter().map(|&v| v as f32 / 255.0).sum();
+        // Flattening cuts corners, but by no more than the tolerance along the whole outline.
+        let expected = std::f32::consts::PI * 100.0;
+        let max_error = std::f32::consts::PI * 20.0 * OUTLINE_ERROR as f32 / 64.0;
+        assert!(area < expected && area > expected - max_error, "{}", area);
+    }
+}
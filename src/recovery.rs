@@ -0,0 +1,289 @@
+//! Keeping a recent copy of the editing session around, so that a crash doesn't lose it.
+//!
+//! While the editor runs, the session is periodically saved to a recovery file, and a panic hook
+//! makes one last save on the way down. The file is removed when the editor exits normally,
+//! so if it's still there on the next start, the previous session crashed.
+//!
+//! A panic in the middle of changing the session, e.g. inside `Editor::handle`, leaves it half-changed
+//! and borrowed, so the hook saves nothing and the last periodic save is what gets recovered.
+
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::Once;
+use std::time::{Duration, Instant};
+
+use crate::project::{Project, ProjectError};
+
+/// How often the session is saved, at most.
+pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Produces the project for the panic hook to save, if it can be had.
+type Snapshot = Box<dyn Fn() -> Option<Project>>;
+
+thread_local! {
+    /// Where this thread's session goes if it panics, and how to get it.
+    static SNAPSHOT: RefCell<Option<(PathBuf, Snapshot)>> = RefCell::new(None);
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+/// Where the recovery file goes if nobody says otherwise.
+pub fn default_path() -> PathBuf {
+    let dir = env::var_os("LOCALAPPDATA")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir);
+    dir.join("assdraw-rs").join("recovery.adproj")
+}
+
+/// Writes a file such that a crash partway through leaves the old contents intact.
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)
+}
+
+fn hash(data: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Periodically saves the session to a recovery file.
+#[derive(Debug)]
+pub struct Autosave {
+    path: PathBuf,
+    interval: Duration,
+    last_save: Option<Instant>,
+    /// A hash of what was last saved, so that unchanged sessions aren't saved again.
+    last_hash: Option<u64>,
+}
+
+impl Autosave {
+    pub fn new(path: PathBuf) -> Self {
+        Self::with_interval(path, AUTOSAVE_INTERVAL)
+    }
+
+    pub fn with_interval(path: PathBuf, interval: Duration) -> Self {
+        Self {
+            path,
+            interval,
+            last_save: None,
+            last_hash: None,
+        }
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Saves the project if the interval has passed since the last save, and it changed since then.
+    /// Returns whether it was saved.
+    pub fn tick(&mut self, now: Instant, project: impl FnOnce() -> Project) -> io::Result<bool> {
+        if let Some(last_save) = self.last_save {
+            if now.saturating_duration_since(last_save) < self.interval {
+                return Ok(false);
+            }
+        }
+        self.last_save = Some(now);
+        self.save(&project())
+    }
+
+    /// Saves the project right away, unless it's the same as what was last saved.
+    pub fn save(&mut self, project: &Project) -> io::Result<bool> {
+        let data = project.save();
+        let hash = hash(&data);
+        if self.last_hash == Some(hash) {
+            return Ok(false);
+        }
+        write_atomic(&self.path, &data)?;
+        self.last_hash = Some(hash);
+        Ok(true)
+    }
+
+    /// Removes the recovery file, once the session has ended normally.
+    pub fn discard(&mut self) -> io::Result<()> {
+        self.last_hash = None;
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Looks for a session left behind by a crash.
+pub fn find_recovery(path: &Path) -> Option<Result<Project, ProjectError>> {
+    if !path.exists() {
+        return None;
+    }
+    Some(Project::load_from(path))
+}
+
+/// Makes panics on this thread save one last snapshot of the session to `path` before unwinding,
+/// instead of wherever an earlier call on this thread said to.
+///
+/// `snapshot` is called from inside the panic hook, so it should give up (return `None`)
+/// rather than panic if the session can't be had, e.g. because it's in the middle of being changed.
+/// The recovery file then keeps whatever `Autosave` last put there.
+pub fn install_panic_hook(path: PathBuf, snapshot: impl Fn() -> Option<Project> + 'static) {
+    SNAPSHOT.with(|s| *s.borrow_mut() = Some((path, Box::new(snapshot))));
+    // The hook is shared by every thread, and each one saves its own session.
+    INSTALL_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            save_snapshot();
+            previous(info);
+        }));
+    });
+}
+
+/// Stops panics on this thread from saving anything.
+pub fn remove_panic_snapshot() {
+    SNAPSHOT.with(|s| *s.borrow_mut() = None);
+}
+
+fn save_snapshot() -> Option<()> {
+    SNAPSHOT
+        .try_with(|s| {
+            let s = s.try_borrow().ok()?;
+            let (path, snapshot) = s.as_ref()?;
+            write_atomic(path, &snapshot()?.save()).ok()
+        })
+        .ok()?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::env;
+    use std::panic;
+    use std::path::PathBuf;
+    use std::process;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use super::{find_recovery, install_panic_hook, remove_panic_snapshot, Autosave};
+    use crate::drawing::Command;
+    use crate::editor::{Editor, InputEvent, MouseButton};
+    use crate::point::Point;
+    use crate::project::Project;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("assdraw-test-{}-{}.adproj", process::id(), name))
+    }
+
+    fn add_point(project: &mut Project, x: f32) {
+        let drawing = &mut project.history.layer_mut(0).drawing;
+        drawing.push(Command::Line(Point::new(x, 0.0)));
+        project.history.commit();
+    }
+
+    #[test]
+    fn test_autosave() {
+        let path = temp_path("autosave");
+        let mut autosave = Autosave::with_interval(path.clone(), Duration::from_secs(10));
+        let mut project = Project::default();
+        add_point(&mut project, 1.0);
+
+        let start = Instant::now();
+        assert!(autosave.tick(start, || project.clone()).unwrap());
+        add_point(&mut project, 2.0);
+        let too_soon = start + Duration::from_secs(5);
+        assert!(!autosave.tick(too_soon, || unreachable!()).unwrap());
+        let later = start + Duration::from_secs(11);
+        assert!(autosave.tick(later, || project.clone()).unwrap());
+        let unchanged = start + Duration::from_secs(30);
+        assert!(!autosave.tick(unchanged, || project.clone()).unwrap());
+
+        let recovered = find_recovery(&path).unwrap().unwrap();
+        assert_eq!(recovered.history.layers(), project.history.layers());
+
+        autosave.discard().unwrap();
+        assert!(find_recovery(&path).is_none());
+        autosave.discard().unwrap();
+    }
+
+    #[test]
+    fn test_crash_recovery() {
+        let path = temp_path("crash");
+        let editor = Rc::new(RefCell::new(Editor::new()));
+        // Installing again moves the snapshot rather than saving it in both places.
+        let stale_path = temp_path("crash-stale");
+        install_panic_hook(stale_path.clone(), || None);
+        let weak = Rc::downgrade(&editor);
+        install_panic_hook(path.clone(), move || {
+            Some(weak.upgrade()?.try_borrow().ok()?.to_project())
+        });
+
+        // A different number of points than the other crash test, whose recovery file must stay its own.
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let mut e = editor.borrow_mut();
+            for x in [10, 20, 30] {
+                let pos = Point::new(x, 20);
+                let _ = e.handle(InputEvent::PointerDown(MouseButton::Left, pos));
+                let _ = e.handle(InputEvent::PointerUp(MouseButton::Left, pos));
+            }
+            // Done changing the editor, so the hook can get at it.
+            drop(e);
+            panic!("simulated crash");
+        }));
+        assert!(result.is_err());
+        remove_panic_snapshot();
+        assert!(find_recovery(&stale_path).is_none());
+
+        let recovered = find_recovery(&path).unwrap().unwrap();
+        let mut restarted = Editor::new();
+        let _ = restarted.load_project(recovered);
+        assert_eq!(restarted.drawing(), editor.borrow().drawing());
+        assert_eq!(restarted.drawing().len(), 3);
+
+        Autosave::new(path.clone()).discard().unwrap();
+        assert!(find_recovery(&path).is_none());
+    }
+
+    #[test]
+    fn test_crash_while_editing() {
+        let path = temp_path("crash-while-editing");
+        let editor = Rc::new(RefCell::new(Editor::new()));
+        let weak = Rc::downgrade(&editor);
+        install_panic_hook(path.clone(), move || {
+            Some(weak.upgrade()?.try_borrow().ok()?.to_project())
+        });
+
+        let mut autosave = Autosave::new(path.clone());
+        let pos = Point::new(10, 20);
+        let _ = editor
+            .borrow_mut()
+            .handle(InputEvent::PointerDown(MouseButton::Left, pos));
+        let _ = editor
+            .borrow_mut()
+            .handle(InputEvent::PointerUp(MouseButton::Left, pos));
+        autosave.save(&editor.borrow().to_project()).unwrap();
+
+        // The editor is still borrowed when it panics, so only the autosave survives.
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let mut e = editor.borrow_mut();
+            let pos = Point::new(30, 20);
+            let _ = e.handle(InputEvent::PointerDown(MouseButton::Left, pos));
+            let _ = e.handle(InputEvent::PointerUp(MouseButton::Left, pos));
+            panic!("simulated crash");
+        }));
+        assert!(result.is_err());
+        remove_panic_snapshot();
+
+        let recovered = find_recovery(&path).unwrap().unwrap();
+        assert_eq!(recovered.history.layers()[0].drawing.len(), 1);
+        assert_eq!(editor.borrow().drawing().len(), 2);
+
+        autosave.discard().unwrap();
+    }
+}
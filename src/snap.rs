@@ -0,0 +1,437 @@
+use crate::codec::{self, Decode, DecodeError, Encode, Reader, Writer};
+use crate::drawing::{Drawing, PointId, Segment};
+use crate::point::{Point, Rect};
+use crate::viewport::Viewport;
+
+pub type Line = (Point<f32>, Point<f32>);
+
+/// Grid lines closer together than this many screen pixels aren't worth drawing.
+const MIN_GRID_SPACING: f32 = 4.0;
+
+/// Half the size of the marker drawn around a snapped-to point, in screen pixels.
+const HINT_RADIUS: f32 = 6.0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Guide {
+    /// A horizontal line at the given y coordinate.
+    Horizontal(f32),
+    /// A vertical line at the given x coordinate.
+    Vertical(f32),
+}
+
+impl Guide {
+    /// The part of the guide that lies within `rect`.
+    pub fn line(self, rect: Rect) -> Line {
+        match self {
+            Self::Horizontal(y) => (Point::new(rect.min.x, y), Point::new(rect.max.x, y)),
+            Self::Vertical(x) => (Point::new(x, rect.min.y), Point::new(x, rect.max.y)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SnapSettings {
+    pub enabled: bool,
+    /// Round to whole pixels.
+    pub pixels: bool,
+    /// Round to multiples of this spacing.
+    pub grid: Option<f32>,
+    pub nodes: bool,
+    pub midpoints: bool,
+    /// Line up horizontally or vertically with the points on either side.
+    pub alignment: bool,
+    pub guides: bool,
+    /// How close a point needs to be to something to snap to it, in screen pixels.
+    pub tolerance: f32,
+}
+
+impl Default for SnapSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            pixels: false,
+            grid: None,
+            nodes: true,
+            midpoints: true,
+            alignment: true,
+            guides: true,
+            tolerance: 8.0,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SnapTarget {
+    Node(PointId),
+    Midpoint,
+    Alignment(PointId),
+    Guide(usize),
+    Grid,
+    Pixel,
+}
+
+/// A snapped point, along with what each of its coordinates snapped to.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Snap {
+    pub point: Point<f32>,
+    pub x: Option<SnapTarget>,
+    pub y: Option<SnapTarget>,
+}
+
+impl Snap {
+    #[inline]
+    pub fn is_snapped(&self) -> bool {
+        self.x.is_some() || self.y.is_some()
+    }
+
+    /// Lines that show the user what the point snapped to.
+    pub fn hint_lines(&self, drawing: &Drawing<Point<f32>>, viewport: &Viewport) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let p = self.point;
+        match (self.x, self.y) {
+            (Some(SnapTarget::Node(_)), _) | (Some(SnapTarget::Midpoint), _) => {
+                let r = HINT_RADIUS / viewport.scale;
+                let (n, e, s, w) = (
+                    p + Point::new(0.0, -r),
+                    p + Point::new(r, 0.0),
+                    p + Point::new(0.0, r),
+                    p + Point::new(-r, 0.0),
+                );
+                lines.extend_from_slice(&[(n, e), (e, s), (s, w), (w, n)]);
+            }
+            (x, y) => {
+                if let Some(SnapTarget::Alignment(id)) = x {
+                    if let Some(&q) = drawing.point(id) {
+                        lines.push((q, p));
+                    }
+                }
+                if let Some(SnapTarget::Alignment(id)) = y {
+                    if let Some(&q) = drawing.point(id) {
+                        lines.push((q, p));
+                    }
+                }
+            }
+        }
+        lines
+    }
+}
+
+/// Keeps whichever candidate is closest, as long as it's within the tolerance.
+struct Closest<T> {
+    tolerance: f32,
+    best: Option<(f32, T)>,
+}
+
+impl<T> Closest<T> {
+    fn new(tolerance: f32) -> Self {
+        Self {
+            tolerance,
+            best: None,
+        }
+    }
+
+    fn consider(&mut self, distance: f32, candidate: T) {
+        let closer = match &self.best {
+            Some((best, _)) => distance < *best,
+            None => true,
+        };
+        if distance <= self.tolerance && closer {
+            self.best = Some((distance, candidate));
+        }
+    }
+
+    fn get(self) -> Option<T> {
+        self.best.map(|(_, candidate)| candidate)
+    }
+}
+
+fn midpoint(points: &[Point<f32>], segment: Segment<usize>) -> Point<f32> {
+    match segment {
+        Segment::Line(a, b) | Segment::ClosingLine(a, b) => points[a].lerp(points[b], 0.5),
+        Segment::Bezier(a, b, c, d) => {
+            (points[a] + points[b] * 3.0 + points[c] * 3.0 + points[d]) / 8.0
+        }
+    }
+}
+
+fn distance(a: Point<f32>, b: Point<f32>) -> f32 {
+    let d = a - b;
+    d.x.hypot(d.y)
+}
+
+impl SnapSettings {
+    /// Snaps `candidate`, which is where the user is trying to put point `moving` (or a new point, if `None`).
+    pub fn snap(
+        &self,
+        candidate: Point<f32>,
+        drawing: &Drawing<Point<f32>>,
+        guides: &[Guide],
+        moving: Option<PointId>,
+        scale: f32,
+    ) -> Snap {
+        let unsnapped = Snap {
+            point: candidate,
+            x: None,
+            y: None,
+        };
+        if !self.enabled {
+            return unsnapped;
+        }
+
+        let tolerance = self.tolerance / scale;
+        let points = drawing.points();
+        let moving_idx = moving.and_then(|id| drawing.point_index(id));
+
+        let mut closest = Closest::new(tolerance);
+        if self.nodes {
+            for i in drawing.node_indices().filter(|&i| Some(i) != moving_idx) {
+                let id = drawing.point_id(i).unwrap();
+                closest.consider(
+                    distance(candidate, points[i]),
+                    (points[i], SnapTarget::Node(id)),
+                );
+            }
+        }
+        if self.midpoints {
+            for segment in drawing.segment_indices() {
+                // A segment's midpoint moves along with its points, so it's no use as a target.
+                if segment.points().any(|i| Some(i) == moving_idx) {
+                    continue;
+                }
+                let mid = midpoint(points, segment);
+                closest.consider(distance(candidate, mid), (mid, SnapTarget::Midpoint));
+            }
+        }
+        if let Some((point, target)) = closest.get() {
+            return Snap {
+                point,
+                x: Some(target),
+                y: Some(target),
+            };
+        }
+
+        let neighbors: Vec<usize> = match moving_idx {
+            Some(i) => [i.checked_sub(1), Some(i + 1)]
+                .iter()
+                .flatten()
+                .copied()
+                .filter(|&j| j < points.len())
+                .collect(),
+            None => points.len().checked_sub(1).into_iter().collect(),
+        };
+
+        let mut snap_x = Closest::new(tolerance);
+        let mut snap_y = Closest::new(tolerance);
+        if self.guides {
+            for (i, guide) in guides.iter().enumerate() {
+                match *guide {
+                    Guide::Horizontal(y) => {
+                        snap_y.consider((candidate.y - y).abs(), (y, SnapTarget::Guide(i)))
+                    }
+                    Guide::Vertical(x) => {
+                        snap_x.consider((candidate.x - x).abs(), (x, SnapTarget::Guide(i)))
+                    }
+                }
+            }
+        }
+        if self.alignment {
+            for &j in &neighbors {
+                let target = SnapTarget::Alignment(drawing.point_id(j).unwrap());
+                let p = points[j];
+                snap_x.consider((candidate.x - p.x).abs(), (p.x, target));
+                snap_y.consider((candidate.y - p.y).abs(), (p.y, target));
+            }
+        }
+
+        let (x, x_target) = self.round(candidate.x, snap_x.get());
+        let (y, y_target) = self.round(candidate.y, snap_y.get());
+        Snap {
+            point: Point::new(x, y),
+            x: x_target,
+            y: y_target,
+        }
+    }
+
+    /// Falls back to the grid or whole pixels for a coordinate that didn't snap to anything in particular.
+    fn round(&self, value: f32, snapped: Option<(f32, SnapTarget)>) -> (f32, Option<SnapTarget>) {
+        if let Some((value, target)) = snapped {
+            (value, Some(target))
+        } else if let Some(spacing) = self.grid {
+            ((value / spacing).round() * spacing, Some(SnapTarget::Grid))
+        } else if self.pixels {
+            (value.round(), Some(SnapTarget::Pixel))
+        } else {
+            (value, None)
+        }
+    }
+}
+
+/// The grid lines that are currently on screen, or none if they'd be too dense to be useful.
+pub fn grid_lines(viewport: &Viewport, spacing: f32) -> Vec<Line> {
+    if spacing <= 0.0 || spacing * viewport.scale < MIN_GRID_SPACING {
+        return Vec::new();
+    }
+
+    let rect = viewport.visible_rect();
+    let steps = |min: f32, max: f32| {
+        let first = (min / spacing).ceil() as i64;
+        let last = (max / spacing).floor() as i64;
+        (first..=last).map(move |i| i as f32 * spacing)
+    };
+
+    let mut lines = Vec::new();
+    for x in steps(rect.min.x, rect.max.x) {
+        lines.push((Point::new(x, rect.min.y), Point::new(x, rect.max.y)));
+    }
+    for y in steps(rect.min.y, rect.max.y) {
+        lines.push((Point::new(rect.min.x, y), Point::new(rect.max.x, y)));
+    }
+    lines
+}
+
+/// Everything drawn on top of the scene that isn't part of the drawing itself.
+#[derive(Debug, Default, Clone)]
+pub struct Overlay {
+    pub grid: Vec<Line>,
+    pub guides: Vec<Line>,
+    pub hints: Vec<Line>,
+}
+
+impl Encode for Guide {
+    fn encode(&self, w: &mut Writer) {
+        match *self {
+            Self::Horizontal(y) => w.write(&(0u8, y)),
+            Self::Vertical(x) => w.write(&(1u8, x)),
+        }
+    }
+}
+
+impl Decode for Guide {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        match r.read::<(u8, f32)>()? {
+            (0, y) => Ok(Self::Horizontal(y)),
+            (1, x) => Ok(Self::Vertical(x)),
+            _ => Err(DecodeError::Invalid("guide")),
+        }
+    }
+}
+
+impl Encode for SnapSettings {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.enabled);
+        w.write(&self.pixels);
+        w.write(&self.grid);
+        w.write(&self.nodes);
+        w.write(&self.midpoints);
+        w.write(&self.alignment);
+        w.write(&self.guides);
+        w.write(&self.tolerance);
+    }
+}
+
+impl Decode for SnapSettings {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        let enabled = r.read()?;
+        let pixels = r.read()?;
+        let grid: Option<f32> = r.read()?;
+        if grid.is_some_and(|spacing| !spacing.is_finite() || spacing <= 0.0) {
+            return Err(DecodeError::Invalid("grid spacing"));
+        }
+        Ok(Self {
+            enabled,
+            pixels,
+            grid,
+            nodes: r.read()?,
+            midpoints: r.read()?,
+            alignment: r.read()?,
+            guides: r.read()?,
+            tolerance: r.read()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{grid_lines, Guide, SnapSettings, SnapTarget};
+    use crate::drawing::{Command, Drawing};
+    use crate::point::Point;
+    use crate::viewport::Viewport;
+
+    fn square() -> Drawing<Point<f32>> {
+        let mut d = Drawing::new();
+        d.push(Command::Move(Point::new(0.0, 0.0)));
+        d.push(Command::Line(Point::new(100.0, 0.0)));
+        d.push(Command::Line(Point::new(100.0, 100.0)));
+        d.push(Command::Line(Point::new(0.0, 100.0)));
+        d
+    }
+
+    #[test]
+    fn test_snap_to_points() {
+        let d = square();
+        let settings = SnapSettings::default();
+
+        let snap = settings.snap(Point::new(97.0, 3.0), &d, &[], None, 1.0);
+        assert_eq!(snap.point, Point::new(100.0, 0.0));
+        assert_eq!(snap.x, Some(SnapTarget::Node(d.point_id(1).unwrap())));
+
+        let snap = settings.snap(Point::new(52.0, 96.0), &d, &[], None, 1.0);
+        assert_eq!(snap.point, Point::new(50.0, 100.0));
+        assert_eq!(snap.y, Some(SnapTarget::Midpoint));
+
+        // Out of range at 1x, but within range when zoomed out.
+        let snap = settings.snap(Point::new(88.0, 12.0), &d, &[], None, 1.0);
+        assert!(!snap.is_snapped());
+        let snap = settings.snap(Point::new(88.0, 12.0), &d, &[], None, 0.25);
+        assert_eq!(snap.point, Point::new(100.0, 0.0));
+    }
+
+    #[test]
+    fn test_snap_axes() {
+        let d = square();
+        let settings = SnapSettings {
+            grid: Some(10.0),
+            ..SnapSettings::default()
+        };
+
+        // Dragging the third point: it lines up with its neighbors, but not with itself.
+        let moving = d.point_id(2);
+        let snap = settings.snap(Point::new(103.0, 64.0), &d, &[], moving, 1.0);
+        assert_eq!(snap.point, Point::new(100.0, 60.0));
+        assert_eq!(snap.x, Some(SnapTarget::Alignment(d.point_id(1).unwrap())));
+        assert_eq!(snap.y, Some(SnapTarget::Grid));
+
+        let guides = [Guide::Horizontal(63.5)];
+        let snap = settings.snap(Point::new(103.0, 64.0), &d, &guides, moving, 1.0);
+        assert_eq!(snap.point, Point::new(100.0, 63.5));
+        assert_eq!(snap.y, Some(SnapTarget::Guide(0)));
+
+        let settings = SnapSettings {
+            pixels: true,
+            alignment: false,
+            ..SnapSettings::default()
+        };
+        let snap = settings.snap(Point::new(103.4, 64.6), &d, &[], moving, 1.0);
+        assert_eq!(snap.point, Point::new(103.0, 65.0));
+
+        let settings = SnapSettings {
+            enabled: false,
+            ..settings
+        };
+        let snap = settings.snap(Point::new(103.4, 64.6), &d, &[], moving, 1.0);
+        assert_eq!(snap.point, Point::new(103.4, 64.6));
+    }
+
+    #[test]
+    fn test_grid_lines() {
+        let vp = Viewport {
+            screen_dims: Point::new(100.0, 50.0),
+            scene_pos: Point::new(-5.0, 0.0),
+            scale: 1.0,
+        };
+        let lines = grid_lines(&vp, 10.0);
+        // x = 0, 10, ..., 90 and y = 0, 10, ..., 50
+        assert_eq!(lines.len(), 10 + 6);
+        assert!(grid_lines(&vp, 2.0).is_empty());
+    }
+}
@@ -1,83 +1,837 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem;
 use std::ops::{Deref, DerefMut};
+use std::time::SystemTime;
 
+use crate::codec::{self, Decode, DecodeError, Encode, Reader, Writer};
+
+/// The label given to entries committed without one.
+pub const DEFAULT_LABEL: &str = "Edit";
+
+/// An invertible record of one change to a `T`, as stored in an `UndoStack`.
+pub trait Delta<T> {
+    /// Records the change that turned `old` into `new`.
+    fn diff(old: T, new: &T) -> Self;
+    /// Turns the state from before the change into the state after it.
+    fn apply(&mut self, state: &mut T);
+    /// Turns the state from after the change back into the state before it.
+    fn revert(&mut self, state: &mut T);
+    /// Whether `apply` can be used on `state` without panicking or leaving it inconsistent.
+    /// Diffed deltas always fit the states they were diffed from; this is for checking decoded ones.
+    fn can_apply(&self, _state: &T) -> bool {
+        true
+    }
+    /// Whether `revert` can be used on `state`, like `can_apply`.
+    fn can_revert(&self, _state: &T) -> bool {
+        true
+    }
+    /// Roughly how many bytes this delta takes up, for enforcing `Limits::max_bytes`.
+    fn size(&self) -> usize;
+}
+
+/// The simplest kind of history entry: a full copy of the state on the other side of the change.
 #[derive(Debug, Clone)]
-pub struct UndoStack<T> {
-    history: Vec<T>,
+pub struct Snapshot<T>(T);
+
+impl<T> Delta<T> for Snapshot<T> {
+    #[inline]
+    fn diff(old: T, _new: &T) -> Self {
+        Self(old)
+    }
+
+    #[inline]
+    fn apply(&mut self, state: &mut T) {
+        mem::swap(&mut self.0, state);
+    }
+
+    #[inline]
+    fn revert(&mut self, state: &mut T) {
+        mem::swap(&mut self.0, state);
+    }
+
+    /// Always zero, since there's no general way to measure what `T` has allocated,
+    /// and counting just `T` itself would make `Limits::max_bytes` an entry count in disguise.
+    #[inline]
+    fn size(&self) -> usize {
+        0
+    }
+}
+
+/// How much history an `UndoStack` keeps before it starts forgetting the oldest entries.
+///
+/// The most recent entry is always kept, even if it's bigger than `max_bytes` on its own.
+/// `max_bytes` is ignored by stacks that keep `Snapshot`s, which can't tell how big they are.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub max_entries: Option<usize>,
+    pub max_bytes: Option<usize>,
+}
+
+/// Whether abandoned redo history is thrown away or kept around as a branch.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HistoryMode {
+    /// Committing after an undo forgets everything that could have been redone.
+    Linear,
+    /// Committing after an undo starts a new branch, leaving the old one reachable with `go_to_sibling`.
+    Tree,
+}
+
+impl Default for HistoryMode {
+    #[inline]
+    fn default() -> Self {
+        Self::Linear
+    }
+}
+
+/// Identifies a history entry. IDs are handed out in the order entries are committed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EntryId(u64);
+
+#[derive(Debug, Clone)]
+struct Entry<D> {
+    /// The change from the parent's state to this entry's state.
+    delta: D,
+    label: String,
+    time: SystemTime,
+    parent: Option<EntryId>,
+    children: Vec<EntryId>,
+    /// The child that `redo` goes to: the one that was most recently committed or undone.
+    redo_child: Option<EntryId>,
+}
+
+/// A description of one history entry, as yielded by `UndoStack::entries`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EntryInfo<'a> {
+    pub id: EntryId,
+    /// The entry this one was committed on top of, or `None` if it's one of the oldest.
+    pub parent: Option<EntryId>,
+    pub label: &'a str,
+    /// When the entry was committed.
+    pub time: SystemTime,
+    /// Whether the entry is part of the current state, as opposed to having been undone.
+    pub applied: bool,
+}
+
+/// A value with undo history.
+///
+/// Mutating the value through `DerefMut` starts an edit, which becomes a history entry on `commit`.
+/// Each entry is stored as a `D`, which by default is a full snapshot of the value.
+///
+/// The history is a tree of entries, each one holding the change from its parent.
+/// In `HistoryMode::Linear`, that tree never branches.
+///
+/// Inside a transaction, commits are held back until the outermost transaction is committed,
+/// so that a compound edit becomes a single history entry.
+#[derive(Debug, Clone)]
+pub struct UndoStack<T, D = Snapshot<T>> {
+    /// The value as of `head`.
+    current: T,
     active: Option<T>,
-    idx: usize,
+    entries: BTreeMap<EntryId, Entry<D>>,
+    /// The entry whose state is `current`, or `None` for the oldest state available.
+    head: Option<EntryId>,
+    /// The `children` and `redo_child` of the oldest state available.
+    roots: Vec<EntryId>,
+    root_redo: Option<EntryId>,
+    next_id: u64,
+    mode: HistoryMode,
+    limits: Limits,
+    bytes: usize,
+    /// The active edit as it was when each open transaction began, innermost last.
+    savepoints: Vec<Option<T>>,
 }
 
 impl<T> UndoStack<T> {
+    /// Creates an undo stack that keeps unlimited snapshots.
     pub fn new(initial_state: T) -> Self {
+        Self::with_limits(initial_state, Limits::default())
+    }
+}
+
+impl<T, D: Delta<T>> UndoStack<T, D> {
+    pub fn with_limits(initial_state: T, limits: Limits) -> Self {
         Self {
-            history: vec![initial_state],
+            current: initial_state,
             active: None,
-            idx: 0,
+            entries: BTreeMap::new(),
+            head: None,
+            roots: Vec::new(),
+            root_redo: None,
+            next_id: 0,
+            mode: HistoryMode::default(),
+            limits,
+            bytes: 0,
+            savepoints: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.enforce_limits();
+    }
+
+    #[inline]
+    pub fn mode(&self) -> HistoryMode {
+        self.mode
+    }
+
+    /// Switching to `HistoryMode::Linear` forgets every branch other than the one `entries` goes through.
+    pub fn set_mode(&mut self, mode: HistoryMode) {
+        self.mode = mode;
+        if mode == HistoryMode::Linear {
+            let path: HashSet<_> = self.path().into_iter().collect();
+            let abandoned: Vec<_> = self
+                .entries
+                .keys()
+                .filter(|id| !path.contains(id))
+                .copied()
+                .collect();
+            for id in abandoned {
+                self.remove_subtree(id);
+            }
+        }
+    }
+
+    fn children(&self, id: Option<EntryId>) -> &[EntryId] {
+        match id {
+            Some(id) => &self.entries[&id].children,
+            None => &self.roots,
+        }
+    }
+
+    fn children_mut(&mut self, id: Option<EntryId>) -> &mut Vec<EntryId> {
+        match id {
+            Some(id) => &mut self.entries.get_mut(&id).unwrap().children,
+            None => &mut self.roots,
+        }
+    }
+
+    fn redo_child(&self, id: Option<EntryId>) -> Option<EntryId> {
+        match id {
+            Some(id) => self.entries[&id].redo_child,
+            None => self.root_redo,
+        }
+    }
+
+    fn set_redo_child(&mut self, id: Option<EntryId>, child: Option<EntryId>) {
+        match id {
+            Some(id) => self.entries.get_mut(&id).unwrap().redo_child = child,
+            None => self.root_redo = child,
+        }
+    }
+
+    /// `id` and all of its ancestors, newest first.
+    fn ancestors(&self, id: Option<EntryId>) -> impl Iterator<Item = EntryId> + '_ {
+        std::iter::successors(id, move |id| self.entries[id].parent)
+    }
+
+    /// The entries that `entries()` yields: from the oldest to `head`, then on through everything `redo` would redo.
+    fn path(&self) -> Vec<EntryId> {
+        let mut path: Vec<_> = self.ancestors(self.head).collect();
+        path.reverse();
+        let redos =
+            std::iter::successors(self.redo_child(self.head), |&id| self.redo_child(Some(id)));
+        path.extend(redos);
+        path
+    }
+
+    fn info(&self, id: EntryId, applied: bool) -> EntryInfo<'_> {
+        let entry = &self.entries[&id];
+        EntryInfo {
+            id,
+            parent: entry.parent,
+            label: &entry.label,
+            time: entry.time,
+            applied,
         }
     }
 
+    /// The history that undo and redo move along, oldest first, including entries that have been undone.
+    pub fn entries(&self) -> impl Iterator<Item = EntryInfo<'_>> + '_ {
+        let position = self.position();
+        self.path()
+            .into_iter()
+            .enumerate()
+            .map(move |(i, id)| self.info(id, i < position))
+    }
+
+    /// Every history entry, on every branch, in the order they were committed.
+    pub fn all_entries(&self) -> impl Iterator<Item = EntryInfo<'_>> + '_ {
+        let applied: HashSet<_> = self.ancestors(self.head).collect();
+        self.entries
+            .keys()
+            .map(move |&id| self.info(id, applied.contains(&id)))
+    }
+
+    /// The entry whose state is current, or `None` if every entry has been undone.
+    #[inline]
+    pub fn head(&self) -> Option<EntryId> {
+        self.head
+    }
+
+    /// How many entries are applied to the current state. `jump_to(0)` goes back to the oldest state still available.
+    #[inline]
+    pub fn position(&self) -> usize {
+        self.ancestors(self.head).count()
+    }
+
+    /// The label of the entry that `undo` would undo.
+    pub fn undo_label(&self) -> Option<&str> {
+        Some(&self.entries[&self.head?].label)
+    }
+
+    /// The label of the entry that `redo` would redo.
+    pub fn redo_label(&self) -> Option<&str> {
+        Some(&self.entries[&self.redo_child(self.head)?].label)
+    }
+
+    /// How many history entries there are, counting ones that have been undone and other branches.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The approximate size of the history, as reported by `Delta::size`.
+    #[inline]
+    pub fn memory_usage(&self) -> usize {
+        self.bytes
+    }
+
+    /// How many transactions are open.
+    #[inline]
+    pub fn transaction_depth(&self) -> usize {
+        self.savepoints.len()
+    }
+
+    /// Undo and redo are unavailable while a transaction is open.
     #[inline]
     pub fn can_undo(&self) -> bool {
-        self.idx > 0
+        self.head.is_some() && self.savepoints.is_empty()
     }
 
+    /// Moves to the parent of the current entry.
     pub fn undo(&mut self) {
         if self.can_undo() {
-            self.idx -= 1;
             self.active = None;
+            let head = self.head.unwrap();
+            let entry = self.entries.get_mut(&head).unwrap();
+            entry.delta.revert(&mut self.current);
+            self.head = entry.parent;
+            self.set_redo_child(self.head, Some(head));
         }
     }
 
     #[inline]
     pub fn can_redo(&self) -> bool {
-        self.idx < self.history.len() - 1
+        self.redo_child(self.head).is_some() && self.savepoints.is_empty()
     }
 
+    /// Moves to the child of the current entry that was most recently committed or undone.
     pub fn redo(&mut self) {
         if self.can_redo() {
-            self.idx += 1;
             self.active = None;
+            let child = self.redo_child(self.head).unwrap();
+            self.entries
+                .get_mut(&child)
+                .unwrap()
+                .delta
+                .apply(&mut self.current);
+            self.head = Some(child);
         }
     }
 
+    /// Undoes or redoes until `position() == position`, or as close as the history allows.
+    pub fn jump_to(&mut self, position: usize) {
+        let target = match position.checked_sub(1) {
+            Some(i) => {
+                let path = self.path();
+                path.get(i.min(path.len().saturating_sub(1))).copied()
+            }
+            None => None,
+        };
+        self.go_to(target);
+    }
+
+    /// Moves to any entry in the tree, or to the oldest state available if `target` is `None`.
+    pub fn go_to(&mut self, target: Option<EntryId>) {
+        if !self.savepoints.is_empty() || target.is_some_and(|id| !self.entries.contains_key(&id)) {
+            return;
+        }
+        self.active = None;
+
+        let mut downward: Vec<_> = self.ancestors(target).collect();
+        let depths: HashMap<_, _> = downward
+            .iter()
+            .enumerate()
+            .map(|(i, &id)| (id, i))
+            .collect();
+        while let Some(head) = self.head {
+            if let Some(&i) = depths.get(&head) {
+                downward.truncate(i);
+                break;
+            }
+            self.undo();
+        }
+        for id in downward.into_iter().rev() {
+            self.set_redo_child(self.head, Some(id));
+            self.redo();
+        }
+    }
+
+    /// Moves to a sibling of the current entry, `offset` places later (or earlier, if negative) in commit order.
+    /// Returns whether there was such a sibling.
+    pub fn go_to_sibling(&mut self, offset: isize) -> bool {
+        let head = match self.head {
+            Some(head) => head,
+            None => return false,
+        };
+        let siblings = self.children(self.entries[&head].parent);
+        let i = siblings.iter().position(|&id| id == head).unwrap() as isize + offset;
+        match siblings.get(i as usize) {
+            Some(&sibling) if i >= 0 && self.savepoints.is_empty() => {
+                self.go_to(Some(sibling));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Moves to the state as of `time`: the newest entry committed no later than that,
+    /// or the oldest state available if there is none.
+    pub fn jump_to_time(&mut self, time: SystemTime) {
+        let target = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.time <= time)
+            .map(|(&id, _)| id)
+            .last();
+        self.go_to(target);
+    }
+
+    #[inline]
     pub fn push_state(&mut self, new_state: T) {
+        self.push_state_as(new_state, DEFAULT_LABEL);
+    }
+
+    pub fn push_state_as(&mut self, new_state: T, label: impl Into<String>) {
+        if !self.savepoints.is_empty() {
+            self.active = Some(new_state);
+            return;
+        }
+
         self.active = None;
-        self.history.truncate(self.idx + 1);
-        self.history.push(new_state);
-        self.idx += 1;
+        if self.mode == HistoryMode::Linear {
+            for id in self.children(self.head).to_vec() {
+                self.remove_subtree(id);
+            }
+        }
+
+        let old_state = mem::replace(&mut self.current, new_state);
+        let delta = D::diff(old_state, &self.current);
+        self.bytes += delta.size();
+
+        let id = EntryId(self.next_id);
+        self.next_id += 1;
+        self.entries.insert(
+            id,
+            Entry {
+                delta,
+                label: label.into(),
+                time: SystemTime::now(),
+                parent: self.head,
+                children: Vec::new(),
+                redo_child: None,
+            },
+        );
+        self.children_mut(self.head).push(id);
+        self.set_redo_child(self.head, Some(id));
+        self.head = Some(id);
+
+        self.enforce_limits();
     }
 
+    #[inline]
     pub fn commit(&mut self) {
+        self.commit_as(DEFAULT_LABEL);
+    }
+
+    /// Turns the active edit, if there is one, into a history entry with the given label.
+    ///
+    /// Does nothing inside a transaction; the edit will be committed along with the transaction instead.
+    pub fn commit_as(&mut self, label: impl Into<String>) {
+        if !self.savepoints.is_empty() {
+            return;
+        }
         if let Some(new_state) = self.active.take() {
-            self.push_state(new_state);
+            self.push_state_as(new_state, label);
+        }
+    }
+
+    /// Folds the active edit into the current entry if it has the given label, and otherwise commits it
+    /// as a new entry with that label, so that a run of small edits like dragging a slider undoes at once.
+    /// The entry keeps the time it was first committed at.
+    ///
+    /// An entry that has been undone to can't be amended, since whatever can be redone from it depends on its state.
+    /// Does nothing inside a transaction, like `commit_as`.
+    pub fn amend_as(&mut self, label: impl Into<String>) {
+        if !self.savepoints.is_empty() {
+            return;
+        }
+        let label = label.into();
+        let head = match self.head {
+            Some(head)
+                if self.entries[&head].label == label
+                    && self.entries[&head].children.is_empty() =>
+            {
+                head
+            }
+            _ => return self.commit_as(label),
+        };
+        let new_state = match self.active.take() {
+            Some(new_state) => new_state,
+            None => return,
+        };
+
+        let entry = self.entries.get_mut(&head).unwrap();
+        self.bytes -= entry.delta.size();
+        entry.delta.revert(&mut self.current);
+        let old_state = mem::replace(&mut self.current, new_state);
+        entry.delta = D::diff(old_state, &self.current);
+        self.bytes += entry.delta.size();
+
+        self.enforce_limits();
+    }
+
+    /// Forgets an entry that isn't applied, along with everything committed on top of it.
+    fn remove_subtree(&mut self, id: EntryId) {
+        let children = match self.entries.get(&id) {
+            Some(entry) => entry.children.clone(),
+            None => return,
+        };
+        // Children first, while the entries they unlink themselves from are still there.
+        for child in children {
+            self.remove_subtree(child);
         }
+        let entry = self.entries.remove(&id).unwrap();
+        self.bytes -= entry.delta.size();
+        self.children_mut(entry.parent).retain(|&child| child != id);
+        if self.redo_child(entry.parent) == Some(id) {
+            self.set_redo_child(entry.parent, None);
+        }
+    }
+
+    /// Forgets entries until the history fits within `self.limits`:
+    /// first abandoned branches, then the oldest entries on the current one.
+    fn enforce_limits(&mut self) {
+        let Limits {
+            max_entries,
+            max_bytes,
+        } = self.limits;
+        // Nothing here changes which entries are on the path, besides removing the oldest ones.
+        let mut path: HashSet<_> = self.path().into_iter().collect();
+        while max_entries.is_some_and(|max| self.entries.len() > max)
+            || max_bytes.is_some_and(|max| self.bytes > max)
+        {
+            let abandoned_leaf = self
+                .entries
+                .iter()
+                .find(|(id, entry)| entry.children.is_empty() && !path.contains(id))
+                .map(|(&id, _)| id);
+            if let Some(id) = abandoned_leaf {
+                self.remove_subtree(id);
+                continue;
+            }
+
+            // Forgetting the oldest entry makes its state the oldest one available.
+            // The most recent applied entry is always kept.
+            if self.roots.len() != 1 || self.position() <= 1 {
+                break;
+            }
+            path.remove(&self.roots[0]);
+            let oldest = self.entries.remove(&self.roots[0]).unwrap();
+            self.bytes -= oldest.delta.size();
+            for child in &oldest.children {
+                self.entries.get_mut(child).unwrap().parent = None;
+            }
+            self.roots = oldest.children;
+            self.root_redo = oldest.redo_child;
+        }
+    }
+}
+
+impl<T: Clone, D: Delta<T>> UndoStack<T, D> {
+    /// Opens a transaction, which can be nested inside another one.
+    pub fn begin(&mut self) {
+        self.savepoints.push(self.active.clone());
+    }
+
+    /// Closes the innermost transaction, keeping its changes.
+    /// If it was the outermost one, they become a history entry with the given label.
+    ///
+    /// Panics if there's no open transaction.
+    pub fn commit_transaction(&mut self, label: impl Into<String>) {
+        self.savepoints.pop().expect("no transaction to commit");
+        self.commit_as(label);
+    }
+
+    /// Closes the innermost transaction, discarding its changes.
+    ///
+    /// Panics if there's no open transaction.
+    pub fn rollback(&mut self) {
+        self.active = self.savepoints.pop().expect("no transaction to roll back");
+    }
+
+    /// Opens a transaction that rolls back when the returned guard is dropped, unless it's committed first.
+    pub fn transaction(&mut self) -> Transaction<'_, T, D> {
+        self.begin();
+        Transaction {
+            stack: self,
+            committed: false,
+        }
+    }
+}
+
+/// An open transaction on an `UndoStack`, which it dereferences to.
+#[must_use]
+pub struct Transaction<'a, T: Clone, D: Delta<T>> {
+    stack: &'a mut UndoStack<T, D>,
+    committed: bool,
+}
+
+impl<T: Clone, D: Delta<T>> Transaction<'_, T, D> {
+    pub fn commit(mut self, label: impl Into<String>) {
+        self.committed = true;
+        self.stack.commit_transaction(label);
+    }
+
+    #[inline]
+    pub fn rollback(self) {}
+}
+
+impl<T: Clone, D: Delta<T>> Drop for Transaction<'_, T, D> {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.stack.rollback();
+        }
+    }
+}
+
+impl<T: Clone, D: Delta<T>> Deref for Transaction<'_, T, D> {
+    type Target = UndoStack<T, D>;
+    fn deref(&self) -> &Self::Target {
+        self.stack
+    }
+}
+
+impl<T: Clone, D: Delta<T>> DerefMut for Transaction<'_, T, D> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.stack
+    }
+}
+
+impl Encode for EntryId {
+    fn encode(&self, w: &mut Writer) {
+        self.0.encode(w);
+    }
+}
+
+impl Decode for EntryId {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        u64::decode(r).map(Self)
+    }
+}
+
+impl Encode for HistoryMode {
+    fn encode(&self, w: &mut Writer) {
+        (*self as u8).encode(w);
+    }
+}
+
+impl Decode for HistoryMode {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        match u8::decode(r)? {
+            0 => Ok(Self::Linear),
+            1 => Ok(Self::Tree),
+            _ => Err(DecodeError::Invalid("history mode")),
+        }
+    }
+}
+
+/// Saves the whole tree, along with any uncommitted edit.
+/// Open transactions and the limits aren't saved; a decoded stack has no limits.
+impl<T: Encode, D: Encode> Encode for UndoStack<T, D> {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.current);
+        let active = match self.savepoints.first() {
+            Some(saved) => saved.as_ref(),
+            None => self.active.as_ref(),
+        };
+        w.write(&active);
+        self.entries.len().encode(w);
+        for (id, entry) in &self.entries {
+            w.write(id);
+            w.write(&entry.delta);
+            w.write(&entry.label);
+            w.write(&entry.time);
+            w.write(&entry.parent);
+            w.write(&entry.children);
+            w.write(&entry.redo_child);
+        }
+        w.write(&self.head);
+        w.write(&self.roots);
+        w.write(&self.root_redo);
+        w.write(&self.next_id);
+        w.write(&self.mode);
+    }
+}
+
+impl<T: Decode, D: Decode + Delta<T>> Decode for UndoStack<T, D> {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        let mut stack = Self::with_limits(r.read()?, Limits::default());
+        stack.active = r.read()?;
+        let num_entries: usize = r.read()?;
+        for _ in 0..num_entries {
+            let id: EntryId = r.read()?;
+            let entry: Entry<D> = Entry {
+                delta: r.read()?,
+                label: r.read()?,
+                time: r.read()?,
+                parent: r.read()?,
+                children: r.read()?,
+                redo_child: r.read()?,
+            };
+            stack.bytes += entry.delta.size();
+            stack.entries.insert(id, entry);
+        }
+        stack.head = r.read()?;
+        stack.roots = r.read()?;
+        stack.root_redo = r.read()?;
+        stack.next_id = r.read()?;
+        stack.mode = r.read()?;
+
+        // Every link has to point somewhere, and go both ways.
+        let parent_is = |id: &EntryId, parent| {
+            stack
+                .entries
+                .get(id)
+                .is_some_and(|entry| entry.parent == parent)
+        };
+        let links_ok = stack.head.is_none_or(|id| stack.entries.contains_key(&id))
+            && stack.roots.iter().all(|id| parent_is(id, None))
+            && stack.root_redo.is_none_or(|id| stack.roots.contains(&id))
+            && stack.entries.iter().all(|(&id, entry)| {
+                id.0 < stack.next_id
+                    && entry.parent.is_none_or(|p| stack.entries.contains_key(&p))
+                    && stack.children(entry.parent).contains(&id)
+                    && entry
+                        .children
+                        .iter()
+                        .all(|child| parent_is(child, Some(id)))
+                    && entry.redo_child.is_none_or(|c| entry.children.contains(&c))
+            });
+        if !links_ok || stack.entries.len() != num_entries || !stack.is_tree() {
+            return Err(DecodeError::Invalid("history"));
+        }
+        if !stack.replay() {
+            return Err(DecodeError::Invalid("history entry"));
+        }
+        Ok(stack)
+    }
+}
+
+impl<T, D: Delta<T>> UndoStack<T, D> {
+    /// Whether walking down from the roots reaches every entry exactly once, `head` included,
+    /// so that following parents always ends at a root. Assumes the links go both ways.
+    fn is_tree(&self) -> bool {
+        let mut reached = HashSet::new();
+        let mut unvisited = self.roots.clone();
+        while let Some(id) = unvisited.pop() {
+            if !reached.insert(id) {
+                return false;
+            }
+            unvisited.extend_from_slice(&self.entries[&id].children);
+        }
+        reached.len() == self.entries.len() && self.head.is_none_or(|id| reached.contains(&id))
+    }
+
+    /// Undoes back to the oldest state and visits every entry from there, checking that each delta fits the
+    /// state it's applied to, then goes back to `head`. Returns whether they all fit.
+    fn replay(&mut self) -> bool {
+        let applied: Vec<_> = self.ancestors(self.head).collect();
+        for &id in &applied {
+            let delta = &mut self.entries.get_mut(&id).unwrap().delta;
+            if !delta.can_revert(&self.current) {
+                return false;
+            }
+            delta.revert(&mut self.current);
+        }
+
+        // Each entry is pushed twice: once to apply it, then again underneath its children to revert it.
+        let mut unvisited: Vec<_> = self.roots.iter().map(|&id| (id, true)).collect();
+        while let Some((id, entering)) = unvisited.pop() {
+            let entry = self.entries.get_mut(&id).unwrap();
+            if entering {
+                if !entry.delta.can_apply(&self.current) {
+                    return false;
+                }
+                entry.delta.apply(&mut self.current);
+                unvisited.push((id, false));
+                unvisited.extend(entry.children.iter().map(|&child| (child, true)));
+            } else {
+                if !entry.delta.can_revert(&self.current) {
+                    return false;
+                }
+                entry.delta.revert(&mut self.current);
+            }
+        }
+
+        for &id in applied.iter().rev() {
+            let delta = &mut self.entries.get_mut(&id).unwrap().delta;
+            if !delta.can_apply(&self.current) {
+                return false;
+            }
+            delta.apply(&mut self.current);
+        }
+        true
     }
 }
 
-impl<T: Default> Default for UndoStack<T> {
+impl<T: Default, D: Delta<T>> Default for UndoStack<T, D> {
     fn default() -> Self {
-        Self::new(T::default())
+        Self::with_limits(T::default(), Limits::default())
     }
 }
 
-impl<T> Deref for UndoStack<T> {
+impl<T, D> Deref for UndoStack<T, D> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
-        self.active.as_ref().unwrap_or(&self.history[self.idx])
+        self.active.as_ref().unwrap_or(&self.current)
     }
 }
 
-impl<T: Clone> DerefMut for UndoStack<T> {
+impl<T: Clone, D> DerefMut for UndoStack<T, D> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        let history = &self.history;
-        let idx = self.idx;
-        self.active.get_or_insert_with(|| history[idx].clone())
+        let current = &self.current;
+        self.active.get_or_insert_with(|| current.clone())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::UndoStack;
+    use std::thread;
+    use std::time::{Duration, SystemTime};
+
+    use super::{Delta, HistoryMode, Limits, UndoStack, DEFAULT_LABEL};
 
     #[test]
     fn test_undo_stack() {
@@ -124,5 +878,267 @@ mod tests {
         assert_eq!(s.a, -2);
         s.redo();
         assert_eq!(s.a, -2); // Check that stuff got truncated on commit
+
+        s.set_limits(Limits {
+            max_entries: None,
+            max_bytes: Some(1),
+        });
+        assert_eq!((s.len(), s.memory_usage()), (2, 0)); // Snapshots don't count towards max_bytes
+    }
+
+    #[test]
+    fn test_truncate_nested() {
+        let mut s = UndoStack::new(0);
+        for i in 1..=3 {
+            *s = i;
+            s.commit();
+        }
+        s.jump_to(0);
+        *s = 10;
+        s.commit();
+        assert_eq!((*s, s.len(), s.redo_label()), (10, 1, None));
+    }
+
+    #[test]
+    fn test_limits() {
+        struct Add(i32);
+        impl Delta<i32> for Add {
+            fn diff(old: i32, new: &i32) -> Self {
+                Self(new - old)
+            }
+            fn apply(&mut self, state: &mut i32) {
+                *state += self.0;
+            }
+            fn revert(&mut self, state: &mut i32) {
+                *state -= self.0;
+            }
+            fn size(&self) -> usize {
+                4
+            }
+        }
+
+        let limits = Limits {
+            max_entries: Some(3),
+            max_bytes: None,
+        };
+        let mut s: UndoStack<i32, Add> = UndoStack::with_limits(0, limits);
+        for i in 1..=5 {
+            *s += i;
+            s.commit();
+        }
+        assert_eq!((*s, s.len(), s.memory_usage()), (15, 3, 12));
+        while s.can_undo() {
+            s.undo();
+        }
+        assert_eq!(*s, 3);
+        s.redo();
+        assert_eq!(*s, 6);
+
+        s.set_limits(Limits {
+            max_entries: None,
+            max_bytes: Some(1),
+        });
+        assert_eq!((s.len(), s.memory_usage()), (3, 12)); // The latest applied entry is always kept
+        s.redo();
+        s.set_limits(s.limits());
+        assert_eq!((*s, s.len()), (10, 2));
+        s.undo();
+        assert_eq!(*s, 6);
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut s = UndoStack::new(0);
+        for (i, label) in ["Add line", "Move point 1", "Clear"].iter().enumerate() {
+            *s = i + 1;
+            s.commit_as(*label);
+        }
+        *s = 10;
+        s.commit();
+        assert_eq!(s.undo_label(), Some(DEFAULT_LABEL));
+        assert_eq!(s.redo_label(), None);
+
+        s.jump_to(1);
+        assert_eq!((*s, s.position()), (1, 1));
+        assert_eq!(s.undo_label(), Some("Add line"));
+        assert_eq!(s.redo_label(), Some("Move point 1"));
+        let labels: Vec<_> = s.entries().map(|e| (e.label, e.applied)).collect();
+        assert_eq!(
+            labels,
+            [
+                ("Add line", true),
+                ("Move point 1", false),
+                ("Clear", false),
+                (DEFAULT_LABEL, false)
+            ]
+        );
+        assert!(s
+            .entries()
+            .zip(s.entries().skip(1))
+            .all(|(a, b)| a.time <= b.time));
+
+        s.jump_to(100);
+        assert_eq!((*s, s.position()), (10, 4));
+        s.jump_to(0);
+        assert_eq!(*s, 0);
+        assert_eq!(s.undo_label(), None);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let mut s = UndoStack::new(vec![1]);
+        s.begin();
+        s.push(2);
+        s.commit(); // Held back until the transaction is committed
+        s.begin();
+        s.push(3);
+        s.rollback();
+        s.begin();
+        s.push(4);
+        s.commit_transaction("inner");
+        assert_eq!(*s, [1, 2, 4]);
+        assert_eq!(s.position(), 0);
+        s.undo(); // Can't undo halfway through a transaction
+        s.commit_transaction("outer");
+        assert_eq!(s.undo_label(), Some("outer"));
+        assert_eq!(s.transaction_depth(), 0);
+
+        fn fail_partway(s: &mut UndoStack<Vec<i32>>) -> Result<(), ()> {
+            let mut tx = s.transaction();
+            tx.push(5);
+            let mut inner = tx.transaction();
+            inner.push(6);
+            inner.commit("push 6");
+            tx.push(7);
+            Err(())
+        }
+        let failed = fail_partway(&mut s);
+        assert!(failed.is_err());
+        assert_eq!(*s, [1, 2, 4]);
+        assert_eq!(s.len(), 1);
+
+        let mut tx = s.transaction();
+        tx.clear();
+        tx.commit("clear");
+        assert_eq!((s.len(), s.undo_label()), (2, Some("clear")));
+        s.undo();
+        assert_eq!(*s, [1, 2, 4]);
+    }
+
+    #[test]
+    fn test_amend() {
+        let mut s = UndoStack::new(vec![1]);
+        s.push(2);
+        s.amend_as("push");
+        let time = s.all_entries().next().unwrap().time;
+        thread::sleep(Duration::from_millis(5));
+        s.push(3);
+        s.amend_as("push");
+        // Still sorted by when it was first committed, for `jump_to_time`.
+        assert_eq!(s.all_entries().next().unwrap().time, time);
+        s.push(4);
+        s.amend_as("push four");
+        assert_eq!((s.len(), s.undo_label()), (2, Some("push four")));
+        s.undo();
+        assert_eq!(*s, [1, 2, 3]);
+
+        // Amending an entry that can be redone from starts a new one instead.
+        s.undo();
+        s.push(5);
+        s.amend_as("push");
+        assert_eq!(s.len(), 1);
+        s.push(6);
+        s.amend_as("push");
+        assert_eq!((s.len(), s.position()), (1, 1));
+        s.undo();
+        assert_eq!(*s, [1]);
+        s.redo();
+        assert_eq!(*s, [1, 5, 6]);
+    }
+
+    #[test]
+    fn test_undo_tree() {
+        let mut s = UndoStack::new(0);
+        s.set_mode(HistoryMode::Tree);
+        let start = SystemTime::now();
+        for i in 1..=3 {
+            *s = i;
+            s.commit_as(format!("set {}", i));
+        }
+        thread::sleep(Duration::from_millis(5));
+        let before_branch = SystemTime::now();
+
+        s.undo();
+        s.undo();
+        *s = 20;
+        s.commit();
+        assert_eq!(s.len(), 4);
+        assert_eq!(s.position(), 2);
+        assert_eq!(s.redo_label(), None);
+
+        // The abandoned branch is still there, next to the new one.
+        assert!(!s.go_to_sibling(1));
+        assert!(s.go_to_sibling(-1));
+        assert_eq!(*s, 2);
+        assert_eq!(s.redo_label(), Some("set 3"));
+        s.redo();
+        assert_eq!(*s, 3);
+
+        s.jump_to_time(start);
+        assert_eq!(*s, 0);
+        s.jump_to_time(before_branch);
+        assert_eq!(*s, 3);
+        s.jump_to_time(SystemTime::now());
+        assert_eq!(*s, 20);
+
+        let ids: Vec<_> = s.all_entries().map(|e| e.id).collect();
+        let tree: Vec<_> = s.all_entries().map(|e| (e.label, e.parent)).collect();
+        assert_eq!(
+            tree,
+            [
+                ("set 1", None),
+                ("set 2", Some(ids[0])),
+                ("set 3", Some(ids[1])),
+                (DEFAULT_LABEL, Some(ids[0])),
+            ]
+        );
+        s.go_to(Some(ids[2]));
+        assert_eq!(*s, 3);
+        assert_eq!(s.position(), 3);
+
+        // Limits forget abandoned branches before anything else.
+        s.set_limits(Limits {
+            max_entries: Some(3),
+            max_bytes: None,
+        });
+        assert_eq!(s.all_entries().map(|e| e.id).collect::<Vec<_>>(), &ids[..3]);
+
+        s.undo();
+        *s = 30;
+        s.commit();
+        s.set_mode(HistoryMode::Linear);
+        let labels: Vec<_> = s.entries().map(|e| e.label).collect();
+        assert_eq!(labels, ["set 1", "set 2", "Edit"]);
+        assert_eq!(s.len(), 3);
+    }
+
+    #[test]
+    fn test_corrupt_tree() {
+        let mut s = UndoStack::new(0);
+        for i in 1..=3 {
+            *s = i;
+            s.commit();
+        }
+        assert!(s.is_tree());
+        assert!(s.replay());
+        assert_eq!(*s, 3);
+
+        // Parents and children that agree, but go around in a circle that no root leads into.
+        let ids: Vec<_> = s.entries.keys().copied().collect();
+        s.entries.get_mut(&ids[0]).unwrap().parent = Some(ids[2]);
+        s.entries.get_mut(&ids[2]).unwrap().children.push(ids[0]);
+        s.roots.clear();
+        s.root_redo = None;
+        assert!(!s.is_tree());
     }
 }
@@ -0,0 +1,213 @@
+use crate::codec::{self, Decode, DecodeError, Encode, Reader, Writer};
+use crate::point::{Point, Rect};
+
+/// The most zoomed-out the view can get: one screen pixel per 32 scene pixels.
+pub const MIN_SCALE: f32 = 1.0 / 32.0;
+/// The most zoomed-in the view can get: 64 screen pixels per scene pixel.
+pub const MAX_SCALE: f32 = 64.0;
+
+/// Steps `scale` by `factor` notches along the zoom ladder `..., 1/3, 1/2, 1, 2, 3, ...`.
+pub fn change_scale(mut scale: f32, factor: i32) -> f32 {
+    assert!(scale > 0.0);
+    if scale < 1.0 {
+        scale = scale.recip().round();
+        scale = -scale + 2.0;
+    }
+
+    scale += factor as f32;
+
+    if scale < 1.0 {
+        scale = (scale - 2.0).abs();
+        scale = scale.recip();
+    }
+    scale
+}
+
+/// Snaps an arbitrary scale down to the nearest rung of the zoom ladder used by `change_scale`.
+fn floor_scale(scale: f32) -> f32 {
+    let scale = if scale >= 1.0 {
+        scale.floor()
+    } else {
+        scale.recip().ceil().recip()
+    };
+    scale.clamp(MIN_SCALE, MAX_SCALE)
+}
+
+/// Which part of the scene is visible, and how large it appears on screen.
+///
+/// A scene point `p` appears at screen position `(p - scene_pos) * scale`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Viewport {
+    /// The size of the visible area, in screen pixels.
+    pub screen_dims: Point<f32>,
+    /// The scene position shown at the top-left corner of the screen.
+    pub scene_pos: Point<f32>,
+    /// Screen pixels per scene pixel.
+    pub scale: f32,
+}
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Self {
+            screen_dims: [100.0, 100.0].into(),
+            scene_pos: [0.0, 0.0].into(),
+            scale: 1.0,
+        }
+    }
+}
+
+impl Viewport {
+    #[inline]
+    pub fn screen_to_scene(&self, screen_pos: Point<f32>) -> Point<f32> {
+        self.scene_pos + (screen_pos / self.scale)
+    }
+
+    #[inline]
+    pub fn scene_to_screen(&self, scene_pos: Point<f32>) -> Point<f32> {
+        (scene_pos - self.scene_pos) * self.scale
+    }
+
+    /// The part of the scene that is currently on screen.
+    pub fn visible_rect(&self) -> Rect {
+        Rect::new(self.scene_pos, self.screen_to_scene(self.screen_dims))
+    }
+
+    /// Changes the scale, keeping the scene point under `screen_pos` where it is.
+    pub fn zoom_about(&mut self, screen_pos: Point<f32>, new_scale: f32) {
+        let anchor = self.screen_to_scene(screen_pos);
+        self.scale = new_scale.clamp(MIN_SCALE, MAX_SCALE);
+        self.scene_pos = anchor - (screen_pos / self.scale);
+    }
+
+    /// Zooms by some number of notches along the zoom ladder, keeping `screen_pos` anchored.
+    pub fn zoom_steps(&mut self, screen_pos: Point<f32>, factor: i32) {
+        self.zoom_about(screen_pos, change_scale(self.scale, factor));
+    }
+
+    /// Returns to 100% zoom around the center of the screen.
+    pub fn reset_zoom(&mut self) {
+        self.zoom_about(self.screen_dims / 2.0, 1.0);
+    }
+
+    /// Centers `rect` on screen at the largest zoom level where it fits, leaving `margin` screen pixels of room.
+    pub fn fit(&mut self, rect: Rect, margin: f32) {
+        let room = self.screen_dims - margin * 2.0;
+        let size = rect.size();
+        let scale = match (size.x > 0.0, size.y > 0.0) {
+            (true, true) => f32::min(room.x / size.x, room.y / size.y),
+            (true, false) => room.x / size.x,
+            (false, true) => room.y / size.y,
+            (false, false) => self.scale,
+        };
+        self.scale = floor_scale(scale);
+        self.scene_pos = rect.center() - (self.screen_dims / 2.0) / self.scale;
+    }
+}
+
+impl Encode for Viewport {
+    fn encode(&self, w: &mut Writer) {
+        w.write(&self.screen_dims);
+        w.write(&self.scene_pos);
+        w.write(&self.scale);
+    }
+}
+
+impl Decode for Viewport {
+    fn decode(r: &mut Reader<'_>) -> codec::Result<Self> {
+        let screen_dims: Point<f32> = r.read()?;
+        let scene_pos: Point<f32> = r.read()?;
+        let scale: f32 = r.read()?;
+        let finite = [
+            screen_dims.x,
+            screen_dims.y,
+            scene_pos.x,
+            scene_pos.y,
+            scale,
+        ];
+        if !finite.iter().all(|v| v.is_finite()) {
+            return Err(DecodeError::Invalid("viewport"));
+        }
+        Ok(Self {
+            screen_dims,
+            scene_pos,
+            scale: scale.clamp(MIN_SCALE, MAX_SCALE),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{change_scale, Viewport, MAX_SCALE, MIN_SCALE};
+    use crate::codec::{DecodeError, Reader, Writer};
+    use crate::point::{Point, Rect};
+
+    #[test]
+    fn test_change_scale() {
+        assert_eq!(change_scale(1.0, 1), 2.0);
+        assert_eq!(change_scale(1.0, -1), 0.5);
+        assert_eq!(change_scale(0.5, -1), 1.0 / 3.0);
+        assert_eq!(change_scale(1.0 / 3.0, 2), 1.0);
+        assert_eq!(change_scale(3.0, -4), 1.0 / 3.0);
+    }
+
+    #[test]
+    fn test_conversions() {
+        let vp = Viewport {
+            screen_dims: Point::new(200.0, 100.0),
+            scene_pos: Point::new(-10.0, 20.0),
+            scale: 4.0,
+        };
+        let scene = Point::new(5.0, 25.0);
+        assert_eq!(vp.scene_to_screen(scene), Point::new(60.0, 20.0));
+        assert_eq!(vp.screen_to_scene(Point::new(60.0, 20.0)), scene);
+        assert_eq!(vp.visible_rect().max, Point::new(40.0, 45.0));
+    }
+
+    #[test]
+    fn test_zoom_limits() {
+        let mut vp = Viewport::default();
+        let anchor = Point::new(30.0, 70.0);
+        let before = vp.screen_to_scene(anchor);
+        vp.zoom_steps(anchor, 1000);
+        assert_eq!(vp.scale, MAX_SCALE);
+        assert_eq!(vp.screen_to_scene(anchor), before);
+        vp.zoom_steps(anchor, -1000);
+        assert_eq!(vp.scale, MIN_SCALE);
+        assert_eq!(vp.screen_to_scene(anchor), before);
+
+        // Decoding clamps the scale, but doesn't let through one that's not a number.
+        let decode = |scale: f32| {
+            let mut w = Writer::new();
+            w.write(&Viewport { scale, ..vp });
+            Reader::new(&w.finish())
+                .read::<Viewport>()
+                .map(|vp| vp.scale)
+        };
+        assert_eq!(decode(1e9), Ok(MAX_SCALE));
+        assert_eq!(decode(f32::NAN), Err(DecodeError::Invalid("viewport")));
+        assert_eq!(decode(f32::INFINITY), Err(DecodeError::Invalid("viewport")));
+    }
+
+    #[test]
+    fn test_fit() {
+        let mut vp = Viewport {
+            screen_dims: Point::new(400.0, 300.0),
+            ..Viewport::default()
+        };
+        vp.fit(
+            Rect::new(Point::new(10.0, 10.0), Point::new(110.0, 60.0)),
+            0.0,
+        );
+        assert_eq!(vp.scale, 4.0);
+        assert_eq!(
+            vp.scene_to_screen(Point::new(60.0, 35.0)),
+            Point::new(200.0, 150.0)
+        );
+
+        vp.fit(Rect::new(Point::default(), Point::new(1920.0, 1080.0)), 0.0);
+        assert_eq!(vp.scale, 1.0 / 5.0);
+
+        vp.reset_zoom();
+        assert_eq!(vp.scale, 1.0);
+    }
+}
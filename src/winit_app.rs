@@ -0,0 +1,457 @@
+//! The editor in a winit window, which runs wherever glutin does.
+//!
+//! There are no controls besides the canvas, so everything the editor can't do on its own has a shortcut:
+//!
+//! - M, L, B: draw moves, lines or curves
+//! - G: toggle the grid, S: toggle snapping
+//! - K: lock or unlock the background, O: fade it out a step, back to opaque after the faintest
+//! - Ctrl+S: save the project to the path given on the command line
+//! - Ctrl+E: copy the layers as ASS events
+//!
+//! Dropping an image on the window makes it the background, and Shift+right-dragging moves it.
+//!
+//! If the last session crashed, the title bar asks whether to recover it, and Y or N answers.
+//! Until then, nothing else can be done, and nothing is autosaved over the recovery file.
+//!
+//! Errors, and anything else worth telling, show up in the title bar too.
+
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use glutin::dpi::LogicalSize;
+use glutin::event::{
+    ElementState, Event, KeyboardInput, ModifiersState, MouseScrollDelta, VirtualKeyCode,
+    WindowEvent,
+};
+use glutin::event_loop::{ControlFlow, EventLoop};
+use glutin::window::WindowBuilder;
+use glutin::{PossiblyCurrent, WindowedContext};
+
+use crate::clipboard::{Clipboard, PortableClipboard};
+use crate::drawing::CommandKind;
+use crate::editor::{Editor, InputEvent, Key, MouseButton, Request, Response};
+use crate::gl::{context_builder, OpenGlCanvas};
+use crate::point::Point;
+use crate::project::Project;
+use crate::recovery::{self, Autosave};
+
+type Canvas = OpenGlCanvas<WindowedContext<PossiblyCurrent>>;
+
+const TITLE: &str = "assdraw";
+
+const GRID_SPACING: f32 = 10.0;
+
+/// The background opacities O steps through.
+const BACKGROUND_OPACITIES: [f32; 4] = [1.0, 0.75, 0.5, 0.25];
+
+/// How often to check whether the session needs autosaving.
+const AUTOSAVE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+fn translate_key(key: VirtualKeyCode) -> Option<Key> {
+    use VirtualKeyCode as K;
+    let key = match key {
+        K::LControl | K::RControl => Key::Control,
+        K::LShift | K::RShift => Key::Shift,
+        K::LAlt | K::RAlt => Key::Alt,
+        K::C => Key::C,
+        K::V => Key::V,
+        K::Y => Key::Y,
+        K::H => Key::H,
+        K::Z => Key::Z,
+        K::Key0 => Key::Zero,
+        K::Key1 => Key::One,
+        K::Key2 => Key::Two,
+        _ => return None,
+    };
+    Some(key)
+}
+
+fn translate_button(button: glutin::event::MouseButton) -> Option<MouseButton> {
+    match button {
+        glutin::event::MouseButton::Left => Some(MouseButton::Left),
+        glutin::event::MouseButton::Right => Some(MouseButton::Right),
+        _ => None,
+    }
+}
+
+struct App {
+    canvas: Canvas,
+    editor: Rc<RefCell<Editor>>,
+    autosave: Autosave,
+    /// Not there if the system clipboard couldn't be opened, which was reported already.
+    clipboard: Option<PortableClipboard>,
+    /// Where Ctrl+S saves to.
+    project_path: Option<PathBuf>,
+    /// The crashed session, until the user says whether to recover it.
+    pending_recovery: Option<Project>,
+    /// Whether the title shows that the last autosave failed, to clear it once one succeeds.
+    autosave_failed: bool,
+    modifiers: ModifiersState,
+    cursor: Point<i32>,
+    /// Scrolling that hasn't added up to a whole notch yet, which touchpads report.
+    wheel: f64,
+}
+
+impl App {
+    fn handle_input(&mut self, event: InputEvent) {
+        let response = self.editor.borrow_mut().handle(event);
+        self.apply(response);
+    }
+
+    /// Carries out whatever the editor asked for after handling some input.
+    fn apply(&mut self, response: Response) {
+        // No need to capture the pointer: winit keeps reporting it while a button is held,
+        // even outside the window.
+        match response.request {
+            Some(Request::Copy(text)) => self.copy(&text),
+            Some(Request::Paste) => self.paste(),
+            None => (),
+        }
+
+        let editor = self.editor.borrow();
+        if response.drawing_changed {
+            self.canvas
+                .update_layers(editor.layers(), editor.active_layer(), editor.raster_mode());
+        }
+        if response.background_changed {
+            self.canvas.set_image(editor.background());
+        }
+        if response.redraw {
+            self.canvas.surface().window().request_redraw();
+        }
+    }
+
+    fn copy(&mut self, text: &str) {
+        let result = match &mut self.clipboard {
+            Some(clipboard) => clipboard.set_text(text),
+            None => return,
+        };
+        if let Err(e) = result {
+            self.show_message(Some(&format!("Couldn't copy: {}", e)));
+        }
+    }
+
+    /// Pastes a drawing, a clip or a background, depending on what's on the clipboard.
+    fn paste(&mut self) {
+        let clipboard = match &mut self.clipboard {
+            Some(clipboard) => clipboard,
+            None => return,
+        };
+        let result = self.editor.borrow_mut().paste(clipboard);
+        match result {
+            Ok(response) => self.apply(response),
+            Err(e) => self.show_message(Some(&format!("Couldn't paste: {}", e))),
+        }
+    }
+
+    fn render(&self) {
+        let editor = self.editor.borrow();
+        self.canvas
+            .set_background_transform(editor.background_transform());
+        self.canvas.set_colors(editor.colors());
+        self.canvas.update_overlay(&editor.overlay());
+        self.canvas.render(editor.viewport());
+    }
+
+    fn handle_resize(&mut self) {
+        let screen_dims = self.canvas.resize();
+        let response = self.editor.borrow_mut().resize(screen_dims);
+        self.apply(response);
+    }
+
+    fn handle_wheel(&mut self, delta: MouseScrollDelta) {
+        self.wheel += match delta {
+            MouseScrollDelta::LineDelta(_, y) => y as f64,
+            MouseScrollDelta::PixelDelta(pos) => pos.y / 120.0,
+        };
+        let notches = self.wheel.trunc();
+        if notches != 0.0 {
+            self.wheel -= notches;
+            self.handle_input(InputEvent::Wheel(notches as i32, self.cursor));
+        }
+    }
+
+    fn handle_key(&mut self, input: KeyboardInput) {
+        let key = match input.virtual_keycode {
+            Some(key) => key,
+            None => return,
+        };
+        if let Some(key) = translate_key(key) {
+            self.handle_input(match input.state {
+                ElementState::Pressed => InputEvent::KeyDown(key),
+                ElementState::Released => InputEvent::KeyUp(key),
+            });
+        }
+        if input.state != ElementState::Pressed {
+            return;
+        }
+
+        use VirtualKeyCode as K;
+        if self.modifiers.ctrl() {
+            match key {
+                K::S => self.save_project(),
+                K::E => {
+                    let text = self.editor.borrow().export_text();
+                    self.copy(&text);
+                }
+                _ => (),
+            }
+            return;
+        }
+        match key {
+            K::M => self.editor.borrow_mut().set_draw_mode(CommandKind::Move),
+            K::L => self.editor.borrow_mut().set_draw_mode(CommandKind::Line),
+            K::B => self.editor.borrow_mut().set_draw_mode(CommandKind::Bezier),
+            K::G | K::S => {
+                let mut editor = self.editor.borrow_mut();
+                let mut settings = editor.snap_settings();
+                if key == K::G {
+                    settings.grid = match settings.grid {
+                        Some(_) => None,
+                        None => Some(GRID_SPACING),
+                    };
+                } else {
+                    settings.enabled = !settings.enabled;
+                }
+                let response = editor.set_snap_settings(settings);
+                drop(editor);
+                self.apply(response);
+            }
+            K::K | K::O => {
+                let mut editor = self.editor.borrow_mut();
+                let mut transform = editor.background_transform();
+                if key == K::K {
+                    transform.locked = !transform.locked;
+                } else {
+                    let next = BACKGROUND_OPACITIES
+                        .iter()
+                        .position(|&opacity| opacity < transform.opacity)
+                        .unwrap_or(0);
+                    transform.opacity = BACKGROUND_OPACITIES[next];
+                }
+                let response = editor.set_background_transform(transform);
+                drop(editor);
+                self.apply(response);
+            }
+            _ => (),
+        }
+    }
+
+    fn save_project(&self) {
+        let path = match &self.project_path {
+            Some(path) => path,
+            None => {
+                self.show_message(Some(
+                    "Nowhere to save to: start the editor with a project path to save to it.",
+                ));
+                return;
+            }
+        };
+        let project = self.editor.borrow().to_project();
+        match project.save_to(path) {
+            Ok(()) => self.show_message(Some(&format!("Saved {}", path.display()))),
+            Err(e) => self.show_message(Some(&format!("Couldn't save the project: {}", e))),
+        }
+    }
+
+    fn load_project(&mut self, project: Project) {
+        let response = self.editor.borrow_mut().load_project(project);
+        self.apply(response);
+    }
+
+    /// Shows a message in the title bar, or just the title if there's none.
+    fn show_message(&self, message: Option<&str>) {
+        let title = match message {
+            Some(message) => format!("{} - {}", TITLE, message),
+            None => TITLE.to_owned(),
+        };
+        self.canvas.surface().window().set_title(&title);
+    }
+
+    /// Asks whether to pick up where the last session left off, if it crashed,
+    /// and otherwise opens the project given on the command line.
+    fn start(&mut self) {
+        match recovery::find_recovery(self.autosave.path()) {
+            Some(Ok(project)) => {
+                self.pending_recovery = Some(project);
+                self.show_message(Some(
+                    "The editor didn't exit normally last time. Recover its last session? (Y/N)",
+                ));
+            }
+            Some(Err(e)) => {
+                self.finish_start(None);
+                self.show_message(Some(&format!("Couldn't recover the last session: {}", e)));
+            }
+            None => self.finish_start(None),
+        }
+    }
+
+    fn answer_recovery(&mut self, recover: bool) {
+        let project = self.pending_recovery.take();
+        self.show_message(None);
+        self.finish_start(project.filter(|_| recover));
+    }
+
+    /// Loads the recovered session if there is one, and the project given on the command line otherwise,
+    /// then starts keeping the session safe from crashes.
+    fn finish_start(&mut self, recovered: Option<Project>) {
+        if let Some(project) = recovered {
+            self.load_project(project);
+        } else if let Some(path) = self.project_path.clone().filter(|path| path.exists()) {
+            match Project::load_from(&path) {
+                Ok(project) => self.load_project(project),
+                Err(e) => self.show_message(Some(&format!("Couldn't open the project: {}", e))),
+            }
+        }
+
+        let editor = Rc::downgrade(&self.editor);
+        recovery::install_panic_hook(self.autosave.path().to_owned(), move || {
+            let editor = editor.upgrade()?;
+            let editor = editor.try_borrow().ok()?;
+            Some(editor.to_project())
+        });
+    }
+
+    fn autosave(&mut self) {
+        if self.pending_recovery.is_some() {
+            return;
+        }
+        let editor = self.editor.borrow();
+        let result = self.autosave.tick(Instant::now(), || editor.to_project());
+        drop(editor);
+        match result {
+            Ok(true) if self.autosave_failed => {
+                self.autosave_failed = false;
+                self.show_message(None);
+            }
+            Ok(_) => (),
+            Err(e) => {
+                self.autosave_failed = true;
+                self.show_message(Some(&format!("Autosave failed: {}", e)));
+            }
+        }
+    }
+
+    fn exit(&mut self) {
+        recovery::remove_panic_snapshot();
+        // A session that was never recovered is kept for next time.
+        if self.pending_recovery.is_none() {
+            self.autosave.discard().unwrap_or((/* ignore */));
+        }
+    }
+
+    fn handle_window_event(&mut self, event: WindowEvent) {
+        if self.pending_recovery.is_some() {
+            match event {
+                WindowEvent::Resized(_) | WindowEvent::ScaleFactorChanged { .. } => {
+                    self.handle_resize()
+                }
+                WindowEvent::KeyboardInput { input, .. }
+                    if input.state == ElementState::Pressed =>
+                {
+                    match input.virtual_keycode {
+                        Some(VirtualKeyCode::Y) => self.answer_recovery(true),
+                        Some(VirtualKeyCode::N) => self.answer_recovery(false),
+                        _ => (),
+                    }
+                }
+                _ => (),
+            }
+            return;
+        }
+        match event {
+            WindowEvent::Resized(_) | WindowEvent::ScaleFactorChanged { .. } => {
+                self.handle_resize()
+            }
+            WindowEvent::ModifiersChanged(modifiers) => self.modifiers = modifiers,
+            WindowEvent::KeyboardInput { input, .. } => self.handle_key(input),
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor = Point::new(position.x as i32, position.y as i32);
+                self.handle_input(InputEvent::PointerMove(self.cursor));
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                if let Some(button) = translate_button(button) {
+                    self.handle_input(match state {
+                        ElementState::Pressed => InputEvent::PointerDown(button, self.cursor),
+                        ElementState::Released => InputEvent::PointerUp(button, self.cursor),
+                    });
+                }
+            }
+            WindowEvent::MouseWheel { delta, .. } => self.handle_wheel(delta),
+            WindowEvent::DroppedFile(path) => {
+                let result = self.editor.borrow_mut().open_background(&path);
+                match result {
+                    Ok(response) => self.apply(response),
+                    Err(e) => {
+                        self.show_message(Some(&format!("Couldn't load the background: {}", e)))
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Opens the editor window, with the project at the path in the first argument if there is one,
+/// and runs it until it's closed.
+pub fn run() -> ! {
+    let project_path = std::env::args_os().nth(1).map(PathBuf::from);
+
+    let event_loop = EventLoop::new();
+    let window = WindowBuilder::new()
+        .with_title(TITLE)
+        .with_inner_size(LogicalSize::new(800.0, 800.0))
+        .with_min_inner_size(LogicalSize::new(200.0, 200.0));
+    let ctx = context_builder()
+        .build_windowed(window, &event_loop)
+        .expect("Failed to build opengl context");
+    let ctx = unsafe {
+        ctx.make_current()
+            .map_err(|(_, e)| e)
+            .expect("Failed to set opengl context as current")
+    };
+
+    let (clipboard, clipboard_error) = match PortableClipboard::new() {
+        Ok(clipboard) => (Some(clipboard), None),
+        Err(e) => (None, Some(format!("No clipboard: {}", e))),
+    };
+    let mut app = App {
+        canvas: OpenGlCanvas::new(ctx),
+        editor: Rc::new(RefCell::new(Editor::new())),
+        autosave: Autosave::new(recovery::default_path()),
+        clipboard,
+        project_path,
+        pending_recovery: None,
+        autosave_failed: false,
+        modifiers: ModifiersState::empty(),
+        cursor: Point::new(0, 0),
+        wheel: 0.0,
+    };
+    app.handle_resize();
+    app.show_message(clipboard_error.as_deref());
+    app.start();
+
+    let mut next_autosave = Instant::now() + AUTOSAVE_CHECK_INTERVAL;
+    event_loop.run(move |event, _, control_flow| {
+        *control_flow = ControlFlow::WaitUntil(next_autosave);
+        match event {
+            Event::WindowEvent {
+                event: WindowEvent::CloseRequested,
+                ..
+            } => {
+                app.exit();
+                *control_flow = ControlFlow::Exit;
+            }
+            Event::WindowEvent { event, .. } => app.handle_window_event(event),
+            Event::RedrawRequested(_) => app.render(),
+            Event::NewEvents(_) if Instant::now() >= next_autosave => {
+                app.autosave();
+                next_autosave = Instant::now() + AUTOSAVE_CHECK_INTERVAL;
+                *control_flow = ControlFlow::WaitUntil(next_autosave);
+            }
+            _ => (),
+        }
+    })
+}